@@ -60,7 +60,7 @@ fn main() {
 
                 println!("wire 1 recv ret: {:?}", ret);
 
-                if let Some(_) = ret.get("aaa") {
+                if ret.get("aaa").is_some() {
                     wire1.send(msg!{
                         CHAN: "hello",
                         CODE: 0i32,
@@ -9,8 +9,9 @@ use nson::MessageId;
 
 use crate::dict;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub enum Method {
+    #[default]
     Aes128Gcm,
     Aes256Gcm,
     ChaCha20Poly1305
@@ -47,12 +48,6 @@ impl FromStr for Method {
     }
 }
 
-impl Default for Method {
-    fn default() -> Self {
-        Method::Aes128Gcm
-    }
-}
-
 #[derive(Debug)]
 pub struct Crypto {
     inner: LessSafeKey
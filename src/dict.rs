@@ -8,6 +8,13 @@ pub const LEAVE:       &str = "_le";
 pub const PING:        &str = "_pi";
 pub const MINE:        &str = "_mi";
 pub const CUSTOM:      &str = "_cu";
+pub const SCHEDULE:    &str = "_sd";
+pub const CANCEL:      &str = "_ca";
+pub const GRANT:       &str = "_gr";
+pub const WILL:        &str = "_wi";
+pub const GOODBYE:     &str = "_gb";
+pub const UPDATE:      &str = "_up";
+pub const EXPIRED:     &str = "_xp";
 
 // params
 pub const SOCKET_ID:   &str = "_so";
@@ -18,12 +25,49 @@ pub const TO_SOCKET:   &str = "_ts";
 pub const FROM:        &str = "_fr";
 pub const FROM_SOCKET: &str = "_fs";
 pub const SHARE:       &str = "_sh";
+pub const GROUP:       &str = "_gp";
 pub const ATTR:        &str = "_at";
 pub const ADDR:        &str = "_ad";
 pub const CHANS:       &str = "_cs";
 pub const SHARE_CHANS: &str = "_sc";
+pub const SHARE_GROUPS: &str = "_sg";
 pub const JOINED:      &str = "_jd";
 pub const TAGS:        &str = "_tg";
+pub const ADD_TAGS:    &str = "_ta";
+pub const REMOVE_TAGS: &str = "_tr";
+pub const UNSET:       &str = "_un";
+pub const TTL:         &str = "_tt";
+pub const EXPIRE:      &str = "_ex";
+pub const DELAY:       &str = "_dy";
+pub const TIME:        &str = "_tm";
+pub const REQUEST:     &str = "_rq";
+pub const TIMEOUT:     &str = "_ot";
+pub const GATHER:      &str = "_ga";
+pub const MISSING:     &str = "_ms";
+pub const STREAM:      &str = "_sm";
+pub const STRATEGY:    &str = "_sy";
+pub const SHARE_KEY:   &str = "_sk";
+pub const FILTER:      &str = "_fl";
+pub const CONFIRM:     &str = "_cf";
+pub const QUEUED:      &str = "_qu";
+pub const DROPPED:     &str = "_dr";
+pub const ORIGIN:      &str = "_og";
+
+// tags expression
+pub const TAG_ALL:     &str = "$all";
+pub const TAG_ANY:     &str = "$any";
+pub const TAG_NONE:    &str = "$none";
+
+// filter
+pub const FILTER_EQ:     &str = "$eq";
+pub const FILTER_NE:     &str = "$ne";
+pub const FILTER_GT:     &str = "$gt";
+pub const FILTER_GTE:    &str = "$gte";
+pub const FILTER_LT:     &str = "$lt";
+pub const FILTER_LTE:    &str = "$lte";
+pub const FILTER_EXISTS: &str = "$exists";
+pub const FILTER_PREFIX: &str = "$prefix";
+pub const FILTER_IN:     &str = "$in";
 
 // message id
 pub const ID:        &str = "_id";
@@ -37,10 +81,19 @@ pub const SLOT_READY:  &str = "_slre";
 pub const SLOT_BREAK:  &str = "_slbr";
 pub const SLOT_ATTACH: &str = "_slat";
 pub const SLOT_DETACH: &str = "_slde";
+pub const SLOT_TAKEOVER: &str = "_slto";
+pub const SLOT_UPDATE: &str = "_slup";
 
 // attr
 pub const SEND_NUM:    &str = "_snum";
 pub const RECV_NUM:    &str = "_rnum";
+pub const WEIGHT:      &str = "_wt";
+pub const SESSION:     &str = "_sess";
+// limits
+pub const MESSAGE_RATE:    &str = "_mrat";
+pub const BYTE_RATE:       &str = "_brat";
+pub const MAX_CHANS:       &str = "_mchs";
+pub const MAX_SHARE_CHANS: &str = "_mscs";
 
 // crypto
 pub const AES_128_GCM:       &str = "A1G";
@@ -53,3 +106,13 @@ pub const KEEP_ALIVE:  &str = "_ke";
 
 pub const METHOD:      &str = "_me";
 pub const SECURE:      &str = "_se";
+
+// transfer
+pub const TRANSFER:    &str = "_tf";
+pub const SEQ:         &str = "_sq";
+pub const ACK:         &str = "_ak";
+pub const CREDIT:      &str = "_cr";
+pub const SIZE:        &str = "_sz";
+pub const CHECKSUM:    &str = "_ck";
+pub const FINISH:      &str = "_fi";
+pub const RESUME:      &str = "_rs";
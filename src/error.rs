@@ -96,6 +96,37 @@ pub enum Code {
     InvalidShareFieldType = 208,
     InvalidToSocketFieldType = 209,
     InvalidTagsFieldType = 210,
+    InvalidTtlFieldType = 211,
+    InvalidExpireFieldType = 212,
+    MessageExpired = 213,
+    InvalidDelayFieldType = 214,
+    InvalidTimeFieldType = 215,
+    InvalidIdFieldType = 216,
+    InvalidTimeoutFieldType = 217,
+    RequestTimeout = 218,
+    TargetSlotBroken = 219,
+    TransferSizeMismatch = 220,
+    TransferChecksumMismatch = 221,
+    RateLimited = 222,
+    TooManyChans = 223,
+    InvalidLimitFieldType = 224,
+    InvalidStrategyFieldType = 225,
+    InvalidTagsExpression = 226,
+    InvalidFilterFieldType = 227,
+    NoSubscribers = 228,
+    TargetSocketIdNotExist = 229,
+    WireFull = 230,
+    InvalidCreditFieldType = 231,
+    CreditExhausted = 232,
+    StreamCanceled = 233,
+    SlotTakenOver = 234,
+    InvalidSessionFieldType = 235,
+    InvalidWillFieldType = 236,
+    InvalidUpdateFieldType = 237,
+    DuplicateRequestId = 238,
+    ScheduleLimitExceeded = 239,
+    LimitGroupConflict = 240,
+    OfflineQueueFull = 241,
 
     InternalError = 300,
     UnsupportedFormat = 301,
@@ -132,6 +163,37 @@ impl Code {
             208 => Code::InvalidShareFieldType,
             209 => Code::InvalidToSocketFieldType,
             210 => Code::InvalidTagsFieldType,
+            211 => Code::InvalidTtlFieldType,
+            212 => Code::InvalidExpireFieldType,
+            213 => Code::MessageExpired,
+            214 => Code::InvalidDelayFieldType,
+            215 => Code::InvalidTimeFieldType,
+            216 => Code::InvalidIdFieldType,
+            217 => Code::InvalidTimeoutFieldType,
+            218 => Code::RequestTimeout,
+            219 => Code::TargetSlotBroken,
+            220 => Code::TransferSizeMismatch,
+            221 => Code::TransferChecksumMismatch,
+            222 => Code::RateLimited,
+            223 => Code::TooManyChans,
+            224 => Code::InvalidLimitFieldType,
+            225 => Code::InvalidStrategyFieldType,
+            226 => Code::InvalidTagsExpression,
+            227 => Code::InvalidFilterFieldType,
+            228 => Code::NoSubscribers,
+            229 => Code::TargetSocketIdNotExist,
+            230 => Code::WireFull,
+            231 => Code::InvalidCreditFieldType,
+            232 => Code::CreditExhausted,
+            233 => Code::StreamCanceled,
+            234 => Code::SlotTakenOver,
+            235 => Code::InvalidSessionFieldType,
+            236 => Code::InvalidWillFieldType,
+            237 => Code::InvalidUpdateFieldType,
+            238 => Code::DuplicateRequestId,
+            239 => Code::ScheduleLimitExceeded,
+            240 => Code::LimitGroupConflict,
+            241 => Code::OfflineQueueFull,
 
             300 => Code::InternalError,
             301 => Code::UnsupportedFormat,
@@ -164,6 +226,37 @@ impl Code {
             Code::InvalidShareFieldType => "InvalidShareFieldType",
             Code::InvalidToSocketFieldType => "InvalidToSocketFieldType",
             Code::InvalidTagsFieldType => "InvalidTagsFieldType",
+            Code::InvalidTtlFieldType => "InvalidTtlFieldType",
+            Code::InvalidExpireFieldType => "InvalidExpireFieldType",
+            Code::MessageExpired => "MessageExpired",
+            Code::InvalidDelayFieldType => "InvalidDelayFieldType",
+            Code::InvalidTimeFieldType => "InvalidTimeFieldType",
+            Code::InvalidIdFieldType => "InvalidIdFieldType",
+            Code::InvalidTimeoutFieldType => "InvalidTimeoutFieldType",
+            Code::RequestTimeout => "RequestTimeout",
+            Code::TargetSlotBroken => "TargetSlotBroken",
+            Code::TransferSizeMismatch => "TransferSizeMismatch",
+            Code::TransferChecksumMismatch => "TransferChecksumMismatch",
+            Code::RateLimited => "RateLimited",
+            Code::TooManyChans => "TooManyChans",
+            Code::InvalidLimitFieldType => "InvalidLimitFieldType",
+            Code::InvalidStrategyFieldType => "InvalidStrategyFieldType",
+            Code::InvalidTagsExpression => "InvalidTagsExpression",
+            Code::InvalidFilterFieldType => "InvalidFilterFieldType",
+            Code::NoSubscribers => "NoSubscribers",
+            Code::TargetSocketIdNotExist => "TargetSocketIdNotExist",
+            Code::WireFull => "WireFull",
+            Code::InvalidCreditFieldType => "InvalidCreditFieldType",
+            Code::CreditExhausted => "CreditExhausted",
+            Code::StreamCanceled => "StreamCanceled",
+            Code::SlotTakenOver => "SlotTakenOver",
+            Code::InvalidSessionFieldType => "InvalidSessionFieldType",
+            Code::InvalidWillFieldType => "InvalidWillFieldType",
+            Code::InvalidUpdateFieldType => "InvalidUpdateFieldType",
+            Code::DuplicateRequestId => "DuplicateRequestId",
+            Code::ScheduleLimitExceeded => "ScheduleLimitExceeded",
+            Code::LimitGroupConflict => "LimitGroupConflict",
+            Code::OfflineQueueFull => "OfflineQueueFull",
 
             Code::InternalError => "InternalError",
             Code::UnsupportedFormat => "UnsupportedFormat",
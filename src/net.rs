@@ -3,10 +3,14 @@ use crate::crypto::Method;
 pub use codec::{Codec, NsonCodec};
 pub use network::{Packet, NetWork};
 pub use keepalive::KeepAlive;
+pub use metrics::Metrics;
+pub use frame::FrameReader;
 
 mod codec;
 mod network;
 mod keepalive;
+mod metrics;
+mod frame;
 pub mod tcp_ext;
 
 #[derive(Debug, Clone)]
@@ -8,6 +8,12 @@ pub trait Codec: Send + 'static {
     fn decode(&mut self, crypto: &Option<Crypto>, bytes: Vec<u8>) -> Result<Message>;
 
     fn encode(&mut self, crypto: &Option<Crypto>, message: Message) -> Result<Vec<u8>>;
+
+    // 编码共享的消息，不获取所有权
+    // 未加密时，编码结果会被同一消息的其他接收者复用，因此结果只能与消息本身有关
+    fn encode_ref(&mut self, crypto: &Option<Crypto>, message: &Message) -> Result<Vec<u8>> {
+        self.encode(crypto, message.clone())
+    }
 }
 
 pub struct NsonCodec;
@@ -30,6 +36,10 @@ impl Codec for NsonCodec {
     }
 
     fn encode(&mut self, crypto: &Option<Crypto>, message: Message) -> Result<Vec<u8>> {
+        self.encode_ref(crypto, &message)
+    }
+
+    fn encode_ref(&mut self, crypto: &Option<Crypto>, message: &Message) -> Result<Vec<u8>> {
         let mut bytes = message.to_bytes().map_err(|err| Error::InvalidData(format!("{}", err)) )?;
 
         if let Some(crypto) = &crypto {
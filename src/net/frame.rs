@@ -0,0 +1,258 @@
+use std::io::{self, Read, ErrorKind::InvalidData};
+
+// 每次读取时至少保留的空闲空间
+const READ_CHUNK: usize = 64 * 1024;
+// 帧的最小长度，4 字节的长度加上至少 1 字节的内容
+const MIN_FRAME_LEN: usize = 5;
+
+// 按长度前缀分帧的读取器，长度为 4 字节小端序，并且包含长度本身
+// 每次尽量多读，一次读取可以解析出多个帧，缓冲区会被复用
+pub struct FrameReader {
+    buf: Vec<u8>,
+    // 未解析的数据为 buf[start..end]
+    start: usize,
+    end: usize,
+    max_len: usize
+}
+
+impl FrameReader {
+    pub fn new(max_len: usize) -> Self {
+        FrameReader {
+            buf: Vec::new(),
+            start: 0,
+            end: 0,
+            max_len
+        }
+    }
+
+    #[inline]
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    // 修改帧的最大长度，比如握手结束后连接交给网络线程时
+    #[inline]
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+    }
+
+    // 缓冲区中尚未解析的字节数
+    #[inline]
+    pub fn buffered(&self) -> usize {
+        self.end - self.start
+    }
+
+    // 从 reader 读取一次，返回读取的字节数，0 表示对方已关闭
+    pub fn read_from(&mut self, reader: &mut impl Read) -> io::Result<usize> {
+        self.reserve();
+
+        let size = reader.read(&mut self.buf[self.end..])?;
+        self.end += size;
+
+        Ok(size)
+    }
+
+    // 解析下一帧，数据不足时返回 None
+    // 长度不合法时返回 InvalidData，此时连接应当关闭
+    pub fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.buffered() < 4 {
+            return Ok(None)
+        }
+
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(&self.buf[self.start..self.start + 4]);
+
+        let len = u32::from_le_bytes(len_bytes) as usize;
+
+        if len < MIN_FRAME_LEN || len > self.max_len {
+            return Err(io::Error::new(InvalidData, format!("Invalid length of {}", len)))
+        }
+
+        if self.buffered() < len {
+            return Ok(None)
+        }
+
+        let frame = self.buf[self.start..self.start + len].to_vec();
+
+        self.start += len;
+
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+
+        Ok(Some(frame))
+    }
+
+    // 将未解析的数据移到缓冲区的开头，并保证有足够的空闲空间
+    fn reserve(&mut self) {
+        if self.start > 0 {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+
+        // 读取过大的帧之后，释放多余的内存
+        if self.end == 0 && self.buf.len() > READ_CHUNK * 4 {
+            self.buf = Vec::new();
+        }
+
+        if self.buf.len() - self.end < READ_CHUNK {
+            self.buf.resize(self.end + READ_CHUNK, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Cursor, ErrorKind::{InvalidData, WouldBlock}};
+
+    use rand::{Rng, SeedableRng, rngs::SmallRng};
+
+    use super::FrameReader;
+
+    fn frame(len: usize, fill: u8) -> Vec<u8> {
+        let mut frame = vec![fill; len];
+        frame[..4].copy_from_slice(&(len as u32).to_le_bytes());
+        frame
+    }
+
+    // 每次返回随机长度的数据，模拟任意的分割点
+    struct SplitReader {
+        data: Vec<u8>,
+        pos: usize,
+        rand: SmallRng
+    }
+
+    impl Read for SplitReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos == self.data.len() {
+                return Ok(0)
+            }
+
+            // 偶尔返回 WouldBlock
+            if self.rand.gen_ratio(1, 8) {
+                return Err(io::Error::new(WouldBlock, "WouldBlock"))
+            }
+
+            let remain = self.data.len() - self.pos;
+            let size = self.rand.gen_range(1..=remain.min(buf.len()).min(300));
+
+            buf[..size].copy_from_slice(&self.data[self.pos..self.pos + size]);
+            self.pos += size;
+
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn multiple_frames() {
+        let mut data = frame(10, 1);
+        data.extend(frame(5, 2));
+        data.extend(frame(100, 3));
+
+        let mut reader = FrameReader::new(1024);
+        let mut cursor = Cursor::new(data);
+
+        assert!(reader.read_from(&mut cursor).unwrap() == 115);
+
+        assert!(reader.next_frame().unwrap().unwrap() == frame(10, 1));
+        assert!(reader.next_frame().unwrap().unwrap() == frame(5, 2));
+        assert!(reader.next_frame().unwrap().unwrap() == frame(100, 3));
+        assert!(reader.next_frame().unwrap().is_none());
+        assert!(reader.buffered() == 0);
+
+        assert!(reader.read_from(&mut cursor).unwrap() == 0);
+    }
+
+    #[test]
+    fn random_split() {
+        for seed in 0..50 {
+            let mut rand = SmallRng::seed_from_u64(seed);
+
+            let frames: Vec<Vec<u8>> = (0..rand.gen_range(1..50))
+                .map(|i| frame(rand.gen_range(5..2000), i as u8))
+                .collect();
+
+            let mut reader = SplitReader {
+                data: frames.concat(),
+                pos: 0,
+                rand: SmallRng::seed_from_u64(seed + 1000)
+            };
+
+            let mut frame_reader = FrameReader::new(2000);
+            let mut recv = vec![];
+
+            loop {
+                while let Some(frame) = frame_reader.next_frame().unwrap() {
+                    recv.push(frame);
+                }
+
+                match frame_reader.read_from(&mut reader) {
+                    Ok(0) => break,
+                    Ok(_) => (),
+                    Err(err) => assert!(err.kind() == WouldBlock)
+                }
+            }
+
+            assert!(recv == frames);
+            assert!(frame_reader.buffered() == 0);
+        }
+    }
+
+    #[test]
+    fn large_frame() {
+        let data = frame(1024 * 1024, 7);
+
+        let mut reader = SplitReader {
+            data: [data.clone(), frame(5, 8)].concat(),
+            pos: 0,
+            rand: SmallRng::seed_from_u64(0)
+        };
+
+        let mut frame_reader = FrameReader::new(2 * 1024 * 1024);
+        let mut recv = vec![];
+
+        loop {
+            while let Some(frame) = frame_reader.next_frame().unwrap() {
+                recv.push(frame);
+            }
+
+            match frame_reader.read_from(&mut reader) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(err) => assert!(err.kind() == WouldBlock)
+            }
+        }
+
+        assert!(recv == vec![data, frame(5, 8)]);
+    }
+
+    #[test]
+    fn malformed_length() {
+        for len in [0u32, 1, 4, 2001, u32::MAX] {
+            let mut frame_reader = FrameReader::new(2000);
+
+            // 读到长度后立即报错，不等待整帧
+            let mut cursor = Cursor::new(len.to_le_bytes().to_vec());
+            assert!(frame_reader.read_from(&mut cursor).unwrap() == 4);
+
+            let err = frame_reader.next_frame().unwrap_err();
+            assert!(err.kind() == InvalidData);
+        }
+
+        // 随机的数据，只能正常解析或者报错，不能 panic
+        for seed in 0..200 {
+            let mut rand = SmallRng::seed_from_u64(seed);
+
+            let data: Vec<u8> = (0..rand.gen_range(0..64)).map(|_| rand.gen()).collect();
+
+            let mut frame_reader = FrameReader::new(32);
+            let _ = frame_reader.read_from(&mut Cursor::new(data));
+
+            while let Ok(Some(frame)) = frame_reader.next_frame() {
+                assert!(frame.len() >= 5 && frame.len() <= 32);
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// 网络层的统计数据，由同一个 Node 或 Port 的所有网络线程共享
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // 写入连接前发现已过期的消息数，这些消息以 EXPIRED 交给 Socket 放入死信频道
+    pub expired: AtomicUsize,
+    // 写入连接的系统调用次数
+    pub writes: AtomicUsize,
+    // 写入连接的消息数
+    pub write_messages: AtomicUsize,
+    // 完成的握手数
+    pub handshakes: AtomicUsize,
+    // 超时未完成而中止的握手数
+    pub handshake_timeouts: AtomicUsize,
+    // 因协议错误、验证失败或连接断开而中止的握手数
+    pub handshake_failures: AtomicUsize,
+    // 进行中的握手过多而被直接关闭的连接数
+    pub handshake_rejects: AtomicUsize,
+    // 因允许和拒绝列表而被关闭的连接数
+    pub deny_rejects: AtomicUsize,
+    // 来源被封禁而被关闭的连接数
+    pub ban_rejects: AtomicUsize,
+    // 超过接受新连接的速率而被关闭的连接数
+    pub rate_rejects: AtomicUsize,
+    // 超过最大连接数而被关闭的连接数
+    pub limit_rejects: AtomicUsize,
+    // 因连续握手失败而封禁来源的次数
+    pub bans: AtomicUsize
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 平均每条消息的写入系统调用次数，多条消息合并写入时小于 1
+    pub fn writes_per_message(&self) -> f64 {
+        let messages = self.write_messages.load(Ordering::Relaxed);
+
+        if messages == 0 {
+            return 0.0
+        }
+
+        self.writes.load(Ordering::Relaxed) as f64 / messages as f64
+    }
+
+    // 中止的握手总数
+    pub fn handshake_aborts(&self) -> usize {
+        self.handshake_timeouts.load(Ordering::Relaxed) +
+        self.handshake_failures.load(Ordering::Relaxed) +
+        self.handshake_rejects.load(Ordering::Relaxed)
+    }
+}
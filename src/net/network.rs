@@ -1,12 +1,10 @@
 use std::time::Instant;
 use std::time::Duration;
 use std::io::{
-    self,
     Write,
-    Read,
-    ErrorKind::{WouldBlock, Interrupted, InvalidData, BrokenPipe}
+    ErrorKind::{WouldBlock, Interrupted}
 };
-use std::mem;
+use std::sync::{Arc, atomic::Ordering};
 
 use queen_io::{
     epoll::{Epoll, Event, Events, Token, Ready, EpollOpt},
@@ -23,17 +21,29 @@ use crate::crypto::Crypto;
 use crate::error::{Error, Result, RecvError, Code};
 use crate::dict::*;
 use crate::timer::wheel::Wheel;
+use crate::util::message::{now_millis, is_expired};
+use crate::node::ConnGuard;
 use crate::MAX_MESSAGE_LEN;
 
 use super::Codec;
 use super::KeepAlive;
+use super::Metrics;
+use super::frame::FrameReader;
 
+// 每次合并写入的字节数上限，单条消息超出时单独写入
+const WRITE_BUDGET: usize = 64 * 1024;
+
+#[allow(clippy::large_enum_variant)]
 pub enum Packet<C: Codec> {
     NewConn {
         wire: Wire<Message>,
         stream: TcpStream,
         codec: C,
-        crypto: Option<Crypto>
+        crypto: Option<Crypto>,
+        // Node 的连接占位，连接关闭时释放
+        guard: Option<ConnGuard>,
+        // 握手时的读取器，其中可能有握手之后已经读到的数据
+        reader: Option<FrameReader>
     },
     Close
 }
@@ -49,13 +59,17 @@ pub struct NetWork<C: Codec> {
     timer_id_counter: usize,
     wheel: Wheel<(usize, usize)>,
     instant: Instant,
+    metrics: Arc<Metrics>,
+    // 过期的消息以 EXPIRED 交给 Socket 放入死信频道
+    // 为 true 时交回给 Wire 的另一端（Node），否则发送给对端（Port）
+    return_expired: bool
 }
 
 impl<C: Codec> NetWork<C> {
     const QUEUE_TOKEN: usize = usize::MAX;
     const TIMER_TOKEN: usize = usize::MAX - 1;
 
-    pub fn new(queue: Queue<Packet<C>>, keep_alive: KeepAlive) -> Result<Self> {
+    pub fn new(queue: Queue<Packet<C>>, keep_alive: KeepAlive, metrics: Arc<Metrics>) -> Result<Self> {
         Ok(Self {
             epoll: Epoll::new()?,
             events: Events::with_capacity(1024),
@@ -66,10 +80,16 @@ impl<C: Codec> NetWork<C> {
             timer: TimerFd::new()?,
             timer_id_counter: 0,
             wheel: Wheel::default(),
-            instant: Instant::now()
+            instant: Instant::now(),
+            metrics,
+            return_expired: false
         })
     }
 
+    pub fn set_return_expired(&mut self, return_expired: bool) {
+        self.return_expired = return_expired;
+    }
+
     fn next_timer_id(&mut self) -> usize {
         self.timer_id_counter = self.timer_id_counter.wrapping_add(1);
         self.timer_id_counter
@@ -121,7 +141,7 @@ impl<C: Codec> NetWork<C> {
     fn dispatch_queue(&mut self) -> Result<bool> {
         if let Some(packet) = self.queue.pop() {
             match packet {
-                Packet::NewConn { wire, stream, codec, crypto } => {
+                Packet::NewConn { wire, stream, codec, crypto, guard, reader } => {
                     let time_id = self.next_timer_id();
 
                     let entry1 = self.wires.vacant_entry();
@@ -146,21 +166,36 @@ impl<C: Codec> NetWork<C> {
                         EpollOpt::edge()
                     )?;
 
-                    let conn = Conn::new(
+                    // 握手之后已经读到的数据不会再触发可读事件，需要直接处理
+                    let buffered = reader.as_ref().is_some_and(|reader| reader.buffered() > 0);
+
+                    let mut conn = Conn::new(
                         token2,
                         stream,
                         codec,
                         crypto,
                         time_id,
-                        self.keep_alive.clone()
+                        self.keep_alive.clone(),
+                        guard
                     );
 
+                    if let Some(mut reader) = reader {
+                        reader.set_max_len(MAX_MESSAGE_LEN);
+                        conn.reader = reader;
+                    }
+
+                    conn.return_expired = self.return_expired;
+
                     // timer
                     self.wheel.insert((token, time_id), conn.keep_alive.idle).expect("can't insert id into wheel");
 
                     entry1.insert(wire);
                     entry2.insert(conn);
-                                }
+
+                    if buffered {
+                        self.dispatch_conn(token / 2, Ready::readable())?;
+                    }
+                }
                 Packet::Close => {
                     return Ok(true)
                 }
@@ -201,7 +236,7 @@ impl<C: Codec> NetWork<C> {
                             };
 
                             conn.push_message(message)?;
-                            conn.write(&self.wires[index])?;
+                            conn.write(&self.wires[index], &self.metrics)?;
                         }
                     } else {
                         self.remove_conn(index)?;
@@ -216,7 +251,7 @@ impl<C: Codec> NetWork<C> {
     fn dispatch_io(&mut self, event: Event) -> Result<()> {
         let token = event.token().0;
 
-        if token % 2 == 0 {
+        if token.is_multiple_of(2) {
             self.dispatch_wire(token / 2)?;
         } else {
             self.dispatch_conn(token / 2, event.readiness())?;
@@ -230,7 +265,7 @@ impl<C: Codec> NetWork<C> {
 
         if let Some(conn) = self.conns.get_mut(index) {
             if conn.writable {
-                let ret = conn.write(&self.wires[index]);
+                let ret = conn.write(&self.wires[index], &self.metrics);
                 if ret.is_err() {
                     log::debug!("conn.read: {:?}", ret);
                     remove = true;
@@ -252,7 +287,7 @@ impl<C: Codec> NetWork<C> {
             if let Some(conn) = self.conns.get_mut(index) {
                 conn.keep_alive.reset(self.instant);
 
-                let ret = conn.read(&self.wires[index]);
+                let ret = conn.read(&self.wires[index], &self.metrics);
                 if ret.is_err() {
                     log::debug!("conn.read: {:?}", ret);
                     remove = true;
@@ -262,7 +297,7 @@ impl<C: Codec> NetWork<C> {
 
         if ready.is_writable() {
             if let Some(conn) = self.conns.get_mut(index) {
-                let ret = conn.write(&self.wires[index]);
+                let ret = conn.write(&self.wires[index], &self.metrics);
                 if ret.is_err() {
                     log::debug!("conn.read: {:?}", ret);
                     remove = true;
@@ -292,56 +327,69 @@ struct Conn<C: Codec> {
     token: usize,
     stream: TcpStream,
     writable: bool,
-    r_buffer: Buffer,
+    reader: FrameReader,
     w_buffer: Buffer,
     codec: C,
     crypto: Option<Crypto>,
     timer_id: usize,
-    keep_alive: KeepAlive
+    keep_alive: KeepAlive,
+    return_expired: bool,
+    _guard: Option<ConnGuard>
 }
 
 impl<C: Codec> Conn<C> {
-    fn new(token: usize, stream: TcpStream, codec: C, crypto: Option<Crypto>, timer_id: usize, mut keep_alive: KeepAlive) -> Self {
+    fn new(
+        token: usize,
+        stream: TcpStream,
+        codec: C,
+        crypto: Option<Crypto>,
+        timer_id: usize,
+        mut keep_alive: KeepAlive,
+        guard: Option<ConnGuard>
+    ) -> Self {
         keep_alive.reset(Instant::now());
 
         Self {
             token,
             stream,
             writable: true,
-            r_buffer: Buffer::new(),
+            reader: FrameReader::new(MAX_MESSAGE_LEN),
             w_buffer: Buffer::new(),
             codec,
             crypto,
             timer_id,
-            keep_alive
+            keep_alive,
+            return_expired: false,
+            _guard: guard
         }
     }
 
-    fn read(&mut self, wire: &Wire<Message>) -> Result<()> {
+    fn read(&mut self, wire: &Wire<Message>, metrics: &Metrics) -> Result<()> {
         loop {
-            let ret = read(&mut self.stream, &mut self.r_buffer);
+            // 一次读取可能包含多个帧
+            while let Some(bytes) = self.reader.next_frame()? {
+                let mut message = self.codec.decode(&self.crypto, bytes)?;
 
-            match ret {
-                Ok(ret) => {
-                    if let Some(bytes) = ret {
-                        let mut message = self.codec.decode(&self.crypto, bytes)?;
+                if message.get_str(CHAN) == Ok(KEEP_ALIVE) {
+                    log::debug!("recv keep alive message, addr: {:?}", self.stream.peer_addr()?);
 
-                        if message.get_str(CHAN) == Ok(KEEP_ALIVE) {
-                            log::debug!("recv keep alive message, addr: {:?}", self.stream.peer_addr()?);
+                    if Code::get(&message).is_none() && self.w_buffer.is_empty() {
+                        Code::Ok.set(&mut message);
 
-                            if Code::get(&message).is_none() {
-                                if self.w_buffer.is_empty() {
-                                    Code::Ok.set(&mut message);
+                        self.push_message(message)?;
+                        self.write(wire, metrics)?;
+                    }
 
-                                    self.push_message(message)?;
-                                    self.write(wire)?;
-                                }
-                            }
+                    continue
+                }
 
-                            continue
-                        }
+                let _ = wire.send(message);
+            }
 
-                        let _ = wire.send(message);
+            match self.reader.read_from(&mut self.stream) {
+                Ok(size) => {
+                    if size == 0 {
+                        return Err(Error::BrokenPipe("stream.read".to_string()))
                     }
                 }
                 Err(err) => {
@@ -359,75 +407,106 @@ impl<C: Codec> Conn<C> {
         Ok(())
     }
 
-    fn write(&mut self, wire: &Wire<Message>) -> Result<()> {
+    fn write(&mut self, wire: &Wire<Message>, metrics: &Metrics) -> Result<()> {
         loop {
-            if !self.w_buffer.is_empty() {
-                match self.stream.write(&self.w_buffer.buf[self.w_buffer.pos..]) {
-                    Ok(size) => {
-                        if size == 0 {
-                            return Err(Error::BrokenPipe("stream.write".to_string()))
-                        } else if size >= self.w_buffer.buf.len() - self.w_buffer.pos {
-                            self.w_buffer.reset();
-                        } else {
-                            self.w_buffer.pos += size;
-                        }
+            if self.w_buffer.is_empty() {
+                // 一次取出多条消息，合并后再写入，减少系统调用
+                let messages = self.fill(wire, metrics)?;
 
-                        self.writable = true;
+                if messages == 0 {
+                    break;
+                }
+
+                metrics.write_messages.fetch_add(messages, Ordering::Relaxed);
+            }
+
+            metrics.writes.fetch_add(1, Ordering::Relaxed);
+
+            match self.stream.write(&self.w_buffer.buf[self.w_buffer.pos..]) {
+                Ok(size) => {
+                    if size == 0 {
+                        return Err(Error::BrokenPipe("stream.write".to_string()))
+                    } else if size >= self.w_buffer.buf.len() - self.w_buffer.pos {
+                        self.w_buffer.clear();
+                    } else {
+                        self.w_buffer.pos += size;
                     }
-                    Err(err) => {
-                        if err.kind() == WouldBlock {
-                            self.writable = false;
-                            break;
-                        } else if err.kind() == Interrupted {
-                            continue;
-                        } else {
-                            return Err(err.into())
-                        }
+
+                    self.writable = true;
+                }
+                Err(err) => {
+                    if err.kind() == WouldBlock {
+                        self.writable = false;
+                        break;
+                    } else if err.kind() == Interrupted {
+                        continue;
+                    } else {
+                        return Err(err.into())
                     }
                 }
             }
+        }
 
-            if self.w_buffer.is_empty() {
-                match wire.recv() {
-                    Ok(message) => {
-                        let bytes = self.codec.encode(&self.crypto, message)?;
-
-                        match self.stream.write(&bytes) {
-                            Ok(size) => {
-                                if size == 0 {
-                                    return Err(Error::BrokenPipe("stream.write".to_string()))
-                                } else if size < bytes.len() {
-                                    self.w_buffer.buf.extend(&bytes[..size]);
-                                }
-
-                                self.writable = true;
-                            }
-                            Err(err) => {
-                                if err.kind() == WouldBlock {
-                                    self.w_buffer.buf.extend(bytes);
-                                    self.writable = false;
-                                    break;
-                                } else if err.kind() == Interrupted {
-                                    self.w_buffer.buf.extend(bytes);
-                                    continue;
-                                } else {
-                                    return Err(err.into())
-                                }
-                            }
+        Ok(())
+    }
+
+    // 从 Wire 中取出消息编码后放入 w_buffer，直到超出 WRITE_BUDGET，返回消息数
+    fn fill(&mut self, wire: &Wire<Message>, metrics: &Metrics) -> Result<usize> {
+        let mut messages = 0;
+
+        while self.w_buffer.buf.len() < WRITE_BUDGET {
+            match wire.recv_shared() {
+                Ok(message) => {
+                    // 消息在 Wire 中等待期间可能已经过期，交给 Socket 放入死信频道
+                    if message.contains_key(EXPIRE) && is_expired(&message, now_millis()) {
+                        metrics.expired.fetch_add(1, Ordering::Relaxed);
+
+                        let expired = msg!{
+                            CHAN: EXPIRED,
+                            VALUE: message.get().clone()
+                        };
+
+                        if self.return_expired {
+                            let _ = wire.send(expired);
+                        } else {
+                            self.push_message(expired)?;
+                            messages += 1;
                         }
-                    },
-                    Err(err) => {
-                        if matches!(err, RecvError::Empty) {
-                            break;
+
+                        continue
+                    }
+
+                    // 未加密时，同一消息的多个接收者共用一份编码结果
+                    // 最后一个接收者只读取缓存，仍有其他接收者时才需要缓存新的编码结果
+                    if self.crypto.is_none() {
+                        if let Some(bytes) = message.cached::<C>() {
+                            self.w_buffer.buf.extend_from_slice(&bytes);
+                        } else if Arc::strong_count(&message) > 1 {
+                            let codec = &mut self.codec;
+                            let bytes = message.encoded::<C, _>(|message| codec.encode_ref(&None, message))?;
+                            self.w_buffer.buf.extend_from_slice(&bytes);
                         } else {
-                            return Err(err.into())
+                            let bytes = self.codec.encode_ref(&None, &message)?;
+                            self.w_buffer.buf.extend_from_slice(&bytes);
                         }
+                    } else {
+                        let bytes = self.codec.encode_ref(&self.crypto, &message)?;
+                        self.w_buffer.buf.extend_from_slice(&bytes);
+                    }
+
+                    messages += 1;
+                },
+                Err(err) => {
+                    if matches!(err, RecvError::Empty) {
+                        break;
+                    } else {
+                        return Err(err.into())
                     }
                 }
             }
         }
 
-        Ok(())
+        Ok(messages)
     }
 
     fn push_message(&mut self, message: Message) -> Result<()> {
@@ -459,94 +538,14 @@ impl Buffer {
         self.buf = Vec::new();
         self.pos = 0;
     }
-}
-
-fn read(stream: &mut TcpStream, buffer: &mut Buffer) -> io::Result<Option<Vec<u8>>> {
-    if buffer.buf.is_empty() {
-        let mut len_bytes = [0u8; 4];
-        let size = stream.peek(&mut len_bytes)?;
-
-        if size == 0 {
-            return Err(io::Error::new(BrokenPipe, "BrokenPipe"))
-        } else if size < 4 {
-            let size = stream.read(&mut len_bytes[..size])?;
-            if size == 0 {
-                return Err(io::Error::new(BrokenPipe, "BrokenPipe"))
-            }
-
-            buffer.pos = size;
-            buffer.buf = len_bytes.to_vec();
-
-            return Ok(None)
-        }
 
-        let len = u32::from_le_bytes(len_bytes) as usize;
-        if len < 5 || len > MAX_MESSAGE_LEN {
-            return Err(io::Error::new(InvalidData, format!("Invalid length of {}", len)))
-        }
-
-        // let mut buf = vec![0u8; len];
-        let mut buf = Vec::with_capacity(len);
-        unsafe { buf.set_len(len); }
-
-        let size = stream.read(&mut buf)?;
-
-        if size == 0 {
-            return Err(io::Error::new(BrokenPipe, "BrokenPipe"))
-        } else if size < len {
-            buffer.pos = size;
-            buffer.buf = buf;
-
-            return Ok(None)
-        }
-
-        // assert!(size == len);
-        return Ok(Some(buf))
-    } else {
-        if buffer.pos < 4 {
-            let size = stream.read(&mut buffer.buf[buffer.pos..])?;
-            if size == 0 {
-                return Err(io::Error::new(BrokenPipe, "BrokenPipe"))
-            }
-
-            buffer.pos += size;
-
-            if buffer.pos == 4 {
-                let len = read_u32(&buffer.buf, 0);
-                if len < 5 || len > MAX_MESSAGE_LEN {
-                    return Err(io::Error::new(InvalidData, format!("Invalid length of {}", len)))
-                }
-
-                buffer.pos = 4;
-                buffer.buf.reserve(len - 4);
-                unsafe { buffer.buf.set_len(len); }
-            }
+    // 保留已分配的内存，供下次写入复用，过大时才释放
+    fn clear(&mut self) {
+        if self.buf.capacity() > WRITE_BUDGET * 4 {
+            self.reset();
         } else {
-            let size = stream.read(&mut buffer.buf[buffer.pos..])?;
-            if size == 0 {
-                return Err(io::Error::new(BrokenPipe, "BrokenPipe"))
-            }
-
-            buffer.pos += size;
-
-            if buffer.pos == buffer.buf.len() {
-                let mut vec = Vec::new();
-                mem::swap(&mut buffer.buf, &mut vec);
-                buffer.pos = 0;
-
-                return Ok(Some(vec))
-            }
+            self.buf.clear();
+            self.pos = 0;
         }
     }
-
-    return Ok(None)
-}
-
-fn read_u32(buf: &[u8], start: usize) -> usize {
-    (
-        u32::from(buf[start]) |
-        u32::from(buf[start + 1]) << 8 |
-        u32::from(buf[start + 2]) << 16 |
-        u32::from(buf[start + 3]) << 24
-    ) as usize
 }
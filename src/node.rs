@@ -1,36 +1,45 @@
 use std::io::ErrorKind::{WouldBlock, Interrupted};
-use std::io::Write;
 use std::os::unix::io::AsRawFd;
 use std::thread;
 use std::time::Duration;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, IpAddr};
 use std::sync::{
     Arc,
     atomic::{AtomicBool, Ordering}
 };
-use std::str::FromStr;
 
 use queen_io::{
     epoll::{Epoll, Events, Token, Ready, EpollOpt},
     queue::mpsc::Queue,
-    net::tcp::{TcpListener, TcpStream}
+    net::tcp::TcpListener,
+    plus::slab::Slab
 };
+use queen_io::sys::timerfd::{TimerFd, TimerSpec, SetTimeFlags};
 
 use rand::{SeedableRng, seq::SliceRandom, rngs::SmallRng};
 
-use nson::{Message, MessageId};
+use nson::Message;
 
 use crate::Socket;
 use crate::Wire;
-use crate::net::{NetWork, Packet, Codec, KeepAlive};
-use crate::crypto::{Crypto, Method};
-use crate::dict::*;
-use crate::util::message::read_block;
+use crate::net::{NetWork, Packet, Codec, KeepAlive, Metrics};
+use crate::timer::wheel::Wheel;
 use crate::error::{Result, Error, Code};
 
 pub use hook::{Hook, NonHook};
+pub use options::NodeOptions;
+pub use limits::ConnGuard;
+
+use handshake::Handshake;
+use limits::{Limits, Reject};
 
 mod hook;
+mod options;
+mod handshake;
+mod limits;
+
+// 握手定时器的间隔，单位毫秒
+const HANDSHAKE_TICK: u64 = 100;
 
 pub trait Connector: Send + 'static {
     fn connect(
@@ -40,6 +49,13 @@ pub trait Connector: Send + 'static {
         timeout: Option<Duration>
     ) -> Result<Wire<Message>>;
 
+    // 不等待结果，Wire 收到的第一个消息即为结果，握手时使用，避免阻塞 Node 的线程
+    fn open(
+        &self,
+        attr: Message,
+        capacity: Option<usize>
+    ) -> Result<Wire<Message>>;
+
     fn running(&self) -> bool;
 }
 
@@ -53,6 +69,14 @@ impl Connector for Socket {
         self.connect(attr, capacity, timeout)
     }
 
+    fn open(
+        &self,
+        attr: Message,
+        capacity: Option<usize>
+    ) -> Result<Wire<Message>> {
+        self.open(attr, capacity)
+    }
+
     fn running(&self) -> bool {
         self.running()
     }
@@ -61,7 +85,9 @@ impl Connector for Socket {
 pub struct Node<C: Codec> {
     #[allow(clippy::rc_buffer)]
     queues: Arc<Vec<Queue<Packet<C>>>>,
-    run: Arc<AtomicBool>
+    run: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+    limits: Arc<Limits>
 }
 
 impl<C: Codec> Node<C> {
@@ -71,6 +97,21 @@ impl<C: Codec> Node<C> {
         addrs: Vec<SocketAddr>,
         keep_alive: KeepAlive,
         hook: impl Hook
+    ) -> Result<Self> {
+        let options = NodeOptions {
+            keep_alive,
+            ..NodeOptions::default()
+        };
+
+        Self::with_options(connector, worker_num, addrs, options, hook)
+    }
+
+    pub fn with_options(
+        connector: impl Connector,
+        worker_num: usize,
+        addrs: Vec<SocketAddr>,
+        options: NodeOptions,
+        hook: impl Hook
     ) -> Result<Self> {
         let mut queues = Vec::new();
 
@@ -81,14 +122,16 @@ impl<C: Codec> Node<C> {
 
         let node = Self {
             queues: Arc::new(queues),
-            run: Arc::new(AtomicBool::new(true))
+            run: Arc::new(AtomicBool::new(true)),
+            metrics: Arc::new(Metrics::new()),
+            limits: Arc::new(Limits::new(&options))
         };
 
         let mut inner: Inner<C, _> = Inner::new(
             node.clone(),
             connector,
             addrs,
-            keep_alive,
+            options,
             hook
         )?;
 
@@ -112,6 +155,36 @@ impl<C: Codec> Node<C> {
     pub fn running(&self) -> bool {
         self.run.load(Ordering::Relaxed)
     }
+
+    #[inline]
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    // 当前的连接数，包括握手中的连接
+    #[inline]
+    pub fn connections(&self) -> usize {
+        self.limits.conns()
+    }
+
+    // 地址所属来源的连接数
+    pub fn connections_of(&self, ip: IpAddr) -> usize {
+        self.limits.conns_of(ip)
+    }
+
+    // 临时封禁地址所属的来源，已建立的连接不受影响
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.limits.ban(ip, duration)
+    }
+
+    pub fn unban(&self, ip: IpAddr) {
+        self.limits.unban(ip)
+    }
+
+    // 被封禁的来源和剩余的封禁时间
+    pub fn bans(&self) -> Vec<(IpAddr, Duration)> {
+        self.limits.bans()
+    }
 }
 
 struct Inner<C: Codec, H: Hook> {
@@ -120,16 +193,26 @@ struct Inner<C: Codec, H: Hook> {
     epoll: Epoll,
     events: Events,
     listens: Vec<TcpListener>,
+    options: NodeOptions,
+    // 进行中的握手，连接的 Token 为其索引的 2 倍，等待 Socket 结果的 Wire 的 Token 为 2 倍加 1
+    handshakes: Slab<Handshake<C>>,
+    timer: TimerFd,
+    timer_id_counter: usize,
+    wheel: Wheel<(usize, usize)>,
     rand: SmallRng,
     hook: H,
 }
 
 impl<C: Codec, H: Hook> Inner<C, H> {
+    const TIMER_TOKEN: usize = usize::MAX;
+    // 监听的 Token 从 LISTEN_TOKEN 开始递减
+    const LISTEN_TOKEN: usize = usize::MAX - 1;
+
     fn new(
         node: Node<C>,
         connector: impl Connector,
         addrs: Vec<SocketAddr>,
-        keep_alive: KeepAlive,
+        options: NodeOptions,
         hook: H
     ) -> Result<Self> {
         let mut listens = Vec::new();
@@ -139,7 +222,13 @@ impl<C: Codec, H: Hook> Inner<C, H> {
         }
 
         for queue in node.queues.iter() {
-            let mut net_work = NetWork::<C>::new(queue.clone(), keep_alive.clone())?;
+            let mut net_work = NetWork::<C>::new(
+                queue.clone(),
+                options.keep_alive.clone(),
+                node.metrics.clone()
+            )?;
+
+            net_work.set_return_expired(true);
 
             let run2 = node.run.clone();
 
@@ -159,8 +248,13 @@ impl<C: Codec, H: Hook> Inner<C, H> {
             node,
             connector: Box::new(connector),
             epoll: Epoll::new()?,
-            events: Events::with_capacity(16),
+            events: Events::with_capacity(256),
             listens,
+            options,
+            handshakes: Slab::new(),
+            timer: TimerFd::new()?,
+            timer_id_counter: 0,
+            wheel: Wheel::default(),
             hook,
             rand: SmallRng::from_entropy()
         })
@@ -171,11 +265,25 @@ impl<C: Codec, H: Hook> Inner<C, H> {
         self.node.run.load(Ordering::Relaxed)
     }
 
+    fn next_timer_id(&mut self) -> usize {
+        self.timer_id_counter = self.timer_id_counter.wrapping_add(1);
+        self.timer_id_counter
+    }
+
     pub fn run(&mut self) -> Result<()> {
         for (id, listen) in self.listens.iter().enumerate() {
-            self.epoll.add(&listen.as_raw_fd(), Token(id), Ready::readable(), EpollOpt::edge())?;
+            self.epoll.add(&listen.as_raw_fd(), Token(Self::LISTEN_TOKEN - id), Ready::readable(), EpollOpt::edge())?;
         }
 
+        self.epoll.add(&self.timer, Token(Self::TIMER_TOKEN), Ready::readable(), EpollOpt::edge())?;
+
+        let timerspec = TimerSpec {
+            interval: Duration::from_millis(HANDSHAKE_TICK),
+            value: Duration::from_millis(HANDSHAKE_TICK)
+        };
+
+        self.timer.settime(timerspec, SetTimeFlags::Default)?;
+
         while self.running() && self.connector.running() {
             let size = match self.epoll.wait(&mut self.events, None) {
                 Ok(size) => size,
@@ -190,54 +298,14 @@ impl<C: Codec, H: Hook> Inner<C, H> {
 
             for i in 0..size {
                 let event = self.events.get(i).unwrap();
-                let token = event.token();
-
-                if let Some(listen) = self.listens.get(token.0) {
-                    loop {
-                        let (mut stream, addr) = match listen.accept() {
-                            Ok(stream) => stream,
-                            Err(err) => {
-                                if err.kind() == WouldBlock {
-                                    break;
-                                } else {
-                                    return Err(err.into())
-                                }
-                            }
-                        };
-
-                        if !self.hook.accept(&mut stream) {
-                            continue;
-                        }
-
-                        stream.set_nodelay(true)?;
-                        // 握手开始
-                        stream.set_nonblocking(false)?;
-                        // 连接成功后，5秒内收不到握手消息应当断开
-                        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
-                        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
-
-                        let (wire, codec, crypto) = match Self::hand(&self.hook, &*self.connector, &mut stream, &addr) {
-                            Ok(ret) => ret,
-                            Err(err) => {
-                                log::debug!("{}", err);
-                                continue;
-                            }
-                        };
-
-                        stream.set_nonblocking(true)?;
-                        stream.set_read_timeout(None)?;
-                        stream.set_write_timeout(None)?;
-                        // 握手结束
-
-                        if let Some(queue) = self.node.queues.choose(&mut self.rand) {
-                            queue.push(Packet::NewConn {
-                                wire,
-                                stream,
-                                codec,
-                                crypto
-                            })
-                        }
-                    }
+                let token = event.token().0;
+
+                if token == Self::TIMER_TOKEN {
+                    self.dispatch_timer()?;
+                } else if token > Self::LISTEN_TOKEN - self.listens.len() {
+                    self.accept(Self::LISTEN_TOKEN - token)?;
+                } else {
+                    self.advance(token)?;
                 }
             }
         }
@@ -245,158 +313,159 @@ impl<C: Codec, H: Hook> Inner<C, H> {
         Ok(())
     }
 
-    fn hand(
-        hook: &H,
-        connector: &dyn Connector,
-        stream: &mut TcpStream,
-        addr: &SocketAddr
-    ) -> Result<(Wire<Message>, C, Option<Crypto>)> {
-        let mut codec = C::new();
-
-        // 握手时的消息，不能超过 2048 字节
-        let bytes = read_block(stream, Some(2048))?;
-        let mut message = codec.decode(&None, bytes)?;
-
-        let chan = match message.get_str(CHAN) {
-            Ok(chan) => chan,
-            Err(_) => {
-                #[cfg(debug_assertions)]
-                {
-                    Code::CannotGetChanField.set(&mut message);
-                    let _ = Self::send(&mut codec, stream, message);
+    fn accept(&mut self, id: usize) -> Result<()> {
+        loop {
+            let (mut stream, addr) = match self.listens[id].accept() {
+                Ok(stream) => stream,
+                Err(err) => {
+                    if err.kind() == WouldBlock {
+                        return Ok(())
+                    } else {
+                        return Err(err.into())
+                    }
                 }
+            };
 
-                return Err(Error::ErrorCode(Code::CannotGetChanField))
-            }
-        };
+            let metrics = &self.node.metrics;
 
-        if chan != HAND {
-            #[cfg(debug_assertions)]
-            {
-                Code::UnsupportedChan.set(&mut message);
-                let _ = Self::send(&mut codec, stream, message);
-            }
+            if let Err(reject) = self.node.limits.check(addr.ip()) {
+                log::debug!("reject conn: {:?}, addr: {:?}", reject, addr);
 
-            return Err(Error::ErrorCode(Code::UnsupportedChan))
-        }
+                match reject {
+                    Reject::Deny => metrics.deny_rejects.fetch_add(1, Ordering::Relaxed),
+                    Reject::Banned => metrics.ban_rejects.fetch_add(1, Ordering::Relaxed),
+                    _ => metrics.rate_rejects.fetch_add(1, Ordering::Relaxed)
+                };
 
-        // SLOT_ID
-        let slot_id = if let Some(slot_id) = message.get(SLOT_ID) {
-            if let Some(slot_id) = slot_id.as_message_id() {
-                *slot_id
-            } else {
-                #[cfg(debug_assertions)]
-                {
-                    Code::InvalidSlotIdFieldType.set(&mut message);
-                    let _ = Self::send(&mut codec, stream, message);
-                }
+                continue;
+            }
 
-                return Err(Error::ErrorCode(Code::InvalidSlotIdFieldType));
+            if !self.hook.accept(&mut stream) {
+                continue;
             }
-        } else {
-            let slot_id = MessageId::new();
-            message.insert(SLOT_ID, slot_id);
-            slot_id
-        };
 
-        // 握手消息是可以修改的，修改后的消息会发回客户端，因此可以携带自定义数据
-        // 但是对于一些握手必备的属性，请谨慎修改，比如加密方式（METHOD）
-        if !hook.start(slot_id, &mut message) {
-            #[cfg(debug_assertions)]
-            {
-                Code::AuthenticationFailed.set(&mut message);
-                let _ = Self::send(&mut codec, stream, message);
+            // 握手的数量是有限的，避免大量不发送握手消息的连接耗尽资源
+            if self.handshakes.len() >= self.options.max_handshakes {
+                metrics.handshake_rejects.fetch_add(1, Ordering::Relaxed);
+                continue;
             }
 
-            return Err(Error::ErrorCode(Code::AuthenticationFailed));
-        }
+            let guard = match self.node.limits.acquire(addr.ip()) {
+                Ok(guard) => guard,
+                Err(_) => {
+                    log::debug!("reject conn: too many connections, addr: {:?}", addr);
+                    metrics.limit_rejects.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
 
-        if !hook.enable_secure() {
-            // 没有开启加密
+            stream.set_nodelay(true)?;
+            stream.set_nonblocking(true)?;
 
-            // 但是要注意，握手消息是没有加密的，不能传递敏感数据
-            let mut attr = message.clone();
+            let timer_id = self.next_timer_id();
 
-            attr.remove(CHAN);
-            attr.insert(ADDR, addr.to_string());
+            let handshake = Handshake::new(stream, addr, guard, timer_id, self.options.handshake_max_len);
 
-            let wire = connector.connect(attr, None, None)?;
+            let index = self.handshakes.insert(handshake);
 
-            // 这里可以修改 Wire 的属性
-            hook.finish(slot_id, &mut message, &wire);
+            // 以 RawFd 注册，握手结束后连接还要注册到网络线程的 Epoll
+            self.epoll.add(
+                &self.handshakes[index].stream.as_raw_fd(),
+                Token(index * 2),
+                Ready::readable() | Ready::writable() | Ready::hup(),
+                EpollOpt::edge()
+            )?;
 
-            Code::Ok.set(&mut message);
+            // 连接成功后，超时仍未完成握手的应当断开
+            let ticks = u64::from(self.options.handshake_timeout).div_ceil(HANDSHAKE_TICK).max(1) as u32;
 
-            // 握手消息发回
-            Self::send(&mut codec, stream, message)?;
+            // 无法计时的握手直接断开，不能让它一直占用
+            if self.wheel.insert((index, timer_id), ticks).is_err() {
+                let handshake = self.handshakes.remove(index);
+                handshake.deregister(&self.epoll)?;
 
-            return Ok((wire, codec, None))
+                self.node.metrics.handshake_failures.fetch_add(1, Ordering::Relaxed);
+            }
         }
+    }
 
-        if let Ok(method) = message.get_str(METHOD) {
-            let method = if let Ok(method) = Method::from_str(method) {
-                method
-            } else {
-                #[cfg(debug_assertions)]
-                {
-                    Code::UnsupportedFormat.set(&mut message);
-                    let _ = Self::send(&mut codec, stream, message);
-                }
-
-                return Err(Error::ErrorCode(Code::UnsupportedFormat));
-            };
+    fn advance(&mut self, token: usize) -> Result<()> {
+        let index = token / 2;
 
-            // 握手消息是可以修改的，修改后的消息会发回客户端，因此可以携带自定义数据
-            // 但是对于一些握手必备的属性，请谨慎修改
-            // 这里需要根据传递的自定义数据，返回一个加密密钥
-            // let secret = hook.access(&mut message).ok_or_else(|| Error::ErrorCode(Code::PermissionDenied))?;
-            let secret = match hook.access(slot_id, &mut message) {
-                Some(s) => s,
-                None => {
-                    #[cfg(debug_assertions)]
-                    {
-                        Code::PermissionDenied.set(&mut message);
-                        let _ = Self::send(&mut codec, stream, message);
-                    }
+        let handshake = match self.handshakes.get_mut(index) {
+            Some(handshake) => handshake,
+            None => return Ok(())
+        };
 
-                    return Err(Error::ErrorCode(Code::PermissionDenied))
+        match handshake.advance(&self.hook, &*self.connector, &self.epoll, index * 2 + 1) {
+            Ok(None) => (),
+            Ok(Some((wire, codec, crypto, reader))) => {
+                let handshake = self.handshakes.remove(index);
+                handshake.deregister(&self.epoll)?;
+
+                self.node.metrics.handshakes.fetch_add(1, Ordering::Relaxed);
+                self.node.limits.success(handshake.addr.ip());
+
+                if let Some(queue) = self.node.queues.choose(&mut self.rand) {
+                    queue.push(Packet::NewConn {
+                        wire,
+                        stream: handshake.stream,
+                        codec,
+                        crypto,
+                        guard: Some(handshake.guard),
+                        reader: Some(reader)
+                    })
                 }
-            };
+            }
+            Err(err) => {
+                log::debug!("{}", err);
 
-            // 但是要注意，握手消息是没有加密的，不能传递敏感数据
-            let mut attr = message.clone();
+                let handshake = self.handshakes.remove(index);
+                handshake.deregister(&self.epoll)?;
 
-            attr.remove(CHAN);
-            attr.insert(ADDR, addr.to_string());
+                self.node.metrics.handshake_failures.fetch_add(1, Ordering::Relaxed);
 
-            let wire = connector.connect(attr, None, None)?;
+                // 验证失败次数过多时封禁来源
+                if matches!(err, Error::ErrorCode(Code::AuthenticationFailed | Code::PermissionDenied)) &&
+                    self.node.limits.failure(handshake.addr.ip())
+                {
+                    log::debug!("ban addr: {:?}", handshake.addr);
+                    self.node.metrics.bans.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
 
-            // 这里可以修改 Wire 的属性
-            hook.finish(slot_id, &mut message, &wire);
+        Ok(())
+    }
 
-            Code::Ok.set(&mut message);
+    fn dispatch_timer(&mut self) -> Result<()> {
+        // 读到的是上次读取之后定时器到期的次数
+        let count = match self.timer.read() {
+            Ok(count) => count,
+            Err(err) => {
+                if err.kind() == WouldBlock {
+                    return Ok(())
+                } else {
+                    return Err(err.into())
+                }
+            }
+        };
 
-            // 握手消息发回
-            Self::send(&mut codec, stream, message)?;
+        self.node.limits.prune();
 
-            let crypto = Crypto::new(&method, secret.as_bytes());
+        for _ in 0..count {
+            for (index, timer_id) in self.wheel.tick() {
+                if self.handshakes.get(index).map(|h| h.timer_id) == Some(timer_id) {
+                    let handshake = self.handshakes.remove(index);
+                    handshake.deregister(&self.epoll)?;
 
-            return Ok((wire, codec, Some(crypto)))
-        }
+                    log::debug!("handshake timeout, addr: {:?}", handshake.stream.peer_addr());
 
-        #[cfg(debug_assertions)]
-        {
-            Code::PermissionDenied.set(&mut message);
-            let _ = Self::send(&mut codec, stream, message);
+                    self.node.metrics.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+                }
+            }
         }
 
-        Err(Error::ErrorCode(Code::PermissionDenied))
-    }
-
-    fn send(codec: &mut impl Codec, stream: &mut TcpStream, message: Message) -> Result<()> {
-        let bytes = codec.encode(&None, message)?;
-        stream.write_all(&bytes)?;
-
         Ok(())
     }
 }
@@ -405,7 +474,9 @@ impl<C: Codec> Clone for Node<C> {
     fn clone(&self) -> Self {
         Self {
             queues: self.queues.clone(),
-            run: self.run.clone()
+            run: self.run.clone(),
+            metrics: self.metrics.clone(),
+            limits: self.limits.clone()
         }
     }
 }
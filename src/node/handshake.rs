@@ -0,0 +1,252 @@
+use std::io::{Write, ErrorKind::{WouldBlock, Interrupted, BrokenPipe}};
+use std::os::unix::io::AsRawFd;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use queen_io::net::tcp::TcpStream;
+use queen_io::epoll::{Epoll, Token, Ready, EpollOpt};
+
+use nson::{Message, MessageId};
+
+use crate::Wire;
+use crate::net::{Codec, FrameReader};
+use crate::crypto::{Crypto, Method};
+use crate::dict::*;
+use crate::error::{Result, Error, Code, RecvError};
+
+use super::{Hook, Connector, ConnGuard};
+
+type HandResult = Result<(Wire<Message>, Option<Crypto>)>;
+// 握手完成后交给网络线程的连接，读取器中可能有握手之后已经读到的数据
+type Established<C> = (Wire<Message>, C, Option<Crypto>, FrameReader);
+
+// 进行中的握手，连接是非阻塞的，可读或可写时推进
+// 先读取一个握手消息，处理后连接到 Socket，收到 Socket 的结果后发回回复，回复写完后握手结束
+pub(crate) struct Handshake<C: Codec> {
+    pub stream: TcpStream,
+    pub timer_id: usize,
+    pub addr: SocketAddr,
+    // 连接的占位，握手成功后随连接交给网络线程
+    pub guard: ConnGuard,
+    codec: C,
+    reader: FrameReader,
+    // 待发回的回复，reply[pos..] 尚未写入
+    reply: Vec<u8>,
+    pos: usize,
+    // 等待 Socket 的连接结果，期间 Wire 注册在 Node 的 Epoll 上
+    pending: Option<Pending>,
+    // 握手的结果，回复写完后生效
+    result: Option<HandResult>
+}
+
+struct Pending {
+    wire: Wire<Message>,
+    message: Message,
+    slot_id: MessageId,
+    crypto: Option<Crypto>
+}
+
+impl<C: Codec> Handshake<C> {
+    pub fn new(
+        stream: TcpStream,
+        addr: SocketAddr,
+        guard: ConnGuard,
+        timer_id: usize,
+        max_len: usize
+    ) -> Self {
+        Handshake {
+            stream,
+            timer_id,
+            addr,
+            guard,
+            codec: C::new(),
+            reader: FrameReader::new(max_len),
+            reply: Vec::new(),
+            pos: 0,
+            pending: None,
+            result: None
+        }
+    }
+
+    // 握手结束（失败或超时）时从 Epoll 中移除
+    pub fn deregister(&self, epoll: &Epoll) -> Result<()> {
+        epoll.delete(&self.stream.as_raw_fd())?;
+
+        if let Some(pending) = &self.pending {
+            epoll.delete(&pending.wire)?;
+        }
+
+        Ok(())
+    }
+
+    // 尽量推进握手，返回 None 表示需要等待连接可读、可写或者 Socket 的结果
+    // wire_token 为等待结果时 Wire 在 Epoll 上的 Token
+    pub fn advance(
+        &mut self,
+        hook: &impl Hook,
+        connector: &dyn Connector,
+        epoll: &Epoll,
+        wire_token: usize
+    ) -> Result<Option<Established<C>>> {
+        loop {
+            if self.result.is_some() {
+                while self.pos < self.reply.len() {
+                    match self.stream.write(&self.reply[self.pos..]) {
+                        Ok(0) => return Err(Error::BrokenPipe("handshake write zero".to_string())),
+                        Ok(size) => self.pos += size,
+                        Err(err) if err.kind() == WouldBlock => return Ok(None),
+                        Err(err) if err.kind() == Interrupted => (),
+                        Err(err) => return Err(err.into())
+                    }
+                }
+
+                let (wire, crypto) = self.result.take().unwrap()?;
+
+                let codec = std::mem::replace(&mut self.codec, C::new());
+                let reader = std::mem::replace(&mut self.reader, FrameReader::new(0));
+
+                return Ok(Some((wire, codec, crypto, reader)))
+            }
+
+            if let Some(pending) = &self.pending {
+                let ret = match pending.wire.recv() {
+                    Ok(ret) => ret,
+                    Err(RecvError::Empty) => return Ok(None),
+                    Err(_) => return Err(Error::Disconnected("handshake connect".to_string()))
+                };
+
+                let Pending { wire, mut message, slot_id, crypto } = self.pending.take().unwrap();
+
+                epoll.delete(&wire)?;
+
+                match Code::get(&ret) {
+                    Some(Code::Ok) => (),
+                    Some(code) => return Err(Error::ErrorCode(code)),
+                    None => return Err(Error::InvalidData("handshake connect".to_string()))
+                }
+
+                // 这里可以修改 Wire 的属性
+                hook.finish(slot_id, &mut message, &wire);
+
+                Code::Ok.set(&mut message);
+
+                self.reply = self.codec.encode(&None, message)?;
+                self.result = Some(Ok((wire, crypto)));
+
+                continue
+            }
+
+            if let Some(frame) = self.reader.next_frame()? {
+                let message = self.codec.decode(&None, frame)?;
+
+                match hand(hook, connector, message, &self.addr) {
+                    Ok(pending) => {
+                        epoll.add(&pending.wire, Token(wire_token), Ready::readable(), EpollOpt::edge())?;
+
+                        self.pending = Some(pending);
+                    }
+                    Err((Some(reply), err)) => {
+                        self.reply = self.codec.encode(&None, reply)?;
+                        self.result = Some(Err(err));
+                    }
+                    // 失败且不需要告知客户端
+                    Err((None, err)) => return Err(err)
+                }
+
+                continue
+            }
+
+            match self.reader.read_from(&mut self.stream) {
+                Ok(0) => return Err(Error::IoError(BrokenPipe.into())),
+                Ok(_) => (),
+                Err(err) if err.kind() == WouldBlock => return Ok(None),
+                Err(err) if err.kind() == Interrupted => (),
+                Err(err) => return Err(err.into())
+            }
+        }
+    }
+}
+
+// 握手失败，只在 debug 模式下将错误码发回客户端
+fn fail(mut message: Message, code: Code) -> (Option<Message>, Error) {
+    code.set(&mut message);
+
+    let reply = if cfg!(debug_assertions) { Some(message) } else { None };
+
+    (reply, Error::ErrorCode(code))
+}
+
+// 处理握手消息，验证通过后连接到 Socket，但不等待结果
+// 失败时返回发回客户端的消息和错误
+fn hand(
+    hook: &impl Hook,
+    connector: &dyn Connector,
+    mut message: Message,
+    addr: &SocketAddr
+) -> std::result::Result<Pending, (Option<Message>, Error)> {
+    let chan = match message.get_str(CHAN) {
+        Ok(chan) => chan,
+        Err(_) => return Err(fail(message, Code::CannotGetChanField))
+    };
+
+    if chan != HAND {
+        return Err(fail(message, Code::UnsupportedChan))
+    }
+
+    // SLOT_ID
+    let slot_id = if let Some(slot_id) = message.get(SLOT_ID) {
+        if let Some(slot_id) = slot_id.as_message_id() {
+            *slot_id
+        } else {
+            return Err(fail(message, Code::InvalidSlotIdFieldType))
+        }
+    } else {
+        let slot_id = MessageId::new();
+        message.insert(SLOT_ID, slot_id);
+        slot_id
+    };
+
+    // 握手消息是可以修改的，修改后的消息会发回客户端，因此可以携带自定义数据
+    // 但是对于一些握手必备的属性，请谨慎修改，比如加密方式（METHOD）
+    if !hook.start(slot_id, &mut message) {
+        return Err(fail(message, Code::AuthenticationFailed))
+    }
+
+    let crypto = if !hook.enable_secure() {
+        // 没有开启加密
+        None
+    } else if let Ok(method) = message.get_str(METHOD) {
+        let method = match Method::from_str(method) {
+            Ok(method) => method,
+            Err(_) => return Err(fail(message, Code::UnsupportedFormat))
+        };
+
+        // 这里需要根据传递的自定义数据，返回一个加密密钥
+        let secret = match hook.access(slot_id, &mut message) {
+            Some(secret) => secret,
+            None => return Err(fail(message, Code::PermissionDenied))
+        };
+
+        Some(Crypto::new(&method, secret.as_bytes()))
+    } else {
+        return Err(fail(message, Code::PermissionDenied))
+    };
+
+    // 但是要注意，握手消息是没有加密的，不能传递敏感数据
+    let mut attr = message.clone();
+
+    attr.remove(CHAN);
+    attr.insert(ADDR, addr.to_string());
+
+    let wire = match connector.open(attr, None) {
+        Ok(wire) => wire,
+        Err(err) => return Err((None, err))
+    };
+
+    Ok(Pending {
+        wire,
+        message,
+        slot_id,
+        crypto
+    })
+}
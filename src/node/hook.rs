@@ -20,3 +20,54 @@ pub struct NonHook;
 
 impl Hook for NonHook {}
 impl Hook for () {}
+
+// 多个 Hook 可以组合成元组，按顺序依次调用
+// 任意一个 Hook 开启加密即开启加密；access 返回第一个 Some
+// 返回 bool 的回调遇到第一个 false 即停止，后面的 Hook 不会再被调用
+macro_rules! impl_hook_for_tuple {
+    ($($name:ident)+) => {
+        #[allow(non_snake_case)]
+        impl<$($name: Hook),+> Hook for ($($name,)+) {
+            fn enable_secure(&self) -> bool {
+                let ($($name,)+) = self;
+                $($name.enable_secure())||+
+            }
+
+            fn accept(&self, stream: &mut TcpStream) -> bool {
+                let ($($name,)+) = self;
+                $($name.accept(stream))&&+
+            }
+
+            fn start(&self, slot_id: MessageId, message: &mut Message) -> bool {
+                let ($($name,)+) = self;
+                $($name.start(slot_id, message))&&+
+            }
+
+            fn access(&self, slot_id: MessageId, message: &mut Message) -> Option<String> {
+                let ($($name,)+) = self;
+
+                $(
+                    if let Some(secret) = $name.access(slot_id, message) {
+                        return Some(secret)
+                    }
+                )+
+
+                None
+            }
+
+            fn finish(&self, slot_id: MessageId, message: &mut Message, wire: &Wire<Message>) {
+                let ($($name,)+) = self;
+                $($name.finish(slot_id, message, wire);)+
+            }
+        }
+    };
+}
+
+impl_hook_for_tuple!(A);
+impl_hook_for_tuple!(A B);
+impl_hook_for_tuple!(A B C);
+impl_hook_for_tuple!(A B C D);
+impl_hook_for_tuple!(A B C D E);
+impl_hook_for_tuple!(A B C D E F);
+impl_hook_for_tuple!(A B C D E F G);
+impl_hook_for_tuple!(A B C D E F G H);
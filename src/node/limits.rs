@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::util::lock::Lock;
+use crate::util::bucket::TokenBucket;
+use crate::util::cidr::{self, Cidr};
+
+use super::NodeOptions;
+
+// 清理过期的封禁和失败计数的间隔
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+// 拒绝连接的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reject {
+    // 不在允许列表中，或者在拒绝列表中
+    Deny,
+    // 来源被临时封禁
+    Banned,
+    // 超过接受新连接的速率
+    Rate,
+    // 超过最大连接数
+    Limit
+}
+
+// Node 的连接限制，由 Node 和网络线程共享
+// 连接数按来源统计，来源为按 ipv4_prefix 和 ipv6_prefix 截取后的地址
+pub(crate) struct Limits {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    max_conns: Option<usize>,
+    max_conns_per_ip: Option<usize>,
+    ban_after: Option<u32>,
+    ban_duration: Duration,
+    total: AtomicUsize,
+    // 来源，连接数
+    conns: Lock<HashMap<IpAddr, usize>>,
+    // 来源，解封的时间
+    bans: Lock<HashMap<IpAddr, Instant>>,
+    // 来源，连续握手失败的次数和第一次失败的时间，超过 ban_duration 后重新计数
+    failures: Lock<HashMap<IpAddr, (u32, Instant)>>,
+    // 上次清理的时间
+    pruned: Lock<Instant>,
+    bucket: Lock<Option<TokenBucket>>
+}
+
+// 连接的占位，连接关闭时释放
+pub struct ConnGuard {
+    limits: Arc<Limits>,
+    source: IpAddr
+}
+
+impl Limits {
+    pub fn new(options: &NodeOptions) -> Limits {
+        Limits {
+            allow: options.allow.clone(),
+            deny: options.deny.clone(),
+            ipv4_prefix: options.ipv4_prefix,
+            ipv6_prefix: options.ipv6_prefix,
+            max_conns: options.max_conns,
+            max_conns_per_ip: options.max_conns_per_ip,
+            ban_after: options.ban_after,
+            ban_duration: Duration::from_millis(u64::from(options.ban_duration)),
+            total: AtomicUsize::new(0),
+            conns: Lock::new(HashMap::new()),
+            bans: Lock::new(HashMap::new()),
+            failures: Lock::new(HashMap::new()),
+            pruned: Lock::new(Instant::now()),
+            bucket: Lock::new(options.accept_rate.map(|rate| TokenBucket::new(rate, options.accept_burst)))
+        }
+    }
+
+    // 地址所属的来源
+    pub fn source(&self, ip: IpAddr) -> IpAddr {
+        let ip = cidr::canonical(ip);
+
+        match ip {
+            IpAddr::V4(_) => cidr::mask(ip, self.ipv4_prefix),
+            IpAddr::V6(_) => cidr::mask(ip, self.ipv6_prefix)
+        }
+    }
+
+    // 接受连接之前的检查，依次为允许和拒绝列表、封禁、速率
+    pub fn check(&self, ip: IpAddr) -> Result<(), Reject> {
+        if !self.allow.is_empty() && !self.allow.iter().any(|cidr| cidr.contains(&ip)) {
+            return Err(Reject::Deny)
+        }
+
+        if self.deny.iter().any(|cidr| cidr.contains(&ip)) {
+            return Err(Reject::Deny)
+        }
+
+        {
+            let mut bans = self.bans.lock();
+
+            if let Some(until) = bans.get(&self.source(ip)) {
+                if *until > Instant::now() {
+                    return Err(Reject::Banned)
+                }
+
+                bans.remove(&self.source(ip));
+            }
+        }
+
+        if let Some(bucket) = self.bucket.lock().as_mut() {
+            if !bucket.take() {
+                return Err(Reject::Rate)
+            }
+        }
+
+        Ok(())
+    }
+
+    // 占用一个连接数，超过限制时返回 Reject::Limit
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnGuard, Reject> {
+        let source = self.source(ip);
+
+        let mut conns = self.conns.lock();
+
+        if let Some(max) = self.max_conns {
+            if self.total.load(Ordering::Relaxed) >= max {
+                return Err(Reject::Limit)
+            }
+        }
+
+        let count = conns.entry(source).or_insert(0);
+
+        if let Some(max) = self.max_conns_per_ip {
+            if *count >= max {
+                if *count == 0 {
+                    conns.remove(&source);
+                }
+
+                return Err(Reject::Limit)
+            }
+        }
+
+        *count += 1;
+        self.total.fetch_add(1, Ordering::Relaxed);
+
+        Ok(ConnGuard {
+            limits: self.clone(),
+            source
+        })
+    }
+
+    fn release(&self, source: &IpAddr) {
+        let mut conns = self.conns.lock();
+
+        if let Some(count) = conns.get_mut(source) {
+            *count -= 1;
+
+            if *count == 0 {
+                conns.remove(source);
+            }
+
+            self.total.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // 记录一次握手失败，达到 ban_after 次时封禁来源，返回是否封禁
+    pub fn failure(&self, ip: IpAddr) -> bool {
+        let ban_after = match self.ban_after {
+            Some(ban_after) => ban_after,
+            None => return false
+        };
+
+        let source = self.source(ip);
+        let now = Instant::now();
+
+        {
+            let mut failures = self.failures.lock();
+
+            let (count, first) = failures.entry(source).or_insert((0, now));
+
+            // 距离第一次失败超过 ban_duration 时重新计数
+            if *first + self.ban_duration <= now {
+                *count = 0;
+                *first = now;
+            }
+
+            *count += 1;
+
+            if *count < ban_after {
+                return false
+            }
+
+            failures.remove(&source);
+        }
+
+        self.ban(ip, self.ban_duration);
+
+        true
+    }
+
+    // 握手成功后重新计数
+    pub fn success(&self, ip: IpAddr) {
+        if self.ban_after.is_some() {
+            self.failures.lock().remove(&self.source(ip));
+        }
+    }
+
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.bans.lock().insert(self.source(ip), Instant::now() + duration);
+    }
+
+    pub fn unban(&self, ip: IpAddr) {
+        self.bans.lock().remove(&self.source(ip));
+    }
+
+    // 被封禁的来源和剩余的封禁时间
+    pub fn bans(&self) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+
+        let mut bans = self.bans.lock();
+
+        bans.retain(|_, until| *until > now);
+
+        bans.iter().map(|(ip, until)| (*ip, *until - now)).collect()
+    }
+
+    // 移除已解封的来源和超过 ban_duration 的失败计数，避免大量来源各失败一次时无限增长
+    // 由 Node 的定时器调用，每 PRUNE_INTERVAL 最多清理一次
+    pub fn prune(&self) {
+        let now = Instant::now();
+
+        {
+            let mut pruned = self.pruned.lock();
+
+            if now.duration_since(*pruned) < PRUNE_INTERVAL {
+                return
+            }
+
+            *pruned = now;
+        }
+
+        self.prune_at(now);
+    }
+
+    fn prune_at(&self, now: Instant) {
+        self.bans.lock().retain(|_, until| *until > now);
+        self.failures.lock().retain(|_, (_, first)| *first + self.ban_duration > now);
+    }
+
+    #[inline]
+    pub fn conns(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    pub fn conns_of(&self, ip: IpAddr) -> usize {
+        self.conns.lock().get(&self.source(ip)).copied().unwrap_or(0)
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.limits.release(&self.source);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::node::NodeOptions;
+
+    use super::Limits;
+
+    fn limits() -> Limits {
+        let options = NodeOptions {
+            ban_after: Some(2),
+            ban_duration: 50,
+            ..NodeOptions::default()
+        };
+
+        Limits::new(&options)
+    }
+
+    #[test]
+    fn failure_window() {
+        let limits = limits();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert!(!limits.failure(ip));
+
+        // 超过 ban_duration 后重新计数
+        thread::sleep(Duration::from_millis(60));
+        assert!(!limits.failure(ip));
+
+        assert!(limits.failure(ip));
+        assert!(limits.bans().len() == 1);
+    }
+
+    #[test]
+    fn prune() {
+        let limits = limits();
+
+        // 大量来源各失败一次
+        for i in 0..100u8 {
+            limits.failure(IpAddr::from([10, 0, 1, i]));
+        }
+
+        limits.ban("10.0.2.1".parse().unwrap(), Duration::from_millis(50));
+        limits.ban("10.0.2.2".parse().unwrap(), Duration::from_secs(60));
+
+        assert!(limits.failures.lock().len() == 100);
+
+        limits.prune_at(Instant::now() + Duration::from_millis(60));
+
+        assert!(limits.failures.lock().is_empty());
+        assert!(limits.bans.lock().len() == 1);
+
+        // 未到清理间隔时不清理
+        limits.failure("10.0.3.1".parse().unwrap());
+
+        thread::sleep(Duration::from_millis(60));
+
+        limits.prune();
+        assert!(limits.failures.lock().len() == 1);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::net::KeepAlive;
+use crate::util::cidr::Cidr;
+
+#[derive(Debug, Clone)]
+pub struct NodeOptions {
+    pub keep_alive: KeepAlive,
+    // 同时进行中的握手数上限，超过时新连接会被直接关闭
+    pub max_handshakes: usize,
+    // 握手的超时时间，单位毫秒，连接后在此时间内未完成握手的会被关闭
+    pub handshake_timeout: u32,
+    // 握手消息的最大长度
+    pub handshake_max_len: usize,
+    // 最大连接数，包括握手中的连接，None 为不限制
+    pub max_conns: Option<usize>,
+    // 每个来源的最大连接数，None 为不限制
+    pub max_conns_per_ip: Option<usize>,
+    // 按来源统计时 IPv4 和 IPv6 地址保留的前缀长度，默认为整个地址
+    // 比如 ipv6_prefix 为 64 时，同一个 /64 网段的地址视为同一个来源
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    // 每秒接受的新连接数，None 为不限制
+    pub accept_rate: Option<u32>,
+    // 接受新连接的突发数
+    pub accept_burst: u32,
+    // ban_duration 内握手失败（AuthenticationFailed、PermissionDenied）达到此次数后临时封禁来源
+    pub ban_after: Option<u32>,
+    // 封禁时间，单位毫秒
+    pub ban_duration: u32,
+    // 不为空时只接受这些地址段的连接
+    pub allow: Vec<Cidr>,
+    // 拒绝这些地址段的连接，优先于 allow
+    pub deny: Vec<Cidr>
+}
+
+impl Default for NodeOptions {
+    fn default() -> Self {
+        NodeOptions {
+            keep_alive: KeepAlive::default(),
+            max_handshakes: 1024,
+            handshake_timeout: 5000,
+            handshake_max_len: 2048,
+            max_conns: None,
+            max_conns_per_ip: None,
+            ipv4_prefix: 32,
+            ipv6_prefix: 128,
+            accept_rate: None,
+            accept_burst: 100,
+            ban_after: None,
+            ban_duration: 60 * 1000,
+            allow: Vec::new(),
+            deny: Vec::new()
+        }
+    }
+}
+
+impl NodeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
@@ -12,7 +12,7 @@ use queen_io::queue::mpsc::Queue;
 
 use nson::Message;
 
-use crate::net::{NetWork, Packet, CryptoOptions, Codec, KeepAlive};
+use crate::net::{NetWork, Packet, CryptoOptions, Codec, KeepAlive, Metrics};
 use crate::Wire;
 use crate::crypto::Crypto;
 use crate::dict::*;
@@ -27,7 +27,8 @@ pub struct Port<C: Codec> {
 struct PortInner<C: Codec> {
     queue: Queue<Packet<C>>,
     run: AtomicBool,
-    keep_alive: KeepAlive
+    keep_alive: KeepAlive,
+    metrics: Arc<Metrics>
 }
 
 impl<C: Codec> Port<C> {
@@ -36,13 +37,15 @@ impl<C: Codec> Port<C> {
             inner: Arc::new(PortInner {
                 queue: Queue::new()?,
                 run: AtomicBool::new(true),
-                keep_alive
+                keep_alive,
+                metrics: Arc::new(Metrics::new())
             })
         };
 
         let mut net_work = NetWork::<C>::new(
             port.inner.queue.clone(),
-            port.inner.keep_alive.clone()
+            port.inner.keep_alive.clone(),
+            port.inner.metrics.clone()
         )?;
 
         let inner = port.inner.clone();
@@ -70,6 +73,10 @@ impl<C: Codec> Port<C> {
         self.inner.run.load(Ordering::Relaxed)
     }
 
+    pub fn metrics(&self) -> &Metrics {
+        &self.inner.metrics
+    }
+
     pub fn connect<A: ToSocketAddrs>(
         &self,
         addr: A,
@@ -128,7 +135,9 @@ impl<C: Codec> Port<C> {
                     wire: wire1,
                     stream,
                     codec,
-                    crypto
+                    crypto,
+                    guard: None,
+                    reader: None
                 });
 
                 return Ok(wire2)
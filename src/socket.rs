@@ -5,12 +5,13 @@ use std::sync::{
     atomic::{AtomicBool, Ordering}
 };
 
-use std::io::ErrorKind::Interrupted;
+use std::io::ErrorKind::{Interrupted, WouldBlock};
 
 use queen_io::{
     epoll::{Epoll, Events, Token, Ready, EpollOpt},
     queue::mpsc::Queue
 };
+use queen_io::sys::timerfd::{TimerFd, TimerSpec, SetTimeFlags};
 
 use nson::{
     Message,
@@ -20,13 +21,24 @@ use nson::{
 use crate::Wire;
 use crate::error::{Result, Error, RecvError, Code};
 
-pub use hook::{Hook, NonHook};
+pub use hook::{Hook, ShardHook, NonHook};
 pub use switch::Switch;
-pub use slot::Slot;
+use switch::Delivery;
+pub use slot::{Slot, SlotLimits};
+pub use options::SocketOptions;
+pub use balance::Strategy;
+
+use router::{Router, Shard, Scope};
 
 mod hook;
 mod switch;
 mod slot;
+mod options;
+mod router;
+mod balance;
+mod tags;
+mod filter;
+mod session;
 
 #[derive(Clone)]
 pub struct Socket {
@@ -35,41 +47,87 @@ pub struct Socket {
 
 struct Inner {
     id: MessageId,
-    queue: Queue<Packet>,
+    router: Arc<Router>,
     run: AtomicBool
 }
 
 impl Socket {
     pub fn new(id: MessageId, hook: impl Hook) -> Result<Self> {
-        let queue = Queue::new()?;
+        Self::with_options(id, SocketOptions::default(), hook)
+    }
+
+    pub fn with_options(id: MessageId, options: SocketOptions, hook: impl Hook) -> Result<Self> {
+        Self::start(id, options, vec![hook])
+    }
+
+    // 启动多个分片，每个分片一个线程，各自负责一部分 SLOT
+    // SLOT 按 SLOT_ID 的哈希值分配到分片，没有 SLOT_ID 时轮流分配
+    // 每个分片持有一个 Hook 的副本，按分片运行时的不同见 ShardHook
+    pub fn sharded(
+        id: MessageId,
+        options: SocketOptions,
+        shards: usize,
+        hook: impl ShardHook
+    ) -> Result<Self> {
+        let shards = shards.max(1);
+
+        let mut hooks = vec![hook; shards];
+
+        for (index, hook) in hooks.iter_mut().enumerate() {
+            hook.shard(index, shards);
+        }
+
+        Self::start(id, options, hooks)
+    }
+
+    fn start<H: Hook>(id: MessageId, options: SocketOptions, hooks: Vec<H>) -> Result<Self> {
+        let shards = hooks.len();
+        let router = Arc::new(Router::new(shards)?);
 
         let socket = Socket {
             inner: Arc::new(Inner {
                 id,
-                queue: queue.clone(),
+                router: router.clone(),
                 run: AtomicBool::new(true)
             })
         };
 
-        let mut main_loop = MainLoop::new(
-            id,
-            queue,
-            hook,
-        )?;
+        for (index, hook) in hooks.into_iter().enumerate() {
+            // 只有一个分片时，不需要维护共享的路由表
+            let shard = if shards > 1 {
+                Some(Shard { index, router: router.clone() })
+            } else {
+                None
+            };
 
-        let socket2 = socket.clone();
-        thread::Builder::new().name("socket".to_string()).spawn(move || {
-            let ret = main_loop.run();
-            if ret.is_err() {
-                log::error!("socket loop exit: {:?}", ret);
+            let mut main_loop = MainLoop::new(
+                id,
+                router.queue(index).clone(),
+                options.clone(),
+                shard,
+                hook,
+            )?;
+
+            let name = if shards > 1 {
+                format!("socket-{}", index)
             } else {
-                log::trace!("socket loop exit");
-            }
+                "socket".to_string()
+            };
+
+            let socket2 = socket.clone();
+            thread::Builder::new().name(name).spawn(move || {
+                let ret = main_loop.run();
+                if ret.is_err() {
+                    log::error!("socket loop exit: {:?}", ret);
+                } else {
+                    log::trace!("socket loop exit");
+                }
 
-            socket2.inner.run.store(false, Ordering::Relaxed);
+                socket2.inner.run.store(false, Ordering::Relaxed);
 
-            main_loop.hook.stop(&main_loop.switch);
-        }).unwrap();
+                main_loop.hook.stop(&main_loop.switch);
+            }).unwrap();
+        }
 
         Ok(socket)
     }
@@ -80,24 +138,44 @@ impl Socket {
 
     pub fn stop(&self) {
         self.inner.run.store(false, Ordering::Relaxed);
-        self.inner.queue.push(Packet::Close);
+
+        for shard in 0..self.inner.router.shards() {
+            self.inner.router.push(shard, Packet::Close);
+        }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.inner.router.shards()
     }
 
     pub fn running(&self) -> bool {
         self.inner.run.load(Ordering::Relaxed)
     }
 
-    pub fn connect(
+    // 与 connect 相同，但是不等待结果，Wire 收到的第一个消息即为结果，CODE 为 Ok 时连接成功
+    pub fn open(
         &self,
         attr: Message,
-        capacity: Option<usize>,
-        timeout: Option<Duration>
+        capacity: Option<usize>
     ) -> Result<Wire<Message>> {
+        let shard = self.inner.router.select(&attr);
+
         let (wire1, wire2) = Wire::pipe(capacity.unwrap_or(64), attr)?;
 
         let packet = Packet::NewSlot(wire1);
 
-        self.inner.queue.push(packet);
+        self.inner.router.push(shard, packet);
+
+        Ok(wire2)
+    }
+
+    pub fn connect(
+        &self,
+        attr: Message,
+        capacity: Option<usize>,
+        timeout: Option<Duration>
+    ) -> Result<Wire<Message>> {
+        let wire2 = self.open(attr, capacity)?;
 
         let ret = wire2.wait(Some(timeout.unwrap_or_else(|| Duration::from_secs(10))))?;
 
@@ -115,7 +193,8 @@ impl Socket {
 
 impl Drop for Socket {
     fn drop(&mut self) {
-        if Arc::strong_count(&self.inner) <= 2 {
+        // 每个分片的线程各持有一个
+        if Arc::strong_count(&self.inner) <= 1 + self.inner.router.shards() {
             self.stop()
         }
     }
@@ -125,32 +204,80 @@ struct MainLoop<H> {
     epoll: Epoll,
     events: Events,
     queue: Queue<Packet>,
+    timer: TimerFd,
+    // 定时器是否已启动，只在有未到期的项时启动
+    armed: bool,
     hook: H,
     switch: Switch
 }
 
-enum Packet {
+pub(crate) enum Packet {
     NewSlot(Wire<Message>),
+    // 其他分片转发的消息
+    Relay(String, Message, Scope),
+    // 其他分片转发的事件，发给本分片 CHAN 的普通订阅者
+    Event(String, Message),
+    // 其他分片报告的发布确认的投递数
+    Delivered(usize, Delivery),
     Close
 }
 
 impl<H: Hook> MainLoop<H> {
-    const QUEUE_TOKEN: Token = Token(usize::max_value());
-
-    fn new(socket_id: MessageId, queue: Queue<Packet>, hook: H) -> Result<MainLoop<H>> {
+    const QUEUE_TOKEN: Token = Token(usize::MAX);
+    const TIMER_TOKEN: Token = Token(usize::MAX - 1);
+
+    fn new(
+        socket_id: MessageId,
+        queue: Queue<Packet>,
+        options: SocketOptions,
+        shard: Option<Shard>,
+        hook: H
+    ) -> Result<MainLoop<H>> {
         Ok(MainLoop {
             epoll: Epoll::new()?,
             events: Events::with_capacity(1024),
             queue,
+            timer: TimerFd::new()?,
+            armed: false,
             hook,
-            switch: Switch::new(socket_id)
+            switch: Switch::new(socket_id, options, shard)
         })
     }
 
+    // 定时器中有未到期的项时每 TICK 毫秒触发一次，没有时停止，避免空闲时反复唤醒
+    fn update_timer(&mut self) -> Result<()> {
+        let pending = self.switch.has_timers();
+
+        if pending == self.armed {
+            return Ok(())
+        }
+
+        let tick = if pending {
+            Duration::from_millis(switch::TICK)
+        } else {
+            // 都为 0 时停止定时器
+            Duration::from_millis(0)
+        };
+
+        let timerspec = TimerSpec {
+            interval: tick,
+            value: tick
+        };
+
+        self.timer.settime(timerspec, SetTimeFlags::Default)?;
+
+        self.armed = pending;
+
+        Ok(())
+    }
+
     fn run(&mut self) -> Result<()> {
         self.epoll.add(&self.queue, Self::QUEUE_TOKEN, Ready::readable(), EpollOpt::level())?;
+        self.epoll.add(&self.timer, Self::TIMER_TOKEN, Ready::readable(), EpollOpt::edge())?;
 
         loop {
+            self.update_timer()?;
+
             let size = match self.epoll.wait(&mut self.events, None) {
                 Ok(size) => size,
                 Err(err) => {
@@ -174,12 +301,38 @@ impl<H: Hook> MainLoop<H> {
                                 Packet::NewSlot(wire) => {
                                     self.switch.add_slot(&self.epoll, &self.hook, wire)?;
                                 }
+                                Packet::Relay(chan, message, scope) => {
+                                    self.switch.relay_forwarded(&self.hook, chan, message, scope);
+                                }
+                                Packet::Event(chan, message) => {
+                                    self.switch.relay_forwarded_event(&self.hook, &chan, message);
+                                }
+                                Packet::Delivered(confirm_id, delivery) => {
+                                    self.switch.relay_delivered(&self.hook, confirm_id, delivery);
+                                }
                                 Packet::Close => {
                                     return Ok(())
                                 }
                             }
                         }
                     }
+                    Self::TIMER_TOKEN => {
+                        // 读到的是上次读取之后定时器到期的次数
+                        let count = match self.timer.read() {
+                            Ok(count) => count,
+                            Err(err) => {
+                                if err.kind() == WouldBlock {
+                                    continue;
+                                } else {
+                                    return Err(err.into())
+                                }
+                            }
+                        };
+
+                        for _ in 0..count {
+                            self.switch.tick(&self.hook);
+                        }
+                    }
                     _ => {
                         let token = token.0;
                         if let Some(slot) = self.switch.slots.get(token) {
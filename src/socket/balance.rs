@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::fmt;
+
+use rand::{Rng, seq::SliceRandom, rngs::SmallRng};
+
+use nson::{Value, message_id::MessageId};
+
+// 从多个订阅者中选择一个的策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    // 随机
+    #[default]
+    Random,
+    // 轮流
+    RoundRobin,
+    // 待接收的消息（Wire::pending）最少
+    LeastLoaded,
+    // 按 SLOT 属性中的 WEIGHT 加权随机，没有时为 1
+    Weighted,
+    // 按消息的 SHARE_KEY 一致性哈希，订阅者不变时同一个 KEY 总是选择同一个订阅者
+    // 消息没有 SHARE_KEY 时随机
+    Hash
+}
+
+impl FromStr for Strategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Strategy::Random),
+            "round_robin" => Ok(Strategy::RoundRobin),
+            "least_loaded" => Ok(Strategy::LeastLoaded),
+            "weighted" => Ok(Strategy::Weighted),
+            "hash" => Ok(Strategy::Hash),
+            _ => Err(())
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Strategy::Random => "random",
+            Strategy::RoundRobin => "round_robin",
+            Strategy::LeastLoaded => "least_loaded",
+            Strategy::Weighted => "weighted",
+            Strategy::Hash => "hash"
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+// 轮流选择的位置，按 CHAN 和共享订阅组分别记录
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Cursor {
+    // 携带 SHARE 的消息，选择 CHAN 的一个普通订阅者
+    Chan(String),
+    // CHAN 的共享订阅组
+    Group(String, String),
+    // TO 为数组并携带 SHARE 的消息
+    To
+}
+
+// 候选的订阅者
+#[derive(Debug)]
+pub(crate) struct Candidate<T> {
+    pub value: T,
+    pub id: MessageId,
+    pub pending: usize,
+    pub weight: usize
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Balancer {
+    cursors: HashMap<Cursor, usize>
+}
+
+impl Balancer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 按策略选择一个候选者，轮流选择时按 SLOT_ID 排序，避免受集合顺序的影响
+    pub fn select<T: Copy>(
+        &mut self,
+        rand: &mut SmallRng,
+        strategy: Strategy,
+        cursor: Cursor,
+        key: Option<&Value>,
+        candidates: &mut [Candidate<T>]
+    ) -> Option<T> {
+        if candidates.len() <= 1 {
+            return candidates.first().map(|c| c.value)
+        }
+
+        match strategy {
+            Strategy::Random => candidates.choose(rand).map(|c| c.value),
+            Strategy::RoundRobin => {
+                candidates.sort_by_key(|c| c.id);
+
+                let next = self.cursors.entry(cursor).or_insert(0);
+                let candidate = &candidates[*next % candidates.len()];
+
+                *next = next.wrapping_add(1);
+
+                Some(candidate.value)
+            }
+            Strategy::LeastLoaded => {
+                let min = candidates.iter().map(|c| c.pending).min()?;
+
+                let least: Vec<&Candidate<T>> = candidates.iter().filter(|c| c.pending == min).collect();
+
+                least.choose(rand).map(|c| c.value)
+            }
+            Strategy::Weighted => {
+                let weights: Vec<usize> = candidates.iter().map(|c| c.weight).collect();
+
+                weighted(rand, &weights).map(|i| candidates[i].value)
+            }
+            Strategy::Hash => match key {
+                Some(key) => {
+                    let key = key_hash(key);
+
+                    candidates.iter()
+                        .max_by_key(|c| rendezvous(key, &c.id))
+                        .map(|c| c.value)
+                }
+                None => candidates.choose(rand).map(|c| c.value)
+            }
+        }
+    }
+
+    // CHAN 或共享订阅组没有订阅者后，移除轮流选择的位置
+    pub fn remove(&mut self, cursor: &Cursor) {
+        self.cursors.remove(cursor);
+    }
+}
+
+// 按权重随机选择，权重都为 0 时返回 None
+pub(crate) fn weighted(rand: &mut SmallRng, weights: &[usize]) -> Option<usize> {
+    let total: usize = weights.iter().sum();
+
+    if total == 0 {
+        return None
+    }
+
+    let mut n = rand.gen_range(0..total);
+
+    for (i, weight) in weights.iter().enumerate() {
+        if n < *weight {
+            return Some(i)
+        }
+
+        n -= weight;
+    }
+
+    None
+}
+
+// SHARE_KEY 的哈希，相同的值在不同的进程中也相同
+pub(crate) fn key_hash(key: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    match key {
+        Value::String(s) => s.hash(&mut hasher),
+        Value::Binary(b) => b.0.hash(&mut hasher),
+        Value::MessageId(id) => id.bytes().hash(&mut hasher),
+        Value::I32(v) => i64::from(*v).hash(&mut hasher),
+        Value::I64(v) => v.hash(&mut hasher),
+        Value::U32(v) => u64::from(*v).hash(&mut hasher),
+        Value::U64(v) => v.hash(&mut hasher),
+        other => format!("{:?}", other).hash(&mut hasher)
+    }
+
+    hasher.finish()
+}
+
+// 最高随机权重哈希，成员变化时只有选中离开成员的 KEY 会改变选择
+pub(crate) fn rendezvous(key: u64, member: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    key.hash(&mut hasher);
+    member.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::SmallRng};
+
+    use nson::{Value, message_id::MessageId};
+
+    use super::{Balancer, Candidate, Cursor, Strategy};
+
+    fn candidates(ids: &[MessageId]) -> Vec<Candidate<usize>> {
+        ids.iter().enumerate().map(|(i, id)| Candidate { value: i, id: *id, pending: 0, weight: 1 }).collect()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut rand = SmallRng::from_entropy();
+        let mut balancer = Balancer::new();
+
+        let ids: Vec<MessageId> = (0..3).map(|_| MessageId::new()).collect();
+
+        let mut counts = [0; 3];
+
+        for _ in 0..30 {
+            let mut candidates = candidates(&ids);
+            let i = balancer.select(&mut rand, Strategy::RoundRobin, Cursor::To, None, &mut candidates).unwrap();
+            counts[i] += 1;
+        }
+
+        assert!(counts == [10, 10, 10]);
+    }
+
+    #[test]
+    fn test_least_loaded() {
+        let mut rand = SmallRng::from_entropy();
+        let mut balancer = Balancer::new();
+
+        let ids: Vec<MessageId> = (0..3).map(|_| MessageId::new()).collect();
+
+        let mut candidates = candidates(&ids);
+        candidates[0].pending = 5;
+        candidates[1].pending = 1;
+        candidates[2].pending = 3;
+
+        for _ in 0..10 {
+            assert!(balancer.select(&mut rand, Strategy::LeastLoaded, Cursor::To, None, &mut candidates) == Some(1));
+        }
+    }
+
+    #[test]
+    fn test_weighted() {
+        let mut rand = SmallRng::from_entropy();
+        let mut balancer = Balancer::new();
+
+        let ids: Vec<MessageId> = (0..3).map(|_| MessageId::new()).collect();
+
+        let mut candidates = candidates(&ids);
+        candidates[0].weight = 0;
+        candidates[2].weight = 0;
+
+        for _ in 0..10 {
+            assert!(balancer.select(&mut rand, Strategy::Weighted, Cursor::To, None, &mut candidates) == Some(1));
+        }
+
+        candidates[1].weight = 0;
+
+        assert!(balancer.select(&mut rand, Strategy::Weighted, Cursor::To, None, &mut candidates).is_none());
+    }
+
+    #[test]
+    fn test_hash() {
+        let mut rand = SmallRng::from_entropy();
+        let mut balancer = Balancer::new();
+
+        let ids: Vec<MessageId> = (0..4).map(|_| MessageId::new()).collect();
+
+        for key in 0..20 {
+            let key = Value::I32(key);
+
+            let mut all = candidates(&ids);
+            let chosen = balancer.select(&mut rand, Strategy::Hash, Cursor::To, Some(&key), &mut all).unwrap();
+
+            // 相同的 KEY 选择相同的订阅者
+            for _ in 0..5 {
+                assert!(balancer.select(&mut rand, Strategy::Hash, Cursor::To, Some(&key), &mut all) == Some(chosen));
+            }
+
+            // 离开的不是选中的订阅者时，选择不变
+            let other = (chosen + 1) % ids.len();
+            let mut rest: Vec<Candidate<usize>> = candidates(&ids).into_iter().filter(|c| c.value != other).collect();
+
+            assert!(balancer.select(&mut rand, Strategy::Hash, Cursor::To, Some(&key), &mut rest) == Some(chosen));
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        for strategy in [Strategy::Random, Strategy::RoundRobin, Strategy::LeastLoaded, Strategy::Weighted, Strategy::Hash] {
+            assert!(strategy.to_string().parse::<Strategy>() == Ok(strategy));
+        }
+
+        assert!("foo".parse::<Strategy>().is_err());
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+
+use nson::{Message, Value};
+
+use crate::error::Code;
+use crate::dict::{FILTER_EQ, FILTER_NE, FILTER_GT, FILTER_GTE, FILTER_LT, FILTER_LTE, FILTER_EXISTS, FILTER_PREFIX, FILTER_IN};
+
+// ATTACH 时附带的过滤条件，只投递满足所有条件的消息
+// {
+//     "a": 1,                      等于
+//     "b": {"$gte": 1, "$lt": 10}, 范围
+//     "c": {"$exists": false},     是否存在
+//     "d": {"$prefix": "abc"},     字符串前缀
+//     "e": {"$in": [1, 2, 3]},     在集合中
+//     "f": {"$ne": "x"}            不等于，字段不存在时也满足
+// }
+// 整数之间按数值比较，整数和浮点数之间按浮点数比较
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Filter {
+    conds: Vec<(String, Cond)>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Cond {
+    Eq(Value),
+    Ne(Value),
+    Range(Ordering, bool, Value),
+    Exists(bool),
+    Prefix(String),
+    In(Vec<Value>)
+}
+
+// 可以索引的值
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Int(i64),
+    Str(String),
+    Bool(bool),
+    Bytes(Vec<u8>)
+}
+
+impl Filter {
+    pub fn parse(filter: &Message) -> Result<Filter, Code> {
+        let mut conds = Vec::new();
+
+        for (field, value) in filter.iter() {
+            match value {
+                Value::Message(ops) => {
+                    if ops.is_empty() {
+                        return Err(Code::InvalidFilterFieldType)
+                    }
+
+                    for (op, operand) in ops.iter() {
+                        let cond = match op.as_str() {
+                            FILTER_EQ => Cond::Eq(operand.clone()),
+                            FILTER_NE => Cond::Ne(operand.clone()),
+                            FILTER_GT => Cond::Range(Ordering::Greater, false, operand.clone()),
+                            FILTER_GTE => Cond::Range(Ordering::Greater, true, operand.clone()),
+                            FILTER_LT => Cond::Range(Ordering::Less, false, operand.clone()),
+                            FILTER_LTE => Cond::Range(Ordering::Less, true, operand.clone()),
+                            FILTER_EXISTS => match operand {
+                                Value::Bool(exists) => Cond::Exists(*exists),
+                                _ => return Err(Code::InvalidFilterFieldType)
+                            },
+                            FILTER_PREFIX => match operand {
+                                Value::String(prefix) => Cond::Prefix(prefix.clone()),
+                                _ => return Err(Code::InvalidFilterFieldType)
+                            },
+                            FILTER_IN => match operand {
+                                Value::Array(array) => Cond::In(array.iter().cloned().collect()),
+                                _ => return Err(Code::InvalidFilterFieldType)
+                            },
+                            _ => return Err(Code::InvalidFilterFieldType)
+                        };
+
+                        conds.push((field.clone(), cond));
+                    }
+                }
+                value => conds.push((field.clone(), Cond::Eq(value.clone())))
+            }
+        }
+
+        Ok(Filter { conds })
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        self.conds.iter().all(|(field, cond)| cond.matches(message.get(field)))
+    }
+
+    // 用于索引的等于条件
+    fn index_key(&self) -> Option<(&str, Key)> {
+        self.conds.iter().find_map(|(field, cond)| match cond {
+            Cond::Eq(value) => key(value).map(|key| (field.as_str(), key)),
+            _ => None
+        })
+    }
+}
+
+impl Cond {
+    fn matches(&self, value: Option<&Value>) -> bool {
+        match (self, value) {
+            (Cond::Exists(exists), value) => *exists == value.is_some(),
+            (Cond::Ne(expected), Some(value)) => !equal(value, expected),
+            (Cond::Ne(_), None) => true,
+            (_, None) => false,
+            (Cond::Eq(expected), Some(value)) => equal(value, expected),
+            (Cond::Range(ordering, inclusive, bound), Some(value)) => {
+                match compare(value, bound) {
+                    Some(Ordering::Equal) => *inclusive,
+                    Some(o) => o == *ordering,
+                    None => false
+                }
+            }
+            (Cond::Prefix(prefix), Some(value)) => {
+                value.as_str().is_some_and(|s| s.starts_with(prefix.as_str()))
+            }
+            (Cond::In(set), Some(value)) => set.iter().any(|v| equal(value, v))
+        }
+    }
+}
+
+fn as_i64(value: &Value) -> Option<i64> {
+    match value {
+        Value::I32(v) => Some(i64::from(*v)),
+        Value::I64(v) => Some(*v),
+        Value::U32(v) => Some(i64::from(*v)),
+        Value::U64(v) => i64::try_from(*v).ok(),
+        _ => None
+    }
+}
+
+fn as_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::F32(v) => Some(f64::from(*v)),
+        Value::F64(v) => Some(*v),
+        _ => as_i64(value).map(|v| v as f64)
+    }
+}
+
+// 整数值的浮点数与整数使用相同的键，与按浮点数比较的结果一致
+fn key(value: &Value) -> Option<Key> {
+    if let Some(v) = as_i64(value) {
+        return Some(Key::Int(v))
+    }
+
+    match value {
+        Value::F32(_) | Value::F64(_) => {
+            let v = as_f64(value)?;
+
+            if v.fract() == 0.0 && v >= i64::MIN as f64 && v < i64::MAX as f64 {
+                Some(Key::Int(v as i64))
+            } else {
+                None
+            }
+        }
+        Value::String(s) => Some(Key::Str(s.clone())),
+        Value::Bool(b) => Some(Key::Bool(*b)),
+        Value::MessageId(id) => Some(Key::Bytes(id.bytes().to_vec())),
+        Value::Binary(b) => Some(Key::Bytes(b.0.clone())),
+        _ => None
+    }
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+    match (key(a), key(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => compare(a, b).map_or(a == b, |o| o == Ordering::Equal)
+    }
+}
+
+// 数字和字符串可以比较大小
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (as_i64(a), as_i64(b)) {
+        return Some(a.cmp(&b))
+    }
+
+    if let (Some(a), Some(b)) = (as_f64(a), as_f64(b)) {
+        return a.partial_cmp(&b)
+    }
+
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None
+    }
+}
+
+// 一个 CHAN 上带过滤条件的订阅
+// 有等于条件的订阅按字段和值索引，消息只需检查字段值对应的订阅，其他订阅逐个检查
+#[derive(Debug, Default)]
+pub(crate) struct FilterIndex {
+    filters: HashMap<usize, Filter>,
+    // 字段，值，Token
+    eq: HashMap<String, HashMap<Key, HashSet<usize>>>,
+    scan: HashSet<usize>
+}
+
+impl FilterIndex {
+    pub fn insert(&mut self, token: usize, filter: Filter) {
+        self.remove(token);
+
+        match filter.index_key() {
+            Some((field, key)) => {
+                self.eq.entry(field.to_string()).or_default().entry(key).or_default().insert(token);
+            }
+            None => {
+                self.scan.insert(token);
+            }
+        }
+
+        self.filters.insert(token, filter);
+    }
+
+    pub fn remove(&mut self, token: usize) {
+        let filter = match self.filters.remove(&token) {
+            Some(filter) => filter,
+            None => return
+        };
+
+        match filter.index_key() {
+            Some((field, key)) => {
+                if let Some(values) = self.eq.get_mut(field) {
+                    if let Some(tokens) = values.get_mut(&key) {
+                        tokens.remove(&token);
+
+                        if tokens.is_empty() {
+                            values.remove(&key);
+                        }
+                    }
+
+                    if values.is_empty() {
+                        self.eq.remove(field);
+                    }
+                }
+            }
+            None => {
+                self.scan.remove(&token);
+            }
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    #[inline]
+    pub fn get(&self, token: usize) -> Option<&Filter> {
+        self.filters.get(&token)
+    }
+
+    #[inline]
+    pub fn contains(&self, token: usize) -> bool {
+        self.filters.contains_key(&token)
+    }
+
+    // 对一个消息的过滤结果
+    pub fn check(&self, message: &Message) -> Checked<'_> {
+        Checked {
+            index: self,
+            matched: self.matched(message)
+        }
+    }
+
+    // 满足过滤条件的订阅
+    pub fn matched(&self, message: &Message) -> HashSet<usize> {
+        let mut matched = HashSet::new();
+
+        for (field, values) in &self.eq {
+            let tokens = message.get(field).and_then(key).and_then(|key| values.get(&key));
+
+            for token in tokens.into_iter().flatten() {
+                if self.filters[token].matches(message) {
+                    matched.insert(*token);
+                }
+            }
+        }
+
+        for token in &self.scan {
+            if self.filters[token].matches(message) {
+                matched.insert(*token);
+            }
+        }
+
+        matched
+    }
+}
+
+pub(crate) struct Checked<'a> {
+    index: &'a FilterIndex,
+    matched: HashSet<usize>
+}
+
+impl Checked<'_> {
+    // 没有过滤条件，或者满足过滤条件
+    #[inline]
+    pub fn accept(&self, token: usize) -> bool {
+        !self.index.contains(token) || self.matched.contains(&token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nson::msg;
+
+    use crate::error::Code;
+    use super::{Filter, FilterIndex};
+
+    fn filter(message: nson::Message) -> Filter {
+        Filter::parse(&message).unwrap()
+    }
+
+    #[test]
+    fn test_matches() {
+        let f = filter(msg!{"a": 1, "b": {"$gte": 1.5, "$lt": 10}});
+
+        assert!(f.matches(&msg!{"a": 1i64, "b": 2}));
+        assert!(f.matches(&msg!{"a": 1u32, "b": 1.5}));
+        assert!(!f.matches(&msg!{"a": 2, "b": 2}));
+        assert!(!f.matches(&msg!{"a": 1, "b": 10}));
+        assert!(!f.matches(&msg!{"a": 1}));
+
+        let f = filter(msg!{"c": {"$exists": false}, "d": {"$prefix": "ab"}});
+
+        assert!(f.matches(&msg!{"d": "abc"}));
+        assert!(!f.matches(&msg!{"d": "bc"}));
+        assert!(!f.matches(&msg!{"c": 1, "d": "abc"}));
+
+        let f = filter(msg!{"e": {"$in": [1, "x"]}, "f": {"$ne": "y"}});
+
+        assert!(f.matches(&msg!{"e": "x"}));
+        assert!(f.matches(&msg!{"e": 1i64, "f": "z"}));
+        assert!(!f.matches(&msg!{"e": 2}));
+        assert!(!f.matches(&msg!{"e": 1, "f": "y"}));
+
+        let f = filter(msg!{"s": {"$gt": "b"}});
+
+        assert!(f.matches(&msg!{"s": "c"}));
+        assert!(!f.matches(&msg!{"s": "a"}));
+        assert!(!f.matches(&msg!{"s": 1}));
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(Filter::parse(&msg!{"a": {}}) == Err(Code::InvalidFilterFieldType));
+        assert!(Filter::parse(&msg!{"a": {"$foo": 1}}) == Err(Code::InvalidFilterFieldType));
+        assert!(Filter::parse(&msg!{"a": {"$exists": 1}}) == Err(Code::InvalidFilterFieldType));
+        assert!(Filter::parse(&msg!{"a": {"$prefix": 1}}) == Err(Code::InvalidFilterFieldType));
+        assert!(Filter::parse(&msg!{"a": {"$in": 1}}) == Err(Code::InvalidFilterFieldType));
+    }
+
+    #[test]
+    fn test_index() {
+        let mut index = FilterIndex::default();
+
+        index.insert(1, filter(msg!{"a": 1}));
+        index.insert(2, filter(msg!{"a": 2, "b": {"$gt": 0}}));
+        index.insert(3, filter(msg!{"b": {"$gt": 0}}));
+
+        assert!(index.matched(&msg!{"a": 1}) == [1].into_iter().collect());
+        assert!(index.matched(&msg!{"a": 2i64, "b": 1}) == [2, 3].into_iter().collect());
+        assert!(index.matched(&msg!{"a": 2}).is_empty());
+
+        // 修改条件
+        index.insert(1, filter(msg!{"a": 2}));
+
+        assert!(index.matched(&msg!{"a": 1}).is_empty());
+        assert!(index.matched(&msg!{"a": 2}) == [1].into_iter().collect());
+
+        index.remove(1);
+        index.remove(2);
+        index.remove(3);
+
+        // 整数和浮点数
+        index.insert(4, filter(msg!{"a": 1}));
+        index.insert(5, filter(msg!{"a": 2.0}));
+        index.insert(6, filter(msg!{"a": 1.5}));
+
+        assert!(index.matched(&msg!{"a": 1.0}) == [4].into_iter().collect());
+        assert!(index.matched(&msg!{"a": 2i64}) == [5].into_iter().collect());
+        assert!(index.matched(&msg!{"a": 1.5f32}) == [6].into_iter().collect());
+
+        index.remove(4);
+        index.remove(5);
+        index.remove(6);
+
+        assert!(index.is_empty());
+        assert!(index.eq.is_empty() && index.scan.is_empty());
+    }
+}
@@ -2,6 +2,9 @@ use nson::Message;
 
 use super::{Switch, Slot};
 
+// Socket 的回调，所有方法都有默认实现
+// 注意：自定义的 Hook 默认不共享广播的消息（immutable 返回 false），每个接收者都会得到一份副本并单独编码
+// 如果 push 和 send 不修改消息，请实现 immutable 并返回 true 以开启共享，NonHook 和 () 已经开启
 pub trait Hook: Send + 'static {
     fn accept(&self, _: &Slot) -> bool { true }
 
@@ -23,14 +26,145 @@ pub trait Hook: Send + 'static {
 
     fn emit(&self, _: &Slot, _: &mut Message) -> bool { true }
 
+    // 运行时修改 SLOT 的属性和标签（UPDATE）
+    fn update(&self, _: &Slot, _: &mut Message) -> bool { true }
+
     fn push(&self, _: &Slot, _: &mut Message) -> bool { true }
 
     fn custom(&self, _: &Switch, _token: usize, _: &mut Message) {}
 
     fn stop(&self, _: &Switch) {}
+
+    // push 和 send 不会修改消息时返回 true
+    // 此时广播的消息由所有接收者共享，不再为每个接收者克隆和编码
+    // 默认为 false，因为无法确定自定义的 push 和 send 是否修改消息，需要自行开启
+    // 开启后 push 和 send 对消息的修改会被后面的接收者看到，元组只有在所有 Hook 都开启时才开启
+    fn immutable(&self) -> bool { false }
+}
+
+// Socket::sharded 使用的 Hook，需要自行实现以表明 Hook 可以按分片运行
+// 每个分片持有一个副本，回调在 SLOT 所在的分片上调用，与只有一个分片时有以下不同：
+// custom 和 stop 拿到的是所在分片的 Switch，只能看到本分片的 SLOT，stop 在每个分片退出时各调用一次
+// 延迟投递、请求跟踪和流式请求只在发送者所在的分片内进行，聚合请求只投递给发送者所在分片的 SLOT
+// 副本之间需要共享的状态，请放在 Arc 等共享的结构中
+pub trait ShardHook: Hook + Clone {
+    // 分片启动之前调用，index 为副本所在的分片
+    fn shard(&mut self, _index: usize, _shards: usize) {}
 }
 
+#[derive(Clone)]
 pub struct NonHook;
 
-impl Hook for NonHook {}
-impl Hook for () {}
+impl Hook for NonHook {
+    fn immutable(&self) -> bool { true }
+}
+
+impl ShardHook for NonHook {}
+
+impl Hook for () {
+    fn immutable(&self) -> bool { true }
+}
+
+impl ShardHook for () {}
+
+// 多个 Hook 可以组合成元组，例如 (LogHook, AuthHook, MetricsHook)
+// 按顺序依次调用，每个 Hook 都可以修改消息，后面的 Hook 能看到前面的修改
+// 返回 bool 的回调遇到第一个 false 即停止，后面的 Hook 不会再被调用
+macro_rules! impl_hook_for_tuple {
+    ($($name:ident)+) => {
+        #[allow(non_snake_case)]
+        impl<$($name: Hook),+> Hook for ($($name,)+) {
+            fn accept(&self, slot: &Slot) -> bool {
+                let ($($name,)+) = self;
+                $($name.accept(slot))&&+
+            }
+
+            fn remove(&self, slot: &Slot) {
+                let ($($name,)+) = self;
+                $($name.remove(slot);)+
+            }
+
+            fn recv(&self, slot: &Slot, message: &mut Message) -> bool {
+                let ($($name,)+) = self;
+                $($name.recv(slot, message))&&+
+            }
+
+            fn send(&self, slot: &Slot, message: &mut Message) -> bool {
+                let ($($name,)+) = self;
+                $($name.send(slot, message))&&+
+            }
+
+            fn attach(&self, slot: &Slot, message: &mut Message, chan: &str) -> bool {
+                let ($($name,)+) = self;
+                $($name.attach(slot, message, chan))&&+
+            }
+
+            fn detach(&self, slot: &Slot, message: &mut Message, chan: &str) -> bool {
+                let ($($name,)+) = self;
+                $($name.detach(slot, message, chan))&&+
+            }
+
+            fn join(&self, slot: &Slot, message: &mut Message) -> bool {
+                let ($($name,)+) = self;
+                $($name.join(slot, message))&&+
+            }
+
+            fn leave(&self, slot: &Slot, message: &mut Message) -> bool {
+                let ($($name,)+) = self;
+                $($name.leave(slot, message))&&+
+            }
+
+            fn ping(&self, slot: &Slot, message: &mut Message) {
+                let ($($name,)+) = self;
+                $($name.ping(slot, message);)+
+            }
+
+            fn emit(&self, slot: &Slot, message: &mut Message) -> bool {
+                let ($($name,)+) = self;
+                $($name.emit(slot, message))&&+
+            }
+
+            fn update(&self, slot: &Slot, message: &mut Message) -> bool {
+                let ($($name,)+) = self;
+                $($name.update(slot, message))&&+
+            }
+
+            fn push(&self, slot: &Slot, message: &mut Message) -> bool {
+                let ($($name,)+) = self;
+                $($name.push(slot, message))&&+
+            }
+
+            fn custom(&self, switch: &Switch, token: usize, message: &mut Message) {
+                let ($($name,)+) = self;
+                $($name.custom(switch, token, message);)+
+            }
+
+            fn stop(&self, switch: &Switch) {
+                let ($($name,)+) = self;
+                $($name.stop(switch);)+
+            }
+
+            fn immutable(&self) -> bool {
+                let ($($name,)+) = self;
+                $($name.immutable())&&+
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<$($name: ShardHook),+> ShardHook for ($($name,)+) {
+            fn shard(&mut self, index: usize, shards: usize) {
+                let ($($name,)+) = self;
+                $($name.shard(index, shards);)+
+            }
+        }
+    };
+}
+
+impl_hook_for_tuple!(A);
+impl_hook_for_tuple!(A B);
+impl_hook_for_tuple!(A B C);
+impl_hook_for_tuple!(A B C D);
+impl_hook_for_tuple!(A B C D E);
+impl_hook_for_tuple!(A B C D E F);
+impl_hook_for_tuple!(A B C D E F G);
+impl_hook_for_tuple!(A B C D E F G H);
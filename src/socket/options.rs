@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use super::{SlotLimits, Strategy};
+
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    // 死信频道，无法投递的消息会被包装后发送到这个频道，CODE 为原因:
+    // MessageExpired 已过期，NoSubscribers 没有订阅者，TargetSlotIdNotExist TO 中的 SLOT 不存在，
+    // TargetSocketIdNotExist TO_SOCKET 不存在，WireFull 接收者的 Wire 已满，TargetSlotBroken 接收者已断开，
+    // OfflineQueueFull 离线的持久会话暂存的消息已满
+    // {
+    //     CHAN: $dead_letter,
+    //     CODE: $reason,
+    //     ORIGIN: $chan,
+    //     SLOT_ID: $from_slot_id,
+    //     TO: $target_slot_id, 投递失败时
+    //     VALUE: $message
+    // }
+    pub dead_letter: Option<String>,
+    // 请求（携带 REQUEST 和 ID 的消息）的默认超时时间，单位毫秒，消息可以用 TIMEOUT 指定
+    pub request_timeout: u32,
+    // 流式请求的空闲超时时间，单位毫秒，超过这个时间没有回复或授权时结束流
+    pub stream_timeout: u32,
+    // 流式请求未携带 CREDIT 时的初始授权数
+    pub stream_credit: u32,
+    // 每个 SLOT 最多暂存的延迟投递的消息数，超过时拒绝（ScheduleLimitExceeded）
+    pub schedule_limit: usize,
+    // SLOT 的默认限制，SLOT 的属性（MESSAGE_RATE 等）只能收紧，Hook::accept 中可以任意修改
+    pub slot_limits: SlotLimits,
+    // 按 SLOT 的这个属性（字符串）分组，同组的 SLOT 共享速率限制
+    pub limit_group: Option<String>,
+    // 共享投递（SHARE 消息和共享订阅组）时选择订阅者的默认策略，消息可以用 STRATEGY 指定
+    pub strategy: Strategy,
+    // CHAN 的策略，优先于默认策略
+    pub chan_strategies: HashMap<String, Strategy>,
+    // SLOT_ID 已被使用时，新的连接通过 Hook::accept 后接管原来的 SLOT，否则拒绝（DuplicateSlotId）
+    // 原来的 SLOT 会收到 SlotTakenOver 后被关闭，其订阅和 JOIN 状态转移到新的 SLOT
+    // 原来的 SLOT 的遗嘱消息会和断开时一样发布，不会转移到新的 SLOT
+    pub takeover: bool,
+    // 持久会话（SLOT 属性 SESSION 为 true 时）在 SLOT 断开后保留的时间，单位毫秒，属性为整数时以属性为准
+    pub session_expiry: u32,
+    // 每个离线的持久会话最多暂存的消息数，超过时进入死信频道（OfflineQueueFull）
+    pub session_limit: usize,
+    // 暂存消息的目录，None 时暂存在内存中
+    pub session_dir: Option<PathBuf>
+}
+
+impl SocketOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            dead_letter: None,
+            request_timeout: 30 * 1000,
+            stream_timeout: 60 * 1000,
+            stream_credit: 64,
+            schedule_limit: 1024,
+            slot_limits: SlotLimits::default(),
+            limit_group: None,
+            strategy: Strategy::default(),
+            chan_strategies: HashMap::new(),
+            takeover: false,
+            session_expiry: 60 * 1000,
+            session_limit: 1024,
+            session_dir: None
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::io;
+
+use queen_io::queue::mpsc::Queue;
+
+use nson::{Message, message_id::MessageId};
+
+use crate::util::lock::Lock;
+use crate::dict::SLOT_ID;
+
+use super::Packet;
+
+// 锁的分段数，按哈希值分段，减少分片之间的竞争
+const STRIPES: usize = 16;
+
+// 共享订阅组，每个分片的订阅数
+type ShareGroups = HashMap<String, Vec<usize>>;
+
+// 多个分片共享的路由表
+// 每个 SLOT 只属于一个分片，SLOT 所在的分片负责读写它的 Wire
+// 发往其他分片的消息通过各分片的队列转发，同一发送者的消息保持顺序
+pub(crate) struct Router {
+    queues: Vec<Queue<Packet>>,
+    // SLOT_ID，分片
+    slots: Vec<Lock<HashMap<MessageId, usize>>>,
+    // CHAN，每个分片的订阅数
+    chans: Vec<Lock<HashMap<String, Vec<usize>>>>,
+    share_chans: Vec<Lock<HashMap<String, ShareGroups>>>,
+    next: AtomicUsize
+}
+
+// Switch 所在的分片
+pub(crate) struct Shard {
+    pub index: usize,
+    pub router: Arc<Router>
+}
+
+// 转发到其他分片的消息的投递范围
+#[derive(Debug, Clone)]
+pub(crate) struct Scope {
+    // 投递给普通订阅
+    pub chans: bool,
+    // 投递给哪些共享订阅组，None 为所有组
+    pub share_groups: Option<Vec<String>>,
+    // 是否继续转发到其他分片
+    pub forward: bool,
+    // 需要发布确认时，为发起的分片和确认的 ID
+    pub confirm: Option<(usize, usize)>
+}
+
+impl Scope {
+    pub const ALL: Scope = Scope { chans: true, share_groups: None, forward: true, confirm: None };
+    // 只在本分片内投递
+    pub const LOCAL: Scope = Scope { chans: true, share_groups: None, forward: false, confirm: None };
+}
+
+fn hash<T: Hash + ?Sized>(value: &T) -> usize {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+fn stripe<T: Hash + ?Sized>(value: &T) -> usize {
+    hash(value) % STRIPES
+}
+
+impl Router {
+    pub fn new(shards: usize) -> io::Result<Router> {
+        let mut queues = Vec::with_capacity(shards);
+
+        for _ in 0..shards {
+            queues.push(Queue::new()?);
+        }
+
+        Ok(Router {
+            queues,
+            slots: (0..STRIPES).map(|_| Lock::new(HashMap::new())).collect(),
+            chans: (0..STRIPES).map(|_| Lock::new(HashMap::new())).collect(),
+            share_chans: (0..STRIPES).map(|_| Lock::new(HashMap::new())).collect(),
+            next: AtomicUsize::new(0)
+        })
+    }
+
+    #[inline]
+    pub fn shards(&self) -> usize {
+        self.queues.len()
+    }
+
+    #[inline]
+    pub fn queue(&self, shard: usize) -> &Queue<Packet> {
+        &self.queues[shard]
+    }
+
+    pub fn push(&self, shard: usize, packet: Packet) {
+        self.queues[shard].push(packet);
+    }
+
+    // 新连接所在的分片，有 SLOT_ID 时按哈希分配，否则轮流分配
+    pub fn select(&self, attr: &Message) -> usize {
+        match attr.get_message_id(SLOT_ID) {
+            Ok(slot_id) => hash(slot_id) % self.shards(),
+            Err(_) => self.next.fetch_add(1, Ordering::Relaxed) % self.shards()
+        }
+    }
+
+    // SLOT_ID 已存在时返回 false
+    pub fn add_slot(&self, slot_id: MessageId, shard: usize) -> bool {
+        let mut slots = self.slots[stripe(&slot_id)].lock();
+
+        if slots.contains_key(&slot_id) {
+            return false
+        }
+
+        slots.insert(slot_id, shard);
+
+        true
+    }
+
+    pub fn del_slot(&self, slot_id: &MessageId) {
+        self.slots[stripe(slot_id)].lock().remove(slot_id);
+    }
+
+    pub fn slot_shard(&self, slot_id: &MessageId) -> Option<usize> {
+        self.slots[stripe(slot_id)].lock().get(slot_id).copied()
+    }
+
+    // 更新某个分片上 CHAN 的订阅数
+    pub fn set_subscribers(&self, chan: &str, shard: usize, count: usize) {
+        let mut chans = self.chans[stripe(chan)].lock();
+
+        if count == 0 {
+            if let Some(counts) = chans.get_mut(chan) {
+                counts[shard] = 0;
+
+                if counts.iter().all(|c| *c == 0) {
+                    chans.remove(chan);
+                }
+            }
+        } else {
+            let shards = self.shards();
+            chans.entry(chan.to_string()).or_insert_with(|| vec![0; shards])[shard] = count;
+        }
+    }
+
+    // 每个分片上 CHAN 的订阅数
+    pub fn subscribers(&self, chan: &str) -> Option<Vec<usize>> {
+        self.chans[stripe(chan)].lock().get(chan).cloned()
+    }
+
+    // 更新某个分片上 CHAN 的共享订阅组的订阅数
+    pub fn set_share_subscribers(&self, chan: &str, group: &str, shard: usize, count: usize) {
+        let mut chans = self.share_chans[stripe(chan)].lock();
+
+        if count == 0 {
+            if let Some(groups) = chans.get_mut(chan) {
+                if let Some(counts) = groups.get_mut(group) {
+                    counts[shard] = 0;
+
+                    if counts.iter().all(|c| *c == 0) {
+                        groups.remove(group);
+                    }
+                }
+
+                if groups.is_empty() {
+                    chans.remove(chan);
+                }
+            }
+        } else {
+            let shards = self.shards();
+
+            chans.entry(chan.to_string())
+                .or_default()
+                .entry(group.to_string())
+                .or_insert_with(|| vec![0; shards])[shard] = count;
+        }
+    }
+
+    // CHAN 的每个共享订阅组在每个分片上的订阅数
+    pub fn share_subscribers(&self, chan: &str) -> Vec<(String, Vec<usize>)> {
+        self.share_chans[stripe(chan)].lock()
+            .get(chan)
+            .map(|groups| groups.iter().map(|(group, counts)| (group.clone(), counts.clone())).collect())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write, BufReader};
+use std::io::ErrorKind::UnexpectedEof;
+use std::path::{Path, PathBuf};
+
+use nson::{Message, Value, message_id::MessageId};
+
+use crate::error::Code;
+use crate::util::message::{read_block, as_millis};
+
+use super::filter::Filter;
+
+// 持久会话，SLOT 断开后保留普通订阅和共享订阅，并暂存匹配订阅的消息
+// SHARE 消息和共享订阅组的消息，只在没有在线的订阅者收到时暂存到其中一个会话
+// 相同 SLOT_ID 的 SLOT 重新连接时恢复订阅，并按顺序投递暂存的消息
+pub(crate) struct Session {
+    pub timer_id: usize,
+    pub chans: HashSet<String>,
+    // CHAN，共享订阅组
+    pub share_chans: HashMap<String, String>,
+    // CHAN，订阅的过滤条件
+    pub filters: HashMap<String, Filter>,
+    pub share_filters: HashMap<String, Filter>,
+    pub tags: HashSet<String>,
+    pub queue: OfflineQueue
+}
+
+// 离线时暂存的消息，超过上限时不再接收
+// 指定了目录时，消息追加到目录中以 SLOT_ID 命名的文件中，只用于减少内存占用，进程重启后不会恢复
+// 文件在第一次追加时打开，取出或清空之前一直保持打开
+// 写入失败时截断到上次成功写入的位置，避免留下不完整的消息
+#[derive(Debug)]
+pub(crate) struct OfflineQueue {
+    limit: usize,
+    len: usize,
+    memory: VecDeque<Message>,
+    file: Option<PathBuf>,
+    handle: Option<File>,
+    // 文件中完整的消息的字节数
+    size: u64
+}
+
+impl OfflineQueue {
+    pub fn new(limit: usize, dir: Option<&Path>, slot_id: &MessageId) -> Self {
+        let file = dir.map(|dir| dir.join(slot_id.to_hex()));
+
+        // 移除之前遗留的文件
+        if let Some(file) = &file {
+            let _ = fs::remove_file(file);
+        }
+
+        OfflineQueue {
+            limit,
+            len: 0,
+            memory: VecDeque::new(),
+            file,
+            handle: None,
+            size: 0
+        }
+    }
+
+    // 已满或者写入文件失败时返回消息
+    pub fn push(&mut self, message: Message) -> Result<(), Message> {
+        if self.len >= self.limit {
+            return Err(message)
+        }
+
+        match &self.file {
+            Some(file) => {
+                if let Err(err) = append(file, &mut self.handle, &mut self.size, &message) {
+                    log::error!("session queue append: {:?}", err);
+
+                    return Err(message)
+                }
+            }
+            None => self.memory.push_back(message)
+        }
+
+        self.len += 1;
+
+        Ok(())
+    }
+
+    // 取出所有消息，并移除文件
+    pub fn drain(&mut self) -> Vec<Message> {
+        self.len = 0;
+        self.handle = None;
+        self.size = 0;
+
+        match &self.file {
+            Some(file) => {
+                let messages = match load(file) {
+                    Ok(messages) => messages,
+                    Err(err) => {
+                        log::error!("session queue load: {:?}", err);
+
+                        Vec::new()
+                    }
+                };
+
+                let _ = fs::remove_file(file);
+
+                messages
+            }
+            None => self.memory.drain(..).collect()
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.memory.clear();
+        self.handle = None;
+        self.size = 0;
+
+        if let Some(file) = &self.file {
+            let _ = fs::remove_file(file);
+        }
+    }
+}
+
+fn append(path: &Path, handle: &mut Option<File>, size: &mut u64, message: &Message) -> io::Result<()> {
+    let bytes = message.to_bytes().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)))?;
+
+    let file = match handle {
+        Some(file) => file,
+        None => handle.insert(OpenOptions::new().create(true).append(true).open(path)?)
+    };
+
+    if let Err(err) = file.write_all(&bytes) {
+        // 可能已经写入了一部分，截断到上次成功写入的位置
+        let _ = file.set_len(*size);
+
+        return Err(err)
+    }
+
+    *size += bytes.len() as u64;
+
+    Ok(())
+}
+
+// 遇到不完整或者无法解析的消息时停止，返回之前的消息
+fn load(path: &Path) -> io::Result<Vec<Message>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut messages = Vec::new();
+
+    loop {
+        let bytes = match read_block(&mut reader, None) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == UnexpectedEof => break,
+            Err(err) => {
+                log::error!("session queue load: {:?}", err);
+
+                break
+            }
+        };
+
+        match Message::from_bytes(&bytes) {
+            Ok(message) => messages.push(message),
+            Err(err) => {
+                log::error!("session queue load: {:?}", err);
+
+                break
+            }
+        }
+    }
+
+    Ok(messages)
+}
+
+// SLOT 属性中的 SESSION，true 时使用默认的过期时间，整数为过期时间，单位毫秒，false 为非持久会话
+pub(crate) fn session_expiry(value: &Value, default: u32) -> Result<Option<u32>, Code> {
+    match value {
+        Value::Bool(true) => Ok(Some(default)),
+        Value::Bool(false) => Ok(None),
+        value => match as_millis(value).and_then(|expiry| u32::try_from(expiry).ok()) {
+            Some(expiry) => Ok(Some(expiry)),
+            None => Err(Code::InvalidSessionFieldType)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use nson::{msg, Value, message_id::MessageId};
+
+    use crate::error::Code;
+    use super::{OfflineQueue, session_expiry};
+
+    #[test]
+    fn test_memory() {
+        let mut queue = OfflineQueue::new(2, None, &MessageId::new());
+
+        assert!(queue.push(msg!{"n": 1}).is_ok());
+        assert!(queue.push(msg!{"n": 2}).is_ok());
+        assert!(queue.push(msg!{"n": 3}) == Err(msg!{"n": 3}));
+
+        let messages = queue.drain();
+        assert!(messages == vec![msg!{"n": 1}, msg!{"n": 2}]);
+
+        // 取出后可以继续暂存
+        assert!(queue.push(msg!{"n": 3}).is_ok());
+    }
+
+    #[test]
+    fn test_file() {
+        let dir = std::env::temp_dir().join(format!("queen-session-{}", MessageId::new().to_hex()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let slot_id = MessageId::new();
+        let mut queue = OfflineQueue::new(10, Some(&dir), &slot_id);
+
+        for i in 0..3 {
+            assert!(queue.push(msg!{"n": i}).is_ok());
+        }
+
+        assert!(dir.join(slot_id.to_hex()).exists());
+
+        // 文件保持打开
+        assert!(queue.handle.is_some());
+
+        let messages = queue.drain();
+        assert!(messages == vec![msg!{"n": 0}, msg!{"n": 1}, msg!{"n": 2}]);
+        assert!(!dir.join(slot_id.to_hex()).exists());
+        assert!(queue.handle.is_none());
+
+        assert!(queue.push(msg!{"n": 0}).is_ok());
+        queue.clear();
+        assert!(queue.handle.is_none());
+
+        // 文件末尾不完整的消息不影响之前的消息
+        assert!(queue.push(msg!{"n": 0}).is_ok());
+        assert!(queue.push(msg!{"n": 1}).is_ok());
+
+        let mut file = fs::OpenOptions::new().append(true).open(dir.join(slot_id.to_hex())).unwrap();
+        file.write_all(&[100, 0, 0, 0, 1, 2]).unwrap();
+
+        let messages = queue.drain();
+        assert!(messages == vec![msg!{"n": 0}, msg!{"n": 1}]);
+        assert!(!dir.join(slot_id.to_hex()).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expiry() {
+        assert!(session_expiry(&Value::Bool(true), 100) == Ok(Some(100)));
+        assert!(session_expiry(&Value::Bool(false), 100) == Ok(None));
+        assert!(session_expiry(&Value::I32(50), 100) == Ok(Some(50)));
+        assert!(session_expiry(&Value::I32(-1), 100) == Err(Code::InvalidSessionFieldType));
+        assert!(session_expiry(&Value::String("a".into()), 100) == Err(Code::InvalidSessionFieldType));
+    }
+}
@@ -1,4 +1,6 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::time::Instant;
 
 use nson::{
     Message,
@@ -6,6 +8,7 @@ use nson::{
 };
 
 use crate::Wire;
+use crate::util::bucket::TokenBucket;
 
 #[derive(Debug)]
 pub struct Slot {
@@ -14,12 +17,84 @@ pub struct Slot {
     pub joined: bool,
     pub chans: HashSet<String>,
     pub share_chans: HashSet<String>,
+    // CHAN，所在的共享订阅组，与 share_chans 同步，通过 share_group 读取
+    pub(crate) share_groups: HashMap<String, String>,
     pub bind: HashSet<usize>,
     pub bound: HashSet<usize>,
     pub tags: HashSet<String>,
+    // 遗嘱消息，SLOT 未发送 GOODBYE 就断开时发布
+    pub will: Option<Message>,
+    // 通过 limits 和 set_limits 读取和修改
+    pub(crate) limits: RefCell<SlotLimits>,
+    // Hook::accept 之后为 true，此时不能再修改 limits
+    pub(crate) accepted: Cell<bool>,
+    pub(crate) rate: Rate,
+    // 持久会话的过期时间，单位毫秒，None 为非持久会话
+    pub(crate) session: Option<u32>,
     pub wire: Wire<Message>
 }
 
+// SLOT 的限制，None 为不限制
+#[derive(Debug, Clone, Default)]
+pub struct SlotLimits {
+    // 每秒接收的消息数
+    pub messages_per_sec: Option<u32>,
+    // 每秒接收的字节数
+    pub bytes_per_sec: Option<u32>,
+    // ATTACH 的普通频道数
+    pub max_chans: Option<usize>,
+    // ATTACH 的共享频道数
+    pub max_share_chans: Option<usize>,
+    // 所属的组，同组的 SLOT 共享速率限制
+    // 同组的 SLOT 的 messages_per_sec 和 bytes_per_sec 必须相同，否则拒绝连接（LimitGroupConflict）
+    pub group: Option<String>
+}
+
+// 接收消息的速率限制，允许 1 秒的突发
+#[derive(Debug, Default)]
+pub(crate) struct Rate {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>
+}
+
+impl Rate {
+    pub fn new(limits: &SlotLimits) -> Self {
+        Rate {
+            messages: limits.messages_per_sec.map(|rate| TokenBucket::new(rate, rate)),
+            bytes: limits.bytes_per_sec.map(|rate| TokenBucket::new(rate, rate))
+        }
+    }
+
+    // 超过限制时返回 false，两个限制都满足时才会扣除，被拒绝的消息不占用额度
+    pub fn allow(&mut self, message: &Message) -> bool {
+        let now = Instant::now();
+
+        if let Some(bucket) = &mut self.messages {
+            if !bucket.can_take_at(now) {
+                return false
+            }
+        }
+
+        if let Some(bucket) = &mut self.bytes {
+            if !bucket.can_consume_at(now) {
+                return false
+            }
+        }
+
+        if let Some(bucket) = &mut self.messages {
+            bucket.take_at(now);
+        }
+
+        if let Some(bucket) = &mut self.bytes {
+            let size = u32::try_from(message.bytes_size()).unwrap_or(u32::MAX);
+
+            bucket.consume_at(size, now);
+        }
+
+        true
+    }
+}
+
 impl Slot {
     pub fn new(token: usize, id: MessageId, wire: Wire<Message>) -> Self {
         Self {
@@ -28,10 +103,69 @@ impl Slot {
             joined: false,
             chans: HashSet::new(),
             share_chans: HashSet::new(),
+            share_groups: HashMap::new(),
             bind: HashSet::new(),
             bound: HashSet::new(),
             tags: HashSet::new(),
+            will: None,
+            limits: RefCell::new(SlotLimits::default()),
+            accepted: Cell::new(false),
+            rate: Rate::default(),
+            session: None,
             wire
         }
     }
+
+    pub fn limits(&self) -> SlotLimits {
+        self.limits.borrow().clone()
+    }
+
+    // 只能在 Hook::accept 中修改，之后返回 false 并且不会修改
+    pub fn set_limits(&self, limits: SlotLimits) -> bool {
+        if self.accepted.get() {
+            return false
+        }
+
+        *self.limits.borrow_mut() = limits;
+
+        true
+    }
+
+    // 共享订阅的 CHAN 所在的组，未订阅时为 None
+    pub fn share_group(&self, chan: &str) -> Option<&str> {
+        self.share_groups.get(chan).map(String::as_str)
+    }
+
+    // 所有共享订阅的 CHAN 和所在的组
+    pub fn share_groups(&self) -> &HashMap<String, String> {
+        &self.share_groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nson::msg;
+
+    use super::{Rate, SlotLimits};
+
+    #[test]
+    fn rate_rejected_without_taking() {
+        let limits = SlotLimits {
+            messages_per_sec: Some(2),
+            bytes_per_sec: Some(1),
+            ..SlotLimits::default()
+        };
+
+        let mut rate = Rate::new(&limits);
+
+        let message = msg!{"a": "hello"};
+
+        // 字节额度用完后，消息额度不再被扣除
+        assert!(rate.allow(&message));
+        assert!(!rate.allow(&message));
+        assert!(!rate.allow(&message));
+
+        let tokens = rate.messages.as_mut().unwrap();
+        assert!(tokens.take());
+    }
 }
@@ -1,5 +1,7 @@
 use std::collections::{HashMap, HashSet};
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::mem;
 
 use queen_io::{
     epoll::{Epoll, Token, Ready, EpollOpt},
@@ -7,25 +9,44 @@ use queen_io::{
 };
 
 use nson::{
-    Message, msg,
+    Message, Value, msg,
     message_id::MessageId,
     Array
 };
 
-use rand::{SeedableRng, seq::SliceRandom, rngs::SmallRng};
+use rand::{SeedableRng, rngs::SmallRng};
 
-use crate::Wire;
+use crate::wire::{Wire, Priority, Shared};
 use crate::dict::*;
-use crate::error::{Code, Result};
+use crate::error::{Code, Result, SendError};
+use crate::util::message::{now_millis, as_millis, is_expired};
+use crate::timer::wheel::Wheel;
 
 use super::Hook;
 use super::Slot;
+use super::slot::{SlotLimits, Rate};
+use super::SocketOptions;
+use super::Packet;
+use super::router::{Shard, Scope};
+use super::balance::{self, Balancer, Candidate, Cursor, Strategy};
+use super::tags::{self, TagExpr};
+use super::filter::{Filter, FilterIndex};
+use super::session::{self, Session, OfflineQueue};
+
+// 定时器精度，单位毫秒
+pub(crate) const TICK: u64 = 10;
 
 pub struct Switch {
     pub socket_id: MessageId,
     // CHAN，Token
     pub chans: HashMap<String, HashSet<usize>>,
+    // CHAN，共享订阅的 Token，不区分组
     pub share_chans: HashMap<String, HashSet<usize>>,
+    // CHAN，共享订阅组，Token，通过 share_groups 读取
+    share_groups: HashMap<String, HashMap<String, HashSet<usize>>>,
+    // CHAN，带过滤条件的订阅
+    filters: HashMap<String, FilterIndex>,
+    share_filters: HashMap<String, FilterIndex>,
     // SLOT_ID，Token
     pub slot_ids: HashMap<MessageId, usize>,
     // SOCKET_ID, Token
@@ -33,37 +54,237 @@ pub struct Switch {
     pub slots: Slab<Slot>,
     pub send_num: Cell<usize>,
     pub recv_num: Cell<usize>,
+    // 因过期而丢弃的消息数
+    pub expire_num: Cell<usize>,
+    pub options: SocketOptions,
+    // ID，延迟投递的消息
+    scheduled: HashMap<MessageId, Scheduled>,
+    // SLOT_ID，该 SLOT 延迟投递的消息数
+    scheduled_num: HashMap<MessageId, usize>,
+    // 请求者的 Token 和 ID，未完成的请求
+    requests: HashMap<(usize, MessageId), Request>,
+    // 目标的 Token 和 ID，等待其回复的请求者的 Token，流式请求的目标为响应者
+    replies: HashMap<(usize, MessageId), usize>,
+    // 请求者的 Token 和 ID，未结束的流式请求
+    streams: HashMap<(usize, MessageId), Stream>,
+    // SLOT_ID，离线的持久会话
+    sessions: HashMap<MessageId, Session>,
+    // CHAN，订阅了 CHAN 的离线的持久会话
+    offline: HashMap<String, HashSet<MessageId>>,
+    // CHAN，共享订阅组，共享订阅了 CHAN 的离线的持久会话
+    offline_share: HashMap<String, HashMap<String, HashSet<MessageId>>>,
+    wheel: Wheel<Timeout>,
+    timer_id_counter: usize,
+    // 分片时所在的分片，只有一个分片时为 None
+    shard: Option<Shard>,
+    // 限制组，组内的 SLOT 共享速率限制
+    groups: HashMap<String, Group>,
+    // 共享投递时选择订阅者
+    balancer: Balancer,
+    // 等待其他分片报告投递数的发布确认
+    confirms: HashMap<usize, Confirm>,
+    confirm_id_counter: usize,
     rand: SmallRng
 }
 
+// 投递的结果
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Delivery {
+    // 接收者数
+    pub targets: usize,
+    // 放入接收者 Wire 的消息数
+    pub queued: usize,
+    // Wire 已满被丢弃的消息数
+    pub dropped: usize
+}
+
+impl Delivery {
+    fn failed<T>(&mut self, err: &SendError<T>) {
+        if let SendError::Full(_) = err {
+            self.dropped += 1;
+        }
+    }
+
+    fn add(&mut self, other: Delivery) {
+        self.targets += other.targets;
+        self.queued += other.queued;
+        self.dropped += other.dropped;
+    }
+}
+
+// 发布确认
+struct Confirm {
+    // 发送者
+    token: usize,
+    slot_id: MessageId,
+    chan: String,
+    id: Option<MessageId>,
+    delivery: Delivery,
+    // 尚未报告投递数的分片数
+    waiting: usize
+}
+
+struct Group {
+    rate: Rate,
+    // 组的速率，加入的 SLOT 必须与之相同
+    messages_per_sec: Option<u32>,
+    bytes_per_sec: Option<u32>,
+    // 组内的 SLOT 数
+    slots: usize
+}
+
+// 延迟投递的消息
+struct Scheduled {
+    timer_id: usize,
+    slot_id: MessageId,
+    chan: String,
+    // 投递时间，UNIX 时间戳，单位毫秒
+    time: i64,
+    message: Message
+}
+
+// 未完成的请求
+struct Request {
+    timer_id: usize,
+    // 请求者
+    token: usize,
+    chan: String,
+    // Token，SLOT_ID，尚未回复的目标
+    targets: HashMap<usize, MessageId>,
+    // 聚合请求，目标的回复暂存在 Switch 中，最后一起回复请求者
+    gather: Option<Gather>
+}
+
+#[derive(Default)]
+struct Gather {
+    // 收到的回复
+    replies: Array,
+    // 不再等待的目标，不存在、已断开或者在其他分片上
+    missing: Array
+}
+
+// 流式请求，响应者按请求者授予的 CREDIT 发送多个回复
+struct Stream {
+    timer_id: usize,
+    // 最后一次回复或授权的时间，UNIX 时间戳，单位毫秒
+    active: i64,
+    requester: usize,
+    responder: usize,
+    responder_id: MessageId,
+    chan: String,
+    // 响应者还可以发送的消息数
+    credit: u32,
+    // 下一个回复的序号
+    seq: u64
+}
+
+#[derive(Debug)]
+enum Timeout {
+    Schedule(MessageId, usize),
+    Request(usize, MessageId, usize),
+    Stream(usize, MessageId, usize),
+    Session(MessageId, usize)
+}
+
 impl Switch {
-    pub(crate) fn new(socket_id: MessageId) -> Self {
+    pub(crate) fn new(socket_id: MessageId, options: SocketOptions, shard: Option<Shard>) -> Self {
         Self {
             socket_id,
             chans: HashMap::new(),
             share_chans: HashMap::new(),
+            share_groups: HashMap::new(),
+            filters: HashMap::new(),
+            share_filters: HashMap::new(),
             slot_ids: HashMap::new(),
             socket_ids: HashMap::new(),
             slots: Slab::new(),
             send_num: Cell::new(0),
             recv_num: Cell::new(0),
+            expire_num: Cell::new(0),
+            options,
+            scheduled: HashMap::new(),
+            scheduled_num: HashMap::new(),
+            requests: HashMap::new(),
+            replies: HashMap::new(),
+            streams: HashMap::new(),
+            sessions: HashMap::new(),
+            offline: HashMap::new(),
+            offline_share: HashMap::new(),
+            wheel: Wheel::default(),
+            timer_id_counter: 0,
+            shard,
+            groups: HashMap::new(),
+            balancer: Balancer::new(),
+            confirms: HashMap::new(),
+            confirm_id_counter: 0,
             rand: SmallRng::from_entropy()
         }
     }
 
+    // CHAN 的共享订阅组和组内的 Token
+    pub fn share_groups(&self) -> &HashMap<String, HashMap<String, HashSet<usize>>> {
+        &self.share_groups
+    }
+
+    fn next_timer_id(&mut self) -> usize {
+        self.timer_id_counter = self.timer_id_counter.wrapping_add(1);
+        self.timer_id_counter
+    }
+
+    // 毫秒转换为定时器的刻度数，至少为 1
+    fn ticks(millis: i64) -> u32 {
+        let ticks = (millis.max(0) as u64).div_ceil(TICK);
+
+        ticks.clamp(1, u64::from(u32::MAX >> 1)) as u32
+    }
+
+    // 定时器中是否还有未到期的项，没有时不需要再调用 tick
+    pub(crate) fn has_timers(&self) -> bool {
+        !self.wheel.is_empty()
+    }
+
+    // 定时器每 TICK 毫秒调用一次
+    pub(crate) fn tick(&mut self, hook: &impl Hook) {
+        let list = self.wheel.tick();
+
+        for timeout in list {
+            match timeout {
+                Timeout::Schedule(id, timer_id) => {
+                    self.fire_scheduled(hook, id, timer_id);
+                }
+                Timeout::Request(requester, id, timer_id) => {
+                    self.expire_request(hook, requester, id, timer_id);
+                }
+                Timeout::Stream(requester, id, timer_id) => {
+                    self.expire_stream(hook, requester, id, timer_id);
+                }
+                Timeout::Session(slot_id, timer_id) => {
+                    self.expire_session(slot_id, timer_id);
+                }
+            }
+        }
+    }
+
     pub(crate) fn add_slot(
         &mut self,
         epoll: &Epoll,
         hook: &impl Hook,
         wire: Wire<Message>
     ) -> Result<()> {
+        // SLOT_ID 已被使用并且允许接管时，为原来的 SLOT 的 Token
+        let mut takeover = None;
+
         // SLOT_ID
         let slot_id = if let Some(slot_id) = wire.attr().get(SLOT_ID) {
             if let Some(slot_id) = slot_id.as_message_id() {
-                if self.slot_ids.contains_key(slot_id) {
-                    let _ = wire.send(msg!{CODE: Code::DuplicateSlotId.code()});
+                if let Some(old_token) = self.slot_ids.get(slot_id) {
+                    if !self.options.takeover {
+                        let _ = wire.send(msg!{CODE: Code::DuplicateSlotId.code()});
+
+                        return Ok(())
+                    }
 
-                    return Ok(())
+                    takeover = Some(*old_token);
                 }
 
                 *slot_id
@@ -73,51 +294,93 @@ impl Switch {
                 return Ok(())
             }
         } else {
-            let slot_id = MessageId::new();
-            slot_id
+            MessageId::new()
         };
 
         wire.attr().insert(SLOT_ID, slot_id);
 
         // TAGS
-        let mut tags = HashSet::new();
-
-        if let Some(tag) = wire.attr().get(TAGS) {
-            if let Some(tag) = tag.as_str() {
-                tags.insert(tag.to_string());
-            } else if let Some(tag_array) = tag.as_array() {
-                for v in tag_array {
-                    if let Some(v) = v.as_str() {
-                        tags.insert(v.to_string());
-                    } else {
-                        let _ = wire.send(msg!{CODE: Code::InvalidTagsFieldType.code()});
+        let tags = match wire.attr().get(TAGS).map(tags::slot_tags) {
+            Some(Ok(tags)) => tags,
+            Some(Err(code)) => {
+                let _ = wire.send(msg!{CODE: code.code()});
 
-                        return Ok(())
-                    }
-                }
-            } else {
-                let _ = wire.send(msg!{CODE: Code::InvalidTagsFieldType.code()});
+                return Ok(())
+            }
+            None => HashSet::new()
+        };
+
+        // LIMITS
+        let limits = match self.slot_limits(&wire.attr()) {
+            Ok(limits) => limits,
+            Err(code) => {
+                let _ = wire.send(msg!{CODE: code.code()});
+
+                return Ok(())
+            }
+        };
+
+        // SESSION
+        let session = match wire.attr().get(SESSION).map(|value| session::session_expiry(value, self.options.session_expiry)) {
+            Some(Ok(session)) => session,
+            Some(Err(code)) => {
+                let _ = wire.send(msg!{CODE: code.code()});
+
+                return Ok(())
+            }
+            None => None
+        };
+
+        // WILL
+        let will = match wire.attr().get(WILL).map(will_message) {
+            Some(Ok(will)) => Some(will),
+            Some(Err(code)) => {
+                let _ = wire.send(msg!{CODE: code.code()});
 
                 return Ok(())
             }
+            None => None
+        };
+
+        // 接管时 SLOT_ID 已经注册在路由表中
+        if takeover.is_none() && !self.register_slot(slot_id) {
+            let _ = wire.send(msg!{CODE: Code::DuplicateSlotId.code()});
+
+            return Ok(())
         }
 
-        let entry = self.slots.vacant_entry();
-        let token = entry.key();
+        // 接管时使用原来的 SLOT 的 Token
+        let token = match takeover {
+            Some(old_token) => old_token,
+            None => self.slots.vacant_entry().key()
+        };
 
         let mut slot = Slot::new(token, slot_id, wire);
         slot.tags = tags;
+        slot.limits = RefCell::new(limits);
+        slot.session = session;
+        slot.will = will;
 
         // 此处可以验证一下 SLOT 的属性，不过目前只能验证 wire.attr
         // 并且，wire.attr 是可以修改的
         // 但是，SLOT 的属性是不能在这里修改的
         let success = hook.accept(&slot);
+        slot.accepted.set(true);
+
+        // 速率限制在 Hook::accept 之后确定，与所在组的速率不同时拒绝
+        if success && self.group_conflict(&slot.limits.borrow(), takeover) {
+            if takeover.is_none() {
+                self.unregister_slot(&slot.id);
+            }
+
+            let _ = slot.wire.send(msg!{CODE: Code::LimitGroupConflict.code()});
+
+            return Ok(())
+        }
 
         if success && slot.wire.send(msg!{CODE: Code::Ok.code()}) == Ok(()) {
             epoll.add(&slot.wire, Token(token), Ready::readable(), EpollOpt::level())?;
 
-            self.slot_ids.insert(slot.id, token);
-
             // 这里发一个事件，表示有 SLOT 认证成功，准备好接收消息了
             // 注意，只有在 SLOT_READY 和 SLOT_BREAK 这两个事件才会返回 SLOT 的 ATTR
             // slot event
@@ -126,16 +389,60 @@ impl Switch {
             //     SLOT_ID: $slot_id,
             //     ATTR: $attr
             // }
+            // 接管时发送 SLOT_TAKEOVER 事件，不再发送 SLOT_BREAK 和 SLOT_READY
+            // {
+            //     CHAN: SLOT_TAKEOVER,
+            //     SLOT_ID: $slot_id,
+            //     ATTR: $attr
+            // }
+            let event_chan = if takeover.is_some() { SLOT_TAKEOVER } else { SLOT_READY };
+
             let event_message = msg!{
-                CHAN: SLOT_READY,
+                CHAN: event_chan,
                 SLOT_ID: slot.id,
                 ATTR: slot.wire.attr().clone()
             };
 
-            entry.insert(slot);
+            match takeover {
+                Some(old_token) => self.take_over(epoll, hook, old_token, slot)?,
+                None => {
+                    let entry = self.slots.vacant_entry();
+                    debug_assert!(entry.key() == token);
 
-            self.relay_event_message(hook, token, SLOT_READY, event_message);
+                    entry.insert(slot);
+                }
+            }
+
+            self.slot_ids.insert(slot_id, token);
+
+            let limits = self.slots[token].limits.borrow().clone();
+
+            match &limits.group {
+                Some(group) => {
+                    self.groups.entry(group.clone())
+                        .or_insert_with(|| Group {
+                            rate: Rate::new(&limits),
+                            messages_per_sec: limits.messages_per_sec,
+                            bytes_per_sec: limits.bytes_per_sec,
+                            slots: 0
+                        })
+                        .slots += 1;
+                }
+                None => self.slots[token].rate = Rate::new(&limits)
+            }
+
+            self.relay_event_message(hook, token, event_chan, event_message);
+
+            // 相同 SLOT_ID 的持久会话
+            if let Some(session) = self.sessions.remove(&slot_id) {
+                self.restore_session(hook, token, session);
+            }
         } else {
+            // 接管失败时，原来的 SLOT 不受影响
+            if takeover.is_none() {
+                self.unregister_slot(&slot.id);
+            }
+
             let _ = slot.wire.send(msg!{CODE: Code::AuthenticationFailed.code()});
         }
 
@@ -149,35 +456,42 @@ impl Switch {
         token: usize
     ) -> Result<()> {
         if self.slots.contains(token) {
-            let slot = self.slots.remove(token);
+            let mut slot = self.slots.remove(token);
             // slot.wire.close(); 这里不需要主动关闭，离开作用域后会自动关闭
             epoll.delete(&slot.wire)?;
 
+            // 持久会话，在移除订阅之前保存
+            if let Some(expiry) = slot.session {
+                self.keep_session(&slot, expiry);
+            }
+
             for chan in &slot.chans {
                 if let Some(ids) = self.chans.get_mut(chan) {
                     ids.remove(&token);
 
                     if ids.is_empty() {
                         self.chans.remove(chan);
+                        self.balancer.remove(&Cursor::Chan(chan.clone()));
                     }
                 }
+
+                self.sync_chan(chan);
+                self.set_filter(token, chan, false, None);
             }
 
             // 移除共享订阅
-            for chan in &slot.share_chans {
-                if let Some(ids) = self.share_chans.get_mut(chan) {
-                    ids.remove(&token);
-
-                    if ids.is_empty() {
-                        self.chans.remove(chan);
-                    }
-                }
+            for (chan, group) in &slot.share_groups {
+                self.remove_share(token, chan, group);
+                self.set_filter(token, chan, true, None);
             }
 
+            self.leave_group(&slot);
+
             // 这里要记得移除 SLOT_ID，因为 wire 在一开始建立连接时就会默认分配一个
             // 认证成功时可以修改
             self.slot_ids.remove(&slot.id);
             self.socket_ids.remove(&slot.id);
+            self.unregister_slot(&slot.id);
 
             // 清除 BIND
             for target_token in &slot.bind {
@@ -186,8 +500,16 @@ impl Switch {
                 }
             }
 
+            // 未发送 GOODBYE 就断开时，发布遗嘱消息
+            if let Some(will) = slot.will.take() {
+                self.publish_will(hook, usize::MAX, &slot, will);
+            }
+
             hook.remove(&slot);
 
+            self.break_requests(hook, token);
+            self.break_streams(hook, token);
+
             // 这里发一个事件，表示有 SLOT 断开
             // 注意，只有在 SLOT_READY 和 SLOT_BREAK 这两个事件才会返回
             // SLOT 的 ATTR
@@ -209,6 +531,244 @@ impl Switch {
         Ok(())
     }
 
+    // 接管使用中的 SLOT_ID，关闭原来的 SLOT，并告知原因
+    // 新的 SLOT 使用原来的 Token，普通订阅、共享订阅、过滤条件和 JOIN 状态都会保留
+    // 原来的 SLOT 发出的和以其为目标的请求、流式请求都会结束
+    // 原来的 SLOT 未发送 GOODBYE，和断开时一样发布其遗嘱消息，新的 SLOT 只使用自己的遗嘱消息
+    // 新的 SLOT 原地替换原来的 SLOT，使用原来的 Token，chans、share_groups、bind、bound 和过滤条件中的 Token 保持不变
+    fn take_over(
+        &mut self,
+        epoll: &Epoll,
+        hook: &impl Hook,
+        old_token: usize,
+        mut slot: Slot
+    ) -> Result<()> {
+        let old = &mut self.slots[old_token];
+        epoll.delete(&old.wire)?;
+
+        slot.joined = old.joined;
+        slot.chans = mem::take(&mut old.chans);
+        slot.share_chans = mem::take(&mut old.share_chans);
+        slot.share_groups = mem::take(&mut old.share_groups);
+        slot.bind = mem::take(&mut old.bind);
+        slot.bound = mem::take(&mut old.bound);
+
+        let mut old = mem::replace(&mut self.slots[old_token], slot);
+
+        self.leave_group(&old);
+
+        // 以原来的 Token 发布遗嘱消息，新的 SLOT 不会收到
+        if let Some(will) = old.will.take() {
+            self.publish_will(hook, old_token, &old, will);
+        }
+
+        hook.remove(&old);
+
+        self.break_requests(hook, old_token);
+        self.break_streams(hook, old_token);
+
+        let _ = old.wire.send_with_priority(msg!{CODE: Code::SlotTakenOver.code()}, Priority::High);
+
+        Ok(())
+    }
+
+    // 要加入的组已存在并且速率不同时返回 true
+    // 接管时，被接管的 SLOT 是组内唯一的 SLOT 的话，组会随之移除，不算冲突
+    fn group_conflict(&self, limits: &SlotLimits, takeover: Option<usize>) -> bool {
+        let name = match &limits.group {
+            Some(name) => name,
+            None => return false
+        };
+
+        let group = match self.groups.get(name) {
+            Some(group) => group,
+            None => return false
+        };
+
+        if let Some(old) = takeover.and_then(|old_token| self.slots.get(old_token)) {
+            if group.slots == 1 && old.limits.borrow().group.as_ref() == Some(name) {
+                return false
+            }
+        }
+
+        group.messages_per_sec != limits.messages_per_sec || group.bytes_per_sec != limits.bytes_per_sec
+    }
+
+    fn leave_group(&mut self, slot: &Slot) {
+        if let Some(group) = &slot.limits.borrow().group {
+            if let Some(g) = self.groups.get_mut(group) {
+                g.slots -= 1;
+
+                if g.slots == 0 {
+                    self.groups.remove(group);
+                }
+            }
+        }
+    }
+
+    // SLOT 断开时保存持久会话，订阅照常从 chans 等中移除，离线期间记录在 offline 中
+    fn keep_session(&mut self, slot: &Slot, expiry: u32) {
+        let filters = slot.chans.iter()
+            .filter_map(|chan| {
+                self.filters.get(chan)
+                    .and_then(|index| index.get(slot.token))
+                    .map(|filter| (chan.clone(), filter.clone()))
+            })
+            .collect();
+
+        let share_filters = slot.share_groups.keys()
+            .filter_map(|chan| {
+                self.share_filters.get(chan)
+                    .and_then(|index| index.get(slot.token))
+                    .map(|filter| (chan.clone(), filter.clone()))
+            })
+            .collect();
+
+        for chan in &slot.chans {
+            self.offline.entry(chan.clone()).or_default().insert(slot.id);
+        }
+
+        for (chan, group) in &slot.share_groups {
+            self.offline_share.entry(chan.clone()).or_default().entry(group.clone()).or_default().insert(slot.id);
+        }
+
+        let timer_id = self.next_timer_id();
+
+        let _ = self.wheel.insert(Timeout::Session(slot.id, timer_id), Self::ticks(i64::from(expiry)));
+
+        let queue = OfflineQueue::new(self.options.session_limit, self.options.session_dir.as_deref(), &slot.id);
+
+        self.sessions.insert(slot.id, Session {
+            timer_id,
+            chans: slot.chans.clone(),
+            share_chans: slot.share_groups.clone(),
+            filters,
+            share_filters,
+            tags: slot.tags.clone(),
+            queue
+        });
+    }
+
+    // 恢复持久会话的订阅，并按顺序投递暂存的消息，已过期的消息进入死信频道
+    fn restore_session(&mut self, hook: &impl Hook, token: usize, mut session: Session) {
+        let slot_id = self.slots[token].id;
+
+        for chan in &session.chans {
+            self.remove_offline(chan, &slot_id);
+
+            self.chans.entry(chan.clone()).or_default().insert(token);
+            self.sync_chan(chan);
+        }
+
+        for (chan, group) in &session.share_chans {
+            self.remove_offline_share(chan, group, &slot_id);
+
+            self.share_chans.entry(chan.clone()).or_default().insert(token);
+            self.share_groups.entry(chan.clone()).or_default().entry(group.clone()).or_default().insert(token);
+            self.sync_share(chan, group);
+        }
+
+        for (chan, filter) in mem::take(&mut session.filters) {
+            self.set_filter(token, &chan, false, Some(filter));
+        }
+
+        for (chan, filter) in mem::take(&mut session.share_filters) {
+            self.set_filter(token, &chan, true, Some(filter));
+        }
+
+        let slot = &mut self.slots[token];
+        slot.chans.extend(mem::take(&mut session.chans));
+        slot.share_chans.extend(session.share_chans.keys().cloned());
+        slot.share_groups.extend(mem::take(&mut session.share_chans));
+
+        let now = now_millis();
+
+        for message in session.queue.drain() {
+            if is_expired(&message, now) {
+                self.expire_num.set(self.expire_num.get() + 1);
+
+                self.dead_letter(hook, usize::MAX, Code::MessageExpired, message, Some(slot_id));
+
+                continue
+            }
+
+            self.deliver(hook, vec![token], message);
+        }
+    }
+
+    fn expire_session(&mut self, slot_id: MessageId, timer_id: usize) {
+        match self.sessions.get(&slot_id) {
+            Some(session) if session.timer_id == timer_id => (),
+            _ => return
+        }
+
+        if let Some(mut session) = self.sessions.remove(&slot_id) {
+            for chan in &session.chans {
+                self.remove_offline(chan, &slot_id);
+                self.sync_chan(chan);
+            }
+
+            for (chan, group) in &session.share_chans {
+                self.remove_offline_share(chan, group, &slot_id);
+                self.sync_share(chan, group);
+            }
+
+            session.queue.clear();
+        }
+    }
+
+    fn remove_offline(&mut self, chan: &str, slot_id: &MessageId) {
+        if let Some(slot_ids) = self.offline.get_mut(chan) {
+            slot_ids.remove(slot_id);
+
+            if slot_ids.is_empty() {
+                self.offline.remove(chan);
+            }
+        }
+    }
+
+    fn remove_offline_share(&mut self, chan: &str, group: &str, slot_id: &MessageId) {
+        if let Some(groups) = self.offline_share.get_mut(chan) {
+            if let Some(slot_ids) = groups.get_mut(group) {
+                slot_ids.remove(slot_id);
+
+                if slot_ids.is_empty() {
+                    groups.remove(group);
+                }
+            }
+
+            if groups.is_empty() {
+                self.offline_share.remove(chan);
+            }
+        }
+    }
+
+    // 暂存到离线的持久会话，超过上限时进入死信频道
+    fn queue_offline(
+        &mut self,
+        hook: &impl Hook,
+        token: usize,
+        slot_ids: Vec<MessageId>,
+        message: Message
+    ) -> Delivery {
+        let mut delivery = Delivery { targets: slot_ids.len(), ..Default::default() };
+
+        for slot_id in slot_ids {
+            if let Some(session) = self.sessions.get_mut(&slot_id) {
+                match session.queue.push(message.clone()) {
+                    Ok(()) => delivery.queued += 1,
+                    Err(message) => {
+                        delivery.dropped += 1;
+
+                        self.dead_letter(hook, token, Code::OfflineQueueFull, message, Some(slot_id));
+                    }
+                }
+            }
+        }
+
+        delivery
+    }
+
     pub(crate) fn recv_message(
         &mut self,
         hook: &impl Hook,
@@ -217,12 +777,20 @@ impl Switch {
     ) -> Result<()> {
         self.recv_num.set(self.recv_num.get() + 1);
 
+        if !self.allow(token, &message) {
+            Code::RateLimited.set(&mut message);
+
+            self.send_system_message(hook, token, message);
+
+            return Ok(())
+        }
+
         let success = hook.recv(&self.slots[token], &mut message);
 
         if !success {
             Code::PermissionDenied.set(&mut message);
 
-            self.send_message(hook, token, message);
+            self.send_system_message(hook, token, message);
 
             return Ok(())
         }
@@ -232,7 +800,7 @@ impl Switch {
             Err(_) => {
                 Code::CannotGetChanField.set(&mut message);
 
-                self.send_message(hook, token, message);
+                self.send_system_message(hook, token, message);
 
                 return Ok(())
             }
@@ -247,10 +815,17 @@ impl Switch {
                 PING => self.ping(hook, token, message),
                 MINE => self.mine(hook, token, message),
                 CUSTOM => self.custom(hook, token, message),
+                SCHEDULE => self.list_scheduled(hook, token, message),
+                CANCEL => self.cancel(hook, token, message),
+                GRANT => self.grant(hook, token, message),
+                WILL => self.set_will(hook, token, message),
+                GOODBYE => self.goodbye(hook, token, message),
+                UPDATE => self.update(hook, token, message),
+                EXPIRED => self.expired(hook, token, message),
                 _ => {
                     Code::UnsupportedChan.set(&mut message);
 
-                    self.send_message(hook, token, message);
+                    self.send_system_message(hook, token, message);
                 }
             }
         } else {
@@ -264,13 +839,36 @@ impl Switch {
         &self,
         hook: &impl Hook,
         token: usize,
-        mut message: Message
+        message: Message
+    ) {
+        self.send_message_with_priority(hook, token, message, Priority::Normal);
+    }
+
+    // 系统频道的回复和 SLOT 事件使用高优先级，不会排在普通消息之后
+    pub(crate) fn send_system_message(
+        &self,
+        hook: &impl Hook,
+        token: usize,
+        message: Message
+    ) {
+        self.send_message_with_priority(hook, token, message, Priority::High);
+    }
+
+    pub(crate) fn send_message_with_priority(
+        &self,
+        hook: &impl Hook,
+        token: usize,
+        mut message: Message,
+        priority: Priority
     ) {
         if let Some(slot) = self.slots.get(token) {
-            let success = hook.send(slot, &mut message);
+            if !hook.send(slot, &mut message) {
+                return
+            }
 
-            if success && slot.wire.send(message).is_ok() {
-                self.send_num.set(self.send_num.get() + 1);
+            match slot.wire.send_with_priority(message, priority) {
+                Ok(_) => self.send_num.set(self.send_num.get() + 1),
+                Err(err) => self.undelivered(hook, slot, err)
             }
         }
     }
@@ -281,6 +879,19 @@ impl Switch {
         token: usize,
         chan: &str,
         message: Message
+    ) {
+        self.forward_event(chan, &message);
+
+        self.send_event_message(hook, token, chan, message);
+    }
+
+    // 发给本分片 CHAN 的普通订阅者，token 为事件的来源，不会收到该事件
+    fn send_event_message(
+        &self,
+        hook: &impl Hook,
+        token: usize,
+        chan: &str,
+        message: Message
     ) {
         if let Some(tokens) = self.chans.get(chan) {
             for other_token in tokens {
@@ -288,60 +899,245 @@ impl Switch {
                     continue;
                 }
 
-                if let Some(slot) = self.slots.get(*other_token) {
-                    let mut message = message.clone();
-
-                    let success = hook.send(slot, &mut message);
-
-                    if success {
-                        self.send_message(hook, slot.token, message);
-                    }
-                }
+                self.send_system_message(hook, *other_token, message.clone());
             }
         }
     }
 
-    #[allow(clippy::cognitive_complexity)]
-    fn relay_message(
+    // 其他分片转发的事件
+    pub(crate) fn relay_forwarded_event(&self, hook: &impl Hook, chan: &str, message: Message) {
+        self.send_event_message(hook, usize::MAX, chan, message);
+    }
+
+    // 其他分片转发的消息，只在本分片内投递
+    pub(crate) fn relay_forwarded(
         &mut self,
         hook: &impl Hook,
-        token: usize,
         chan: String,
-        mut message: Message
+        message: Message,
+        scope: Scope
     ) {
-        let success = hook.emit(&self.slots[token], &mut message);
-
-        if !success {
-            Code::PermissionDenied.set(&mut message);
+        let confirm = scope.confirm;
 
-            self.send_message(hook, token, message);
+        let delivery = self.route(hook, usize::MAX, chan, message, scope);
 
-            return
+        // 需要确认时，向发起的分片报告投递数
+        if let (Some((index, confirm_id)), Some(shard)) = (confirm, &self.shard) {
+            shard.router.push(index, Packet::Delivered(confirm_id, delivery));
         }
+    }
 
-        macro_rules! send {
-            ($self: ident, $hook: ident, $slot: ident, $message: ident) => {
-                let success = $hook.push($slot, &mut $message);
-
-                if success {
-                    let mut message = $message.clone();
-
-                    if $slot.joined {
-                        // 如果对方已 JOIN，则填充 FROM_SOCKET
-                        if !message.contains_key(FROM_SOCKET) {
-                            message.insert(FROM_SOCKET, self.socket_id);
-                        }
-                    }
+    // 其他分片报告的投递数，所有分片都报告后回复发送者
+    pub(crate) fn relay_delivered(&mut self, hook: &impl Hook, confirm_id: usize, delivery: Delivery) {
+        if let Some(confirm) = self.confirms.get_mut(&confirm_id) {
+            confirm.delivery.add(delivery);
+            confirm.waiting -= 1;
 
-                    $self.send_message($hook, $slot.token, message.clone());
+            if confirm.waiting == 0 {
+                if let Some(confirm) = self.confirms.remove(&confirm_id) {
+                    self.confirm_reply(hook, confirm);
                 }
-            };
+            }
         }
+    }
+
+    // 回复发布确认
+    // {
+    //     CHAN: $chan,
+    //     ID: $id,
+    //     CODE: Ok | NoSubscribers,
+    //     QUEUED: $queued,
+    //     DROPPED: $dropped
+    // }
+    fn confirm_reply(&self, hook: &impl Hook, confirm: Confirm) {
+        // 发送者已经断开
+        if self.slots.get(confirm.token).map(|slot| slot.id) != Some(confirm.slot_id) {
+            return
+        }
+
+        let code = if confirm.delivery.targets == 0 { Code::NoSubscribers } else { Code::Ok };
+
+        let mut message = msg!{
+            CHAN: confirm.chan,
+            CODE: code.code(),
+            QUEUED: confirm.delivery.queued as u64,
+            DROPPED: confirm.delivery.dropped as u64
+        };
+
+        if let Some(id) = confirm.id {
+            message.insert(ID, id);
+        }
+
+        self.send_message(hook, confirm.token, message);
+    }
+
+    fn relay_message(
+        &mut self,
+        hook: &impl Hook,
+        token: usize,
+        chan: String,
+        mut message: Message
+    ) {
+        let success = hook.emit(&self.slots[token], &mut message);
+
+        if !success {
+            Code::PermissionDenied.set(&mut message);
+
+            self.send_system_message(hook, token, message);
+
+            return
+        }
+
+        let slot_id = self.slots[token].id;
 
         if !message.contains_key(FROM) {
-            message.insert(FROM, self.slots[token].id);
+            message.insert(FROM, slot_id);
+        }
+
+        self.publish(hook, token, slot_id, chan, message);
+    }
+
+    // 经过 Hook::emit 之后的消息，token 为发送者，发送者可能已经断开（比如遗嘱消息）
+    fn publish(
+        &mut self,
+        hook: &impl Hook,
+        token: usize,
+        slot_id: MessageId,
+        chan: String,
+        mut message: Message
+    ) {
+        // TTL，单位毫秒，会被转换为 EXPIRE（UNIX 时间戳，单位毫秒）
+        // 这样消息经过网络转发后，仍然可以判断是否过期
+        // 同时携带 TTL 和 EXPIRE 时，以 TTL 为准
+        let now = now_millis();
+
+        if let Some(ttl) = message.remove(TTL) {
+            match as_millis(&ttl) {
+                Some(ttl) if ttl >= 0 => {
+                    message.insert(EXPIRE, now.saturating_add(ttl));
+                }
+                _ => {
+                    message.insert(TTL, ttl);
+
+                    Code::InvalidTtlFieldType.set(&mut message);
+
+                    self.send_system_message(hook, token, message);
+
+                    return
+                }
+            }
+        }
+
+        if let Some(expire) = message.get(EXPIRE) {
+            if as_millis(expire).is_none() {
+                Code::InvalidExpireFieldType.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return
+            }
+        }
+
+        if message.get_bool(REQUEST).ok().unwrap_or(false) {
+            if let Some(timeout) = message.get(TIMEOUT) {
+                if !matches!(as_millis(timeout), Some(timeout) if timeout > 0) {
+                    Code::InvalidTimeoutFieldType.set(&mut message);
+
+                    self.send_system_message(hook, token, message);
+
+                    return
+                }
+            }
+
+            if !matches!(message.get(ID), Some(id) if id.as_message_id().is_some()) {
+                Code::InvalidIdFieldType.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return
+            }
+
+            if message.contains_key(CREDIT) && message.get_u32(CREDIT).is_err() {
+                Code::InvalidCreditFieldType.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return
+            }
+        }
+
+        // 目标回复了请求
+        let message = match self.reply_request(hook, token, message) {
+            Some(message) => message,
+            None => return
+        };
+
+        // 流式请求的回复
+        let message = match self.stream_reply(hook, token, message) {
+            Some(message) => message,
+            None => return
+        };
+
+        // 延迟投递，消息会暂存在 Switch 中，到时间后再投递
+        if message.contains_key(DELAY) || message.contains_key(TIME) {
+            self.schedule(hook, token, slot_id, chan, message, now);
+
+            return
+        }
+
+        self.route(hook, token, chan, message, Scope::ALL);
+    }
+
+    // 投递消息，token 为发送者，发送者可能已经断开（比如延迟投递的消息）
+    #[allow(clippy::cognitive_complexity)]
+    fn route(
+        &mut self,
+        hook: &impl Hook,
+        token: usize,
+        chan: String,
+        mut message: Message,
+        scope: Scope
+    ) -> Delivery {
+        if is_expired(&message, now_millis()) {
+            self.expire_num.set(self.expire_num.get() + 1);
+
+            self.dead_letter(hook, token, Code::MessageExpired, message, None);
+
+            return Delivery::default()
+        }
+
+        let request = message.get_bool(REQUEST).ok().unwrap_or(false);
+
+        // 聚合请求，只投递给本分片的 SLOT
+        let gather = request && message.get_bool(GATHER).ok().unwrap_or(false);
+
+        // 本分片跟踪的请求的 ID，请求者的请求完成之前不能重复使用
+        let request_id = match message.get_message_id(ID) {
+            Ok(id) if request && self.slots.contains(token) => Some(*id),
+            _ => None
+        };
+
+        if let Some(id) = request_id {
+            if self.requests.contains_key(&(token, id)) || self.streams.contains_key(&(token, id)) {
+                Code::DuplicateRequestId.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return Delivery::default()
+            }
         }
 
+        // 先收集接收者，最后统一投递
+        let mut recipients = vec![];
+        // 离线的持久会话
+        let mut offline = vec![];
+
+        // 是否为发布到 CHAN 的消息
+        let mut published = false;
+        // 发布确认的 ID，转发到其他分片的数量
+        let mut confirm = None;
+        let mut relayed = 0;
+
         // TO SOCKET
         let mut goon = true;
 
@@ -356,16 +1152,26 @@ impl Switch {
 
                     if let Some(socket_token) = self.socket_ids.get(to_socket_id) {
                         if let Some(slot) = self.slots.get(*socket_token) {
-                            send!(self, hook, slot, message);
+                            recipients.push(slot.token);
                         }
+                    } else if let Some(index) = self.slot_shard(to_socket_id, &scope) {
+                        let mut message = message.clone();
+                        message.insert(TO_SOCKET, *to_socket_id);
+
+                        self.forward(index, &chan, message, Scope::LOCAL);
+                    } else {
+                        let mut message = message.clone();
+                        message.insert(TO_SOCKET, *to_socket_id);
+
+                        self.dead_letter(hook, token, Code::TargetSocketIdNotExist, message, None);
                     }
                 }
             } else {
                 Code::InvalidToSocketFieldType.set(&mut message);
 
-                self.send_message(hook, token, message);
+                self.send_system_message(hook, token, message);
 
-                return
+                return Delivery::default()
             }
         }
 
@@ -377,190 +1183,1536 @@ impl Switch {
                 }
             }
 
+            // 共享投递时选择订阅者的策略
+            let strategy = match self.strategy(&chan, &message) {
+                Ok(strategy) => strategy,
+                Err(code) => {
+                    code.set(&mut message);
+
+                    self.send_system_message(hook, token, message);
+
+                    return Delivery::default()
+                }
+            };
+
             // P2P 的优先级比较高
             // 不管 SLOT 是否 ATTACH，都可给其发送消息
             // 自己可以收到自己发送的消息
             if let Some(to) = message.get(TO).cloned() {
+                // SLOT_ID，所在的分片（本分片为 None）
                 let mut to_ids = vec![];
+                // 不存在的 SLOT_ID
+                let mut missing = vec![];
 
                 if let Some(to_id) = to.as_message_id() {
                     // TO 可以是单个 SLOT_ID
-                    if self.slot_ids.contains_key(to_id) {
-                        to_ids.push(*to_id);
+                    match self.locate_slot(to_id, &scope) {
+                        Some(index) => to_ids.push((*to_id, index)),
+                        None => missing.push(*to_id)
                     }
                 } else if let Some(to_array) = to.as_array() {
                     // TO 也可以是一个数组
                     for to in to_array {
                         if let Some(to_id) = to.as_message_id() {
-                            if self.slot_ids.contains_key(to_id) {
-                                to_ids.push(*to_id);
+                            match self.locate_slot(to_id, &scope) {
+                                Some(index) => to_ids.push((*to_id, index)),
+                                None => missing.push(*to_id)
                             }
                         } else {
                             Code::InvalidToFieldType.set(&mut message);
 
-                            self.send_message(hook, token, message);
+                            self.send_system_message(hook, token, message);
+
+                            return Delivery::default()
+                        }
+                    }
+                } else {
+                    Code::InvalidToFieldType.set(&mut message);
+
+                    self.send_system_message(hook, token, message);
+
+                    return Delivery::default()
+                }
+
+                // 移除 TO
+                message.remove(TO);
+
+                if !missing.is_empty() {
+                    let mut message = message.clone();
+                    message.insert(TO, missing.iter().map(|id| Value::from(*id)).collect::<Array>());
+
+                    self.dead_letter(hook, token, Code::TargetSlotIdNotExist, message, None);
+                }
+
+                // 请求，目标不存在时告知请求者，聚合请求会在回复中列出
+                let mut gathered = if gather {
+                    Some(Gather { missing: mem::take(&mut missing).into_iter().map(Value::from).collect(), ..Default::default() })
+                } else {
+                    None
+                };
+
+                if request {
+                    for to_id in missing {
+                        if let Ok(id) = message.get_message_id(ID) {
+                            self.request_error(hook, token, &chan, *id, Code::TargetSlotIdNotExist, to_id);
+                        }
+                    }
+                }
+
+                if !to_ids.is_empty() {
+                    if message.get_bool(SHARE).ok().unwrap_or(false) && to_ids.len() > 1 {
+                        // 其他分片上的 SLOT 按负载 0、权重 1 计算
+                        let mut candidates: Vec<Candidate<(MessageId, Option<usize>)>> = to_ids.iter()
+                            .map(|(id, index)| {
+                                match self.slot_ids.get(id).filter(|_| index.is_none()) {
+                                    Some(slot_token) => self.candidate(*slot_token, (*id, *index)),
+                                    None => Candidate { value: (*id, *index), id: *id, pending: 0, weight: 1 }
+                                }
+                            })
+                            .collect();
+
+                        let selected = self.balancer.select(
+                            &mut self.rand,
+                            strategy,
+                            Cursor::To,
+                            message.get(SHARE_KEY),
+                            &mut candidates
+                        );
+
+                        to_ids = selected.into_iter().collect();
+                    }
+
+                    let mut targets = HashMap::new();
+                    // 分片，SLOT_ID
+                    let mut remote: HashMap<usize, Array> = HashMap::new();
+
+                    for (to, index) in &to_ids {
+                        if let Some(index) = index {
+                            // 聚合请求不转发到其他分片
+                            match &mut gathered {
+                                Some(gather) => gather.missing.push(*to),
+                                None => remote.entry(*index).or_default().push(*to)
+                            }
+                        } else if let Some(slot_token) = self.slot_ids.get(to) {
+                            if let Some(slot) = self.slots.get(*slot_token) {
+                                // 目标还未回复其他请求者相同 ID 的请求，无法区分回复，不投递
+                                if let Some(id) = request_id.filter(|id| self.replies.contains_key(&(*slot_token, *id))) {
+                                    match &mut gathered {
+                                        Some(gather) => gather.missing.push(*to),
+                                        None => self.request_error(hook, token, &chan, id, Code::DuplicateRequestId, *to)
+                                    }
+
+                                    continue
+                                }
+
+                                recipients.push(slot.token);
+
+                                targets.insert(*slot_token, *to);
+                            }
+                        }
+                    }
+
+                    // 其他分片上的 SLOT 不会被跟踪请求
+                    for (index, ids) in remote {
+                        let mut message = message.clone();
+                        message.insert(TO, ids);
+
+                        self.forward(index, &chan, message, Scope::LOCAL);
+                    }
+
+                    // 流式请求只能有一个目标
+                    let stream = request && !gather && targets.len() == 1 && to_ids.len() == 1
+                        && message.get_bool(STREAM).ok().unwrap_or(false);
+
+                    if stream {
+                        if let Some((responder, responder_id)) = targets.into_iter().next() {
+                            self.open_stream(token, chan, &mut message, responder, responder_id, now_millis());
+                        }
+                    } else if gathered.is_some() || (request && !targets.is_empty()) {
+                        self.track_request(hook, token, chan, &message, targets, gathered);
+                    }
+                } else if gathered.is_some() {
+                    self.track_request(hook, token, chan, &message, HashMap::new(), gathered);
+                }
+            } else {
+                // tags
+                let tags = match message.get(TAGS).map(TagExpr::parse) {
+                    Some(Ok(tags)) => Some(tags),
+                    Some(Err(code)) => {
+                        code.set(&mut message);
+
+                        self.send_system_message(hook, token, message);
+
+                        return Delivery::default()
+                    }
+                    None => None
+                };
+
+                published = true;
+
+                // 发布确认，只在发送者所在的分片回复
+                if scope.forward && token != usize::MAX && message.get_bool(CONFIRM).ok().unwrap_or(false) {
+                    self.confirm_id_counter = self.confirm_id_counter.wrapping_add(1);
+                    confirm = Some((self.confirm_id_counter, chan.clone()));
+                }
+
+                // 分片时，先转发到其他分片，并决定本分片是否投递
+                let forward_scope = Scope { forward: scope.forward && !gather, ..scope.clone() };
+                let (local_chans, local_share_groups, count) = self.forward_chan(&chan, &message, &forward_scope, strategy, confirm.as_ref().map(|c| c.0));
+                relayed = count;
+
+                // 带过滤条件的订阅，不满足条件时跳过
+                let filters = self.filters.get(&chan).map(|index| index.check(&message));
+                let share_filters = self.share_filters.get(&chan).map(|index| index.check(&message));
+
+                let share = message.get_bool(SHARE).ok().unwrap_or(false);
+
+                if let Some(tokens) = self.chans.get(&chan).filter(|_| local_chans) {
+                    if share {
+                        let mut candidates = Vec::new();
+
+                        for slot_token in tokens.iter() {
+                            // 自己不能收到自己的消息
+                            if slot_token == &token {
+                                continue;
+                            }
+
+                            if filters.as_ref().is_some_and(|filters| !filters.accept(*slot_token)) {
+                                continue
+                            }
+
+                            if let Some(tags) = &tags {
+                                if let Some(slot) = self.slots.get(*slot_token) {
+                                    if !tags.matches(&slot.tags) {
+                                        continue
+                                    }
+                                }
+                            }
+
+                            candidates.push(self.candidate(*slot_token, *slot_token));
+                        }
+
+                        let selected = self.balancer.select(
+                            &mut self.rand,
+                            strategy,
+                            Cursor::Chan(chan.clone()),
+                            message.get(SHARE_KEY),
+                            &mut candidates
+                        );
+
+                        recipients.extend(selected);
+                    } else {
+                        // 给每个 SLOT 发送消息
+                        for slot_token in tokens.iter() {
+                            // 自己不能收到自己的消息
+                            if slot_token == &token {
+                                continue;
+                            }
+
+                            if filters.as_ref().is_some_and(|filters| !filters.accept(*slot_token)) {
+                                continue
+                            }
+
+                            if let Some(slot) = self.slots.get(*slot_token) {
+                                if tags.as_ref().is_some_and(|tags| !tags.matches(&slot.tags)) {
+                                    continue
+                                }
+
+                                recipients.push(slot.token);
+                            }
+                        }
+                    }
+                }
+
+                // 离线的持久会话，SHARE 消息只在没有在线的订阅者收到时，暂存到其中一个会话
+                if let Some(slot_ids) = self.offline.get(&chan).filter(|_| local_chans) {
+                    if !share || recipients.is_empty() {
+                        let matched = slot_ids.iter().filter(|slot_id| {
+                            self.sessions.get(*slot_id).is_some_and(|session| {
+                                session.filters.get(&chan).is_none_or(|filter| filter.matches(&message))
+                                    && tags.as_ref().is_none_or(|tags| tags.matches(&session.tags))
+                            })
+                        });
+
+                        if share {
+                            offline.extend(matched.take(1));
+                        } else {
+                            offline.extend(matched);
+                        }
+                    }
+                }
+
+                // 已投递给在线的 SLOT 的共享订阅组
+                let mut served = HashSet::new();
+
+                // 共享订阅，每个组投递给组内的一个 SLOT
+                // 注意: 共享订阅与普通订阅是两套并行的机制，
+                // 不管发送消息时有没有　SHARE　参数，共享订阅始终能收到消息
+                if let Some(groups) = self.share_groups.get(&chan) {
+                    for (group, tokens) in groups {
+                        if let Some(local) = &local_share_groups {
+                            if !local.contains(group) {
+                                continue
+                            }
+                        }
+
+                        let mut candidates = Vec::new();
+
+                        for slot_token in tokens.iter() {
+                            // 自己不能收到自己的消息
+                            if slot_token == &token {
+                                continue;
+                            }
+
+                            if share_filters.as_ref().is_some_and(|filters| !filters.accept(*slot_token)) {
+                                continue
+                            }
+
+                            if let Some(tags) = &tags {
+                                if let Some(slot) = self.slots.get(*slot_token) {
+                                    if !tags.matches(&slot.tags) {
+                                        continue
+                                    }
+                                }
+                            }
+
+                            candidates.push(self.candidate(*slot_token, *slot_token));
+                        }
+
+                        let selected = self.balancer.select(
+                            &mut self.rand,
+                            strategy,
+                            Cursor::Group(chan.clone(), group.clone()),
+                            message.get(SHARE_KEY),
+                            &mut candidates
+                        );
+
+                        if let Some(selected) = selected {
+                            recipients.push(selected);
+                            served.insert(group.clone());
+                        }
+                    }
+                }
+
+                // 组内没有在线的 SLOT 收到时，暂存到组内其中一个离线的持久会话
+                if let Some(groups) = self.offline_share.get(&chan) {
+                    for (group, slot_ids) in groups {
+                        if served.contains(group) {
+                            continue
+                        }
+
+                        if let Some(local) = &local_share_groups {
+                            if !local.contains(group) {
+                                continue
+                            }
+                        }
+
+                        let matched = slot_ids.iter().find(|slot_id| {
+                            self.sessions.get(*slot_id).is_some_and(|session| {
+                                session.share_filters.get(&chan).is_none_or(|filter| filter.matches(&message))
+                                    && tags.as_ref().is_none_or(|tags| tags.matches(&session.tags))
+                            })
+                        });
+
+                        offline.extend(matched);
+                    }
+                }
+
+                if gather {
+                    let mut gathered = Gather::default();
+                    let mut targets = HashMap::new();
+
+                    // 还未回复其他请求者相同 ID 的请求的订阅者，不投递
+                    recipients.retain(|slot_token| {
+                        match self.slots.get(*slot_token) {
+                            Some(slot) => {
+                                if request_id.is_some_and(|id| self.replies.contains_key(&(*slot_token, id))) {
+                                    gathered.missing.push(slot.id);
+
+                                    return false
+                                }
+
+                                targets.insert(*slot_token, slot.id);
+
+                                true
+                            }
+                            None => false
+                        }
+                    });
+
+                    self.track_request(hook, token, chan.clone(), &message, targets, Some(gathered));
+                }
+            }
+
+        } // end goon
+
+        let id = message.get_message_id(ID).ok().copied();
+
+        // 所有分片都没有接收者时，进入死信频道，聚合请求会直接告知请求者
+        let delivery = if published && scope.forward && !gather && relayed == 0 && recipients.is_empty() && offline.is_empty() {
+            self.dead_letter(hook, token, Code::NoSubscribers, message, None);
+
+            Delivery::default()
+        } else if offline.is_empty() {
+            self.deliver(hook, recipients, message)
+        } else {
+            let mut delivery = self.deliver(hook, recipients, message.clone());
+
+            delivery.add(self.queue_offline(hook, token, offline, message));
+
+            delivery
+        };
+
+        if let Some((confirm_id, chan)) = confirm {
+            let confirm = Confirm {
+                token,
+                slot_id: self.slots[token].id,
+                chan,
+                id,
+                delivery,
+                waiting: relayed
+            };
+
+            if relayed == 0 {
+                self.confirm_reply(hook, confirm);
+            } else {
+                self.confirms.insert(confirm_id, confirm);
+            }
+        }
+
+        delivery
+    }
+
+    // 投递给多个接收者
+    // Hook 不会修改消息时（Hook.immutable），所有接收者共用同一份消息，
+    // 网络连接也可以共用同一份编码结果
+    fn deliver(&self, hook: &impl Hook, recipients: Vec<usize>, mut message: Message) -> Delivery {
+        let mut delivery = Delivery { targets: recipients.len(), ..Default::default() };
+
+        if recipients.is_empty() {
+            return delivery
+        }
+
+        if hook.immutable() {
+            let mut shared = vec![];
+
+            for slot_token in recipients {
+                if let Some(slot) = self.slots.get(slot_token) {
+                    if !hook.push(slot, &mut message) || !hook.send(slot, &mut message) {
+                        continue
+                    }
+
+                    if slot.joined && !message.contains_key(FROM_SOCKET) {
+                        // 如果对方已 JOIN，则填充 FROM_SOCKET
+                        let mut message = message.clone();
+                        message.insert(FROM_SOCKET, self.socket_id);
+
+                        if let Err(err) = slot.wire.send(message) {
+                            delivery.failed(&err);
+
+                            self.undelivered(hook, slot, err);
+                        } else {
+                            delivery.queued += 1;
+                        }
+                    } else {
+                        shared.push(slot);
+                    }
+                }
+            }
+
+            let message = Arc::new(Shared::new(message));
+
+            for slot in shared {
+                if let Err(err) = slot.wire.send_shared(message.clone()) {
+                    delivery.failed(&err);
+
+                    let err = match err {
+                        SendError::Full(message) => SendError::Full(message.get().clone()),
+                        SendError::Disconnected(message) => SendError::Disconnected(message.get().clone())
+                    };
+
+                    self.undelivered(hook, slot, err);
+                } else {
+                    delivery.queued += 1;
+                }
+            }
+
+            self.send_num.set(self.send_num.get() + delivery.queued);
+
+            return delivery
+        }
+
+        let mut iter = recipients.into_iter().peekable();
+
+        while let Some(slot_token) = iter.next() {
+            if let Some(slot) = self.slots.get(slot_token) {
+                // 最后一个接收者不需要克隆
+                let mut message = if iter.peek().is_some() {
+                    message.clone()
+                } else {
+                    mem::take(&mut message)
+                };
+
+                if !hook.push(slot, &mut message) {
+                    continue
+                }
+
+                if slot.joined {
+                    // 如果对方已 JOIN，则填充 FROM_SOCKET
+                    if !message.contains_key(FROM_SOCKET) {
+                        message.insert(FROM_SOCKET, self.socket_id);
+                    }
+                }
+
+                if hook.send(slot, &mut message) {
+                    if let Err(err) = slot.wire.send(message) {
+                        delivery.failed(&err);
+
+                        self.undelivered(hook, slot, err);
+                    } else {
+                        delivery.queued += 1;
+                    }
+                }
+            }
+        }
+
+        self.send_num.set(self.send_num.get() + delivery.queued);
+
+        delivery
+    }
+
+    // 延迟投递
+    // DELAY: 延迟时间，单位毫秒
+    // TIME: 投递时间，UNIX 时间戳，单位毫秒
+    // 同时携带时，以 DELAY 为准
+    // 消息的 ID 用于取消，没有 ID 时会自动生成一个，ID 相同时会覆盖之前的消息
+    fn schedule(
+        &mut self,
+        hook: &impl Hook,
+        token: usize,
+        slot_id: MessageId,
+        chan: String,
+        mut message: Message,
+        now: i64
+    ) {
+        let time = if let Some(delay) = message.remove(DELAY) {
+            message.remove(TIME);
+
+            match as_millis(&delay) {
+                Some(delay) if delay >= 0 => now.saturating_add(delay),
+                _ => {
+                    message.insert(DELAY, delay);
+
+                    Code::InvalidDelayFieldType.set(&mut message);
+
+                    self.send_system_message(hook, token, message);
+
+                    return
+                }
+            }
+        } else if let Some(time) = message.remove(TIME) {
+            match as_millis(&time) {
+                Some(time) => time,
+                None => {
+                    message.insert(TIME, time);
+
+                    Code::InvalidTimeFieldType.set(&mut message);
+
+                    self.send_system_message(hook, token, message);
+
+                    return
+                }
+            }
+        } else {
+            now
+        };
+
+        let id = match message.get(ID) {
+            Some(id) => {
+                if let Some(id) = id.as_message_id() {
+                    *id
+                } else {
+                    Code::InvalidIdFieldType.set(&mut message);
+
+                    self.send_system_message(hook, token, message);
+
+                    return
+                }
+            }
+            None => {
+                let id = MessageId::new();
+                message.insert(ID, id);
+                id
+            }
+        };
+
+        if time <= now {
+            self.route(hook, token, chan, message, Scope::ALL);
+
+            return
+        }
+
+        if let Some(scheduled) = self.scheduled.get(&id) {
+            if scheduled.slot_id != slot_id {
+                Code::PermissionDenied.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return
+            }
+        } else {
+            // 覆盖之前的消息时不占用新的数量
+            let num = self.scheduled_num.get(&slot_id).copied().unwrap_or(0);
+
+            if num >= self.options.schedule_limit {
+                Code::ScheduleLimitExceeded.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return
+            }
+
+            self.scheduled_num.insert(slot_id, num + 1);
+        }
+
+        let timer_id = self.next_timer_id();
+
+        // 延迟为 0 时才会插入失败，这里至少为 1
+        let _ = self.wheel.insert(Timeout::Schedule(id, timer_id), Self::ticks(time - now));
+
+        self.scheduled.insert(id, Scheduled {
+            timer_id,
+            slot_id,
+            chan,
+            time,
+            message
+        });
+    }
+
+    fn fire_scheduled(&mut self, hook: &impl Hook, id: MessageId, timer_id: usize) {
+        match self.scheduled.get(&id) {
+            Some(scheduled) if scheduled.timer_id == timer_id => {
+                // 定时器与系统时间存在误差，或者延迟超出了定时器的范围，需要重新插入
+                let now = now_millis();
+
+                if scheduled.time > now {
+                    let ticks = Self::ticks(scheduled.time - now);
+                    let _ = self.wheel.insert(Timeout::Schedule(id, timer_id), ticks);
+
+                    return
+                }
+            }
+            _ => return
+        }
+
+        if let Some(scheduled) = self.unschedule(id) {
+            // 发送者可能已经断开，此时 token 不存在
+            let token = self.slot_ids.get(&scheduled.slot_id).copied().unwrap_or(usize::MAX);
+
+            self.route(hook, token, scheduled.chan, scheduled.message, Scope::ALL);
+        }
+    }
+
+    fn unschedule(&mut self, id: MessageId) -> Option<Scheduled> {
+        let scheduled = self.scheduled.remove(&id)?;
+
+        if let Some(num) = self.scheduled_num.get_mut(&scheduled.slot_id) {
+            *num -= 1;
+
+            if *num == 0 {
+                self.scheduled_num.remove(&scheduled.slot_id);
+            }
+        }
+
+        Some(scheduled)
+    }
+
+    // 列出该 SLOT 的延迟投递的消息
+    // {
+    //     CHAN: SCHEDULE,
+    //     VALUE: [{ID: $id, CHAN: $chan, TIME: $time}]
+    // }
+    fn list_scheduled(&self, hook: &impl Hook, token: usize, mut message: Message) {
+        let slot_id = self.slots[token].id;
+
+        let mut list: Vec<(i64, Message)> = self.scheduled.iter()
+            .filter(|(_, scheduled)| scheduled.slot_id == slot_id)
+            .map(|(id, scheduled)| (scheduled.time, msg!{
+                ID: *id,
+                CHAN: scheduled.chan.as_str(),
+                TIME: scheduled.time
+            }))
+            .collect();
+
+        list.sort_by_key(|(time, _)| *time);
+
+        let array: Array = list.into_iter().map(|(_, m)| m.into()).collect();
+
+        message.insert(VALUE, array);
+
+        Code::Ok.set(&mut message);
+
+        self.send_system_message(hook, token, message);
+    }
+
+    // 取消延迟投递的消息或者流式请求，只能取消自己发送的消息
+    // {
+    //     CHAN: CANCEL,
+    //     VALUE: $id
+    // }
+    fn cancel(&mut self, hook: &impl Hook, token: usize, mut message: Message) {
+        let id = match message.get_message_id(VALUE) {
+            Ok(id) => *id,
+            Err(_) => {
+                Code::CannotGetValueField.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return
+            }
+        };
+
+        match self.scheduled.get(&id) {
+            Some(scheduled) => {
+                if scheduled.slot_id == self.slots[token].id {
+                    self.unschedule(id);
+
+                    Code::Ok.set(&mut message);
+                } else {
+                    Code::PermissionDenied.set(&mut message);
+                }
+            }
+            None => match self.remove_stream(token, id) {
+                Some(stream) => {
+                    self.stream_canceled(hook, id, stream);
+
+                    Code::Ok.set(&mut message);
+                }
+                None => {
+                    if self.responding(token, id) {
+                        Code::PermissionDenied.set(&mut message);
+                    } else {
+                        Code::NotFound.set(&mut message);
+                    }
+                }
+            }
+        }
+
+        self.send_system_message(hook, token, message);
+    }
+
+    // 开始流式请求，请求者需要携带 REQUEST、STREAM 和 ID，并且 TO 只有一个目标
+    // CREDIT: 初始的授权数，未携带时为 SocketOptions.stream_credit，会告知响应者
+    // 响应者回复时携带相同的 ID，每个回复消耗一个授权，Switch 会为回复填充 SEQ，从 0 开始
+    // 携带 FINISH 的回复结束流，不消耗授权
+    // 超过 SocketOptions.stream_timeout 没有回复或授权时结束流，请求者也可以通过 CANCEL 取消
+    fn open_stream(
+        &mut self,
+        token: usize,
+        chan: String,
+        message: &mut Message,
+        responder: usize,
+        responder_id: MessageId,
+        now: i64
+    ) {
+        // 请求者可能已经断开（比如延迟投递的消息）
+        if !self.slots.contains(token) {
+            return
+        }
+
+        let id = match message.get_message_id(ID) {
+            Ok(id) => *id,
+            Err(_) => return
+        };
+
+        let credit = message.get_u32(CREDIT).unwrap_or(self.options.stream_credit);
+
+        message.insert(CREDIT, credit);
+
+        let timer_id = self.next_timer_id();
+
+        let _ = self.wheel.insert(Timeout::Stream(token, id, timer_id), Self::ticks(i64::from(self.options.stream_timeout)));
+
+        self.replies.insert((responder, id), token);
+
+        self.streams.insert((token, id), Stream {
+            timer_id,
+            active: now,
+            requester: token,
+            responder,
+            responder_id,
+            chan,
+            credit,
+            seq: 0
+        });
+    }
+
+    // 响应者的回复直接投递给请求者，授权用完时告知响应者
+    fn stream_reply(&mut self, hook: &impl Hook, token: usize, mut message: Message) -> Option<Message> {
+        let id = match message.get_message_id(ID) {
+            Ok(id) => *id,
+            Err(_) => return Some(message)
+        };
+
+        let requester = match self.replies.get(&(token, id)) {
+            Some(requester) => *requester,
+            None => return Some(message)
+        };
+
+        let stream = match self.streams.get_mut(&(requester, id)) {
+            Some(stream) if stream.responder == token => stream,
+            _ => return Some(message)
+        };
+
+        stream.active = now_millis();
+
+        let finish = message.get_bool(FINISH).ok().unwrap_or(false);
+
+        if !finish {
+            if stream.credit == 0 {
+                Code::CreditExhausted.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return None
+            }
+
+            stream.credit -= 1;
+        }
+
+        message.remove(TO);
+        message.insert(SEQ, stream.seq);
+
+        stream.seq += 1;
+
+        if finish {
+            self.remove_stream(requester, id);
+        }
+
+        self.deliver(hook, vec![requester], message);
+
+        None
+    }
+
+    // 请求者授予更多的 CREDIT，并告知响应者
+    // {
+    //     CHAN: GRANT,
+    //     VALUE: $id,
+    //     CREDIT: $credit
+    // }
+    fn grant(&mut self, hook: &impl Hook, token: usize, mut message: Message) {
+        let id = match message.get_message_id(VALUE) {
+            Ok(id) => *id,
+            Err(_) => {
+                Code::CannotGetValueField.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return
+            }
+        };
+
+        let credit = match message.get_u32(CREDIT) {
+            Ok(credit) => credit,
+            Err(_) => {
+                Code::InvalidCreditFieldType.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return
+            }
+        };
+
+        match self.streams.get_mut(&(token, id)) {
+            Some(stream) => {
+                stream.credit = stream.credit.saturating_add(credit);
+                stream.active = now_millis();
+
+                let notice = msg!{
+                    CHAN: stream.chan.as_str(),
+                    ID: id,
+                    CREDIT: credit
+                };
+
+                let responder = stream.responder;
+
+                self.send_system_message(hook, responder, notice);
+
+                Code::Ok.set(&mut message);
+            }
+            None => {
+                if self.responding(token, id) {
+                    Code::PermissionDenied.set(&mut message);
+                } else {
+                    Code::NotFound.set(&mut message);
+                }
+            }
+        }
+
+        self.send_system_message(hook, token, message);
+    }
+
+    // 告知响应者流已被取消
+    // {
+    //     CHAN: $chan,
+    //     ID: $id,
+    //     CODE: StreamCanceled,
+    //     FINISH: true
+    // }
+    fn stream_canceled(&self, hook: &impl Hook, id: MessageId, stream: Stream) {
+        let message = msg!{
+            CHAN: stream.chan,
+            ID: id,
+            CODE: Code::StreamCanceled.code(),
+            FINISH: true
+        };
+
+        self.send_system_message(hook, stream.responder, message);
+    }
+
+    // SLOT 断开时结束相关的流，请求者断开时告知响应者，响应者断开时告知请求者
+    fn break_streams(&mut self, hook: &impl Hook, token: usize) {
+        let broken: Vec<(usize, MessageId)> = self.streams.iter()
+            .filter(|(_, stream)| stream.requester == token || stream.responder == token)
+            .map(|(key, _)| *key)
+            .collect();
+
+        for (requester, id) in broken {
+            if let Some(stream) = self.remove_stream(requester, id) {
+                if stream.requester == token {
+                    self.stream_canceled(hook, id, stream);
+                } else {
+                    self.request_error(hook, stream.requester, &stream.chan, id, Code::TargetSlotBroken, stream.responder_id);
+                }
+            }
+        }
+    }
+
+    // 流式请求空闲超时，告知请求者和响应者
+    fn expire_stream(&mut self, hook: &impl Hook, requester: usize, id: MessageId, timer_id: usize) {
+        let active = match self.streams.get(&(requester, id)) {
+            Some(stream) if stream.timer_id == timer_id => stream.active,
+            _ => return
+        };
+
+        // 期间有过回复或授权，按最后活动的时间重新插入
+        let idle = now_millis() - active;
+        let timeout = i64::from(self.options.stream_timeout);
+
+        if idle < timeout {
+            let _ = self.wheel.insert(Timeout::Stream(requester, id, timer_id), Self::ticks(timeout - idle));
+
+            return
+        }
+
+        if let Some(stream) = self.remove_stream(requester, id) {
+            self.request_error(hook, requester, &stream.chan, id, Code::RequestTimeout, stream.responder_id);
+            self.stream_canceled(hook, id, stream);
+        }
+    }
+
+    fn remove_stream(&mut self, requester: usize, id: MessageId) -> Option<Stream> {
+        let stream = self.streams.remove(&(requester, id))?;
+
+        self.replies.remove(&(stream.responder, id));
+
+        Some(stream)
+    }
+
+    // 是否为该 ID 的流式请求的响应者
+    fn responding(&self, token: usize, id: MessageId) -> bool {
+        match self.replies.get(&(token, id)) {
+            Some(requester) => self.streams.contains_key(&(*requester, id)),
+            None => false
+        }
+    }
+
+    // 跟踪请求，目标断开或超时时回复请求者
+    // TIMEOUT: 超时时间，单位毫秒，未携带时使用 SocketOptions.request_timeout
+    // 请求按请求者和 ID 区分，目标的回复按目标和 ID 找到请求者
+    // 聚合请求没有需要等待的目标时，直接回复请求者
+    fn track_request(
+        &mut self,
+        hook: &impl Hook,
+        token: usize,
+        chan: String,
+        message: &Message,
+        targets: HashMap<usize, MessageId>,
+        gather: Option<Gather>
+    ) {
+        // 请求者可能已经断开（比如延迟投递的消息）
+        if !self.slots.contains(token) {
+            return
+        }
+
+        let id = match message.get_message_id(ID) {
+            Ok(id) => *id,
+            Err(_) => return
+        };
+
+        if targets.is_empty() {
+            if let Some(gather) = gather {
+                let code = if gather.missing.is_empty() { Code::NoSubscribers } else { Code::Ok };
+
+                self.gather_reply(hook, token, &chan, id, gather, code);
+            }
+
+            return
+        }
+
+        let timeout = message.get(TIMEOUT)
+            .and_then(as_millis)
+            .unwrap_or_else(|| i64::from(self.options.request_timeout));
+
+        let timer_id = self.next_timer_id();
+
+        let _ = self.wheel.insert(Timeout::Request(token, id, timer_id), Self::ticks(timeout));
+
+        for target in targets.keys() {
+            self.replies.insert((*target, id), token);
+        }
+
+        self.requests.insert((token, id), Request {
+            timer_id,
+            token,
+            chan,
+            targets,
+            gather
+        });
+    }
+
+    // 目标回复了请求，聚合请求的回复会被暂存，返回 None
+    fn reply_request(&mut self, hook: &impl Hook, token: usize, message: Message) -> Option<Message> {
+        let id = match message.get_message_id(ID) {
+            Ok(id) => *id,
+            Err(_) => return Some(message)
+        };
+
+        let requester = match self.replies.get(&(token, id)) {
+            Some(requester) => *requester,
+            None => return Some(message)
+        };
+
+        let request = match self.requests.get_mut(&(requester, id)) {
+            Some(request) => request,
+            None => return Some(message)
+        };
+
+        if request.targets.remove(&token).is_none() {
+            return Some(message)
+        }
+
+        self.replies.remove(&(token, id));
+
+        let message = match &mut request.gather {
+            Some(gather) => {
+                gather.replies.push(message);
+
+                None
+            }
+            None => Some(message)
+        };
+
+        if request.targets.is_empty() {
+            if let Some(request) = self.requests.remove(&(requester, id)) {
+                if let Some(gather) = request.gather {
+                    self.gather_reply(hook, request.token, &request.chan, id, gather, Code::Ok);
+                }
+            }
+        }
+
+        message
+    }
+
+    fn expire_request(&mut self, hook: &impl Hook, requester: usize, id: MessageId, timer_id: usize) {
+        match self.requests.get(&(requester, id)) {
+            Some(request) if request.timer_id == timer_id => (),
+            _ => return
+        }
+
+        if let Some(request) = self.requests.remove(&(requester, id)) {
+            for target in request.targets.keys() {
+                self.replies.remove(&(*target, id));
+            }
+
+            if let Some(mut gather) = request.gather {
+                gather.missing.extend(request.targets.values().map(|target| Value::from(*target)));
+
+                self.gather_reply(hook, request.token, &request.chan, id, gather, Code::RequestTimeout);
+
+                return
+            }
+
+            for target in request.targets.values() {
+                self.request_error(hook, request.token, &request.chan, id, Code::RequestTimeout, *target);
+            }
+        }
+    }
+
+    // SLOT 断开时，移除其发出的请求，并告知以其为目标的请求者
+    // 聚合请求只记录断开的目标，其他目标都回复后再回复请求者
+    fn break_requests(&mut self, hook: &impl Hook, token: usize) {
+        let mut broken = vec![];
+        let mut gathered = vec![];
+
+        let replies = &mut self.replies;
+
+        self.requests.retain(|(requester, id), request| {
+            if *requester == token {
+                for target in request.targets.keys() {
+                    replies.remove(&(*target, *id));
+                }
+
+                return false
+            }
+
+            if let Some(target) = request.targets.remove(&token) {
+                replies.remove(&(token, *id));
+
+                match &mut request.gather {
+                    Some(gather) => gather.missing.push(target),
+                    None => broken.push((request.token, request.chan.clone(), *id, target))
+                }
+            }
+
+            if request.targets.is_empty() {
+                if let Some(gather) = request.gather.take() {
+                    gathered.push((request.token, request.chan.clone(), *id, gather));
+                }
+            }
+
+            !request.targets.is_empty()
+        });
+
+        for (requester, chan, id, target) in broken {
+            self.request_error(hook, requester, &chan, id, Code::TargetSlotBroken, target);
+        }
+
+        for (requester, chan, id, gather) in gathered {
+            self.gather_reply(hook, requester, &chan, id, gather, Code::Ok);
+        }
+    }
+
+    // 回复聚合请求
+    // {
+    //     CHAN: $chan,
+    //     ID: $id,
+    //     CODE: Ok | RequestTimeout | NoSubscribers,
+    //     VALUE: [$reply, ...],
+    //     MISSING: [$slot_id, ...]
+    // }
+    fn gather_reply(
+        &self,
+        hook: &impl Hook,
+        token: usize,
+        chan: &str,
+        id: MessageId,
+        gather: Gather,
+        code: Code
+    ) {
+        let message = msg!{
+            CHAN: chan,
+            ID: id,
+            CODE: code.code(),
+            VALUE: gather.replies,
+            MISSING: gather.missing
+        };
+
+        self.send_message(hook, token, message);
+    }
+
+    // 请求失败时回复请求者
+    // {
+    //     CHAN: $chan,
+    //     ID: $id,
+    //     CODE: $code,
+    //     FROM: $target_slot_id
+    // }
+    fn request_error(
+        &self,
+        hook: &impl Hook,
+        token: usize,
+        chan: &str,
+        id: MessageId,
+        code: Code,
+        target: MessageId
+    ) {
+        let message = msg!{
+            CHAN: chan,
+            ID: id,
+            CODE: code.code(),
+            FROM: target
+        };
+
+        self.send_system_message(hook, token, message);
+    }
+
+    // 将无法投递的消息包装后发送到死信频道，未配置死信频道时直接丢弃
+    // 死信频道只有普通订阅会收到消息
+    // token 为发送者，已经断开或者未知时，使用消息的 FROM
+    // target 为投递失败的接收者
+    fn dead_letter(
+        &self,
+        hook: &impl Hook,
+        token: usize,
+        code: Code,
+        message: Message,
+        target: Option<MessageId>
+    ) {
+        if let Some(dead_letter) = &self.options.dead_letter {
+            // 死信频道本身的消息不再进入死信频道，避免循环
+            if message.get_str(CHAN) == Ok(dead_letter) {
+                return
+            }
+
+            let mut dead_message = msg!{
+                CHAN: dead_letter.as_str(),
+                CODE: code.code()
+            };
+
+            if let Ok(chan) = message.get_str(CHAN) {
+                dead_message.insert(ORIGIN, chan);
+            }
+
+            if let Some(slot) = self.slots.get(token) {
+                dead_message.insert(SLOT_ID, slot.id);
+            } else if let Ok(from) = message.get_message_id(FROM) {
+                dead_message.insert(SLOT_ID, *from);
+            }
+
+            if let Some(target) = target {
+                dead_message.insert(TO, target);
+            }
+
+            dead_message.insert(VALUE, message);
+
+            for index in self.remote_subscribers(dead_letter) {
+                let scope = Scope { chans: true, share_groups: Some(Vec::new()), forward: false, confirm: None };
+
+                self.forward(index, dead_letter, dead_message.clone(), scope);
+            }
+
+            if let Some(tokens) = self.chans.get(dead_letter) {
+                for other_token in tokens {
+                    self.send_message(hook, *other_token, dead_message.clone());
+                }
+            }
+        }
+    }
+
+    // 放入 Wire 失败的消息，Wire 已满或者已断开
+    fn undelivered(&self, hook: &impl Hook, slot: &Slot, err: SendError<Message>) {
+        let (code, message) = match err {
+            SendError::Full(message) => (Code::WireFull, message),
+            SendError::Disconnected(message) => (Code::TargetSlotBroken, message)
+        };
+
+        self.dead_letter(hook, usize::MAX, code, message, Some(slot.id));
+    }
+
+    // 分片时在共享的路由表中登记 SLOT_ID，已存在时返回 false
+    fn register_slot(&self, slot_id: MessageId) -> bool {
+        match &self.shard {
+            Some(shard) => shard.router.add_slot(slot_id, shard.index),
+            None => true
+        }
+    }
+
+    fn unregister_slot(&self, slot_id: &MessageId) {
+        if let Some(shard) = &self.shard {
+            shard.router.del_slot(slot_id);
+        }
+    }
+
+    // 分片时同步本分片 CHAN 的订阅数
+    fn sync_chan(&self, chan: &str) {
+        if let Some(shard) = &self.shard {
+            // 离线的持久会话也计入订阅数，其他分片的消息才会转发过来暂存
+            let count = self.chans.get(chan).map(|ids| ids.len()).unwrap_or(0)
+                + self.offline.get(chan).map(|ids| ids.len()).unwrap_or(0);
+
+            shard.router.set_subscribers(chan, shard.index, count);
+        }
+    }
+
+    // 分片时同步本分片 CHAN 的共享订阅组的订阅数
+    fn sync_share(&self, chan: &str, group: &str) {
+        if let Some(shard) = &self.shard {
+            // 离线的持久会话也计入订阅数
+            let count = self.share_groups.get(chan)
+                .and_then(|groups| groups.get(group))
+                .map(|ids| ids.len())
+                .unwrap_or(0)
+                + self.offline_share.get(chan)
+                .and_then(|groups| groups.get(group))
+                .map(|ids| ids.len())
+                .unwrap_or(0);
+
+            shard.router.set_share_subscribers(chan, group, shard.index, count);
+        }
+    }
+
+    // SLOT 所在的分片，在本分片时为 Some(None)，不存在时为 None
+    fn locate_slot(&self, slot_id: &MessageId, scope: &Scope) -> Option<Option<usize>> {
+        if self.slot_ids.contains_key(slot_id) {
+            return Some(None)
+        }
+
+        self.slot_shard(slot_id, scope).map(Some)
+    }
+
+    // 在其他分片上的 SLOT，转发来的消息不再转发
+    fn slot_shard(&self, slot_id: &MessageId, scope: &Scope) -> Option<usize> {
+        match &self.shard {
+            Some(shard) if scope.forward => {
+                shard.router.slot_shard(slot_id).filter(|index| *index != shard.index)
+            }
+            _ => None
+        }
+    }
+
+    // 其他有 CHAN 普通订阅的分片
+    fn remote_subscribers(&self, chan: &str) -> Vec<usize> {
+        match &self.shard {
+            Some(shard) => {
+                shard.router.subscribers(chan)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(index, count)| *index != shard.index && *count > 0)
+                    .map(|(index, _)| index)
+                    .collect()
+            }
+            None => Vec::new()
+        }
+    }
+
+    fn forward(&self, index: usize, chan: &str, message: Message, scope: Scope) {
+        if let Some(shard) = &self.shard {
+            shard.router.push(index, Packet::Relay(chan.to_string(), message, scope));
+        }
+    }
+
+    fn forward_event(&self, chan: &str, message: &Message) {
+        if let Some(shard) = &self.shard {
+            for index in self.remote_subscribers(chan) {
+                shard.router.push(index, Packet::Event(chan.to_string(), message.clone()));
+            }
+        }
+    }
+
+    // 分片时，将消息转发到其他有订阅的分片，返回本分片是否投递给普通订阅，以及投递给哪些共享订阅组
+    // 普通订阅转发到所有有订阅的分片，SHARE 消息和每个共享订阅组按各分片的订阅数加权选择一个分片，
+    // 一致性哈希时按 SHARE_KEY 选择一个分片，订阅者不变时同一个 KEY 总是选择同一个分片
+    fn forward_chan(
+        &mut self,
+        chan: &str,
+        message: &Message,
+        scope: &Scope,
+        strategy: Strategy,
+        confirm: Option<usize>
+    ) -> (bool, Option<Vec<String>>, usize) {
+        let (index, router) = match &self.shard {
+            Some(shard) if scope.forward => (shard.index, shard.router.clone()),
+            _ => return (scope.chans, scope.share_groups.clone(), 0)
+        };
+
+        let share = message.get_bool(SHARE).ok().unwrap_or(false);
+
+        let mut targets = vec![(false, Vec::new()); router.shards()];
+
+        if let Some(counts) = router.subscribers(chan) {
+            if share {
+                if let Some(i) = self.select_shard(strategy, message, &counts) {
+                    targets[i].0 = true;
+                }
+            } else {
+                for (i, count) in counts.iter().enumerate() {
+                    targets[i].0 = *count > 0;
+                }
+            }
+        }
+
+        for (group, counts) in router.share_subscribers(chan) {
+            if let Some(i) = self.select_shard(strategy, message, &counts) {
+                targets[i].1.push(group);
+            }
+        }
+
+        let local = std::mem::take(&mut targets[index]);
+
+        let mut relayed = 0;
+
+        for (i, (chans, share_groups)) in targets.into_iter().enumerate() {
+            if i != index && (chans || !share_groups.is_empty()) {
+                let scope = Scope {
+                    chans,
+                    share_groups: Some(share_groups),
+                    forward: false,
+                    confirm: confirm.map(|confirm_id| (index, confirm_id))
+                };
+
+                router.push(i, Packet::Relay(chan.to_string(), message.clone(), scope));
+
+                relayed += 1;
+            }
+        }
+
+        (local.0, Some(local.1), relayed)
+    }
+
+    // 按订阅数加权随机选择一个分片，一致性哈希时按 SHARE_KEY 选择
+    fn select_shard(&mut self, strategy: Strategy, message: &Message, counts: &[usize]) -> Option<usize> {
+        if let (Strategy::Hash, Some(key)) = (strategy, message.get(SHARE_KEY)) {
+            let key = balance::key_hash(key);
+
+            return counts.iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .max_by_key(|(i, _)| balance::rendezvous(key, i))
+                .map(|(i, _)| i)
+        }
+
+        balance::weighted(&mut self.rand, counts)
+    }
 
-                            return
-                        }
-                    }
-                } else {
-                    Code::InvalidToFieldType.set(&mut message);
+    // 消息的 STRATEGY，没有时为 CHAN 的策略或默认策略
+    fn strategy(&self, chan: &str, message: &Message) -> std::result::Result<Strategy, Code> {
+        match message.get(STRATEGY) {
+            Some(Value::String(strategy)) => strategy.parse().map_err(|_| Code::InvalidStrategyFieldType),
+            Some(_) => Err(Code::InvalidStrategyFieldType),
+            None => Ok(self.options.chan_strategies.get(chan).copied().unwrap_or(self.options.strategy))
+        }
+    }
 
-                    self.send_message(hook, token, message);
+    // 本分片上的候选订阅者，权重为 SLOT 属性中的 WEIGHT，没有时为 1
+    fn candidate<T>(&self, slot_token: usize, value: T) -> Candidate<T> {
+        let slot = &self.slots[slot_token];
 
-                    return
-                }
+        let weight = slot.wire.attr().get(WEIGHT)
+            .and_then(as_millis)
+            .map(|weight| weight.max(0) as usize)
+            .unwrap_or(1);
 
-                // 移除 TO
-                message.remove(TO);
+        Candidate {
+            value,
+            id: slot.id,
+            pending: slot.wire.pending(),
+            weight
+        }
+    }
 
-                if !to_ids.is_empty() {
-                    if message.get_bool(SHARE).ok().unwrap_or(false) {
-                        if to_ids.len() == 1 {
-                            if let Some(slot_token) = self.slot_ids.get(&to_ids[0]) {
-                                if let Some(slot) = self.slots.get(*slot_token) {
-                                    send!(self, hook, slot, message);
-                                }
-                            }
-                        } else if let Some(to) = to_ids.choose(&mut self.rand) {
-                            if let Some(slot_token) = self.slot_ids.get(to) {
-                                if let Some(slot) = self.slots.get(*slot_token) {
-                                    send!(self, hook, slot, message);
-                                }
-                            }
-                        }
-                    } else {
-                        for to in &to_ids {
-                            if let Some(slot_token) = self.slot_ids.get(to) {
-                                if let Some(slot) = self.slots.get(*slot_token) {
-                                    send!(self, hook, slot, message);
-                                }
-                            }
-                        }
-                    }
-                }
-            } else {
-                // tags
-                let mut tags = HashSet::new();
+    // ATTACH 的时候，可以附带自定义数据，可以通过 Hook.attach 或 SLOT_ATTACH 事件获取
+    // SLOT 的限制，SLOT 的属性只能收紧默认的限制
+    fn slot_limits(&self, attr: &Message) -> std::result::Result<SlotLimits, Code> {
+        fn tighten<T: Ord + Copy>(limit: &mut Option<T>, value: T) {
+            *limit = Some(limit.map_or(value, |limit| limit.min(value)));
+        }
 
-                if let Some(tag) = message.get(TAGS) {
-                    if let Some(tag) = tag.as_str() {
-                        tags.insert(tag.to_string());
-                    } else if let Some(tag_array) = tag.as_array() {
-                        for v in tag_array {
-                            if let Some(v) = v.as_str() {
-                                tags.insert(v.to_string());
-                            } else {
-                                Code::InvalidTagsFieldType.set(&mut message);
+        fn get(attr: &Message, key: &str) -> std::result::Result<Option<i64>, Code> {
+            match attr.get(key) {
+                Some(value) => match as_millis(value) {
+                    Some(value) if value >= 0 => Ok(Some(value)),
+                    _ => Err(Code::InvalidLimitFieldType)
+                },
+                None => Ok(None)
+            }
+        }
 
-                                self.send_message(hook, token, message);
+        let mut limits = self.options.slot_limits.clone();
 
-                                return
-                            }
-                        }
-                    } else {
-                        Code::InvalidTagsFieldType.set(&mut message);
+        if let Some(value) = get(attr, MESSAGE_RATE)? {
+            tighten(&mut limits.messages_per_sec, value.min(i64::from(u32::MAX)) as u32);
+        }
+
+        if let Some(value) = get(attr, BYTE_RATE)? {
+            tighten(&mut limits.bytes_per_sec, value.min(i64::from(u32::MAX)) as u32);
+        }
 
-                        self.send_message(hook, token, message);
+        if let Some(value) = get(attr, MAX_CHANS)? {
+            tighten(&mut limits.max_chans, value as usize);
+        }
 
-                        return
-                    }
+        if let Some(value) = get(attr, MAX_SHARE_CHANS)? {
+            tighten(&mut limits.max_share_chans, value as usize);
+        }
+
+        if let Some(key) = &self.options.limit_group {
+            if let Some(group) = attr.get(key) {
+                match group.as_str() {
+                    Some(group) => limits.group = Some(group.to_string()),
+                    None => return Err(Code::InvalidLimitFieldType)
                 }
+            }
+        }
 
-                if let Some(tokens) = self.chans.get(&chan) {
-                    if message.get_bool(SHARE).ok().unwrap_or(false) {
-                        let mut array: Vec<usize> = Vec::new();
+        Ok(limits)
+    }
 
-                        for slot_token in tokens.iter() {
-                            // 自己不能收到自己的消息
-                            if slot_token == &token {
-                                continue;
-                            }
+    // 接收消息的速率限制，有组时使用组的限制
+    fn allow(&mut self, token: usize, message: &Message) -> bool {
+        let slot = &mut self.slots[token];
 
-                            if !tags.is_empty() {
-                                if let Some(slot) = self.slots.get(*slot_token) {
-                                    if !tags.iter().all(|t| slot.tags.contains(t)) {
-                                        continue
-                                    }
-                                }
-                            }
+        let limits = slot.limits.borrow();
 
-                            array.push(*slot_token);
-                        }
+        match &limits.group {
+            Some(group) => match self.groups.get_mut(group) {
+                Some(group) => group.rate.allow(message),
+                None => true
+            },
+            None => slot.rate.allow(message)
+        }
+    }
 
-                        if !array.is_empty() {
-                            if array.len() == 1 {
-                                if let Some(slot) = self.slots.get(array[0]) {
-                                    send!(self, hook, slot, message);
-                                }
-                            } else if let Some(id) = array.choose(&mut self.rand) {
-                                if let Some(slot) = self.slots.get(*id) {
-                                    send!(self, hook, slot, message);
-                                }
-                            }
-                        }
-                    } else {
-                        // 给每个 SLOT 发送消息
-                        for slot_token in tokens.iter() {
-                            // 自己不能收到自己的消息
-                            if slot_token == &token {
-                                continue;
-                            }
+    // 从 CHAN 的共享订阅组中移除，不修改 SLOT 的 share_chans 和 share_groups
+    // 同一个 CHAN 只能加入一个组，因此也从 share_chans 中移除
+    fn remove_share(&mut self, token: usize, chan: &str, group: &str) {
+        if let Some(ids) = self.share_chans.get_mut(chan) {
+            ids.remove(&token);
 
-                            if let Some(slot) = self.slots.get(*slot_token) {
-                                if !tags.is_empty() {
-                                    if !tags.iter().all(|t| slot.tags.contains(t)) {
-                                        continue
-                                    }
-                                }
+            if ids.is_empty() {
+                self.share_chans.remove(chan);
+            }
+        }
 
-                                send!(self, hook, slot, message);
-                            }
-                        }
-                    }
+        if let Some(groups) = self.share_groups.get_mut(chan) {
+            if let Some(ids) = groups.get_mut(group) {
+                ids.remove(&token);
+
+                if ids.is_empty() {
+                    groups.remove(group);
+                    self.balancer.remove(&Cursor::Group(chan.to_string(), group.to_string()));
                 }
+            }
 
-                // 共享订阅
-                // 注意: 共享订阅与普通订阅是两套并行的机制，
-                // 不管发送消息时有没有　SHARE　参数，共享订阅始终能收到消息
-                if let Some(tokens) = self.share_chans.get(&chan) {
-                    let mut array: Vec<usize> = Vec::new();
+            if groups.is_empty() {
+                self.share_groups.remove(chan);
+            }
+        }
 
-                    for slot_token in tokens.iter() {
-                        // 自己不能收到自己的消息
-                        if slot_token == &token {
-                            continue;
-                        }
+        self.sync_share(chan, group);
+    }
 
-                        if !tags.is_empty() {
-                            if let Some(slot) = self.slots.get(*slot_token) {
-                                if !tags.iter().all(|t| slot.tags.contains(t)) {
-                                    continue
-                                }
-                            }
-                        }
+    // 设置或者移除订阅的过滤条件
+    fn set_filter(&mut self, token: usize, chan: &str, share: bool, filter: Option<Filter>) {
+        let filters = if share { &mut self.share_filters } else { &mut self.filters };
 
-                        array.push(*slot_token);
-                    }
+        match filter {
+            Some(filter) => {
+                filters.entry(chan.to_string()).or_default().insert(token, filter);
+            }
+            None => {
+                if let Some(index) = filters.get_mut(chan) {
+                    index.remove(token);
 
-                    if !array.is_empty() {
-                        if array.len() == 1 {
-                            if let Some(slot) = self.slots.get(array[0]) {
-                                send!(self, hook, slot, message);
-                            }
-                        } else if let Some(slot_token) = array.choose(&mut self.rand) {
-                            if let Some(slot) = self.slots.get(*slot_token) {
-                                send!(self, hook, slot, message);
-                            }
-                        }
+                    if index.is_empty() {
+                        filters.remove(chan);
                     }
                 }
             }
-
-        } // end goon
+        }
     }
 
-    // ATTACH 的时候，可以附带自定义数据，可以通过 Hook.attach 或 SLOT_ATTACH 事件获取
     fn attach(
         &mut self,
         hook: &impl Hook,
@@ -569,18 +2721,58 @@ impl Switch {
     ) {
         if let Ok(chan) = message.get_str(VALUE).map(ToOwned::to_owned) {
             // share
-            let mut share = false;
+            let group = match share_group(&message) {
+                Ok(group) => group,
+                Err(code) => {
+                    code.set(&mut message);
 
-            if let Some(share2) = message.get(SHARE) {
-                if let Some(share2) = share2.as_bool() {
-                    share = share2
-                } else {
-                    Code::InvalidShareFieldType.set(&mut message);
+                    self.send_system_message(hook, token, message);
+
+                    return
+                }
+            };
+
+            let share = group.is_some();
+
+            // 过滤条件，重复 ATTACH 时替换原来的条件
+            let filter = match message.get(FILTER) {
+                Some(Value::Message(filter)) => match Filter::parse(filter) {
+                    Ok(filter) => Some(filter),
+                    Err(code) => {
+                        code.set(&mut message);
 
-                    self.send_message(hook, token, message);
+                        self.send_system_message(hook, token, message);
+
+                        return
+                    }
+                },
+                Some(_) => {
+                    Code::InvalidFilterFieldType.set(&mut message);
+
+                    self.send_system_message(hook, token, message);
 
                     return
                 }
+                None => None
+            };
+
+            // 频道数的限制，重复 ATTACH 不计入
+            let slot = &self.slots[token];
+
+            let (count, max) = if share {
+                (slot.share_groups.len(), slot.limits.borrow().max_share_chans)
+            } else {
+                (slot.chans.len(), slot.limits.borrow().max_chans)
+            };
+
+            let attached = if share { slot.share_groups.contains_key(&chan) } else { slot.chans.contains(&chan) };
+
+            if !attached && max.is_some_and(|max| count >= max) {
+                Code::TooManyChans.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return
             }
 
             // 这里可以验证该 SLOT 是否有权限
@@ -589,7 +2781,7 @@ impl Switch {
             if !success {
                 Code::PermissionDenied.set(&mut message);
 
-                self.send_message(hook, token, message);
+                self.send_system_message(hook, token, message);
 
                 return
             }
@@ -598,7 +2790,8 @@ impl Switch {
             // {
             //     CHAN: SLOT_ATTACH,
             //     VALUE: $chan,
-            //     slot_id: $slot_id
+            //     slot_id: $slot_id,
+            //     SHARE: true, GROUP: $group（共享订阅时）
             // }
             let mut event_message = msg!{
                 CHAN: SLOT_ATTACH,
@@ -606,18 +2799,35 @@ impl Switch {
                 SLOT_ID: self.slots[token].id
             };
 
+            self.set_filter(token, &chan, share, filter);
+
             // session_attach
-            if share {
+            if let Some(group) = group {
                 event_message.insert(SHARE, true);
+                event_message.insert(GROUP, &group);
+
+                // 同一个 CHAN 只能加入一个共享订阅组，换组时先离开原来的组
+                if let Some(old) = self.slots[token].share_groups.get(&chan).cloned() {
+                    if old != group {
+                        self.remove_share(token, &chan, &old);
+                    }
+                }
 
-                let ids = self.share_chans.entry(chan.to_owned()).or_insert_with(HashSet::new);
+                self.share_chans.entry(chan.to_owned()).or_default().insert(token);
+
+                let ids = self.share_groups.entry(chan.to_owned()).or_default().entry(group.clone()).or_default();
                 ids.insert(token);
 
-                self.slots[token].share_chans.insert(chan);
+                self.sync_share(&chan, &group);
+
+                self.slots[token].share_chans.insert(chan.clone());
+                self.slots[token].share_groups.insert(chan, group);
             } else {
-                let ids = self.chans.entry(chan.to_owned()).or_insert_with(HashSet::new);
+                let ids = self.chans.entry(chan.to_owned()).or_default();
                 ids.insert(token);
 
+                self.sync_chan(&chan);
+
                 self.slots[token].chans.insert(chan);
             }
 
@@ -628,7 +2838,7 @@ impl Switch {
             Code::CannotGetValueField.set(&mut message);
         }
 
-        self.send_message(hook, token, message);
+        self.send_system_message(hook, token, message);
     }
 
     // DETACH 的时候，可以附带自定义数据，可以通过 Hook.detach 或 SLOT_DETACH 事件获取
@@ -640,19 +2850,16 @@ impl Switch {
     ) {
         if let Ok(chan) = message.get_str(VALUE).map(ToOwned::to_owned) {
             // share
-            let mut share = false;
+            let group = match share_group(&message) {
+                Ok(group) => group,
+                Err(code) => {
+                    code.set(&mut message);
 
-            if let Some(share2) = message.get(SHARE) {
-                if let Some(share2) = share2.as_bool() {
-                    share = share2
-                } else {
-                    Code::InvalidShareFieldType.set(&mut message);
-
-                    self.send_message(hook, token, message);
+                    self.send_system_message(hook, token, message);
 
                     return
                 }
-            }
+            };
 
             // 这里可以验证该 SLOT 是否有权限
             // 可以让 SLOT 不能 DETACH 某些 CHAN
@@ -661,7 +2868,7 @@ impl Switch {
             if !success {
                 Code::PermissionDenied.set(&mut message);
 
-                self.send_message(hook, token, message);
+                self.send_system_message(hook, token, message);
 
                 return
             }
@@ -679,19 +2886,24 @@ impl Switch {
             };
 
             // session_detach
-            if share {
-                event_message.insert(SHARE, true);
+            if let Some(group) = group {
+                // SHARE 为 true 时离开所在的组，为组名时只在组名一致时离开
+                let named = message.get_str(SHARE).is_ok();
 
-                self.slots[token].share_chans.remove(&chan);
+                let attached = self.slots[token].share_groups.get(&chan)
+                    .filter(|attached| !named || **attached == group)
+                    .cloned();
 
-                if let Some(ids) = self.share_chans.get_mut(&chan) {
-                    ids.remove(&token);
+                event_message.insert(SHARE, true);
+                event_message.insert(GROUP, attached.as_ref().unwrap_or(&group));
 
-                    if ids.is_empty() {
-                        self.share_chans.remove(&chan);
-                    }
-                }
+                if let Some(attached) = attached {
+                    self.slots[token].share_chans.remove(&chan);
+                    self.slots[token].share_groups.remove(&chan);
 
+                    self.remove_share(token, &chan, &attached);
+                    self.set_filter(token, &chan, true, None);
+                }
             } else {
                 self.slots[token].chans.remove(&chan);
 
@@ -700,8 +2912,12 @@ impl Switch {
 
                     if ids.is_empty() {
                         self.chans.remove(&chan);
+                        self.balancer.remove(&Cursor::Chan(chan.clone()));
                     }
                 }
+
+                self.sync_chan(&chan);
+                self.set_filter(token, &chan, false, None);
             }
 
             self.relay_event_message(hook, token, SLOT_DETACH, event_message);
@@ -711,7 +2927,7 @@ impl Switch {
             Code::CannotGetValueField.set(&mut message);
         }
 
-        self.send_message(hook, token, message);
+        self.send_system_message(hook, token, message);
     }
 
     fn join(
@@ -726,7 +2942,7 @@ impl Switch {
         if !success {
             Code::PermissionDenied.set(&mut message);
 
-            self.send_message(hook, token, message);
+            self.send_system_message(hook, token, message);
 
             return
         }
@@ -736,7 +2952,7 @@ impl Switch {
 
         Code::Ok.set(&mut message);
 
-        self.send_message(hook, token, message);
+        self.send_system_message(hook, token, message);
     }
 
     fn leave(
@@ -751,7 +2967,7 @@ impl Switch {
         if !success {
             Code::PermissionDenied.set(&mut message);
 
-            self.send_message(hook, token, message);
+            self.send_system_message(hook, token, message);
 
             return
         }
@@ -761,24 +2977,251 @@ impl Switch {
 
         Code::Ok.set(&mut message);
 
-        self.send_message(hook, token, message);
+        self.send_system_message(hook, token, message);
     }
 
     // PING 的时候可以附带自定义数据，可以通过 Hook.ping 获取
+    // 设置遗嘱消息，重复设置时替换原来的消息，也可以在连接时通过属性 WILL 设置
+    // {
+    //     CHAN: WILL,
+    //     VALUE: {CHAN: $chan, ...}
+    // }
+    fn set_will(&mut self, hook: &impl Hook, token: usize, mut message: Message) {
+        match message.get(VALUE).map(will_message) {
+            Some(Ok(will)) => {
+                self.slots[token].will = Some(will);
+
+                Code::Ok.set(&mut message);
+            }
+            Some(Err(code)) => code.set(&mut message),
+            None => Code::CannotGetValueField.set(&mut message)
+        }
+
+        self.send_system_message(hook, token, message);
+    }
+
+    // 正常断开前发送，移除遗嘱消息
+    // {
+    //     CHAN: GOODBYE
+    // }
+    // 网络层在写入连接前发现已过期的消息，放入死信频道，不回复
+    // {
+    //     CHAN: EXPIRED,
+    //     VALUE: $message
+    // }
+    // 有 FROM 时为投递给该 SLOT 的消息，否则为该 SLOT 发送的消息
+    fn expired(&mut self, hook: &impl Hook, token: usize, mut message: Message) {
+        let value = match message.remove(VALUE) {
+            Some(Value::Message(value)) => value,
+            _ => {
+                Code::CannotGetValueField.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return
+            }
+        };
+
+        self.expire_num.set(self.expire_num.get() + 1);
+
+        if value.contains_key(FROM) {
+            let slot_id = self.slots[token].id;
+
+            self.dead_letter(hook, usize::MAX, Code::MessageExpired, value, Some(slot_id));
+        } else {
+            self.dead_letter(hook, token, Code::MessageExpired, value, None);
+        }
+    }
+
+    fn goodbye(&mut self, hook: &impl Hook, token: usize, mut message: Message) {
+        self.slots[token].will = None;
+
+        Code::Ok.set(&mut message);
+
+        self.send_system_message(hook, token, message);
+    }
+
+    // 遗嘱消息和普通消息一样经过 Hook::emit 和发布，FROM 为断开的 SLOT
+    // TTL、DELAY 和 TIME 同样有效，延迟投递的遗嘱消息属于原来的 SLOT_ID
+    // token 为发布者的 Token，SLOT 已移除时为 usize::MAX，接管时为原来的 Token
+    fn publish_will(&mut self, hook: &impl Hook, token: usize, slot: &Slot, mut message: Message) {
+        if !hook.emit(slot, &mut message) {
+            return
+        }
+
+        message.insert(FROM, slot.id);
+
+        let chan = match message.get_str(CHAN) {
+            Ok(chan) => chan.to_string(),
+            Err(_) => return
+        };
+
+        self.publish(hook, token, slot.id, chan, message);
+    }
+
     fn ping(&mut self, hook: &impl Hook, token: usize, mut message: Message) {
         hook.ping(&self.slots[token], &mut message);
 
         // PING 的时候，会插入 OK: 0
         Code::Ok.set(&mut message);
 
-        self.send_message(hook, token, message);
+        self.send_system_message(hook, token, message);
+    }
+
+    // 运行时修改 SLOT 的属性和标签，系统属性（以 _ 开头）不能修改，标签的格式和属性 TAGS 相同
+    // 属性 TAGS 会同步为修改后的标签数组
+    // {
+    //     CHAN: UPDATE,
+    //     ATTR: {$key: $value, ...},   设置属性
+    //     UNSET: [$key, ...],          移除属性
+    //     ADD_TAGS: [$tag, ...],       添加标签
+    //     REMOVE_TAGS: [$tag, ...]     移除标签
+    // }
+    fn update(&mut self, hook: &impl Hook, token: usize, mut message: Message) {
+        let set = match message.get(ATTR) {
+            Some(Value::Message(set)) => set.clone(),
+            Some(_) => {
+                Code::InvalidUpdateFieldType.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return
+            }
+            None => Message::new()
+        };
+
+        let unset: Option<Vec<String>> = match message.get(UNSET) {
+            Some(Value::Array(array)) => array.iter().map(|key| key.as_str().map(ToOwned::to_owned)).collect(),
+            Some(_) => None,
+            None => Some(Vec::new())
+        };
+
+        let unset = match unset {
+            Some(unset) => unset,
+            None => {
+                Code::InvalidUpdateFieldType.set(&mut message);
+
+                self.send_system_message(hook, token, message);
+
+                return
+            }
+        };
+
+        let mut update_tags = [HashSet::new(), HashSet::new()];
+
+        for (i, key) in [ADD_TAGS, REMOVE_TAGS].iter().enumerate() {
+            match message.get(key).map(tags::slot_tags) {
+                Some(Ok(tags)) => update_tags[i] = tags,
+                Some(Err(code)) => {
+                    code.set(&mut message);
+
+                    self.send_system_message(hook, token, message);
+
+                    return
+                }
+                None => ()
+            }
+        }
+
+        let [add_tags, remove_tags] = update_tags;
+
+        if set.iter().map(|(key, _)| key).chain(unset.iter()).any(|key| key.starts_with('_')) {
+            Code::PermissionDenied.set(&mut message);
+
+            self.send_system_message(hook, token, message);
+
+            return
+        }
+
+        // 这里可以验证该 SLOT 是否有权限
+        let success = hook.update(&self.slots[token], &mut message);
+
+        if !success {
+            Code::PermissionDenied.set(&mut message);
+
+            self.send_system_message(hook, token, message);
+
+            return
+        }
+
+        let slot = &mut self.slots[token];
+
+        // 只记录有变化的部分
+        let mut changed = Message::new();
+        let mut removed = Array::new();
+
+        {
+            let mut attr = slot.wire.attr();
+
+            for (key, value) in set.iter() {
+                if attr.get(key) != Some(value) {
+                    attr.insert(key.clone(), value.clone());
+                    changed.insert(key.clone(), value.clone());
+                }
+            }
+
+            for key in unset {
+                if attr.remove(&key).is_some() {
+                    removed.push(key);
+                }
+            }
+        }
+
+        let mut added_tags: Vec<String> = add_tags.into_iter().filter(|tag| slot.tags.insert(tag.clone())).collect();
+        let mut removed_tags: Vec<String> = remove_tags.into_iter().filter(|tag| slot.tags.remove(tag)).collect();
+
+        if !added_tags.is_empty() || !removed_tags.is_empty() {
+            let mut tags: Vec<&String> = slot.tags.iter().collect();
+            tags.sort();
+
+            slot.wire.attr().insert(TAGS, tags.into_iter().map(|tag| Value::from(tag.as_str())).collect::<Array>());
+        }
+
+        let slot_id = slot.id;
+
+        Code::Ok.set(&mut message);
+
+        self.send_system_message(hook, token, message);
+
+        if changed.is_empty() && removed.is_empty() && added_tags.is_empty() && removed_tags.is_empty() {
+            return
+        }
+
+        added_tags.sort();
+        removed_tags.sort();
+
+        // slot event
+        // {
+        //     CHAN: SLOT_UPDATE,
+        //     SLOT_ID: $slot_id,
+        //     ATTR: {$key: $value, ...},
+        //     UNSET: [$key, ...],
+        //     ADD_TAGS: [$tag, ...],
+        //     REMOVE_TAGS: [$tag, ...]
+        // }
+        let event_message = msg!{
+            CHAN: SLOT_UPDATE,
+            SLOT_ID: slot_id,
+            ATTR: changed,
+            UNSET: removed,
+            ADD_TAGS: added_tags,
+            REMOVE_TAGS: removed_tags
+        };
+
+        self.relay_event_message(hook, token, SLOT_UPDATE, event_message);
     }
 
     fn mine(&self, hook: &impl Hook, token: usize, mut message: Message) {
         if let Some(slot) = self.slots.get(token) {
 
             let chans: Vec<&String> = slot.chans.iter().collect();
-            let share_chans: Vec<&String> = slot.share_chans.iter().collect();
+            let share_chans: Vec<&String> = slot.share_groups.keys().collect();
+
+            let mut share_groups = Message::new();
+
+            for (chan, group) in &slot.share_groups {
+                share_groups.insert(chan.as_str(), group.as_str());
+            }
 
             let mut binded = Array::new();
 
@@ -802,6 +3245,7 @@ impl Switch {
                 ATTR: slot.wire.attr().clone(),
                 CHANS: chans,
                 SHARE_CHANS: share_chans,
+                SHARE_GROUPS: share_groups,
                 SEND_NUM: slot.wire.send_num() as u64,
                 RECV_NUM: slot.wire.recv_num() as u64,
                 JOINED: slot.joined
@@ -812,7 +3256,7 @@ impl Switch {
 
         Code::Ok.set(&mut message);
 
-        self.send_message(hook, token, message);
+        self.send_system_message(hook, token, message);
     }
 
     // 可以在 Hook.custom 自行定制返回数据
@@ -821,6 +3265,28 @@ impl Switch {
 
         // CUSTOM 的时候，不会插入 CODE: 0, 由 hook 函数决定
 
-        self.send_message(hook, token, message);
+        self.send_system_message(hook, token, message);
+    }
+}
+
+// SHARE 为 true 时加入默认的共享订阅组 ""，为字符串时加入这个名字的组，
+// 为 false 或者没有时是普通订阅
+fn share_group(message: &Message) -> std::result::Result<Option<String>, Code> {
+    match message.get(SHARE) {
+        Some(Value::Bool(share)) => Ok(share.then(String::new)),
+        Some(Value::String(group)) => Ok(Some(group.clone())),
+        Some(_) => Err(Code::InvalidShareFieldType),
+        None => Ok(None)
+    }
+}
+
+// 遗嘱消息，需要携带 CHAN，不能是系统频道
+fn will_message(value: &Value) -> std::result::Result<Message, Code> {
+    match value {
+        Value::Message(message) => match message.get_str(CHAN) {
+            Ok(chan) if !chan.starts_with('_') => Ok(message.clone()),
+            _ => Err(Code::InvalidWillFieldType)
+        },
+        _ => Err(Code::InvalidWillFieldType)
     }
 }
@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+use std::cmp::Ordering;
+
+use nson::Value;
+
+use crate::error::Code;
+use crate::dict::{TAG_ALL, TAG_ANY, TAG_NONE};
+
+// 消息的 TAGS，按 SLOT 的 TAGS 匹配
+// "abc"            SLOT 有 abc 标签
+// "region=eu"      SLOT 有 region 标签且值为 eu，还支持 !=、>、>=、<、<=，两边都是数字时按数字比较
+//                  不是合法的比较表达式时按普通标签匹配，例如 "a!b"、"<b>"
+// ["a", "b"]       满足所有
+// {"$all": [..]}   满足所有
+// {"$any": [..]}   满足任意一个
+// {"$none": [..]}  都不满足
+// 组合表达式可以嵌套，同一个消息中的多个操作符需要都满足
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TagExpr {
+    Has(String),
+    Compare(String, Op, String),
+    All(Vec<TagExpr>),
+    Any(Vec<TagExpr>),
+    None(Vec<TagExpr>)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le
+}
+
+impl TagExpr {
+    // 字符串或者字符串数组的类型错误为 InvalidTagsFieldType，组合表达式的错误为 InvalidTagsExpression
+    pub fn parse(value: &Value) -> Result<TagExpr, Code> {
+        match value {
+            Value::String(tag) => Ok(Self::term(tag)),
+            Value::Array(array) => {
+                let mut exprs = Vec::with_capacity(array.len());
+
+                for v in array.iter() {
+                    match v {
+                        Value::String(tag) => exprs.push(Self::term(tag)),
+                        Value::Message(_) => exprs.push(Self::parse(v)?),
+                        _ => return Err(Code::InvalidTagsFieldType)
+                    }
+                }
+
+                Ok(TagExpr::All(exprs))
+            }
+            Value::Message(message) => {
+                if message.is_empty() {
+                    return Err(Code::InvalidTagsExpression)
+                }
+
+                let mut exprs = Vec::with_capacity(message.len());
+
+                for (key, v) in message.iter() {
+                    let operands = Self::operands(v)?;
+
+                    let expr = match key.as_str() {
+                        TAG_ALL => TagExpr::All(operands),
+                        TAG_ANY => TagExpr::Any(operands),
+                        TAG_NONE => TagExpr::None(operands),
+                        _ => return Err(Code::InvalidTagsExpression)
+                    };
+
+                    exprs.push(expr);
+                }
+
+                if exprs.len() == 1 {
+                    Ok(exprs.remove(0))
+                } else {
+                    Ok(TagExpr::All(exprs))
+                }
+            }
+            _ => Err(Code::InvalidTagsFieldType)
+        }
+    }
+
+    // 操作符的参数，可以是单个表达式或者表达式数组
+    fn operands(value: &Value) -> Result<Vec<TagExpr>, Code> {
+        let map = |code| if code == Code::InvalidTagsFieldType { Code::InvalidTagsExpression } else { code };
+
+        match value {
+            Value::Array(array) => {
+                array.iter().map(|v| Self::parse(v).map_err(map)).collect()
+            }
+            Value::String(_) | Value::Message(_) => Ok(vec![Self::parse(value).map_err(map)?]),
+            _ => Err(Code::InvalidTagsExpression)
+        }
+    }
+
+    // 不是合法的比较表达式时，按普通标签匹配
+    fn term(tag: &str) -> TagExpr {
+        // 先匹配两个字符的操作符
+        const OPS: [(&str, Op); 6] = [
+            ("!=", Op::Ne),
+            (">=", Op::Ge),
+            ("<=", Op::Le),
+            ("=", Op::Eq),
+            (">", Op::Gt),
+            ("<", Op::Lt)
+        ];
+
+        let pos = tag.find(['=', '!', '>', '<']);
+
+        if let Some(pos) = pos.filter(|pos| *pos > 0) {
+            let (key, rest) = tag.split_at(pos);
+
+            for (s, op) in OPS {
+                if let Some(value) = rest.strip_prefix(s) {
+                    return TagExpr::Compare(key.to_string(), op, value.to_string())
+                }
+            }
+        }
+
+        TagExpr::Has(tag.to_string())
+    }
+
+    pub fn matches(&self, tags: &HashSet<String>) -> bool {
+        match self {
+            TagExpr::Has(tag) => tags.contains(tag),
+            TagExpr::Compare(key, op, value) => {
+                let mut values = tags.iter().filter_map(|tag| {
+                    tag.split_once('=').filter(|(k, _)| k == key).map(|(_, v)| v)
+                });
+
+                // 不等于时所有的值都不能相等，没有该标签时也满足
+                match op {
+                    Op::Ne => values.all(|v| op.eval(compare(v, value))),
+                    _ => values.any(|v| op.eval(compare(v, value)))
+                }
+            }
+            TagExpr::All(exprs) => exprs.iter().all(|e| e.matches(tags)),
+            TagExpr::Any(exprs) => exprs.iter().any(|e| e.matches(tags)),
+            TagExpr::None(exprs) => !exprs.iter().any(|e| e.matches(tags))
+        }
+    }
+}
+
+impl Op {
+    fn eval(self, ordering: Ordering) -> bool {
+        match self {
+            Op::Eq => ordering == Ordering::Equal,
+            Op::Ne => ordering != Ordering::Equal,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater
+        }
+    }
+}
+
+// 两边都是数字时按数字比较，否则按字符串比较
+fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b)
+    }
+}
+
+// SLOT 属性中的 TAGS，可以是字符串、字符串数组，或者键值对 {key: value}，键值对保存为 "key=value"
+pub(crate) fn slot_tags(value: &Value) -> Result<HashSet<String>, Code> {
+    let mut tags = HashSet::new();
+
+    match value {
+        Value::String(tag) => {
+            tags.insert(tag.to_string());
+        }
+        Value::Array(array) => {
+            for v in array.iter() {
+                match v.as_str() {
+                    Some(tag) => tags.insert(tag.to_string()),
+                    None => return Err(Code::InvalidTagsFieldType)
+                };
+            }
+        }
+        Value::Message(message) => {
+            for (key, v) in message.iter() {
+                let v = match v {
+                    Value::String(v) => v.to_string(),
+                    Value::I32(v) => v.to_string(),
+                    Value::I64(v) => v.to_string(),
+                    Value::U32(v) => v.to_string(),
+                    Value::U64(v) => v.to_string(),
+                    Value::F32(v) => v.to_string(),
+                    Value::F64(v) => v.to_string(),
+                    Value::Bool(v) => v.to_string(),
+                    _ => return Err(Code::InvalidTagsFieldType)
+                };
+
+                tags.insert(format!("{}={}", key, v));
+            }
+        }
+        _ => return Err(Code::InvalidTagsFieldType)
+    }
+
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use nson::{msg, Value, Array};
+
+    use crate::error::Code;
+    use super::{TagExpr, slot_tags};
+
+    fn tags(list: &[&str]) -> HashSet<String> {
+        list.iter().map(|t| t.to_string()).collect()
+    }
+
+    fn matches(expr: impl Into<Value>, list: &[&str]) -> bool {
+        TagExpr::parse(&expr.into()).unwrap().matches(&tags(list))
+    }
+
+    #[test]
+    fn test_plain() {
+        assert!(matches("a", &["a", "b"]));
+        assert!(!matches("c", &["a", "b"]));
+        assert!(matches(vec!["a", "b"], &["a", "b", "c"]));
+        assert!(!matches(vec!["a", "d"], &["a", "b", "c"]));
+        assert!(matches(Vec::<String>::new(), &[]));
+    }
+
+    #[test]
+    fn test_compare() {
+        let list = ["region=eu", "version=3", "name=abc"];
+
+        assert!(matches("region=eu", &list));
+        assert!(!matches("region=us", &list));
+        assert!(matches("region!=us", &list));
+        assert!(matches("zone!=us", &list));
+        assert!(matches("version>2", &list));
+        assert!(matches("version>=3", &list));
+        assert!(!matches("version>3", &list));
+        assert!(matches("version<10", &list));
+        assert!(matches("version<=3", &list));
+        assert!(matches("name<abd", &list));
+        assert!(!matches("zone=", &list));
+
+        // 两边都是数字时按数字比较
+        assert!(matches("version=3.0", &list));
+        assert!(!matches("version!=3.0", &list));
+        assert!(matches("version!=3.5", &list));
+    }
+
+    #[test]
+    fn test_nested() {
+        let list = ["a", "region=eu", "version=3"];
+
+        assert!(matches(msg!{"$any": ["x", "a"]}, &list));
+        assert!(!matches(msg!{"$any": ["x", "y"]}, &list));
+        assert!(matches(msg!{"$none": ["x", "y"]}, &list));
+        assert!(!matches(msg!{"$none": ["x", "a"]}, &list));
+        assert!(matches(msg!{"$all": ["a", "version>=2"]}, &list));
+
+        // (region=us or version>2) and not x
+        let expr = msg!{
+            "$all": [
+                {"$any": ["region=us", "version>2"]},
+                {"$none": "x"}
+            ]
+        };
+
+        assert!(matches(expr, &list));
+
+        // 多个操作符
+        assert!(matches(msg!{"$any": ["a"], "$none": ["x"]}, &list));
+        assert!(!matches(msg!{"$any": ["a"], "$none": ["a"]}, &list));
+
+        // 数组中也可以是组合表达式
+        let expr = Array::from_vec(vec![Value::from("a"), Value::from(msg!{"$any": ["x", "region=eu"]})]);
+        assert!(matches(expr, &list));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let parse = |v: Value| TagExpr::parse(&v).err();
+
+        assert!(parse(Value::I32(1)) == Some(Code::InvalidTagsFieldType));
+        assert!(parse(Array::from_vec(vec![Value::I32(1)]).into()) == Some(Code::InvalidTagsFieldType));
+        assert!(parse(msg!{}.into()) == Some(Code::InvalidTagsExpression));
+        assert!(parse(msg!{"$foo": ["a"]}.into()) == Some(Code::InvalidTagsExpression));
+        assert!(parse(msg!{"$any": 1}.into()) == Some(Code::InvalidTagsExpression));
+        assert!(parse(msg!{"$any": [1]}.into()) == Some(Code::InvalidTagsExpression));
+    }
+
+    #[test]
+    fn test_plain_fallback() {
+        // 不是合法的比较表达式时按普通标签匹配
+        assert!(matches("=a", &["=a"]));
+        assert!(matches("a!b", &["a!b"]));
+        assert!(matches("<b>", &["<b>"]));
+        assert!(matches("wow!", &["wow!"]));
+        assert!(!matches("wow!", &["wow"]));
+    }
+
+    #[test]
+    fn test_slot_tags() {
+        assert!(slot_tags(&"a".into()).unwrap() == tags(&["a"]));
+        assert!(slot_tags(&vec!["a", "b"].into()).unwrap() == tags(&["a", "b"]));
+        assert!(slot_tags(&msg!{"region": "eu", "version": 3}.into()).unwrap() == tags(&["region=eu", "version=3"]));
+        assert!(slot_tags(&Value::I32(1)).is_err());
+        assert!(slot_tags(&Array::from_vec(vec![Value::I32(1)]).into()).is_err());
+    }
+}
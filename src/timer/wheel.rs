@@ -198,7 +198,7 @@ where
 const LEVEL1_LEN: u32 = 1 << 8; // 2^8
 const LEVEL2_LEN: u32 = 1 << 16; // 2^16
 const LEVEL3_LEN: u32 = 1 << 24; // 2^24
-const LEVEL4_LEN: u32 = u32::max_value(); // 2^32
+const LEVEL4_LEN: u32 = u32::MAX; // 2^32
 
 pub fn default_prune<E>(_e: &E) -> bool {
     true
@@ -228,9 +228,15 @@ where
         }
     }
 
+    /// True if no timeout is left in any level
+    pub fn is_empty(&self) -> bool {
+        self.level1.is_empty() && self.level2.is_empty() &&
+            self.level3.is_empty() && self.level4.is_empty()
+    }
+
     /// Described how many ticks are left before the timer has wrapped around completely
     pub fn remaining(&self) -> u32 {
-        LEVEL4_LEN - (self.current() as u32)
+        LEVEL4_LEN - self.current()
     }
 
     /// Produces a 32-bit timestamp including the current index of every wheel
@@ -442,7 +448,7 @@ mod tests {
         assert!(res[0] == id);
 
         let res = wheel.tick();
-        assert!(res.len() == 0);
+        assert!(res.is_empty());
     }
 
     #[test]
@@ -462,6 +468,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_range_loop)]
     fn increasing_schedule() {
         let mut wheel = Wheel::default();
         let mut ids: [usize; 25] = [0; 25];
@@ -479,7 +486,7 @@ mod tests {
 
             for _ in (prev + 1)..target {
                 let res = wheel.tick();
-                assert!(res.len() == 0);
+                assert!(res.is_empty());
             }
 
             let res = wheel.tick();
@@ -0,0 +1,699 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::time::{Duration, Instant};
+use std::{mem, cmp};
+
+use nson::{Message, MessageId, Value, msg};
+
+use ring::digest::{Context, SHA256};
+
+use crate::Wire;
+use crate::dict::*;
+use crate::error::{Error, Result, Code, SendError, RecvError};
+
+// 大块数据的分块传输，数据不再受 MAX_MESSAGE_LEN 限制，也不需要一次性放入内存
+// 发送方通过 CHAN 和 TO 将数据按顺序分块发送，接收方授信控制流量，结束时校验长度和 SHA-256
+//
+// 开始，发送方 -> 接收方，续传时携带 RESUME: true
+// { CHAN: $chan, TO: $to, TRANSFER: $id }
+//
+// 授信，接收方 -> 发送方
+// ACK 之前的块已经收到（共 SIZE 字节），发送方可以发送 SEQ < ACK + CREDIT 的块
+// 携带 RESUME: true 时，发送方需要从 ACK 处重发
+// { CHAN: $chan, TO: $from, TRANSFER: $id, ACK: $seq, SIZE: $size, CREDIT: $credit }
+//
+// 数据块，发送方 -> 接收方
+// { CHAN: $chan, TO: $to, TRANSFER: $id, SEQ: $seq, VALUE: $binary }
+//
+// 结束，发送方 -> 接收方，接收方校验后回复
+// { CHAN: $chan, TO: $to, TRANSFER: $id, FINISH: true, SIZE: $size, CHECKSUM: $sha256 }
+// { CHAN: $chan, TO: $from, TRANSFER: $id, FINISH: true, CODE: $code }
+//
+// 双方只接受传输开始时的对方（发送方为 TO，接收方为开始消息的 FROM）的消息，其他 SLOT 的同 ID 消息会被丢弃，
+// 因此重连后续传时需要使用原来的 SLOT_ID
+//
+// 传输期间收到的其他消息不会被丢弃，可以通过 others 取出，最多暂存 others_limit 条
+#[derive(Debug, Clone)]
+pub struct TransferOptions {
+    // 每块的最大字节数
+    pub chunk_size: usize,
+    // 接收方每次授信的块数
+    pub window: u32,
+    // 等待对方消息和 Wire 空间的超时时间
+    pub timeout: Duration,
+    // 传输期间最多暂存的其他消息数，超过时传输失败
+    pub others_limit: usize
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        TransferOptions {
+            chunk_size: 256 * 1024,
+            window: 8,
+            timeout: Duration::from_secs(10),
+            others_limit: 1024
+        }
+    }
+}
+
+impl TransferOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+// 是否为传输开始的消息，接收方据此调用 Reader::accept
+pub fn is_start(message: &Message) -> bool {
+    message.contains_key(TRANSFER) &&
+    !message.contains_key(SEQ) &&
+    !message.contains_key(ACK) &&
+    !message.contains_key(FINISH)
+}
+
+// 发送方，写入的数据按 chunk_size 分块发送，写完后需要调用 finish
+pub struct Writer<'a> {
+    wire: &'a Wire<Message>,
+    chan: String,
+    to: MessageId,
+    id: MessageId,
+    options: TransferOptions,
+    // 下一块的序号
+    seq: u64,
+    // 可以发送 SEQ < limit 的块
+    limit: u64,
+    // 已写入的字节数
+    size: u64,
+    buf: Vec<u8>,
+    // 已发送但未确认的块，接收方重连后从 ACK 处重发
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    digest: Context,
+    others: Vec<Message>
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(
+        wire: &'a Wire<Message>,
+        chan: impl Into<String>,
+        to: MessageId,
+        options: TransferOptions
+    ) -> Result<Writer<'a>> {
+        let mut writer = Writer::create(wire, chan.into(), to, MessageId::new(), options)?;
+
+        writer.start(false)?;
+
+        Ok(writer)
+    }
+
+    // 发送方重连后继续之前的传输，source 为完整的数据源
+    // 根据接收方已收到的长度重新计算校验和，并将 source 定位到续传的位置
+    pub fn resume(
+        wire: &'a Wire<Message>,
+        chan: impl Into<String>,
+        to: MessageId,
+        id: MessageId,
+        source: &mut (impl Read + Seek),
+        options: TransferOptions
+    ) -> Result<Writer<'a>> {
+        let mut writer = Writer::create(wire, chan.into(), to, id, options)?;
+
+        let received = writer.start(true)?;
+
+        source.seek(SeekFrom::Start(0))?;
+
+        let mut prefix = source.by_ref().take(received);
+        let mut buf = vec![0; 64 * 1024];
+
+        loop {
+            let size = prefix.read(&mut buf)?;
+
+            if size == 0 {
+                break
+            }
+
+            writer.digest.update(&buf[..size]);
+            writer.size += size as u64;
+        }
+
+        if writer.size != received {
+            return Err(Error::InvalidData("transfer source is shorter than received".to_string()))
+        }
+
+        Ok(writer)
+    }
+
+    fn create(
+        wire: &'a Wire<Message>,
+        chan: String,
+        to: MessageId,
+        id: MessageId,
+        options: TransferOptions
+    ) -> Result<Writer<'a>> {
+        if options.chunk_size == 0 || options.chunk_size >= crate::MAX_MESSAGE_LEN / 2 {
+            return Err(Error::InvalidData("invalid transfer chunk size".to_string()))
+        }
+
+        Ok(Writer {
+            wire,
+            chan,
+            to,
+            id,
+            seq: 0,
+            limit: 0,
+            size: 0,
+            buf: Vec::with_capacity(options.chunk_size),
+            unacked: VecDeque::new(),
+            digest: Context::new(&SHA256),
+            others: Vec::new(),
+            options
+        })
+    }
+
+    #[inline]
+    pub fn id(&self) -> MessageId {
+        self.id
+    }
+
+    // 已写入的字节数
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // 取出传输期间收到的其他消息
+    pub fn others(&mut self) -> Vec<Message> {
+        mem::take(&mut self.others)
+    }
+
+    // 发送开始消息并等待授信，返回接收方已收到的字节数
+    fn start(&mut self, resume: bool) -> Result<u64> {
+        let mut message = msg!{
+            CHAN: self.chan.clone(),
+            TO: self.to,
+            TRANSFER: self.id
+        };
+
+        if resume {
+            message.insert(RESUME, true);
+        }
+
+        send(self.wire, message, self.options.timeout)?;
+
+        let deadline = Instant::now() + self.options.timeout;
+
+        loop {
+            let message = self.wait(deadline)?;
+
+            if message.contains_key(ACK) {
+                // 续传时从接收方已收到的块开始
+                self.seq = message.get_u64(ACK).unwrap_or(0);
+
+                self.credit(&message)?;
+
+                return Ok(message.get_u64(SIZE).unwrap_or(0))
+            }
+
+            check_code(&message)?;
+        }
+    }
+
+    // 处理授信，返回是否重发了数据块
+    fn credit(&mut self, message: &Message) -> Result<bool> {
+        check_code(message)?;
+
+        let (ack, credit) = match (message.get_u64(ACK), message.get_u32(CREDIT)) {
+            (Ok(ack), Ok(credit)) => (ack, credit),
+            _ => return Err(Error::InvalidData("invalid transfer credit".to_string()))
+        };
+
+        while let Some((seq, _)) = self.unacked.front() {
+            if *seq >= ack {
+                break
+            }
+
+            self.unacked.pop_front();
+        }
+
+        if ack > self.seq {
+            return Err(Error::InvalidData("transfer ack is ahead of sent chunks".to_string()))
+        }
+
+        // 重连前后的授信可能交错到达，不能缩小已有的额度
+        self.limit = cmp::max(self.limit, ack + credit as u64);
+
+        if ack == self.seq || message.get_bool(RESUME).ok() != Some(true) {
+            return Ok(false)
+        }
+
+        // 接收方丢失了部分块（比如重连），从 ACK 处重发
+        if self.unacked.front().map(|(seq, _)| *seq) != Some(ack) {
+            return Err(Error::InvalidData("transfer chunk is no longer available".to_string()))
+        }
+
+        for (seq, chunk) in &self.unacked {
+            let message = self.chunk_message(*seq, chunk.clone());
+
+            send(self.wire, message, self.options.timeout)?;
+        }
+
+        Ok(true)
+    }
+
+    fn wait(&mut self, deadline: Instant) -> Result<Message> {
+        wait(self.wire, &self.id, &self.to, &mut self.others, self.options.others_limit, deadline)
+    }
+
+    fn chunk_message(&self, seq: u64, chunk: Vec<u8>) -> Message {
+        msg!{
+            CHAN: self.chan.clone(),
+            TO: self.to,
+            TRANSFER: self.id,
+            SEQ: seq,
+            VALUE: chunk
+        }
+    }
+
+    // 发送缓冲区中的数据，没有授信时等待
+    fn send_buffer(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(())
+        }
+
+        let deadline = Instant::now() + self.options.timeout;
+
+        while self.seq >= self.limit {
+            let message = self.wait(deadline)?;
+
+            if message.contains_key(ACK) {
+                self.credit(&message)?;
+            } else {
+                check_code(&message)?;
+            }
+        }
+
+        let chunk = mem::replace(&mut self.buf, Vec::with_capacity(self.options.chunk_size));
+
+        let message = self.chunk_message(self.seq, chunk.clone());
+
+        send(self.wire, message, self.options.timeout)?;
+
+        self.unacked.push_back((self.seq, chunk));
+        self.seq += 1;
+
+        Ok(())
+    }
+
+    // 发送剩余的数据和校验和，等待接收方确认
+    pub fn finish(mut self) -> Result<()> {
+        self.send_buffer()?;
+
+        let checksum = self.digest.clone().finish().as_ref().to_vec();
+
+        let finish = |writer: &Writer| msg!{
+            CHAN: writer.chan.clone(),
+            TO: writer.to,
+            TRANSFER: writer.id,
+            FINISH: true,
+            SIZE: writer.size,
+            CHECKSUM: checksum.clone()
+        };
+
+        send(self.wire, finish(&self), self.options.timeout)?;
+
+        let deadline = Instant::now() + self.options.timeout;
+
+        loop {
+            let message = self.wait(deadline)?;
+
+            if message.get_bool(FINISH).ok() == Some(true) {
+                return check_code(&message)
+            }
+
+            if message.contains_key(ACK) {
+                // 接收方重连，重发后需要再次发送结束消息
+                if self.credit(&message)? {
+                    send(self.wire, finish(&self), self.options.timeout)?;
+                }
+            } else {
+                check_code(&message)?;
+            }
+        }
+    }
+}
+
+impl Write for Writer<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() >= self.options.chunk_size {
+            self.send_buffer().map_err(io_error)?;
+        }
+
+        let size = cmp::min(data.len(), self.options.chunk_size - self.buf.len());
+
+        self.buf.extend_from_slice(&data[..size]);
+        self.digest.update(&data[..size]);
+        self.size += size as u64;
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffer().map_err(io_error)
+    }
+}
+
+// 接收方的断点，接收方重连后用 Reader::resume 继续传输
+#[derive(Clone)]
+pub struct Checkpoint {
+    id: MessageId,
+    chan: String,
+    from: MessageId,
+    next_seq: u64,
+    size: u64,
+    digest: Context,
+    // 已收到但尚未读取的数据
+    pending: Vec<u8>
+}
+
+impl Checkpoint {
+    #[inline]
+    pub fn id(&self) -> MessageId {
+        self.id
+    }
+}
+
+// 接收方，按顺序读取数据，读到结尾时校验长度和校验和
+pub struct Reader<'a> {
+    wire: &'a Wire<Message>,
+    chan: String,
+    from: MessageId,
+    id: MessageId,
+    options: TransferOptions,
+    // 期望的下一块的序号
+    next_seq: u64,
+    // 已收到的字节数
+    size: u64,
+    // 最近一次授信时的 ACK
+    acked: u64,
+    digest: Context,
+    chunk: Vec<u8>,
+    pos: usize,
+    // 发现缺失的块后已请求重发，避免重复请求
+    rewinding: bool,
+    done: bool,
+    others: Vec<Message>
+}
+
+impl<'a> Reader<'a> {
+    pub fn accept(
+        wire: &'a Wire<Message>,
+        start: &Message,
+        options: TransferOptions
+    ) -> Result<Reader<'a>> {
+        let (id, chan, from) = match (
+            start.get_message_id(TRANSFER),
+            start.get_str(CHAN),
+            start.get_message_id(FROM)
+        ) {
+            (Ok(id), Ok(chan), Ok(from)) => (*id, chan.to_string(), *from),
+            _ => return Err(Error::InvalidData("invalid transfer start".to_string()))
+        };
+
+        let checkpoint = Checkpoint {
+            id,
+            chan,
+            from,
+            next_seq: 0,
+            size: 0,
+            digest: Context::new(&SHA256),
+            pending: Vec::new()
+        };
+
+        Reader::resume(wire, checkpoint, options)
+    }
+
+    // 接收方重连后继续之前的传输
+    pub fn resume(
+        wire: &'a Wire<Message>,
+        checkpoint: Checkpoint,
+        options: TransferOptions
+    ) -> Result<Reader<'a>> {
+        let mut reader = Reader {
+            wire,
+            chan: checkpoint.chan,
+            from: checkpoint.from,
+            id: checkpoint.id,
+            options,
+            next_seq: checkpoint.next_seq,
+            size: checkpoint.size,
+            acked: checkpoint.next_seq,
+            digest: checkpoint.digest,
+            chunk: checkpoint.pending,
+            pos: 0,
+            rewinding: false,
+            done: false,
+            others: Vec::new()
+        };
+
+        reader.credit(true)?;
+
+        Ok(reader)
+    }
+
+    // 保存断点，连接断开后可以继续传输
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            id: self.id,
+            chan: self.chan.clone(),
+            from: self.from,
+            next_seq: self.next_seq,
+            size: self.size,
+            digest: self.digest.clone(),
+            pending: self.chunk[self.pos..].to_vec()
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> MessageId {
+        self.id
+    }
+
+    // 发送方的 SLOT_ID
+    #[inline]
+    pub fn from(&self) -> MessageId {
+        self.from
+    }
+
+    // 已收到的字节数
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // 取出传输期间收到的其他消息
+    pub fn others(&mut self) -> Vec<Message> {
+        mem::take(&mut self.others)
+    }
+
+    // rewind 为 true 时要求发送方从 next_seq 处重发
+    fn credit(&mut self, rewind: bool) -> Result<()> {
+        let mut message = msg!{
+            CHAN: self.chan.clone(),
+            TO: self.from,
+            TRANSFER: self.id,
+            ACK: self.next_seq,
+            SIZE: self.size,
+            CREDIT: self.options.window
+        };
+
+        if rewind {
+            message.insert(RESUME, true);
+        }
+
+        send(self.wire, message, self.options.timeout)?;
+
+        self.acked = self.next_seq;
+
+        Ok(())
+    }
+
+    // 接收下一块，传输结束时设置 done
+    fn next_chunk(&mut self) -> Result<()> {
+        let deadline = Instant::now() + self.options.timeout;
+
+        loop {
+            let mut message = wait(
+                self.wire,
+                &self.id,
+                &self.from,
+                &mut self.others,
+                self.options.others_limit,
+                deadline
+            )?;
+
+            if let Ok(seq) = message.get_u64(SEQ) {
+                if seq == self.next_seq {
+                    let chunk = match message.remove(VALUE) {
+                        Some(Value::Binary(chunk)) => chunk.0,
+                        _ => return Err(Error::InvalidData("invalid transfer chunk".to_string()))
+                    };
+
+                    self.digest.update(&chunk);
+                    self.size += chunk.len() as u64;
+                    self.next_seq += 1;
+                    self.rewinding = false;
+
+                    self.chunk = chunk;
+                    self.pos = 0;
+
+                    // 读取了半个窗口后继续授信
+                    if self.next_seq - self.acked >= cmp::max(self.options.window / 2, 1) as u64 {
+                        self.credit(false)?;
+                    }
+
+                    return Ok(())
+                } else if seq > self.next_seq && !self.rewinding {
+                    // 中间的块丢失了，请求从 next_seq 重发
+                    self.rewinding = true;
+                    self.credit(true)?;
+                }
+
+                // 重复的块直接丢弃
+                continue
+            }
+
+            if message.get_bool(RESUME).ok() == Some(true) {
+                // 发送方重连
+                self.rewinding = false;
+                self.credit(true)?;
+
+                continue
+            }
+
+            if message.get_bool(FINISH).ok() == Some(true) {
+                let code = self.verify(&message);
+
+                let mut reply = msg!{
+                    CHAN: self.chan.clone(),
+                    TO: self.from,
+                    TRANSFER: self.id,
+                    FINISH: true
+                };
+
+                code.set(&mut reply);
+
+                send(self.wire, reply, self.options.timeout)?;
+
+                if code != Code::Ok {
+                    return Err(code.into())
+                }
+
+                self.done = true;
+
+                return Ok(())
+            }
+        }
+    }
+
+    fn verify(&self, message: &Message) -> Code {
+        if message.get_u64(SIZE).ok() != Some(self.size) {
+            return Code::TransferSizeMismatch
+        }
+
+        let checksum = self.digest.clone().finish();
+
+        match message.get_binary(CHECKSUM) {
+            Ok(binary) if binary.0 == checksum.as_ref() => Code::Ok,
+            _ => Code::TransferChecksumMismatch
+        }
+    }
+}
+
+impl Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.done {
+                return Ok(0)
+            }
+
+            self.next_chunk().map_err(io_error)?;
+        }
+
+        let size = cmp::min(buf.len(), self.chunk.len() - self.pos);
+
+        buf[..size].copy_from_slice(&self.chunk[self.pos..self.pos + size]);
+        self.pos += size;
+
+        Ok(size)
+    }
+}
+
+fn check_code(message: &Message) -> Result<()> {
+    match Code::get(message) {
+        None | Some(Code::Ok) => Ok(()),
+        Some(code) => Err(code.into())
+    }
+}
+
+// Wire 已满时等待空间，直到超时
+fn send(wire: &Wire<Message>, message: Message, timeout: Duration) -> Result<()> {
+    match wire.send_timeout(message, timeout) {
+        Ok(()) => Ok(()),
+        Err(SendError::Full(_)) => Err(Error::TimedOut("transfer send".to_string())),
+        Err(SendError::Disconnected(_)) => Err(Error::Disconnected("transfer send".to_string()))
+    }
+}
+
+// 等待属于本次传输的消息，其他消息暂存起来
+// 只接受 peer 发来的消息，以及 Switch 退回的自己的消息（比如目标不存在），其他 SLOT 的同 ID 消息直接丢弃
+fn wait(
+    wire: &Wire<Message>,
+    id: &MessageId,
+    peer: &MessageId,
+    others: &mut Vec<Message>,
+    limit: usize,
+    deadline: Instant
+) -> Result<Message> {
+    let own = wire.attr().get_message_id(SLOT_ID).ok().copied();
+
+    loop {
+        let now = Instant::now();
+
+        if now >= deadline {
+            return Err(Error::TimedOut("transfer wait".to_string()))
+        }
+
+        match wire.wait(Some(deadline - now)) {
+            Ok(message) => {
+                if message.get_message_id(TRANSFER).ok() == Some(id) {
+                    let from = message.get_message_id(FROM).ok();
+
+                    if from == Some(peer) || (from.is_some() && from == own.as_ref()) {
+                        return Ok(message)
+                    }
+
+                    continue
+                }
+
+                if others.len() >= limit {
+                    return Err(Error::Full("transfer others".to_string()))
+                }
+
+                others.push(message);
+            }
+            Err(RecvError::Empty) => (),
+            Err(RecvError::TimedOut) => return Err(Error::TimedOut("transfer wait".to_string())),
+            Err(RecvError::Disconnected) => return Err(Error::Disconnected("transfer wait".to_string()))
+        }
+    }
+}
+
+fn io_error(err: Error) -> io::Error {
+    let kind = match err {
+        Error::IoError(err) => return err,
+        Error::TimedOut(_) => io::ErrorKind::TimedOut,
+        Error::Disconnected(_) => io::ErrorKind::BrokenPipe,
+        Error::InvalidData(_) | Error::ErrorCode(_) => io::ErrorKind::InvalidData,
+        _ => io::ErrorKind::Other
+    };
+
+    io::Error::new(kind, err.to_string())
+}
@@ -1,3 +1,5 @@
 pub mod message;
 pub mod lock;
 pub mod oneshot;
+pub mod bucket;
+pub mod cidr;
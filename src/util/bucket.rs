@@ -0,0 +1,153 @@
+use std::time::Instant;
+
+// 令牌桶，每秒补充 rate 个令牌，最多积累 burst 个
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+
+        TokenBucket {
+            rate: f64::from(rate),
+            burst,
+            tokens: burst,
+            last: Instant::now()
+        }
+    }
+
+    #[inline]
+    pub fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    // 取出一个令牌，没有令牌时返回 false
+    pub fn take_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true
+        }
+
+        false
+    }
+
+    // 是否可以取出一个令牌，不取出
+    pub fn can_take_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens >= 1.0
+    }
+
+    // 是否可以放行 consume_at，不取出
+    pub fn can_consume_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens > 0.0
+    }
+
+    #[inline]
+    pub fn consume(&mut self, n: u32) -> bool {
+        self.consume_at(n, Instant::now())
+    }
+
+    // 还有令牌时放行并取出 n 个，令牌可以透支，透支的部分需要等待补充
+    // 用于按字节限速，单次的数量可能超过 burst
+    pub fn consume_at(&mut self, n: u32, now: Instant) -> bool {
+        self.refill(now);
+
+        if self.tokens > 0.0 {
+            self.tokens -= f64::from(n);
+            return true
+        }
+
+        false
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.last {
+            let elapsed = now.duration_since(self.last).as_secs_f64();
+
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.last = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::TokenBucket;
+
+    #[test]
+    fn burst_and_refill() {
+        let now = Instant::now();
+
+        let mut bucket = TokenBucket::new(10, 3);
+        bucket.last = now;
+
+        assert!(bucket.take_at(now));
+        assert!(bucket.take_at(now));
+        assert!(bucket.take_at(now));
+        assert!(!bucket.take_at(now));
+
+        // 100 毫秒补充 1 个
+        assert!(bucket.take_at(now + Duration::from_millis(100)));
+        assert!(!bucket.take_at(now + Duration::from_millis(150)));
+
+        // 不会超过 burst
+        let later = now + Duration::from_secs(10);
+        assert!(bucket.take_at(later));
+        assert!(bucket.take_at(later));
+        assert!(bucket.take_at(later));
+        assert!(!bucket.take_at(later));
+    }
+
+    #[test]
+    fn consume() {
+        let now = Instant::now();
+
+        let mut bucket = TokenBucket::new(100, 100);
+        bucket.last = now;
+
+        // 可以透支
+        assert!(bucket.consume_at(250, now));
+        assert!(!bucket.consume_at(1, now));
+
+        // 补足透支的 150 个之后才能继续
+        assert!(!bucket.consume_at(1, now + Duration::from_millis(1500)));
+        assert!(bucket.consume_at(1, now + Duration::from_millis(1510)));
+    }
+
+    #[test]
+    fn check_without_taking() {
+        let now = Instant::now();
+
+        let mut bucket = TokenBucket::new(10, 1);
+        bucket.last = now;
+
+        assert!(bucket.can_take_at(now));
+        assert!(bucket.can_consume_at(now));
+        assert!(bucket.take_at(now));
+
+        assert!(!bucket.can_take_at(now));
+        assert!(!bucket.can_consume_at(now));
+    }
+
+    #[test]
+    fn zero_rate() {
+        let now = Instant::now();
+
+        let mut bucket = TokenBucket::new(0, 1);
+
+        assert!(bucket.take_at(now));
+        assert!(!bucket.take_at(now + Duration::from_secs(100)));
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+// IP 地址段，比如 10.0.0.0/8，不带前缀长度时表示单个地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Cidr> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+
+        if prefix > max {
+            return None
+        }
+
+        Some(Cidr { addr: mask(addr, prefix), prefix })
+    }
+
+    #[inline]
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    #[inline]
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    // IPv4 映射的 IPv6 地址（::ffff:a.b.c.d）按 IPv4 处理
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, canonical(*addr)) {
+            (IpAddr::V4(_), addr @ IpAddr::V4(_)) |
+            (IpAddr::V6(_), addr @ IpAddr::V6(_)) => mask(addr, self.prefix) == self.addr,
+            _ => false
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None)
+        };
+
+        let addr = IpAddr::from_str(addr.trim()).map_err(|_| format!("invalid cidr: {}", s))?;
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().map_err(|_| format!("invalid cidr: {}", s))?,
+            None => if addr.is_ipv4() { 32 } else { 128 }
+        };
+
+        Cidr::new(addr, prefix).ok_or_else(|| format!("invalid cidr: {}", s))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// 保留地址的前 prefix 位，其余置零
+pub fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4);
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - u32::from(prefix.min(32))) };
+            IpAddr::V4(Ipv4Addr::from(bits & mask))
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6);
+            let mask = if prefix == 0 { 0 } else { u128::MAX << (128 - u32::from(prefix.min(128))) };
+            IpAddr::V6(Ipv6Addr::from(bits & mask))
+        }
+    }
+}
+
+// IPv4 映射的 IPv6 地址转换为 IPv4
+pub fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6)
+        },
+        addr => addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{Cidr, mask};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert!(cidr.addr() == ip("10.0.0.0"));
+        assert!(cidr.prefix() == 8);
+        assert!(cidr.to_string() == "10.0.0.0/8");
+
+        let cidr: Cidr = "127.0.0.1".parse().unwrap();
+        assert!(cidr.prefix() == 32);
+
+        let cidr: Cidr = "fe80::1/64".parse().unwrap();
+        assert!(cidr.addr() == ip("fe80::"));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/a".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn contains() {
+        let cidr: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(cidr.contains(&ip("192.168.1.1")));
+        assert!(cidr.contains(&ip("::ffff:192.168.1.1")));
+        assert!(!cidr.contains(&ip("192.169.0.1")));
+        assert!(!cidr.contains(&ip("fe80::1")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&ip("8.8.8.8")));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(&ip("2001:db8:1::1")));
+        assert!(!cidr.contains(&ip("2001:db9::1")));
+
+        assert!(mask(ip("10.1.2.3"), 24) == ip("10.1.2.0"));
+        assert!(mask(ip("10.1.2.3"), 0) == ip("0.0.0.0"));
+    }
+}
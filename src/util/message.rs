@@ -1,8 +1,11 @@
 use std::io::{self, Read};
 use std::io::ErrorKind::InvalidData;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use nson::{Message, Value};
 
 use crate::MAX_MESSAGE_LEN;
+use crate::dict::EXPIRE;
 
 pub fn read_block(reader: &mut impl Read, max_len: Option<usize>) -> io::Result<Vec<u8>> {
     let mut len_bytes = [0u8; 4];
@@ -25,12 +28,40 @@ pub fn read_block(reader: &mut impl Read, max_len: Option<usize>) -> io::Result<
     Ok(buf)
 }
 
+// 当前时间，UNIX 时间戳，单位毫秒
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+// TTL 和 EXPIRE 字段可以是任意整数类型
+pub fn as_millis(value: &Value) -> Option<i64> {
+    match value {
+        Value::I32(v) => Some(i64::from(*v)),
+        Value::I64(v) => Some(*v),
+        Value::U32(v) => Some(i64::from(*v)),
+        Value::U64(v) => i64::try_from(*v).ok(),
+        _ => None
+    }
+}
+
+// 消息携带了 EXPIRE 并且已经过了这个时间
+pub fn is_expired(message: &Message, now: i64) -> bool {
+    match message.get(EXPIRE).and_then(as_millis) {
+        Some(expire) => expire <= now,
+        None => false
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
     use crate::nson::msg;
-    use super::read_block;
+    use crate::dict::EXPIRE;
+    use super::{read_block, is_expired, now_millis};
 
     #[test]
     fn test_read_block() {
@@ -45,4 +76,15 @@ mod tests {
 
         assert!(ret == vec);
     }
+
+    #[test]
+    fn test_is_expired() {
+        let now = now_millis();
+
+        assert!(!is_expired(&msg!{}, now));
+        assert!(!is_expired(&msg!{EXPIRE: now + 1000}, now));
+        assert!(is_expired(&msg!{EXPIRE: now}, now));
+        assert!(is_expired(&msg!{EXPIRE: 1u32}, now));
+        assert!(!is_expired(&msg!{EXPIRE: "abc"}, now));
+    }
 }
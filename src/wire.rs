@@ -1,16 +1,19 @@
 use std::{io, result, fmt};
 use std::sync::{
-    Arc,
-    atomic::{AtomicBool, Ordering}
+    Arc, Mutex, Condvar,
+    atomic::{self, AtomicBool, AtomicUsize, Ordering}
 };
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::marker::PhantomData;
 use std::cell::Cell;
+use std::any::{Any, TypeId};
+use std::ops::Deref;
 
 use queen_io::{
     epoll::{Epoll, Token, Ready, EpollOpt, Source},
-    queue::spsc::Queue
+    plus::spsc_queue,
+    waker::Waker
 };
 
 use queen_io::poll;
@@ -20,10 +23,106 @@ use nson::Message;
 use crate::util::lock::{Lock, LockGuard};
 use crate::error::{Result, SendError, RecvError};
 
+// 优先级，接收时优先取出高优先级的消息
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    Low = 0,
+    #[default]
+    Normal = 1,
+    High = 2
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+
+    // 从高到低
+    pub const ALL: [Priority; Priority::COUNT] = [Priority::High, Priority::Normal, Priority::Low];
+}
+
+// 多个接收者共享的数据，同时缓存编码结果，避免为每个接收者重复编码
+pub struct Shared<T> {
+    data: T,
+    encoded: Lock<Vec<(TypeId, Arc<Vec<u8>>)>>
+}
+
+impl<T> Shared<T> {
+    pub fn new(data: T) -> Self {
+        Shared {
+            data,
+            encoded: Lock::new(Vec::new())
+        }
+    }
+
+    #[inline]
+    pub fn get(&self) -> &T {
+        &self.data
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+
+    // 获取已缓存的编码结果，不会触发编码
+    pub fn cached<K: Any>(&self) -> Option<Arc<Vec<u8>>> {
+        let key = TypeId::of::<K>();
+
+        self.encoded.lock().iter().find(|(k, _)| *k == key).map(|(_, bytes)| bytes.clone())
+    }
+
+    // 获取编码结果，没有时调用 encode 并缓存
+    // K 用于区分不同的编码方式，比如 Codec 的类型
+    pub fn encoded<K: Any, E>(
+        &self,
+        encode: impl FnOnce(&T) -> result::Result<Vec<u8>, E>
+    ) -> result::Result<Arc<Vec<u8>>, E> {
+        if let Some(bytes) = self.cached::<K>() {
+            return Ok(bytes)
+        }
+
+        let key = TypeId::of::<K>();
+
+        // 编码期间不持有锁，多个线程同时编码时以先完成的为准
+        let bytes = Arc::new(encode(&self.data)?);
+
+        let mut encoded = self.encoded.lock();
+
+        if let Some((_, bytes)) = encoded.iter().find(|(k, _)| *k == key) {
+            return Ok(bytes.clone())
+        }
+
+        encoded.push((key, bytes.clone()));
+
+        Ok(bytes)
+    }
+}
+
+impl<T> Deref for Shared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Shared")
+         .field(&self.data)
+         .finish()
+    }
+}
+
+enum Item<T> {
+    Owned(T),
+    // 共享的数据，接收者需要独占时通过 clone 复制一份
+    Shared(Arc<Shared<T>>, fn(&T) -> T)
+}
+
 pub struct Wire<T: Send> {
-    capacity: usize,
-    tx: Queue<result::Result<T, RecvError>>,
-    rx: Queue<result::Result<T, RecvError>>,
+    capacities: [usize; Priority::COUNT],
+    tx: Lanes<result::Result<Item<T>, RecvError>>,
+    rx: Lanes<result::Result<Item<T>, RecvError>>,
     close: Arc<AtomicBool>,
     attr: Arc<Lock<Message>>,
     send_num: Cell<usize>,
@@ -34,7 +133,7 @@ pub struct Wire<T: Send> {
 impl<T: Send> fmt::Debug for Wire<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Wire")
-         .field("capacity", &self.capacity)
+         .field("capacities", &self.capacities)
          .field("close", &self.is_close())
          .field("attr", &*self.attr())
          .field("send_num", &self.send_num.get())
@@ -45,14 +144,22 @@ impl<T: Send> fmt::Debug for Wire<T> {
 
 impl<T: Send> Wire<T> {
     pub fn pipe(capacity: usize, attr: Message) -> Result<(Wire<T>, Wire<T>)> {
-        let queue1 = Queue::with_cache(capacity)?;
-        let queue2 = Queue::with_cache(capacity)?;
+        Self::pipe_with_capacities([capacity; Priority::COUNT], attr)
+    }
+
+    // 每个优先级的容量相互独立，按 Priority 的值索引，即 [Low, Normal, High]
+    pub fn pipe_with_capacities(
+        capacities: [usize; Priority::COUNT],
+        attr: Message
+    ) -> Result<(Wire<T>, Wire<T>)> {
+        let queue1 = Lanes::with_cache(&capacities)?;
+        let queue2 = Lanes::with_cache(&capacities)?;
 
         let close = Arc::new(AtomicBool::new(false));
         let attr = Arc::new(Lock::new(attr));
 
         let wire1 = Wire {
-            capacity,
+            capacities,
             tx: queue1.clone(),
             rx: queue2.clone(),
             close: close.clone(),
@@ -63,7 +170,7 @@ impl<T: Send> Wire<T> {
         };
 
         let wire2 = Wire {
-            capacity,
+            capacities,
             tx: queue2,
             rx: queue1,
             close,
@@ -78,18 +185,26 @@ impl<T: Send> Wire<T> {
 
     #[inline]
     pub fn capacity(&self) -> usize {
-        self.capacity
+        self.capacity_of(Priority::Normal)
+    }
+
+    #[inline]
+    pub fn capacity_of(&self, priority: Priority) -> usize {
+        self.capacities[priority as usize]
     }
 
     #[inline]
-    pub fn attr(&self) -> LockGuard<Message> {
+    pub fn attr(&self) -> LockGuard<'_, Message> {
         self.attr.lock()
     }
 
     #[inline]
     pub fn close(&self) {
-        self.tx.push(Err(RecvError::Disconnected));
+        // 放入最低优先级，对方取完所有消息后才会收到
+        self.tx.push(Priority::Low, Err(RecvError::Disconnected));
         self.close.store(true, Ordering::Release);
+        // 唤醒等待空间的对方
+        self.rx.notify_space();
     }
 
     #[inline]
@@ -99,24 +214,93 @@ impl<T: Send> Wire<T> {
 
     #[inline]
     pub fn is_full(&self) -> bool {
-        self.tx.pending() >= self.capacity
+        self.is_full_of(Priority::Normal)
+    }
+
+    #[inline]
+    pub fn is_full_of(&self, priority: Priority) -> bool {
+        self.tx.pending_of(priority) >= self.capacity_of(priority)
     }
 
+    // 所有优先级的消息总数
     #[inline]
     pub fn pending(&self) -> usize {
         self.tx.pending()
     }
 
+    #[inline]
+    pub fn pending_of(&self, priority: Priority) -> usize {
+        self.tx.pending_of(priority)
+    }
+
+    #[inline]
     pub fn send(&self, data: T) -> result::Result<(), SendError<T>> {
+        self.send_with_priority(data, Priority::Normal)
+    }
+
+    pub fn send_with_priority(&self, data: T, priority: Priority) -> result::Result<(), SendError<T>> {
         if self.is_close() {
             return Err(SendError::Disconnected(data))
         }
 
-        if self.is_full() {
+        if self.is_full_of(priority) {
             return Err(SendError::Full(data))
         }
 
-        self.tx.push(Ok(data));
+        self.tx.push(priority, Ok(Item::Owned(data)));
+
+        self.send_num.set(self.send_num.get() + 1);
+
+        Ok(())
+    }
+
+    // 已满时等待对方取出消息，直到超时，超时后返回 SendError::Full
+    pub fn send_timeout(&self, mut data: T, timeout: Duration) -> result::Result<(), SendError<T>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            match self.send(data) {
+                Err(SendError::Full(back)) => {
+                    data = back;
+
+                    let capacity = self.capacity_of(Priority::Normal);
+
+                    if !self.tx.wait_space(Priority::Normal, capacity, &self.close, deadline) {
+                        if self.is_close() {
+                            return Err(SendError::Disconnected(data))
+                        }
+
+                        return Err(SendError::Full(data))
+                    }
+                }
+                ret => return ret
+            }
+        }
+    }
+
+    #[inline]
+    pub fn send_shared(&self, data: Arc<Shared<T>>) -> result::Result<(), SendError<Arc<Shared<T>>>>
+        where T: Clone + Sync
+    {
+        self.send_shared_with_priority(data, Priority::Normal)
+    }
+
+    pub fn send_shared_with_priority(
+        &self,
+        data: Arc<Shared<T>>,
+        priority: Priority
+    ) -> result::Result<(), SendError<Arc<Shared<T>>>>
+        where T: Clone + Sync
+    {
+        if self.is_close() {
+            return Err(SendError::Disconnected(data))
+        }
+
+        if self.is_full_of(priority) {
+            return Err(SendError::Full(data))
+        }
+
+        self.tx.push(priority, Ok(Item::Shared(data, T::clone)));
 
         self.send_num.set(self.send_num.get() + 1);
 
@@ -128,7 +312,7 @@ impl<T: Send> Wire<T> {
         self.send_num.get()
     }
 
-    pub fn recv(&self) -> result::Result<T, RecvError> {
+    fn pop(&self) -> result::Result<Item<T>, RecvError> {
         match self.rx.pop() {
             Some(data) => {
                 if data.is_ok() {
@@ -140,6 +324,24 @@ impl<T: Send> Wire<T> {
         }
     }
 
+    pub fn recv(&self) -> result::Result<T, RecvError> {
+        match self.pop()? {
+            Item::Owned(data) => Ok(data),
+            Item::Shared(data, clone) => {
+                // 最后一个持有者不需要复制
+                Ok(Arc::try_unwrap(data).map(Shared::into_inner).unwrap_or_else(|data| clone(&data)))
+            }
+        }
+    }
+
+    // 接收共享的数据，可以复用其编码结果
+    pub fn recv_shared(&self) -> result::Result<Arc<Shared<T>>, RecvError> {
+        match self.pop()? {
+            Item::Owned(data) => Ok(Arc::new(Shared::new(data))),
+            Item::Shared(data, _) => Ok(data)
+        }
+    }
+
     #[inline]
     pub fn recv_num(&self) -> usize {
         self.recv_num.get()
@@ -176,6 +378,183 @@ impl<T: Send> Drop for Wire<T> {
 
 unsafe impl<T: Send> Send for Wire<T> {}
 
+// 多个优先级的 spsc 队列，共用一个 Waker
+// 逻辑与 queen_io::queue::spsc::Queue 相同，只是 pending 为所有队列的总数
+struct Lanes<T> {
+    inner: Arc<LanesInner<T>>
+}
+
+struct LanesInner<T> {
+    queues: Vec<spsc_queue::Queue<T>>,
+    pendings: Vec<AtomicUsize>,
+    pending: AtomicUsize,
+    waker: Waker,
+    // 等待空间的发送者，接收者取出消息后唤醒
+    senders: AtomicUsize,
+    space: Mutex<()>,
+    space_cond: Condvar
+}
+
+impl<T> Lanes<T> {
+    fn with_cache(bounds: &[usize; Priority::COUNT]) -> io::Result<Lanes<T>> {
+        Ok(Lanes {
+            inner: Arc::new(LanesInner {
+                // 每个 Lanes 只有一个生产者和一个消费者（Wire 不是 Sync 的）
+                queues: bounds.iter()
+                    .map(|bound| unsafe { spsc_queue::Queue::with_additions(*bound, (), ()) })
+                    .collect(),
+                pendings: bounds.iter().map(|_| AtomicUsize::new(0)).collect(),
+                pending: AtomicUsize::new(0),
+                waker: Waker::new()?,
+                senders: AtomicUsize::new(0),
+                space: Mutex::new(()),
+                space_cond: Condvar::new()
+            })
+        })
+    }
+
+    fn inc(&self) -> io::Result<()> {
+        let cnt = self.inner.pending.fetch_add(1, Ordering::Acquire);
+
+        if 0 == cnt {
+            self.inner.waker.set_readiness(Ready::readable())?;
+        }
+
+        Ok(())
+    }
+
+    fn dec(&self) -> io::Result<()> {
+        let first = self.inner.pending.load(Ordering::Acquire);
+
+        if first == 1 {
+            self.inner.waker.set_readiness(Ready::empty())?;
+        }
+
+        let second = self.inner.pending.fetch_sub(1, Ordering::AcqRel);
+
+        if first == 1 && second > 1 {
+            self.inner.waker.set_readiness(Ready::readable())?;
+        }
+
+        Ok(())
+    }
+
+    fn push(&self, priority: Priority, value: T) {
+        let lane = priority as usize;
+
+        self.inner.queues[lane].push(value);
+        self.inner.pendings[lane].fetch_add(1, Ordering::Acquire);
+        let _ = self.inc();
+    }
+
+    fn pop(&self) -> Option<T> {
+        for priority in &Priority::ALL {
+            let lane = *priority as usize;
+
+            if let Some(value) = self.inner.queues[lane].pop() {
+                self.inner.pendings[lane].fetch_sub(1, Ordering::AcqRel);
+                let _ = self.dec();
+
+                self.notify_space();
+
+                return Some(value)
+            }
+        }
+
+        None
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending.load(Ordering::Relaxed)
+    }
+
+    // 等待 priority 的队列有空间，超时或者关闭时返回 false
+    fn wait_space(&self, priority: Priority, capacity: usize, close: &AtomicBool, deadline: Instant) -> bool {
+        let inner = &self.inner;
+
+        let mut guard = inner.space.lock().unwrap_or_else(|err| err.into_inner());
+
+        inner.senders.fetch_add(1, Ordering::SeqCst);
+
+        let ret = loop {
+            // 与 notify_space 配对，保证不会错过唤醒
+            atomic::fence(Ordering::SeqCst);
+
+            if close.load(Ordering::Acquire) {
+                break false
+            }
+
+            if inner.pendings[priority as usize].load(Ordering::SeqCst) < capacity {
+                break true
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                break false
+            }
+
+            guard = match inner.space_cond.wait_timeout(guard, deadline - now) {
+                Ok((guard, _)) => guard,
+                Err(err) => err.into_inner().0
+            };
+        };
+
+        inner.senders.fetch_sub(1, Ordering::SeqCst);
+
+        ret
+    }
+
+    // 只有在有发送者等待时才需要加锁唤醒
+    fn notify_space(&self) {
+        atomic::fence(Ordering::SeqCst);
+
+        if self.inner.senders.load(Ordering::SeqCst) > 0 {
+            let _guard = self.inner.space.lock().unwrap_or_else(|err| err.into_inner());
+
+            self.inner.space_cond.notify_all();
+        }
+    }
+
+    fn pending_of(&self, priority: Priority) -> usize {
+        self.inner.pendings[priority as usize].load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Clone for Lanes<T> {
+    fn clone(&self) -> Lanes<T> {
+        Lanes {
+            inner: self.inner.clone()
+        }
+    }
+}
+
+impl<T> AsRawFd for Lanes<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.waker.as_raw_fd()
+    }
+}
+
+impl<T> Source for Lanes<T> {
+    fn add(&self, epoll: &Epoll, token: Token, interest: Ready, opts: EpollOpt) -> io::Result<()> {
+        self.inner.waker.add(epoll, token, interest, opts)?;
+
+        if self.inner.pending.load(Ordering::Relaxed) > 0 {
+            self.inner.waker.set_readiness(Ready::readable())?;
+        }
+
+        Ok(())
+    }
+
+    fn modify(&self, epoll: &Epoll, token: Token, interest: Ready, opts: EpollOpt) -> io::Result<()> {
+        self.inner.waker.modify(epoll, token, interest, opts)
+    }
+
+    fn delete(&self, epoll: &Epoll) -> io::Result<()> {
+        self.inner.waker.delete(epoll)
+    }
+}
+
 impl<T: Send> Source for Wire<T> {
     fn add(&self, epoll: &Epoll, token: Token, interest: Ready, opts: EpollOpt) -> io::Result<()> {
         self.rx.add(epoll, token, interest, opts)
@@ -192,8 +571,9 @@ impl<T: Send> Source for Wire<T> {
 
 #[cfg(test)]
 mod tests {
-    use super::Wire;
+    use super::{Wire, Priority, Shared};
     use std::thread;
+    use std::sync::Arc;
     use std::time::Duration;
 
     use nson::msg;
@@ -218,6 +598,95 @@ mod tests {
         assert!(wire1.send(2).err() == Some(SendError::Full(2)));
     }
 
+    #[test]
+    fn send_timeout() {
+        let (wire1, wire2) = Wire::<i32>::pipe(1, msg!{}).unwrap();
+
+        assert!(wire1.send_timeout(1, Duration::from_millis(10)).is_ok());
+        assert!(wire1.send_timeout(2, Duration::from_millis(10)).err() == Some(SendError::Full(2)));
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            assert!(wire2.recv() == Ok(1));
+            assert!(wire2.wait(Some(Duration::from_secs(1))) == Ok(2));
+        });
+
+        assert!(wire1.send_timeout(2, Duration::from_secs(1)).is_ok());
+
+        handle.join().unwrap();
+
+        // 等待期间对方关闭时不再等待
+        let (wire1, wire2) = Wire::<i32>::pipe(1, msg!{}).unwrap();
+
+        assert!(wire1.send(1).is_ok());
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(wire2);
+        });
+
+        let start = std::time::Instant::now();
+        assert!(wire1.send_timeout(2, Duration::from_secs(10)).err() == Some(SendError::Disconnected(2)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn send_priority() {
+        let (wire1, wire2) = Wire::<i32>::pipe_with_capacities([1, 1, 2], msg!{}).unwrap();
+
+        assert!(wire1.send_with_priority(1, Priority::Low).is_ok());
+        assert!(wire1.send_with_priority(2, Priority::Low).err() == Some(SendError::Full(2)));
+        assert!(wire1.send(3).is_ok());
+        assert!(wire1.send_with_priority(4, Priority::High).is_ok());
+        assert!(wire1.send_with_priority(5, Priority::High).is_ok());
+        assert!(wire1.send_with_priority(6, Priority::High).err() == Some(SendError::Full(6)));
+
+        assert!(wire1.pending() == 4);
+        assert!(wire1.pending_of(Priority::High) == 2);
+
+        assert!(wire2.recv() == Ok(4));
+        assert!(wire2.recv() == Ok(5));
+        assert!(wire2.recv() == Ok(3));
+        assert!(wire2.recv() == Ok(1));
+        assert!(wire2.recv() == Err(RecvError::Empty));
+
+        // 关闭的消息在最后
+        assert!(wire1.send_with_priority(7, Priority::High).is_ok());
+        drop(wire1);
+
+        assert!(wire2.recv() == Ok(7));
+        assert!(wire2.recv() == Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn send_shared() {
+        let (wire1, wire2) = Wire::<String>::pipe(4, msg!{}).unwrap();
+        let (wire3, wire4) = Wire::<String>::pipe(4, msg!{}).unwrap();
+
+        let data = Arc::new(Shared::new("hello".to_string()));
+
+        assert!(wire1.send_shared(data.clone()).is_ok());
+        assert!(wire3.send_shared(data.clone()).is_ok());
+        assert!(wire1.send("world".to_string()).is_ok());
+
+        let recv = wire2.recv_shared().unwrap();
+        assert!(Arc::ptr_eq(&recv, &data));
+
+        let bytes = recv.encoded::<(), ()>(|s| Ok(s.as_bytes().to_vec())).unwrap();
+        let bytes2 = data.encoded::<(), ()>(|_| Err(())).unwrap();
+        assert!(Arc::ptr_eq(&bytes, &bytes2));
+        assert!(Arc::ptr_eq(&bytes, &data.cached::<()>().unwrap()));
+        assert!(data.cached::<u8>().is_none());
+
+        drop(data);
+        drop(recv);
+
+        assert!(wire4.recv().unwrap() == "hello");
+        assert!(wire2.recv_shared().unwrap().get() == "world");
+    }
+
     #[test]
     fn test_wait_timeout() {
         let (wire1, wire2) = Wire::<i32>::pipe(1, msg!{}).unwrap();
@@ -1,16 +1,18 @@
 use std::sync::{
-    Arc,
+    Arc, Mutex,
     atomic::{AtomicUsize, AtomicBool, Ordering}
 };
 use std::time::Duration;
 use std::thread;
 
-use queen::{Socket, Hook, Switch, Slot};
+use queen::{Socket, Hook, ShardHook, Switch, Slot};
+use queen::socket::SocketOptions;
 use queen::nson::{msg, MessageId, Message};
 use queen::dict::*;
 use queen::error::Code;
 
 #[test]
+#[allow(clippy::needless_return, clippy::bool_comparison)]
 fn test_hook() {
     #[derive(Clone)]
     struct MyHook {
@@ -219,3 +221,401 @@ fn test_hook() {
 
     assert!(hook.run() == false);
 }
+
+#[test]
+fn test_hook_chain() {
+    #[derive(Clone)]
+    struct CountHook {
+        recvs: Arc<AtomicUsize>
+    }
+
+    impl Hook for CountHook {
+        fn recv(&self, _: &Slot, message: &mut Message) -> bool {
+            self.recvs.fetch_add(1, Ordering::SeqCst);
+            message.insert("count", self.recvs.load(Ordering::SeqCst) as u32);
+
+            true
+        }
+
+        fn attach(&self, _: &Slot, message: &mut Message, _chan: &str) -> bool {
+            message.insert("step", 1);
+
+            true
+        }
+    }
+
+    struct AuthHook;
+
+    impl Hook for AuthHook {
+        fn recv(&self, _: &Slot, message: &mut Message) -> bool {
+            // 能看到前一个 Hook 的修改
+            message.get_u32("count").is_ok() && message.get_str(CHAN) != Ok("aaa")
+        }
+
+        fn attach(&self, _: &Slot, message: &mut Message, chan: &str) -> bool {
+            if chan == "123" {
+                return false
+            }
+
+            let step = message.get_i32("step").unwrap();
+            message.insert("step", step + 1);
+
+            true
+        }
+    }
+
+    struct DenyHook {
+        attachs: Arc<AtomicUsize>
+    }
+
+    impl Hook for DenyHook {
+        fn attach(&self, _: &Slot, message: &mut Message, _chan: &str) -> bool {
+            self.attachs.fetch_add(1, Ordering::SeqCst);
+
+            let step = message.get_i32("step").unwrap();
+            message.insert("step", step + 1);
+
+            true
+        }
+    }
+
+    let count = CountHook { recvs: Arc::new(AtomicUsize::new(0)) };
+    let attachs = Arc::new(AtomicUsize::new(0));
+
+    let socket = Socket::new(
+        MessageId::new(),
+        (count.clone(), AuthHook, DenyHook { attachs: attachs.clone() })
+    ).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = wire1.send(msg!{
+        CHAN: "aaa"
+    });
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::PermissionDenied));
+    assert!(recv.get_u32("count").unwrap() == 1);
+
+    // 第二个 Hook 拒绝后，第三个 Hook 不会被调用
+    let _ = wire1.send(msg!{
+        CHAN: ATTACH,
+        VALUE: "123"
+    });
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::PermissionDenied));
+    assert!(attachs.load(Ordering::SeqCst) == 0);
+
+    let _ = wire1.send(msg!{
+        CHAN: ATTACH,
+        VALUE: "456"
+    });
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_i32(CODE).unwrap() == 0);
+    assert!(recv.get_i32("step").unwrap() == 3);
+    assert!(attachs.load(Ordering::SeqCst) == 1);
+
+    assert!(count.recvs.load(Ordering::SeqCst) == 3);
+}
+
+#[test]
+fn test_hook_push_isolated() {
+    struct PushHook;
+
+    impl Hook for PushHook {
+        fn push(&self, slot: &Slot, message: &mut Message) -> bool {
+            // 每个接收者的修改互不影响
+            if message.contains_key("to") {
+                return false
+            }
+
+            message.insert("to", slot.id);
+
+            true
+        }
+    }
+
+    let socket = Socket::new(MessageId::new(), PushHook).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+    let wire3 = socket.connect(msg!{}, None, None).unwrap();
+
+    for wire in &[&wire1, &wire2] {
+        let _ = wire.send(msg!{
+            CHAN: ATTACH,
+            VALUE: "aaa"
+        });
+
+        let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(recv.get_i32(CODE).unwrap() == 0);
+    }
+
+    let _ = wire3.send(msg!{
+        CHAN: "aaa",
+        "hello": "world"
+    });
+
+    for wire in &[&wire1, &wire2] {
+        let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(recv.get_str("hello").unwrap() == "world");
+        assert!(recv.get_message_id("to").unwrap() == wire.attr().get_message_id(SLOT_ID).unwrap());
+    }
+}
+
+#[test]
+fn test_hook_immutable() {
+    // 只统计，不修改消息，开启共享
+    #[derive(Clone)]
+    struct CountHook(Arc<AtomicUsize>);
+
+    impl Hook for CountHook {
+        fn push(&self, _: &Slot, _: &mut Message) -> bool {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            true
+        }
+
+        fn immutable(&self) -> bool { true }
+    }
+
+    // 没有开启共享的 Hook
+    struct PlainHook;
+
+    impl Hook for PlainHook {}
+
+    fn shared(socket: &Socket) -> bool {
+        let wire1 = socket.connect(msg!{}, None, None).unwrap();
+        let wire2 = socket.connect(msg!{}, None, None).unwrap();
+        let wire3 = socket.connect(msg!{}, None, None).unwrap();
+
+        for wire in &[&wire1, &wire2] {
+            let _ = wire.send(msg!{
+                CHAN: ATTACH,
+                VALUE: "aaa"
+            });
+
+            let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+            assert!(recv.get_i32(CODE).unwrap() == 0);
+        }
+
+        let _ = wire3.send(msg!{
+            CHAN: "aaa",
+            "hello": "world"
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let recv1 = wire1.recv_shared().unwrap();
+        let recv2 = wire2.recv_shared().unwrap();
+
+        assert!(recv1.get_str("hello").unwrap() == "world");
+        assert!(recv2.get_str("hello").unwrap() == "world");
+
+        Arc::ptr_eq(&recv1, &recv2)
+    }
+
+    let count = Arc::new(AtomicUsize::new(0));
+
+    // 默认不共享
+    let socket = Socket::new(MessageId::new(), PlainHook).unwrap();
+    assert!(!shared(&socket));
+
+    // 开启后所有接收者共享同一份消息，push 依然对每个接收者调用
+    let socket = Socket::new(MessageId::new(), CountHook(count.clone())).unwrap();
+    assert!(shared(&socket));
+    assert!(count.load(Ordering::SeqCst) == 2);
+
+    // 元组中有一个未开启时不共享
+    let socket = Socket::new(MessageId::new(), (CountHook(count.clone()), PlainHook)).unwrap();
+    assert!(!shared(&socket));
+
+    let socket = Socket::new(MessageId::new(), (CountHook(count), ())).unwrap();
+    assert!(shared(&socket));
+}
+
+#[test]
+fn test_hook_will() {
+    struct WillHook;
+
+    impl Hook for WillHook {
+        fn emit(&self, _: &Slot, message: &mut Message) -> bool {
+            if message.get_bool("deny").unwrap_or(false) {
+                return false
+            }
+
+            message.insert("checked", true);
+
+            true
+        }
+    }
+
+    let socket = Socket::new(MessageId::new(), WillHook).unwrap();
+
+    let observer = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = observer.send(msg!{CHAN: ATTACH, VALUE: "status"});
+    assert!(observer.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    // 遗嘱消息也经过 Hook::emit
+    let wire = socket.connect(msg!{WILL: {CHAN: "status", "deny": true}}, None, None).unwrap();
+    drop(wire);
+
+    assert!(observer.wait(Some(Duration::from_millis(200))).is_err());
+
+    let wire = socket.connect(msg!{WILL: {CHAN: "status"}}, None, None).unwrap();
+    drop(wire);
+
+    let recv = observer.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_bool("checked").unwrap());
+}
+
+#[test]
+fn test_hook_update() {
+    struct UpdateHook;
+
+    impl Hook for UpdateHook {
+        fn update(&self, _: &Slot, message: &mut Message) -> bool {
+            if let Ok(attr) = message.get_message(ATTR) {
+                if attr.contains_key("role") {
+                    return false
+                }
+            }
+
+            message.insert("checked", true);
+
+            true
+        }
+    }
+
+    let socket = Socket::new(MessageId::new(), UpdateHook).unwrap();
+
+    let wire = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = wire.send(msg!{CHAN: UPDATE, ATTR: {"role": "admin"}});
+    assert!(Code::get(&wire.wait(Some(Duration::from_secs(1))).unwrap()) == Some(Code::PermissionDenied));
+    assert!(wire.attr().get("role").is_none());
+
+    let _ = wire.send(msg!{CHAN: UPDATE, ATTR: {"name": "a"}});
+    let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_i32(CODE).unwrap() == 0);
+    assert!(recv.get_bool("checked").unwrap());
+    assert!(wire.attr().get_str("name").unwrap() == "a");
+}
+
+#[test]
+fn test_hook_share_chans() {
+    // 通过 CUSTOM 查看 Slot 和 Switch 的共享订阅
+    struct ShareHook;
+
+    impl Hook for ShareHook {
+        fn custom(&self, switch: &Switch, token: usize, message: &mut Message) {
+            let slot = &switch.slots[token];
+
+            message.insert("share_chans", slot.share_chans.len() as u32);
+            message.insert("has_aaa", slot.share_chans.contains("aaa"));
+
+            if let Some(group) = slot.share_group("aaa") {
+                message.insert("group", group);
+            }
+
+            message.insert("groups", slot.share_groups().len() as u32);
+
+            let tokens = switch.share_chans.get("aaa").map(|ids| ids.len()).unwrap_or(0);
+            message.insert("tokens", tokens as u32);
+
+            let group_tokens = switch.share_groups().get("aaa")
+                .and_then(|groups| groups.get("g1"))
+                .map(|ids| ids.len())
+                .unwrap_or(0);
+            message.insert("group_tokens", group_tokens as u32);
+
+            Code::Ok.set(message);
+        }
+    }
+
+    let socket = Socket::new(MessageId::new(), ShareHook).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+
+    for (wire, group) in [(&wire1, "g1"), (&wire2, "g2")] {
+        let _ = wire.send(msg!{
+            CHAN: ATTACH,
+            VALUE: "aaa",
+            SHARE: group
+        });
+
+        assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+    }
+
+    let _ = wire1.send(msg!{CHAN: CUSTOM});
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_u32("share_chans").unwrap() == 1);
+    assert!(recv.get_bool("has_aaa").unwrap());
+    assert!(recv.get_str("group").unwrap() == "g1");
+    assert!(recv.get_u32("groups").unwrap() == 1);
+    assert!(recv.get_u32("tokens").unwrap() == 2);
+    assert!(recv.get_u32("group_tokens").unwrap() == 1);
+
+    // 取消共享订阅后同步移除
+    let _ = wire1.send(msg!{
+        CHAN: DETACH,
+        VALUE: "aaa",
+        SHARE: true
+    });
+
+    assert!(wire1.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let _ = wire1.send(msg!{CHAN: CUSTOM});
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_u32("share_chans").unwrap() == 0);
+    assert!(recv.get("group").is_none());
+    assert!(recv.get_u32("tokens").unwrap() == 1);
+    assert!(recv.get_u32("group_tokens").unwrap() == 0);
+}
+
+#[test]
+fn test_hook_shard() {
+    #[derive(Clone)]
+    struct MyHook {
+        index: usize,
+        stopped: Arc<Mutex<Vec<(usize, usize)>>>
+    }
+
+    impl Hook for MyHook {
+        fn stop(&self, switch: &Switch) {
+            self.stopped.lock().unwrap().push((self.index, switch.slots.len()));
+        }
+    }
+
+    impl ShardHook for MyHook {
+        fn shard(&mut self, index: usize, _shards: usize) {
+            self.index = index;
+        }
+    }
+
+    let stopped = Arc::new(Mutex::new(Vec::new()));
+
+    let hook = MyHook { index: usize::MAX, stopped: stopped.clone() };
+
+    let socket = Socket::sharded(MessageId::new(), SocketOptions::default(), 4, hook).unwrap();
+
+    // 轮流分配到各个分片
+    let wires: Vec<_> = (0..4).map(|_| socket.connect(msg!{}, None, None).unwrap()).collect();
+
+    socket.stop();
+
+    thread::sleep(Duration::from_millis(200));
+
+    // 每个分片调用一次 stop，只能看到本分片的 SLOT
+    let mut stopped = stopped.lock().unwrap().clone();
+    stopped.sort();
+
+    assert!(stopped == vec![(0, 1), (1, 1), (2, 1), (3, 1)]);
+
+    drop(wires);
+}
@@ -1,9 +1,15 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::sync::atomic::Ordering;
 
 use queen::{Socket, Node, Port, Wire};
-use queen::node::Hook;
+use queen::node::{Hook, NodeOptions, Connector};
 use queen::nson::{MessageId, msg, Message};
-use queen::net::{CryptoOptions, NsonCodec, KeepAlive};
+use queen::socket::SocketOptions;
+use queen::error::{Error, Code};
+use queen::net::{CryptoOptions, NsonCodec, KeepAlive, Codec, FrameReader};
 use queen::crypto::Method;
 use queen::dict::*;
 
@@ -222,3 +228,696 @@ fn port_secure2() {
     let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
     assert!(recv.get_i32(CODE).unwrap() == 0);
 }
+
+#[test]
+fn port_hook_chain() {
+    // start node
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let addr = get_free_addr();
+
+    struct SecureHook;
+
+    impl Hook for SecureHook {
+        fn enable_secure(&self) -> bool {
+            true
+        }
+
+        fn start(&self, _slot_id: MessageId, message: &mut Message) -> bool {
+            message.insert("lalala", 123);
+
+            true
+        }
+    }
+
+    struct AccessHook;
+
+    impl Hook for AccessHook {
+        fn start(&self, _slot_id: MessageId, message: &mut Message) -> bool {
+            message.get_i32("lalala") == Ok(123)
+        }
+
+        fn access(&self, _slot_id: MessageId, message: &mut Message) -> Option<String> {
+            if let Ok(access) = message.get_str(ACCESS) {
+                if access == "12d3eaf5e9effffb14fb213e" {
+                    return Some("99557df09590ad6043ceefd1".to_string())
+                }
+            }
+
+            None
+        }
+    }
+
+    let _node = Node::<NsonCodec>::new(
+        socket.clone(),
+        2,
+        vec![addr.parse().unwrap()],
+        KeepAlive::default(),
+        (SecureHook, AccessHook)
+    ).unwrap();
+
+    // start port
+    let port = Port::<NsonCodec>::new(KeepAlive::default()).unwrap();
+
+    let crypto_options = CryptoOptions {
+        method: Method::Aes128Gcm,
+        secret: "99557df09590ad6043ceefd1".to_string()
+    };
+
+    let attr = msg!{
+        ACCESS: "12d3eaf5e9effffb14fb213e"
+    };
+
+    let wire1 = port.connect(addr.clone(), attr, Some(crypto_options), None).unwrap();
+    assert!(wire1.attr().get_i32("lalala").unwrap() == 123);
+
+    let _ = wire1.send(msg!{
+        CHAN: PING
+    });
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_i32(CODE).unwrap() == 0);
+
+    // 没有 ACCESS，两个 Hook 都不返回密钥
+    let wire2 = port.connect(addr, msg!{}, None, None);
+    assert!(wire2.is_err());
+}
+
+#[test]
+fn port_expire() {
+    // start node
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let addr = get_free_addr();
+
+    let _node = Node::<NsonCodec>::new(
+        socket.clone(),
+        2,
+        vec![addr.parse().unwrap()],
+        KeepAlive::default(),
+        ()
+    ).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = wire1.send(msg!{
+        CHAN: ATTACH,
+        VALUE: "hello"
+    });
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_i32(CODE).unwrap() == 0);
+
+    // start port
+    let port = Port::<NsonCodec>::new(KeepAlive::default()).unwrap();
+
+    let wire2 = port.connect(addr, msg!{}, None, None).unwrap();
+
+    // 已过期的消息不会投递给订阅者
+    let _ = wire2.send(msg!{
+        CHAN: "hello",
+        EXPIRE: 1000i64,
+        "hello": "world"
+    });
+
+    let _ = wire2.send(msg!{
+        CHAN: "hello",
+        TTL: 1000,
+        "hello": "world2"
+    });
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_str("hello").unwrap() == "world2");
+
+    assert!(port.metrics().expired.load(Ordering::Relaxed) == 1);
+}
+
+#[test]
+fn port_broadcast() {
+    // start node
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let addr = get_free_addr();
+
+    let _node = Node::<NsonCodec>::new(
+        socket.clone(),
+        2,
+        vec![addr.parse().unwrap()],
+        KeepAlive::default(),
+        ()
+    ).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+
+    // start port
+    let port = Port::<NsonCodec>::new(KeepAlive::default()).unwrap();
+
+    // 未加密的连接共用编码结果
+    let wires = vec![
+        port.connect(addr.clone(), msg!{}, None, None).unwrap(),
+        port.connect(addr.clone(), msg!{}, None, None).unwrap(),
+        port.connect(addr, msg!{}, None, None).unwrap()
+    ];
+
+    for wire in &wires {
+        let _ = wire.send(msg!{
+            CHAN: ATTACH,
+            VALUE: "hello"
+        });
+
+        let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(recv.get_i32(CODE).unwrap() == 0);
+    }
+
+    for i in 0..10 {
+        let _ = wire1.send(msg!{
+            CHAN: "hello",
+            "hello": i
+        });
+    }
+
+    for wire in &wires {
+        for i in 0..10 {
+            let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+            assert!(recv.get_i32("hello").unwrap() == i);
+        }
+    }
+}
+
+#[test]
+fn port_batch_write() {
+    // start node
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let addr = get_free_addr();
+
+    let node = Node::<NsonCodec>::new(
+        socket.clone(),
+        2,
+        vec![addr.parse().unwrap()],
+        KeepAlive::default(),
+        ()
+    ).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+
+    // start port
+    let port = Port::<NsonCodec>::new(KeepAlive::default()).unwrap();
+
+    let wire2 = port.connect(addr, msg!{}, None, None).unwrap();
+
+    let _ = wire2.send(msg!{
+        CHAN: ATTACH,
+        VALUE: "hello"
+    });
+
+    let recv = wire2.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_i32(CODE).unwrap() == 0);
+
+    for i in 0..50 {
+        let _ = wire1.send(msg!{
+            CHAN: "hello",
+            "hello": i
+        });
+    }
+
+    for i in 0..50 {
+        let recv = wire2.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(recv.get_i32("hello").unwrap() == i);
+    }
+
+    let metrics = node.metrics();
+    assert!(metrics.write_messages.load(Ordering::Relaxed) >= 51);
+    assert!(metrics.writes_per_message() <= 1.0);
+}
+
+#[test]
+fn node_handshake() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let addr = get_free_addr();
+
+    let options = NodeOptions {
+        max_handshakes: 2,
+        handshake_timeout: 500,
+        ..NodeOptions::default()
+    };
+
+    let node = Node::<NsonCodec>::with_options(
+        socket.clone(),
+        2,
+        vec![addr.parse().unwrap()],
+        options,
+        ()
+    ).unwrap();
+
+    // 不发送握手消息的连接
+    let mut slow = TcpStream::connect(&addr).unwrap();
+    slow.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+
+    thread::sleep(Duration::from_millis(50));
+
+    // 慢连接不会阻塞其他连接的握手
+    let port = Port::<NsonCodec>::new(KeepAlive::default()).unwrap();
+
+    let now = Instant::now();
+    let wire = port.connect(&addr, msg!{}, None, None).unwrap();
+    assert!(now.elapsed() < Duration::from_millis(400));
+
+    let _ = wire.send(msg!{
+        CHAN: PING
+    });
+
+    let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_i32(CODE).unwrap() == 0);
+
+    // 超过进行中的握手数上限，直接关闭
+    let _slow2 = TcpStream::connect(&addr).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    let mut slow3 = TcpStream::connect(&addr).unwrap();
+    slow3.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+    let mut buf = [0u8; 16];
+    assert!(matches!(slow3.read(&mut buf), Ok(0) | Err(_)));
+
+    // 超时后关闭
+    let now = Instant::now();
+    assert!(matches!(slow.read(&mut buf), Ok(0) | Err(_)));
+    assert!(now.elapsed() < Duration::from_millis(1000));
+
+    // 等待第二个慢连接超时
+    thread::sleep(Duration::from_millis(400));
+
+    let metrics = node.metrics();
+    assert!(metrics.handshakes.load(Ordering::Relaxed) == 1);
+    assert!(metrics.handshake_timeouts.load(Ordering::Relaxed) == 2);
+    assert!(metrics.handshake_rejects.load(Ordering::Relaxed) == 1);
+    assert!(metrics.handshake_aborts() == 3);
+}
+
+#[test]
+fn node_handshake_pipelined() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let addr = get_free_addr();
+
+    let _node = Node::<NsonCodec>::new(
+        socket.clone(),
+        2,
+        vec![addr.parse().unwrap()],
+        KeepAlive::default(),
+        ()
+    ).unwrap();
+
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+
+    // 握手消息之后紧跟着的消息，一次写入
+    let mut codec = NsonCodec::new();
+
+    let mut bytes = codec.encode(&None, msg!{CHAN: HAND}).unwrap();
+    bytes.extend(codec.encode(&None, msg!{CHAN: PING, "n": 1}).unwrap());
+
+    stream.write_all(&bytes).unwrap();
+
+    let mut reader = FrameReader::new(1024 * 1024);
+    let mut recv = vec![];
+
+    while recv.len() < 2 {
+        match reader.next_frame().unwrap() {
+            Some(frame) => recv.push(codec.decode(&None, frame).unwrap()),
+            None => assert!(reader.read_from(&mut stream).unwrap() > 0)
+        }
+    }
+
+    assert!(recv[0].get_str(CHAN).unwrap() == HAND);
+    assert!(recv[0].get_i32(CODE).unwrap() == 0);
+    assert!(recv[1].get_str(CHAN).unwrap() == PING);
+    assert!(recv[1].get_i32(CODE).unwrap() == 0);
+    assert!(recv[1].get_i32("n").unwrap() == 1);
+}
+
+#[test]
+fn node_handshake_nonblocking() {
+    // 携带 slow 属性时，连接结果延迟返回
+    struct SlowConnector(Socket);
+
+    impl Connector for SlowConnector {
+        fn connect(
+            &self,
+            attr: Message,
+            capacity: Option<usize>,
+            timeout: Option<Duration>
+        ) -> queen::error::Result<Wire<Message>> {
+            self.0.connect(attr, capacity, timeout)
+        }
+
+        fn open(
+            &self,
+            attr: Message,
+            capacity: Option<usize>
+        ) -> queen::error::Result<Wire<Message>> {
+            if !attr.get_bool("slow").unwrap_or(false) {
+                return self.0.open(attr, capacity)
+            }
+
+            let (wire1, wire2) = Wire::pipe(capacity.unwrap_or(64), attr)?;
+
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(500));
+
+                let _ = wire1.send(msg!{CODE: Code::Ok.code()});
+
+                thread::sleep(Duration::from_millis(500));
+            });
+
+            Ok(wire2)
+        }
+
+        fn running(&self) -> bool {
+            self.0.running()
+        }
+    }
+
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let addr = get_free_addr();
+
+    let _node = Node::<NsonCodec>::new(
+        SlowConnector(socket.clone()),
+        2,
+        vec![addr.parse().unwrap()],
+        KeepAlive::default(),
+        ()
+    ).unwrap();
+
+    thread::sleep(Duration::from_secs(1));
+
+    let addr2 = addr.clone();
+
+    let handle = thread::spawn(move || {
+        let port = Port::<NsonCodec>::new(KeepAlive::default()).unwrap();
+
+        let now = Instant::now();
+        let ret = port.connect(&addr2, msg!{"slow": true}, None, None);
+
+        (ret.is_ok(), now.elapsed())
+    });
+
+    thread::sleep(Duration::from_millis(100));
+
+    // 等待结果的握手不会阻塞其他连接的握手
+    let port = Port::<NsonCodec>::new(KeepAlive::default()).unwrap();
+
+    let now = Instant::now();
+    let wire = port.connect(&addr, msg!{}, None, None).unwrap();
+    assert!(now.elapsed() < Duration::from_millis(300));
+
+    let _ = wire.send(msg!{
+        CHAN: PING
+    });
+
+    let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_i32(CODE).unwrap() == 0);
+
+    let (ok, elapsed) = handle.join().unwrap();
+    assert!(ok);
+    assert!(elapsed >= Duration::from_millis(400));
+}
+
+#[test]
+fn node_limits() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let addr = get_free_addr();
+
+    let options = NodeOptions {
+        max_conns_per_ip: Some(2),
+        ..NodeOptions::default()
+    };
+
+    let node = Node::<NsonCodec>::with_options(
+        socket,
+        2,
+        vec![addr.parse().unwrap()],
+        options,
+        ()
+    ).unwrap();
+
+    let port = Port::<NsonCodec>::new(KeepAlive::default()).unwrap();
+
+    let wire1 = port.connect(&addr, msg!{}, None, None).unwrap();
+    let wire2 = port.connect(&addr, msg!{}, None, None).unwrap();
+    assert!(port.connect(&addr, msg!{}, None, None).is_err());
+
+    assert!(node.connections() == 2);
+    assert!(node.connections_of("127.0.0.1".parse().unwrap()) == 2);
+    assert!(node.metrics().limit_rejects.load(Ordering::Relaxed) == 1);
+
+    // 连接关闭后释放
+    drop(wire1);
+    drop(wire2);
+
+    for _ in 0..100 {
+        if node.connections() == 0 {
+            break
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert!(node.connections() == 0);
+
+    let _wire = port.connect(&addr, msg!{}, None, None).unwrap();
+}
+
+#[test]
+fn node_ban() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let addr = get_free_addr();
+
+    struct MyHook;
+
+    impl Hook for MyHook {
+        fn start(&self, _slot_id: MessageId, message: &mut Message) -> bool {
+            message.get_str(ACCESS).is_ok()
+        }
+    }
+
+    let options = NodeOptions {
+        ban_after: Some(2),
+        ban_duration: 60 * 1000,
+        ..NodeOptions::default()
+    };
+
+    let node = Node::<NsonCodec>::with_options(
+        socket,
+        2,
+        vec![addr.parse().unwrap()],
+        options,
+        MyHook
+    ).unwrap();
+
+    let port = Port::<NsonCodec>::new(KeepAlive::default()).unwrap();
+
+    for _ in 0..2 {
+        let ret = port.connect(&addr, msg!{}, None, None);
+        assert!(matches!(ret, Err(Error::ErrorCode(Code::AuthenticationFailed))));
+    }
+
+    // 被封禁后直接关闭，即使携带了正确的数据
+    let ret = port.connect(&addr, msg!{ACCESS: "a"}, None, None);
+    assert!(matches!(ret, Err(Error::IoError(_))));
+
+    let bans = node.bans();
+    assert!(bans.len() == 1);
+    assert!(bans[0].0 == "127.0.0.1".parse::<std::net::IpAddr>().unwrap());
+
+    let metrics = node.metrics();
+    assert!(metrics.bans.load(Ordering::Relaxed) == 1);
+    assert!(metrics.ban_rejects.load(Ordering::Relaxed) == 1);
+
+    node.unban("127.0.0.1".parse().unwrap());
+    assert!(node.bans().is_empty());
+
+    let _wire = port.connect(&addr, msg!{ACCESS: "a"}, None, None).unwrap();
+}
+
+#[test]
+fn node_deny_and_rate() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let port = Port::<NsonCodec>::new(KeepAlive::default()).unwrap();
+
+    // 拒绝列表
+    let addr = get_free_addr();
+
+    let options = NodeOptions {
+        allow: vec!["127.0.0.0/8".parse().unwrap()],
+        deny: vec!["127.0.0.1".parse().unwrap()],
+        ..NodeOptions::default()
+    };
+
+    let node = Node::<NsonCodec>::with_options(
+        socket.clone(),
+        1,
+        vec![addr.parse().unwrap()],
+        options,
+        ()
+    ).unwrap();
+
+    assert!(port.connect(&addr, msg!{}, None, None).is_err());
+    assert!(node.metrics().deny_rejects.load(Ordering::Relaxed) == 1);
+
+    // 速率为 0 时只能接受 accept_burst 个连接
+    let addr = get_free_addr();
+
+    let options = NodeOptions {
+        accept_rate: Some(0),
+        accept_burst: 2,
+        ..NodeOptions::default()
+    };
+
+    let node = Node::<NsonCodec>::with_options(
+        socket,
+        1,
+        vec![addr.parse().unwrap()],
+        options,
+        ()
+    ).unwrap();
+
+    let _wire1 = port.connect(&addr, msg!{}, None, None).unwrap();
+    let _wire2 = port.connect(&addr, msg!{}, None, None).unwrap();
+    assert!(port.connect(&addr, msg!{}, None, None).is_err());
+    assert!(node.metrics().rate_rejects.load(Ordering::Relaxed) == 1);
+}
+
+#[test]
+fn port_expired_dead_letter() {
+    let mut options = SocketOptions::new();
+    options.dead_letter = Some("dead".to_string());
+
+    let socket = Socket::with_options(MessageId::new(), options, ()).unwrap();
+
+    let addr = get_free_addr();
+
+    let _node = Node::<NsonCodec>::new(
+        socket.clone(),
+        2,
+        vec![addr.parse().unwrap()],
+        KeepAlive::default(),
+        ()
+    ).unwrap();
+
+    let dead = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = dead.send(msg!{CHAN: ATTACH, VALUE: "dead"});
+    assert!(dead.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let port = Port::<NsonCodec>::new(KeepAlive::default()).unwrap();
+
+    let slot_id = MessageId::new();
+    let wire = port.connect(addr, msg!{SLOT_ID: slot_id}, None, None).unwrap();
+
+    // 发送前已过期的消息，由 Node 的 Socket 放入死信频道
+    let _ = wire.send(msg!{
+        CHAN: "hello",
+        EXPIRE: 1000i64,
+        "hello": "world"
+    });
+
+    let recv = dead.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::MessageExpired));
+    assert!(recv.get_str(ORIGIN).unwrap() == "hello");
+    assert!(recv.get_message_id(SLOT_ID).unwrap() == &slot_id);
+    assert!(recv.get(TO).is_none());
+    assert!(recv.get_message(VALUE).unwrap().get_str("hello").unwrap() == "world");
+
+    assert!(port.metrics().expired.load(Ordering::Relaxed) == 1);
+}
+
+#[test]
+fn node_expired_dead_letter() {
+    let mut options = SocketOptions::new();
+    options.dead_letter = Some("dead".to_string());
+
+    let socket = Socket::with_options(MessageId::new(), options, ()).unwrap();
+
+    let addr = get_free_addr();
+
+    let node = Node::<NsonCodec>::new(
+        socket.clone(),
+        2,
+        vec![addr.parse().unwrap()],
+        KeepAlive::default(),
+        ()
+    ).unwrap();
+
+    let dead = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = dead.send(msg!{CHAN: ATTACH, VALUE: "dead"});
+    assert!(dead.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    thread::sleep(Duration::from_millis(100));
+
+    let slot_id = MessageId::new();
+
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+
+    let mut codec = NsonCodec::new();
+
+    let mut bytes = codec.encode(&None, msg!{CHAN: HAND, SLOT_ID: slot_id}).unwrap();
+    bytes.extend(codec.encode(&None, msg!{CHAN: ATTACH, VALUE: "big"}).unwrap());
+
+    stream.write_all(&bytes).unwrap();
+
+    let mut reader = FrameReader::new(2 * 1024 * 1024);
+
+    let mut read = |count: usize| {
+        let mut recv = vec![];
+
+        while recv.len() < count {
+            match reader.next_frame().unwrap() {
+                Some(frame) => recv.push(codec.decode(&None, frame).unwrap()),
+                None => assert!(reader.read_from(&mut stream).unwrap() > 0)
+            }
+        }
+
+        recv
+    };
+
+    let recv = read(2);
+    assert!(recv[1].get_str(CHAN).unwrap() == ATTACH);
+    assert!(recv[1].get_i32(CODE).unwrap() == 0);
+
+    // 不读取连接，直到写满，之后的消息在 Wire 中等待
+    let sender = socket.connect(msg!{}, None, None).unwrap();
+
+    let payload = vec![0u8; 1024 * 1024];
+
+    for _ in 0..32 {
+        let _ = sender.send(msg!{CHAN: "big", "payload": payload.clone()});
+    }
+
+    let _ = sender.send(msg!{CHAN: "big", TTL: 100, "last": true});
+
+    thread::sleep(Duration::from_millis(300));
+
+    let recv = read(32);
+    assert!(recv.iter().all(|message| !message.contains_key("last")));
+
+    // 等待期间过期的消息，交回给 Socket 放入死信频道
+    let recv = dead.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::MessageExpired));
+    assert!(recv.get_str(ORIGIN).unwrap() == "big");
+    assert!(recv.get_message_id(SLOT_ID).unwrap() == sender.attr().get_message_id(SLOT_ID).unwrap());
+    assert!(recv.get_message_id(TO).unwrap() == &slot_id);
+    assert!(recv.get_message(VALUE).unwrap().get_bool("last").unwrap());
+
+    assert!(node.metrics().expired.load(Ordering::Relaxed) == 1);
+}
@@ -1,10 +1,15 @@
 use std::time::Duration;
+use std::io::{Read, Write, Cursor};
+use std::thread;
+use std::sync::mpsc;
 
-use nson::{msg, MessageId};
+use nson::{msg, MessageId, Value};
 
 use queen::{Socket, Hook, Slot};
+use queen::socket::{SocketOptions, SlotLimits, Strategy};
 use queen::dict::*;
 use queen::error::{Code, Error, RecvError};
+use queen::transfer::{self, TransferOptions};
 
 #[test]
 fn conn() {
@@ -646,7 +651,7 @@ fn mine() {
     let value = recv.get_message(VALUE).unwrap();
 
     assert!(value.get_array(CHANS).unwrap().is_empty());
-    assert!(value.is_null(SLOT_ID) == false);
+    assert!(!value.is_null(SLOT_ID));
     assert!(value.get_u64(SEND_NUM).unwrap() == 1);
     assert!(value.get_u64(RECV_NUM).unwrap() == 1);
     assert!(value.get_bool(JOINED).is_ok());
@@ -957,3 +962,2259 @@ fn slot_tags() {
     assert!(recv.get_str("hello").unwrap() == "world");
     assert!(recv.get_message_id(FROM).is_ok());
 }
+
+#[test]
+fn message_ttl() {
+    let mut options = SocketOptions::new();
+    options.dead_letter = Some("dead".to_string());
+
+    let socket = Socket::with_options(MessageId::new(), options, ()).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+    let wire3 = socket.connect(msg!{}, None, None).unwrap();
+
+    // attach
+    let _ = wire1.send(msg!{
+        CHAN: ATTACH,
+        VALUE: "aaa"
+    });
+
+    assert!(wire1.wait(Some(Duration::from_millis(100))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let _ = wire3.send(msg!{
+        CHAN: ATTACH,
+        VALUE: "dead"
+    });
+
+    assert!(wire3.wait(Some(Duration::from_millis(100))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    // invalid ttl
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        TTL: "abc"
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::InvalidTtlFieldType));
+
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        EXPIRE: "abc"
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::InvalidExpireFieldType));
+
+    // ttl
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        TTL: 1000,
+        "hello": "world"
+    });
+
+    let recv = wire1.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(recv.get_str("hello").unwrap() == "world");
+    assert!(recv.get_i64(EXPIRE).is_ok());
+    assert!(recv.get(TTL).is_none());
+
+    // expired
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        EXPIRE: 1000i64,
+        "hello": "world"
+    });
+
+    assert!(wire1.wait(Some(Duration::from_millis(100))) == Err(RecvError::TimedOut));
+
+    let recv = wire3.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(recv.get_str(CHAN).unwrap() == "dead");
+    assert!(Code::get(&recv) == Some(Code::MessageExpired));
+    assert!(recv.get_message_id(SLOT_ID).unwrap() == wire2.attr().get_message_id(SLOT_ID).unwrap());
+
+    let value = recv.get_message(VALUE).unwrap();
+    assert!(value.get_str(CHAN).unwrap() == "aaa");
+    assert!(value.get_str("hello").unwrap() == "world");
+
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        TTL: 0,
+        "hello": "world"
+    });
+
+    assert!(wire1.wait(Some(Duration::from_millis(100))) == Err(RecvError::TimedOut));
+    assert!(Code::get(&wire3.wait(Some(Duration::from_millis(100))).unwrap()) == Some(Code::MessageExpired));
+}
+
+#[test]
+fn delay_message() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = wire1.send(msg!{
+        CHAN: ATTACH,
+        VALUE: "aaa"
+    });
+
+    assert!(wire1.wait(Some(Duration::from_millis(100))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    // invalid delay
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        DELAY: "abc"
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::InvalidDelayFieldType));
+
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        TIME: "abc"
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::InvalidTimeFieldType));
+
+    // delay
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        DELAY: 300,
+        "hello": "world"
+    });
+
+    assert!(wire1.wait(Some(Duration::from_millis(200))) == Err(RecvError::TimedOut));
+
+    let recv = wire1.wait(Some(Duration::from_millis(300))).unwrap();
+    assert!(recv.get_str("hello").unwrap() == "world");
+    assert!(recv.get_message_id(ID).is_ok());
+    assert!(recv.get(DELAY).is_none());
+
+    // time
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        TIME: now + 200,
+        "hello": "world2"
+    });
+
+    let recv = wire1.wait(Some(Duration::from_millis(500))).unwrap();
+    assert!(recv.get_str("hello").unwrap() == "world2");
+
+    // list and cancel
+    let id = MessageId::new();
+
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        ID: id,
+        DELAY: 300,
+        "hello": "world3"
+    });
+
+    let _ = wire2.send(msg!{
+        CHAN: SCHEDULE
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(recv.get_i32(CODE).unwrap() == 0);
+    let list = recv.get_array(VALUE).unwrap();
+    assert!(list.len() == 1);
+    let item = list[0].as_message().unwrap();
+    assert!(item.get_message_id(ID).unwrap() == &id);
+    assert!(item.get_str(CHAN).unwrap() == "aaa");
+
+    // 不能取消别人的消息
+    let _ = wire1.send(msg!{
+        CHAN: CANCEL,
+        VALUE: id
+    });
+
+    let recv = wire1.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::PermissionDenied));
+
+    let _ = wire2.send(msg!{
+        CHAN: CANCEL,
+        VALUE: id
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(recv.get_i32(CODE).unwrap() == 0);
+
+    let _ = wire2.send(msg!{
+        CHAN: CANCEL,
+        VALUE: id
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::NotFound));
+
+    assert!(wire1.wait(Some(Duration::from_millis(400))) == Err(RecvError::TimedOut));
+
+    // 发送者断开后，消息依然会投递
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        DELAY: 200,
+        "hello": "world4"
+    });
+
+    drop(wire2);
+
+    let recv = wire1.wait(Some(Duration::from_millis(500))).unwrap();
+    assert!(recv.get_str("hello").unwrap() == "world4");
+}
+
+#[test]
+fn schedule_limit() {
+    let options = SocketOptions {
+        schedule_limit: 2,
+        ..SocketOptions::default()
+    };
+
+    let socket = Socket::with_options(MessageId::new(), options, ()).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+    let wire3 = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = wire1.send(msg!{
+        CHAN: ATTACH,
+        VALUE: "aaa"
+    });
+
+    assert!(wire1.wait(Some(Duration::from_millis(100))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let id1 = MessageId::new();
+    let id2 = MessageId::new();
+
+    for id in [id1, id2] {
+        let _ = wire2.send(msg!{
+            CHAN: "aaa",
+            ID: id,
+            DELAY: 300
+        });
+    }
+
+    // 超过限制
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        DELAY: 300
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::ScheduleLimitExceeded));
+
+    // 覆盖之前的消息不占用新的数量
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        ID: id1,
+        DELAY: 300,
+        "hello": "world"
+    });
+
+    assert!(wire2.wait(Some(Duration::from_millis(100))) == Err(RecvError::TimedOut));
+
+    // 其他 SLOT 不受影响
+    let _ = wire3.send(msg!{
+        CHAN: "aaa",
+        DELAY: 100
+    });
+
+    assert!(wire3.wait(Some(Duration::from_millis(50))) == Err(RecvError::TimedOut));
+    assert!(wire1.wait(Some(Duration::from_millis(300))).is_ok());
+
+    // 取消后可以再次发送
+    let _ = wire2.send(msg!{
+        CHAN: CANCEL,
+        VALUE: id2
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(recv.get_i32(CODE).unwrap() == 0);
+
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        DELAY: 100
+    });
+
+    assert!(wire2.wait(Some(Duration::from_millis(50))) == Err(RecvError::TimedOut));
+
+    // 投递后释放数量
+    let mut count = 0;
+
+    while wire1.wait(Some(Duration::from_millis(500))).is_ok() {
+        count += 1;
+    }
+
+    assert!(count == 2);
+
+    let _ = wire2.send(msg!{
+        CHAN: SCHEDULE
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(recv.get_array(VALUE).unwrap().is_empty());
+}
+
+#[test]
+fn request_timeout() {
+    let options = SocketOptions {
+        request_timeout: 300,
+        ..SocketOptions::default()
+    };
+
+    let socket = Socket::with_options(MessageId::new(), options, ()).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+
+    let wire1_id = *wire1.attr().get_message_id(SLOT_ID).unwrap();
+    let wire2_id = *wire2.attr().get_message_id(SLOT_ID).unwrap();
+
+    // invalid
+    let _ = wire1.send(msg!{
+        CHAN: "aaa",
+        TO: wire2_id,
+        REQUEST: true
+    });
+
+    let recv = wire1.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::InvalidIdFieldType));
+
+    let _ = wire1.send(msg!{
+        CHAN: "aaa",
+        ID: MessageId::new(),
+        TO: wire2_id,
+        REQUEST: true,
+        TIMEOUT: "abc"
+    });
+
+    let recv = wire1.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::InvalidTimeoutFieldType));
+
+    // target not exist
+    let not_exist = MessageId::new();
+    let id = MessageId::new();
+
+    let _ = wire1.send(msg!{
+        CHAN: "aaa",
+        ID: id,
+        TO: not_exist,
+        REQUEST: true
+    });
+
+    let recv = wire1.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::TargetSlotIdNotExist));
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+    assert!(recv.get_message_id(FROM).unwrap() == &not_exist);
+
+    // reply
+    let id = MessageId::new();
+
+    let _ = wire1.send(msg!{
+        CHAN: "aaa",
+        ID: id,
+        TO: wire2_id,
+        REQUEST: true,
+        TIMEOUT: 100
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+
+    let _ = wire2.send(msg!{
+        CHAN: "aaa",
+        ID: id,
+        TO: wire1_id,
+        "hello": "world"
+    });
+
+    let recv = wire1.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(recv.get_str("hello").unwrap() == "world");
+
+    assert!(wire1.wait(Some(Duration::from_millis(300))) == Err(RecvError::TimedOut));
+
+    // timeout
+    let id = MessageId::new();
+
+    let _ = wire1.send(msg!{
+        CHAN: "aaa",
+        ID: id,
+        TO: wire2_id,
+        REQUEST: true
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+
+    assert!(wire1.wait(Some(Duration::from_millis(200))) == Err(RecvError::TimedOut));
+
+    let recv = wire1.wait(Some(Duration::from_millis(300))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::RequestTimeout));
+    assert!(recv.get_str(CHAN).unwrap() == "aaa");
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+    assert!(recv.get_message_id(FROM).unwrap() == &wire2_id);
+
+    // target broken
+    let id = MessageId::new();
+
+    let _ = wire1.send(msg!{
+        CHAN: "aaa",
+        ID: id,
+        TO: wire2_id,
+        REQUEST: true
+    });
+
+    let recv = wire2.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+
+    drop(wire2);
+
+    let recv = wire1.wait(Some(Duration::from_millis(100))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::TargetSlotBroken));
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+    assert!(recv.get_message_id(FROM).unwrap() == &wire2_id);
+
+    // 目标断开后不会再超时
+    assert!(wire1.wait(Some(Duration::from_millis(500))) == Err(RecvError::TimedOut));
+}
+
+#[test]
+fn request_duplicate_id() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+    let wire3 = socket.connect(msg!{}, None, None).unwrap();
+
+    let wire1_id = *wire1.attr().get_message_id(SLOT_ID).unwrap();
+    let wire2_id = *wire2.attr().get_message_id(SLOT_ID).unwrap();
+    let wire3_id = *wire3.attr().get_message_id(SLOT_ID).unwrap();
+
+    let id = MessageId::new();
+
+    let _ = wire1.send(msg!{CHAN: "aaa", ID: id, TO: wire3_id, REQUEST: true, TIMEOUT: 300});
+
+    let recv = wire3.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+
+    // 请求完成之前，请求者不能重复使用 ID
+    let _ = wire1.send(msg!{CHAN: "aaa", ID: id, TO: wire2_id, REQUEST: true});
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::DuplicateRequestId));
+    assert!(wire2.wait(Some(Duration::from_millis(100))).is_err());
+
+    // 其他请求者使用相同的 ID，目标还未回复时不会投递，也不会影响之前的请求
+    let _ = wire2.send(msg!{CHAN: "aaa", ID: id, TO: wire3_id, REQUEST: true});
+
+    let recv = wire2.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::DuplicateRequestId));
+    assert!(recv.get_message_id(FROM).unwrap() == &wire3_id);
+    assert!(wire3.wait(Some(Duration::from_millis(100))).is_err());
+
+    let _ = wire3.send(msg!{CHAN: "aaa", ID: id, TO: wire1_id, "n": 1});
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_i32("n").unwrap() == 1);
+
+    // 已经回复，不会超时
+    assert!(wire1.wait(Some(Duration::from_millis(500))).is_err());
+    assert!(wire2.wait(Some(Duration::from_millis(100))).is_err());
+
+    // 请求完成后可以再次使用
+    let _ = wire2.send(msg!{CHAN: "aaa", ID: id, TO: wire3_id, REQUEST: true, TIMEOUT: 100});
+
+    let recv = wire3.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+
+    let recv = wire2.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::RequestTimeout));
+}
+
+#[test]
+fn system_priority() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = wire1.send(msg!{
+        CHAN: ATTACH,
+        VALUE: "aaa"
+    });
+
+    assert!(wire1.wait(Some(Duration::from_millis(100))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    for i in 0..10 {
+        let _ = wire2.send(msg!{
+            CHAN: "aaa",
+            "i": i
+        });
+    }
+
+    std::thread::sleep(Duration::from_millis(100));
+
+    let _ = wire1.send(msg!{
+        CHAN: PING
+    });
+
+    std::thread::sleep(Duration::from_millis(100));
+
+    // PING 的回复排在普通消息之前
+    let recv = wire1.recv().unwrap();
+    assert!(recv.get_str(CHAN).unwrap() == PING);
+    assert!(recv.get_i32(CODE).unwrap() == 0);
+
+    for i in 0..10 {
+        let recv = wire1.recv().unwrap();
+        assert!(recv.get_i32("i").unwrap() == i);
+    }
+}
+
+#[test]
+fn sharded() {
+    let socket = Socket::sharded(MessageId::new(), SocketOptions::default(), 4, ()).unwrap();
+    assert!(socket.shards() == 4);
+
+    // 轮流分配到各个分片
+    let wires: Vec<_> = (0..8).map(|_| socket.connect(msg!{}, None, None).unwrap()).collect();
+
+    // 事件
+    let event = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = event.send(msg!{
+        CHAN: ATTACH,
+        VALUE: SLOT_READY
+    });
+
+    assert!(event.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    // 重复的 SLOT_ID，即使在其他分片上
+    let slot_id = MessageId::new();
+
+    let wire = socket.connect(msg!{SLOT_ID: slot_id}, None, None).unwrap();
+
+    // 其他分片的事件是异步转发的，ATTACH 之前连接的 SLOT 的事件也可能收到，按 SLOT_ID 过滤
+    loop {
+        let recv = event.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(recv.get_str(CHAN).unwrap() == SLOT_READY);
+
+        if recv.get_message_id(SLOT_ID).unwrap() == &slot_id {
+            break
+        }
+    }
+
+    let ret = socket.connect(msg!{SLOT_ID: slot_id}, None, None);
+    assert!(matches!(ret, Err(Error::ErrorCode(Code::DuplicateSlotId))));
+
+    drop(wire);
+
+    // 广播，每个分片上都有订阅者
+    for wire in &wires[..4] {
+        let _ = wire.send(msg!{
+            CHAN: ATTACH,
+            VALUE: "aaa"
+        });
+
+        assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+    }
+
+    for i in 0..50 {
+        let _ = wires[5].send(msg!{
+            CHAN: "aaa",
+            "i": i
+        });
+    }
+
+    // 同一发送者的消息保持顺序
+    for wire in &wires[..4] {
+        for i in 0..50 {
+            let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+            assert!(recv.get_i32("i").unwrap() == i);
+        }
+    }
+
+    // SHARE，只有一个订阅者收到
+    for _ in 0..10 {
+        let _ = wires[6].send(msg!{
+            CHAN: "aaa",
+            SHARE: true
+        });
+    }
+
+    std::thread::sleep(Duration::from_millis(200));
+
+    let mut count = 0;
+
+    for wire in &wires[..4] {
+        while wire.recv().is_ok() {
+            count += 1;
+        }
+    }
+
+    assert!(count == 10);
+
+    // 共享订阅
+    for wire in &wires[4..] {
+        let _ = wire.send(msg!{
+            CHAN: ATTACH,
+            VALUE: "bbb",
+            SHARE: true
+        });
+
+        assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+    }
+
+    for _ in 0..10 {
+        let _ = wires[0].send(msg!{
+            CHAN: "bbb"
+        });
+    }
+
+    std::thread::sleep(Duration::from_millis(200));
+
+    let mut count = 0;
+
+    for wire in &wires[4..] {
+        while wire.recv().is_ok() {
+            count += 1;
+        }
+    }
+
+    assert!(count == 10);
+
+    // TO，跨分片
+    let to_ids: Vec<MessageId> = wires[1..4].iter()
+        .map(|wire| *wire.attr().get_message_id(SLOT_ID).unwrap())
+        .collect();
+
+    let _ = wires[0].send(msg!{
+        CHAN: "ccc",
+        TO: to_ids.iter().map(|id| (*id).into()).collect::<nson::Array>()
+    });
+
+    for wire in &wires[1..4] {
+        let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(recv.get_str(CHAN).unwrap() == "ccc");
+    }
+
+    // 目标不存在
+    let _ = wires[0].send(msg!{
+        CHAN: "ccc",
+        ID: MessageId::new(),
+        TO: MessageId::new(),
+        REQUEST: true
+    });
+
+    let recv = wires[0].wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::TargetSlotIdNotExist));
+
+    // 断开事件，跨分片
+    let break_id = *wires[7].attr().get_message_id(SLOT_ID).unwrap();
+
+    let _ = event.send(msg!{
+        CHAN: ATTACH,
+        VALUE: SLOT_BREAK
+    });
+
+    assert!(event.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    drop(wires);
+
+    let mut breaks = 0;
+
+    while let Ok(recv) = event.wait(Some(Duration::from_secs(1))) {
+        if recv.get_str(CHAN).unwrap() == SLOT_BREAK {
+            breaks += 1;
+
+            if recv.get_message_id(SLOT_ID).unwrap() == &break_id {
+                break
+            }
+        }
+    }
+
+    assert!(breaks > 0);
+}
+
+#[test]
+fn transfer() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+    let wire3 = socket.connect(msg!{}, None, None).unwrap();
+
+    let wire2_id = *wire2.attr().get_message_id(SLOT_ID).unwrap();
+
+    let data: Vec<u8> = (0..1024 * 1024 + 1234).map(|_| rand::random()).collect();
+
+    let options = TransferOptions {
+        chunk_size: 64 * 1024,
+        window: 4,
+        ..TransferOptions::default()
+    };
+
+    let data2 = data.clone();
+    let options2 = options.clone();
+
+    let handle = thread::spawn(move || {
+        let mut writer = transfer::Writer::new(&wire1, "aaa", wire2_id, options2).unwrap();
+
+        writer.write_all(&data2).unwrap();
+        writer.finish().unwrap();
+    });
+
+    let start = wire2.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(transfer::is_start(&start));
+
+    let mut reader = transfer::Reader::accept(&wire2, &start, options).unwrap();
+
+    // 传输期间的其他消息
+    wire3.send(msg!{
+        CHAN: "bbb",
+        TO: wire2_id
+    }).unwrap();
+
+    let mut recv = vec![];
+    reader.read_to_end(&mut recv).unwrap();
+
+    handle.join().unwrap();
+
+    assert!(recv == data);
+    assert!(reader.size() == data.len() as u64);
+
+    let others = reader.others();
+    assert!(others.len() == 1);
+    assert!(others[0].get_str(CHAN).unwrap() == "bbb");
+}
+
+#[test]
+fn transfer_resume() {
+    // 续传时使用原来的 SLOT_ID 重连，原来的 SLOT 可能还未移除
+    let options = SocketOptions {
+        takeover: true,
+        ..SocketOptions::default()
+    };
+
+    let socket = Socket::with_options(MessageId::new(), options, ()).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+
+    let wire1_id = *wire1.attr().get_message_id(SLOT_ID).unwrap();
+    let wire2_id = *wire2.attr().get_message_id(SLOT_ID).unwrap();
+
+    let data: Vec<u8> = (0..1024 * 1024).map(|_| rand::random()).collect();
+
+    let options = TransferOptions {
+        chunk_size: 64 * 1024,
+        window: 4,
+        ..TransferOptions::default()
+    };
+
+    let data2 = data.clone();
+    let options2 = options.clone();
+    let socket2 = socket.clone();
+
+    let (tx, rx) = mpsc::channel();
+
+    let handle = thread::spawn(move || {
+        let mut writer = transfer::Writer::new(&wire1, "aaa", wire2_id, options2.clone()).unwrap();
+        let id = writer.id();
+
+        writer.write_all(&data2[..300 * 1024]).unwrap();
+        writer.flush().unwrap();
+
+        // 发送方断开后重连
+        rx.recv().unwrap();
+        drop(writer);
+        drop(wire1);
+
+        let wire3 = socket2.connect(msg!{SLOT_ID: wire1_id}, None, None).unwrap();
+
+        let mut source = Cursor::new(data2);
+
+        let mut writer = transfer::Writer::resume(&wire3, "aaa", wire2_id, id, &mut source, options2).unwrap();
+        assert!(writer.size() == 300 * 1024);
+
+        std::io::copy(&mut source, &mut writer).unwrap();
+        writer.finish().unwrap();
+    });
+
+    let start = wire2.wait(Some(Duration::from_secs(1))).unwrap();
+    let mut reader = transfer::Reader::accept(&wire2, &start, options.clone()).unwrap();
+
+    let mut recv = vec![0; 300 * 1024];
+    reader.read_exact(&mut recv).unwrap();
+
+    tx.send(()).unwrap();
+
+    let mut buf = vec![0; 100 * 1024];
+    reader.read_exact(&mut buf).unwrap();
+    recv.extend_from_slice(&buf);
+
+    // 接收方断开后重连
+    let checkpoint = reader.checkpoint();
+    drop(reader);
+    drop(wire2);
+
+    let wire4 = socket.connect(msg!{SLOT_ID: wire2_id}, None, None).unwrap();
+
+    let mut reader = transfer::Reader::resume(&wire4, checkpoint, options).unwrap();
+    reader.read_to_end(&mut recv).unwrap();
+
+    handle.join().unwrap();
+
+    assert!(recv == data);
+}
+
+#[test]
+fn transfer_peer() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+    let wire3 = socket.connect(msg!{}, None, None).unwrap();
+
+    let wire2_id = *wire2.attr().get_message_id(SLOT_ID).unwrap();
+
+    // 无效的分块大小
+    let options = TransferOptions {
+        chunk_size: 0,
+        ..TransferOptions::default()
+    };
+
+    assert!(transfer::Writer::new(&wire1, "aaa", wire2_id, options).is_err());
+
+    let options = TransferOptions {
+        chunk_size: 4,
+        window: 4,
+        timeout: Duration::from_millis(500),
+        others_limit: 2
+    };
+
+    let options2 = options.clone();
+
+    let handle = thread::spawn(move || {
+        let mut writer = transfer::Writer::new(&wire1, "aaa", wire2_id, options2).unwrap();
+
+        writer.write_all(b"hello world").unwrap();
+        writer.finish().unwrap();
+    });
+
+    let start = wire2.wait(Some(Duration::from_secs(1))).unwrap();
+    let id = *start.get_message_id(TRANSFER).unwrap();
+
+    let mut reader = transfer::Reader::accept(&wire2, &start, options).unwrap();
+
+    // 其他 SLOT 的同 ID 的数据块和续传会被丢弃
+    wire3.send(msg!{CHAN: "aaa", TO: wire2_id, TRANSFER: id, SEQ: 0u64, VALUE: b"evil".to_vec()}).unwrap();
+    wire3.send(msg!{CHAN: "aaa", TO: wire2_id, TRANSFER: id, RESUME: true}).unwrap();
+
+    let mut recv = vec![];
+    reader.read_to_end(&mut recv).unwrap();
+
+    handle.join().unwrap();
+
+    assert!(recv == b"hello world");
+    assert!(reader.from() != *wire3.attr().get_message_id(SLOT_ID).unwrap());
+    assert!(reader.others().is_empty());
+
+    // 暂存的其他消息超过限制
+    let options = TransferOptions {
+        timeout: Duration::from_millis(500),
+        others_limit: 2,
+        ..TransferOptions::default()
+    };
+
+    let wire4 = socket.connect(msg!{}, None, None).unwrap();
+    let wire4_id = *wire4.attr().get_message_id(SLOT_ID).unwrap();
+
+    for _ in 0..3 {
+        wire3.send(msg!{CHAN: "bbb", TO: wire4_id}).unwrap();
+    }
+
+    thread::sleep(Duration::from_millis(100));
+
+    let ret = transfer::Writer::new(&wire4, "aaa", wire2_id, options);
+    assert!(matches!(ret, Err(Error::Full(_))));
+}
+
+#[test]
+fn slot_limits() {
+    struct MyHook;
+
+    impl Hook for MyHook {
+        fn accept(&self, slot: &Slot) -> bool {
+            if slot.wire.attr().get_bool("vip").unwrap_or(false) {
+                let mut limits = slot.limits();
+                limits.messages_per_sec = None;
+
+                assert!(slot.set_limits(limits));
+            }
+
+            true
+        }
+    }
+
+    let options = SocketOptions {
+        slot_limits: SlotLimits {
+            messages_per_sec: Some(5),
+            max_chans: Some(2),
+            ..SlotLimits::default()
+        },
+        limit_group: Some("user".to_string()),
+        ..SocketOptions::default()
+    };
+
+    let socket = Socket::with_options(MessageId::new(), options, MyHook).unwrap();
+
+    // 属性的类型不正确
+    let ret = socket.connect(msg!{MESSAGE_RATE: "abc"}, None, None);
+    assert!(matches!(ret, Err(Error::ErrorCode(Code::InvalidLimitFieldType))));
+
+    fn ping(wire: &queen::Wire<nson::Message>, n: usize) -> usize {
+        for _ in 0..n {
+            wire.send(msg!{CHAN: PING}).unwrap();
+        }
+
+        let mut limited = 0;
+
+        for _ in 0..n {
+            let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+
+            if Code::get(&recv) == Some(Code::RateLimited) {
+                limited += 1;
+            }
+        }
+
+        limited
+    }
+
+    // 每秒 5 条
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    assert!(ping(&wire1, 10) >= 4);
+
+    // 属性只能收紧
+    let wire2 = socket.connect(msg!{MESSAGE_RATE: 2}, None, None).unwrap();
+    assert!(ping(&wire2, 5) >= 2);
+
+    let wire3 = socket.connect(msg!{MESSAGE_RATE: 1000}, None, None).unwrap();
+    assert!(ping(&wire3, 10) >= 4);
+
+    // Hook::accept 中可以修改
+    let wire4 = socket.connect(msg!{"vip": true}, None, None).unwrap();
+    assert!(ping(&wire4, 20) == 0);
+
+    // 同组共享
+    let wire5 = socket.connect(msg!{"user": "a", "vip": true}, None, None).unwrap();
+    let wire6 = socket.connect(msg!{"user": "b"}, None, None).unwrap();
+    let wire7 = socket.connect(msg!{"user": "b"}, None, None).unwrap();
+
+    assert!(ping(&wire5, 20) == 0);
+    assert!(ping(&wire6, 4) == 0);
+    assert!(ping(&wire7, 4) >= 2);
+
+    // 同组的速率必须相同
+    let ret = socket.connect(msg!{"user": "b", "vip": true}, None, None);
+    assert!(matches!(ret, Err(Error::ErrorCode(Code::LimitGroupConflict))));
+
+    let ret = socket.connect(msg!{"user": "a", MESSAGE_RATE: 2}, None, None);
+    assert!(matches!(ret, Err(Error::ErrorCode(Code::LimitGroupConflict))));
+
+    let wire8 = socket.connect(msg!{"user": "a", "vip": true}, None, None).unwrap();
+    assert!(ping(&wire8, 20) == 0);
+
+    // 频道数
+    for chan in ["a", "b", "a"] {
+        wire4.send(msg!{CHAN: ATTACH, VALUE: chan}).unwrap();
+
+        let recv = wire4.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(Code::get(&recv) == Some(Code::Ok));
+    }
+
+    wire4.send(msg!{CHAN: ATTACH, VALUE: "c"}).unwrap();
+
+    let recv = wire4.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::TooManyChans));
+
+    // 共享频道不受普通频道数的限制
+    wire4.send(msg!{CHAN: ATTACH, VALUE: "c", SHARE: true}).unwrap();
+
+    let recv = wire4.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::Ok));
+}
+
+#[test]
+fn share_group() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let event = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = event.send(msg!{
+        CHAN: ATTACH,
+        VALUE: SLOT_ATTACH
+    });
+
+    let _ = event.send(msg!{
+        CHAN: ATTACH,
+        VALUE: SLOT_DETACH
+    });
+
+    assert!(event.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+    assert!(event.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    // 两个组，每组两个 SLOT
+    let wires: Vec<_> = (0..4).map(|_| socket.connect(msg!{}, None, None).unwrap()).collect();
+
+    for (i, wire) in wires.iter().enumerate() {
+        let group = if i < 2 { "g1" } else { "g2" };
+
+        let _ = wire.send(msg!{
+            CHAN: ATTACH,
+            VALUE: "aaa",
+            SHARE: group
+        });
+
+        assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+        let recv = event.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(recv.get_str(CHAN).unwrap() == SLOT_ATTACH);
+        assert!(recv.get_bool(SHARE).unwrap());
+        assert!(recv.get_str(GROUP).unwrap() == group);
+    }
+
+    // 组名的类型不对
+    let _ = wires[0].send(msg!{
+        CHAN: ATTACH,
+        VALUE: "aaa",
+        SHARE: 123
+    });
+
+    let recv = wires[0].wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::InvalidShareFieldType));
+
+    // MINE
+    let _ = wires[2].send(msg!{
+        CHAN: MINE
+    });
+
+    let recv = wires[2].wait(Some(Duration::from_secs(1))).unwrap();
+    let value = recv.get_message(VALUE).unwrap();
+    assert!(value.get_array(SHARE_CHANS).unwrap().len() == 1);
+    assert!(value.get_message(SHARE_GROUPS).unwrap().get_str("aaa").unwrap() == "g2");
+
+    // 每个组收到一份
+    let sender = socket.connect(msg!{}, None, None).unwrap();
+
+    for i in 0..10 {
+        let _ = sender.send(msg!{
+            CHAN: "aaa",
+            "i": i
+        });
+    }
+
+    thread::sleep(Duration::from_millis(200));
+
+    let mut counts = [0; 4];
+
+    for (i, wire) in wires.iter().enumerate() {
+        while wire.recv().is_ok() {
+            counts[i] += 1;
+        }
+    }
+
+    assert!(counts[0] + counts[1] == 10);
+    assert!(counts[2] + counts[3] == 10);
+
+    // 组名不一致时不会离开
+    let _ = wires[0].send(msg!{
+        CHAN: DETACH,
+        VALUE: "aaa",
+        SHARE: "g2"
+    });
+
+    assert!(wires[0].wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+    let _ = event.wait(Some(Duration::from_secs(1))).unwrap();
+
+    // 离开 g1 后，g1 只剩一个 SLOT
+    let _ = wires[0].send(msg!{
+        CHAN: DETACH,
+        VALUE: "aaa",
+        SHARE: true
+    });
+
+    assert!(wires[0].wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let recv = event.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_str(CHAN).unwrap() == SLOT_DETACH);
+    assert!(recv.get_str(GROUP).unwrap() == "g1");
+
+    // 换组，g1 没有 SLOT 了
+    let _ = wires[1].send(msg!{
+        CHAN: ATTACH,
+        VALUE: "aaa",
+        SHARE: "g2"
+    });
+
+    assert!(wires[1].wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    for i in 0..10 {
+        let _ = sender.send(msg!{
+            CHAN: "aaa",
+            "i": i
+        });
+    }
+
+    thread::sleep(Duration::from_millis(200));
+
+    let mut count = 0;
+
+    for wire in &wires {
+        while wire.recv().is_ok() {
+            count += 1;
+        }
+    }
+
+    assert!(count == 10);
+
+    // 分片时，每个组也只收到一份
+    let socket = Socket::sharded(MessageId::new(), SocketOptions::default(), 4, ()).unwrap();
+
+    let wires: Vec<_> = (0..8).map(|_| socket.connect(msg!{}, None, None).unwrap()).collect();
+
+    for (i, wire) in wires.iter().enumerate() {
+        let _ = wire.send(msg!{
+            CHAN: ATTACH,
+            VALUE: "bbb",
+            SHARE: if i % 2 == 0 { "g1" } else { "g2" }
+        });
+
+        assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+    }
+
+    let sender = socket.connect(msg!{}, None, None).unwrap();
+
+    for i in 0..10 {
+        let _ = sender.send(msg!{
+            CHAN: "bbb",
+            "i": i
+        });
+    }
+
+    thread::sleep(Duration::from_millis(200));
+
+    let mut counts = [0; 2];
+
+    for (i, wire) in wires.iter().enumerate() {
+        while wire.recv().is_ok() {
+            counts[i % 2] += 1;
+        }
+    }
+
+    assert!(counts == [10, 10]);
+}
+
+#[test]
+fn share_strategy() {
+    let mut options = SocketOptions::new();
+    options.chan_strategies.insert("rr".to_string(), Strategy::RoundRobin);
+
+    let socket = Socket::with_options(MessageId::new(), options, ()).unwrap();
+
+    // 权重
+    let wires: Vec<_> = (0..3).map(|i| socket.connect(msg!{WEIGHT: if i == 0 { 1 } else { 0 }}, None, None).unwrap()).collect();
+
+    for wire in &wires {
+        for chan in ["rr", "aaa"] {
+            let _ = wire.send(msg!{
+                CHAN: ATTACH,
+                VALUE: chan,
+                SHARE: "g"
+            });
+
+            assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+        }
+    }
+
+    let sender = socket.connect(msg!{}, None, None).unwrap();
+
+    let counts = |wires: &[queen::Wire<nson::Message>]| {
+        thread::sleep(Duration::from_millis(200));
+
+        wires.iter().map(|wire| {
+            let mut count = 0;
+
+            while wire.recv().is_ok() {
+                count += 1;
+            }
+
+            count
+        }).collect::<Vec<usize>>()
+    };
+
+    // CHAN 的策略，轮流
+    for _ in 0..9 {
+        let _ = sender.send(msg!{CHAN: "rr"});
+    }
+
+    assert!(counts(&wires) == vec![3, 3, 3]);
+
+    // 消息指定的策略，加权
+    for _ in 0..9 {
+        let _ = sender.send(msg!{CHAN: "aaa", STRATEGY: "weighted"});
+    }
+
+    assert!(counts(&wires) == vec![9, 0, 0]);
+
+    // 一致性哈希，相同的 KEY 总是选择同一个订阅者
+    for key in 0..5 {
+        for _ in 0..4 {
+            let _ = sender.send(msg!{CHAN: "aaa", STRATEGY: "hash", SHARE_KEY: key});
+        }
+
+        let counts = counts(&wires);
+        assert!(counts.iter().sum::<usize>() == 4);
+        assert!(counts.contains(&4));
+    }
+
+    // 负载最少，第一个订阅者积压了消息
+    for _ in 0..3 {
+        let _ = sender.send(msg!{CHAN: "aaa", TO: *wires[0].attr().get_message_id(SLOT_ID).unwrap()});
+    }
+
+    for _ in 0..4 {
+        let _ = sender.send(msg!{CHAN: "aaa", STRATEGY: "least_loaded"});
+    }
+
+    assert!(counts(&wires)[0] == 3);
+
+    // TO 数组
+    let ids: Vec<MessageId> = wires.iter().map(|wire| *wire.attr().get_message_id(SLOT_ID).unwrap()).collect();
+
+    for _ in 0..6 {
+        let _ = sender.send(msg!{CHAN: "bbb", TO: ids.clone(), SHARE: true, STRATEGY: "round_robin"});
+    }
+
+    assert!(counts(&wires) == vec![2, 2, 2]);
+
+    // 错误的策略
+    let _ = sender.send(msg!{CHAN: "aaa", STRATEGY: "foo"});
+
+    let recv = sender.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::InvalidStrategyFieldType));
+}
+
+#[test]
+fn tag_expr() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let wire1 = socket.connect(msg!{TAGS: {"region": "eu", "version": 3}}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{TAGS: ["region=us", "version=5", "beta", "wow!"]}, None, None).unwrap();
+    let wire3 = socket.connect(msg!{}, None, None).unwrap();
+
+    for wire in [&wire1, &wire2] {
+        let _ = wire.send(msg!{CHAN: ATTACH, VALUE: "aaa"});
+        assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+        let _ = wire.send(msg!{CHAN: ATTACH, VALUE: "bbb", SHARE: true});
+        assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+    }
+
+    let recv = |wire: &queen::Wire<nson::Message>| wire.wait(Some(Duration::from_millis(100))).is_ok();
+
+    // 比较
+    let _ = wire3.send(msg!{CHAN: "aaa", TAGS: "version>=4"});
+
+    assert!(!recv(&wire1));
+    assert!(recv(&wire2));
+
+    // 整数的标签与小数按数字比较
+    let _ = wire3.send(msg!{CHAN: "aaa", TAGS: "version!=3.0"});
+
+    assert!(!recv(&wire1));
+    assert!(recv(&wire2));
+
+    let _ = wire3.send(msg!{CHAN: "aaa", TAGS: "version=3.0"});
+
+    assert!(recv(&wire1));
+    assert!(!recv(&wire2));
+
+    // 不是合法的比较表达式时按普通标签匹配
+    let _ = wire3.send(msg!{CHAN: "aaa", TAGS: "wow!"});
+
+    assert!(!recv(&wire1));
+    assert!(recv(&wire2));
+
+    // 任意一个
+    let _ = wire3.send(msg!{CHAN: "aaa", TAGS: {TAG_ANY: ["region=eu", "beta"]}});
+
+    assert!(recv(&wire1));
+    assert!(recv(&wire2));
+
+    // 嵌套
+    let _ = wire3.send(msg!{CHAN: "aaa", TAGS: {TAG_ALL: [{TAG_ANY: ["region=eu", "region=us"]}, {TAG_NONE: "beta"}]}});
+
+    assert!(recv(&wire1));
+    assert!(!recv(&wire2));
+
+    // 共享订阅
+    for _ in 0..5 {
+        let _ = wire3.send(msg!{CHAN: "bbb", TAGS: {TAG_NONE: ["region=eu"]}});
+    }
+
+    for _ in 0..5 {
+        assert!(recv(&wire2));
+    }
+
+    assert!(!recv(&wire1));
+
+    // SHARE 消息
+    for _ in 0..5 {
+        let _ = wire3.send(msg!{CHAN: "aaa", SHARE: true, TAGS: "version<4"});
+    }
+
+    for _ in 0..5 {
+        assert!(recv(&wire1));
+    }
+
+    assert!(!recv(&wire2));
+
+    // 错误的表达式
+    let _ = wire3.send(msg!{CHAN: "aaa", TAGS: {"$foo": ["beta"]}});
+
+    let recv = wire3.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::InvalidTagsExpression));
+}
+
+#[test]
+fn attach_filter() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let wire1 = socket.connect(msg!{}, None, None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+    let wire3 = socket.connect(msg!{}, None, None).unwrap();
+    let sender = socket.connect(msg!{}, None, None).unwrap();
+
+    // 错误的过滤条件
+    let _ = wire1.send(msg!{CHAN: ATTACH, VALUE: "aaa", FILTER: 123});
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::InvalidFilterFieldType));
+
+    let _ = wire1.send(msg!{CHAN: ATTACH, VALUE: "aaa", FILTER: {"a": {"$foo": 1}}});
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::InvalidFilterFieldType));
+
+    let _ = wire1.send(msg!{CHAN: ATTACH, VALUE: "aaa", FILTER: {"kind": "order", "amount": {"$gte": 100}}});
+    let _ = wire2.send(msg!{CHAN: ATTACH, VALUE: "aaa", FILTER: {"name": {"$prefix": "ab"}}});
+    let _ = wire3.send(msg!{CHAN: ATTACH, VALUE: "aaa"});
+
+    for wire in [&wire1, &wire2, &wire3] {
+        assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+    }
+
+    let recv = |wire: &queen::Wire<nson::Message>| wire.wait(Some(Duration::from_millis(100))).is_ok();
+
+    let _ = sender.send(msg!{CHAN: "aaa", "kind": "order", "amount": 150});
+
+    assert!(recv(&wire1));
+    assert!(!recv(&wire2));
+    assert!(recv(&wire3));
+
+    let _ = sender.send(msg!{CHAN: "aaa", "kind": "order", "amount": 50, "name": "abc"});
+
+    assert!(!recv(&wire1));
+    assert!(recv(&wire2));
+    assert!(recv(&wire3));
+
+    // 重复 ATTACH 时替换过滤条件
+    let _ = wire1.send(msg!{CHAN: ATTACH, VALUE: "aaa", FILTER: {"kind": {"$in": ["refund"]}}});
+    assert!(wire1.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let _ = sender.send(msg!{CHAN: "aaa", "kind": "refund"});
+
+    assert!(recv(&wire1));
+
+    // 不带过滤条件时接收所有消息
+    let _ = wire1.send(msg!{CHAN: ATTACH, VALUE: "aaa"});
+    assert!(wire1.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let _ = sender.send(msg!{CHAN: "aaa"});
+
+    assert!(recv(&wire1));
+    assert!(!recv(&wire2));
+
+    // 共享订阅
+    let _ = wire1.send(msg!{CHAN: ATTACH, VALUE: "bbb", SHARE: true, FILTER: {"level": {"$exists": true}}});
+    let _ = wire2.send(msg!{CHAN: ATTACH, VALUE: "bbb", SHARE: true, FILTER: {"level": {"$exists": false}}});
+
+    for wire in [&wire1, &wire2] {
+        assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+    }
+
+    for _ in 0..5 {
+        let _ = sender.send(msg!{CHAN: "bbb", "level": 1});
+    }
+
+    for _ in 0..5 {
+        assert!(recv(&wire1));
+    }
+
+    assert!(!recv(&wire2));
+}
+
+#[test]
+fn publish_confirm() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let sender = socket.connect(msg!{}, None, None).unwrap();
+
+    // 没有订阅者
+    let id = MessageId::new();
+    let _ = sender.send(msg!{CHAN: "aaa", ID: id, CONFIRM: true});
+
+    let recv = sender.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::NoSubscribers));
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+    assert!(recv.get_u64(QUEUED).unwrap() == 0);
+
+    // 容量为 1 的 Wire，第二个消息会被丢弃
+    let wire1 = socket.connect(msg!{}, Some(1), None).unwrap();
+    let wire2 = socket.connect(msg!{}, None, None).unwrap();
+
+    for wire in [&wire1, &wire2] {
+        let _ = wire.send(msg!{CHAN: ATTACH, VALUE: "aaa"});
+        assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+    }
+
+    let _ = sender.send(msg!{CHAN: "aaa", ID: id, CONFIRM: true});
+
+    let recv = sender.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::Ok));
+    assert!(recv.get_str(CHAN).unwrap() == "aaa");
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+    assert!(recv.get_u64(QUEUED).unwrap() == 2);
+    assert!(recv.get_u64(DROPPED).unwrap() == 0);
+
+    let _ = sender.send(msg!{CHAN: "aaa", CONFIRM: true});
+
+    let recv = sender.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_u64(QUEUED).unwrap() == 1);
+    assert!(recv.get_u64(DROPPED).unwrap() == 1);
+
+    // 不需要确认时没有回复
+    let _ = sender.send(msg!{CHAN: "aaa"});
+    assert!(sender.wait(Some(Duration::from_millis(100))).is_err());
+
+    // 分片时汇总所有分片的投递数
+    let socket = Socket::sharded(MessageId::new(), SocketOptions::default(), 4, ()).unwrap();
+
+    let wires: Vec<_> = (0..8).map(|_| socket.connect(msg!{}, None, None).unwrap()).collect();
+
+    for wire in &wires[..6] {
+        let _ = wire.send(msg!{CHAN: ATTACH, VALUE: "bbb"});
+        assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+    }
+
+    let _ = wires[6].send(msg!{CHAN: ATTACH, VALUE: "bbb", SHARE: "g"});
+    assert!(wires[6].wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let sender = &wires[7];
+
+    for _ in 0..5 {
+        let _ = sender.send(msg!{CHAN: "bbb", ID: id, CONFIRM: true});
+
+        let recv = sender.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(Code::get(&recv) == Some(Code::Ok));
+        assert!(recv.get_u64(QUEUED).unwrap() == 7);
+    }
+
+    let _ = sender.send(msg!{CHAN: "ccc", CONFIRM: true});
+
+    let recv = sender.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::NoSubscribers));
+}
+
+#[test]
+fn dead_letter_reasons() {
+    let mut options = SocketOptions::new();
+    options.dead_letter = Some("dead".to_string());
+
+    let socket = Socket::with_options(MessageId::new(), options, ()).unwrap();
+
+    let dead = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = dead.send(msg!{CHAN: ATTACH, VALUE: "dead"});
+    assert!(dead.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let sender = socket.connect(msg!{}, None, None).unwrap();
+    let sender_id = *sender.attr().get_message_id(SLOT_ID).unwrap();
+
+    // 没有订阅者
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 1});
+
+    let recv = dead.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::NoSubscribers));
+    assert!(recv.get_str(ORIGIN).unwrap() == "aaa");
+    assert!(recv.get_message_id(SLOT_ID).unwrap() == &sender_id);
+    assert!(recv.get_message(VALUE).unwrap().get_i32("n").unwrap() == 1);
+
+    // TO 不存在
+    let missing = MessageId::new();
+    let _ = sender.send(msg!{CHAN: "aaa", TO: missing});
+
+    let recv = dead.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::TargetSlotIdNotExist));
+    assert!(recv.get_message(VALUE).unwrap().get_array(TO).unwrap().len() == 1);
+
+    // TO_SOCKET 不存在
+    let _ = sender.send(msg!{CHAN: "aaa", TO_SOCKET: MessageId::new()});
+
+    let recv = dead.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::TargetSocketIdNotExist));
+    assert!(recv.get_str(ORIGIN).unwrap() == "aaa");
+
+    // 接收者的 Wire 已满
+    let full = socket.connect(msg!{}, Some(1), None).unwrap();
+    let full_id = *full.attr().get_message_id(SLOT_ID).unwrap();
+
+    let _ = full.send(msg!{CHAN: ATTACH, VALUE: "aaa"});
+    assert!(full.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 1});
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 2});
+
+    let recv = dead.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::WireFull));
+    assert!(recv.get_message_id(TO).unwrap() == &full_id);
+    assert!(recv.get_message_id(SLOT_ID).unwrap() == &sender_id);
+    assert!(recv.get_message(VALUE).unwrap().get_i32("n").unwrap() == 2);
+
+    // 死信频道本身不会再进入死信频道
+    let _ = dead.send(msg!{CHAN: DETACH, VALUE: "dead"});
+    assert!(dead.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let _ = sender.send(msg!{CHAN: "dead"});
+    assert!(dead.wait(Some(Duration::from_millis(100))).is_err());
+}
+
+#[test]
+fn gather_request() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let requester = socket.connect(msg!{}, None, None).unwrap();
+
+    let wires: Vec<_> = (0..3).map(|_| socket.connect(msg!{}, None, None).unwrap()).collect();
+    let ids: Vec<MessageId> = wires.iter().map(|wire| *wire.attr().get_message_id(SLOT_ID).unwrap()).collect();
+
+    for wire in &wires {
+        let _ = wire.send(msg!{CHAN: ATTACH, VALUE: "aaa"});
+        assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+    }
+
+    // 所有目标都回复
+    let id = MessageId::new();
+    let _ = requester.send(msg!{CHAN: "aaa", ID: id, REQUEST: true, GATHER: true, TIMEOUT: 1000});
+
+    for (i, wire) in wires.iter().enumerate() {
+        let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(recv.get_message_id(ID).unwrap() == &id);
+
+        let _ = wire.send(msg!{CHAN: "aaa", ID: id, "n": i as i32});
+
+        // 回复被暂存，不会单独投递
+        if i < 2 {
+            assert!(requester.wait(Some(Duration::from_millis(50))).is_err());
+        }
+    }
+
+    let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::Ok));
+    assert!(recv.get_str(CHAN).unwrap() == "aaa");
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+    assert!(recv.get_array(MISSING).unwrap().is_empty());
+
+    let replies = recv.get_array(VALUE).unwrap();
+    assert!(replies.len() == 3);
+
+    let mut froms: Vec<MessageId> = replies.iter().map(|r| *r.as_message().unwrap().get_message_id(FROM).unwrap()).collect();
+    let mut expected = ids.clone();
+    froms.sort();
+    expected.sort();
+    assert!(froms == expected);
+
+    // 超时，列出未回复的目标
+    let id = MessageId::new();
+    let _ = requester.send(msg!{CHAN: "aaa", ID: id, REQUEST: true, GATHER: true, TIMEOUT: 200});
+
+    for wire in &wires {
+        assert!(wire.wait(Some(Duration::from_secs(1))).is_ok());
+    }
+
+    let _ = wires[0].send(msg!{CHAN: "aaa", ID: id});
+
+    assert!(requester.wait(Some(Duration::from_millis(100))).is_err());
+
+    let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::RequestTimeout));
+    assert!(recv.get_array(VALUE).unwrap().len() == 1);
+
+    let missing = recv.get_array(MISSING).unwrap();
+    assert!(missing.len() == 2);
+    assert!(!missing.iter().any(|v| v.as_message_id() == Some(&ids[0])));
+
+    // 目标断开
+    let id = MessageId::new();
+    let _ = requester.send(msg!{CHAN: "aaa", ID: id, REQUEST: true, GATHER: true});
+
+    for wire in &wires {
+        assert!(wire.wait(Some(Duration::from_secs(1))).is_ok());
+    }
+
+    let _ = wires[0].send(msg!{CHAN: "aaa", ID: id});
+    let _ = wires[1].send(msg!{CHAN: "aaa", ID: id});
+
+    let mut wires = wires;
+    drop(wires.pop());
+
+    let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::Ok));
+    assert!(recv.get_array(VALUE).unwrap().len() == 2);
+    assert!(recv.get_array(MISSING).unwrap().iter().map(|v| *v.as_message_id().unwrap()).collect::<Vec<_>>() == vec![ids[2]]);
+
+    // TO 为数组，不存在的目标也会列出
+    let id = MessageId::new();
+    let not_exist = MessageId::new();
+    let _ = requester.send(msg!{CHAN: "aaa", ID: id, TO: [ids[0], not_exist], REQUEST: true, GATHER: true});
+
+    assert!(wires[0].wait(Some(Duration::from_secs(1))).is_ok());
+    assert!(wires[1].wait(Some(Duration::from_millis(100))).is_err());
+
+    let _ = wires[0].send(msg!{CHAN: "aaa", ID: id});
+
+    let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::Ok));
+    assert!(recv.get_array(VALUE).unwrap().len() == 1);
+    assert!(recv.get_array(MISSING).unwrap().iter().map(|v| *v.as_message_id().unwrap()).collect::<Vec<_>>() == vec![not_exist]);
+
+    // 没有订阅者
+    let id = MessageId::new();
+    let _ = requester.send(msg!{CHAN: "bbb", ID: id, REQUEST: true, GATHER: true});
+
+    let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::NoSubscribers));
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+    assert!(recv.get_array(VALUE).unwrap().is_empty());
+
+    // 重复使用聚合请求的 ID，不会覆盖已收集的回复
+    let other = socket.connect(msg!{}, None, None).unwrap();
+
+    let id = MessageId::new();
+    let _ = requester.send(msg!{CHAN: "aaa", ID: id, REQUEST: true, GATHER: true, TIMEOUT: 1000});
+
+    for wire in &wires {
+        assert!(wire.wait(Some(Duration::from_secs(1))).is_ok());
+    }
+
+    let _ = wires[0].send(msg!{CHAN: "aaa", ID: id, "n": 0});
+
+    let _ = requester.send(msg!{CHAN: "aaa", ID: id, TO: ids[1], REQUEST: true});
+    assert!(Code::get(&requester.wait(Some(Duration::from_secs(1))).unwrap()) == Some(Code::DuplicateRequestId));
+
+    // 其他请求者，还未回复的目标不会收到
+    let _ = other.send(msg!{CHAN: "aaa", ID: id, REQUEST: true, GATHER: true, TIMEOUT: 1000});
+
+    assert!(wires[0].wait(Some(Duration::from_secs(1))).is_ok());
+    assert!(wires[1].wait(Some(Duration::from_millis(100))).is_err());
+    let _ = wires[0].send(msg!{CHAN: "aaa", ID: id, "n": 1});
+
+    let recv = other.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_array(VALUE).unwrap().len() == 1);
+    assert!(recv.get_array(MISSING).unwrap().iter().map(|v| *v.as_message_id().unwrap()).collect::<Vec<_>>() == vec![ids[1]]);
+
+    let _ = wires[1].send(msg!{CHAN: "aaa", ID: id, "n": 2});
+
+    let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::Ok));
+
+    let replies = recv.get_array(VALUE).unwrap();
+    assert!(replies.iter().map(|r| r.as_message().unwrap().get_i32("n").unwrap()).collect::<Vec<_>>() == vec![0, 2]);
+}
+
+#[test]
+fn stream_request() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let requester = socket.connect(msg!{}, None, None).unwrap();
+    let responder = socket.connect(msg!{}, None, None).unwrap();
+
+    let requester_id = *requester.attr().get_message_id(SLOT_ID).unwrap();
+    let responder_id = *responder.attr().get_message_id(SLOT_ID).unwrap();
+
+    // invalid
+    let _ = requester.send(msg!{CHAN: "aaa", ID: MessageId::new(), TO: responder_id, REQUEST: true, STREAM: true, CREDIT: "a"});
+
+    let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::InvalidCreditFieldType));
+
+    let id = MessageId::new();
+    let _ = requester.send(msg!{CHAN: "aaa", ID: id, TO: responder_id, REQUEST: true, STREAM: true, CREDIT: 2u32});
+
+    let recv = responder.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+    assert!(recv.get_u32(CREDIT).unwrap() == 2);
+
+    // 授权用完
+    for i in 0..3 {
+        let _ = responder.send(msg!{CHAN: "aaa", ID: id, TO: requester_id, "n": i});
+    }
+
+    for i in 0..2 {
+        let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(recv.get_i32("n").unwrap() == i);
+        assert!(recv.get_u64(SEQ).unwrap() == i as u64);
+        assert!(recv.get_message_id(FROM).unwrap() == &responder_id);
+    }
+
+    let recv = responder.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::CreditExhausted));
+    assert!(recv.get_i32("n").unwrap() == 2);
+
+    assert!(requester.wait(Some(Duration::from_millis(100))).is_err());
+
+    // 授予更多的 CREDIT，响应者会收到通知
+    let _ = requester.send(msg!{CHAN: GRANT, VALUE: id, CREDIT: 1u32});
+    assert!(requester.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let recv = responder.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+    assert!(recv.get_u32(CREDIT).unwrap() == 1);
+
+    // 只有请求者可以授权
+    let _ = responder.send(msg!{CHAN: GRANT, VALUE: id, CREDIT: 1u32});
+    assert!(Code::get(&responder.wait(Some(Duration::from_secs(1))).unwrap()) == Some(Code::PermissionDenied));
+
+    let _ = responder.send(msg!{CHAN: "aaa", ID: id, "n": 2});
+    let _ = responder.send(msg!{CHAN: "aaa", ID: id, FINISH: true});
+
+    let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_u64(SEQ).unwrap() == 2);
+
+    let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_bool(FINISH).unwrap());
+    assert!(recv.get_u64(SEQ).unwrap() == 3);
+
+    // 流结束后，回复按普通消息处理
+    let _ = requester.send(msg!{CHAN: GRANT, VALUE: id, CREDIT: 1u32});
+    assert!(Code::get(&requester.wait(Some(Duration::from_secs(1))).unwrap()) == Some(Code::NotFound));
+
+    // 请求者取消
+    let id = MessageId::new();
+    let _ = requester.send(msg!{CHAN: "aaa", ID: id, TO: responder_id, REQUEST: true, STREAM: true});
+
+    let recv = responder.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_u32(CREDIT).unwrap() == 64);
+
+    let _ = requester.send(msg!{CHAN: CANCEL, VALUE: id});
+    assert!(requester.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let recv = responder.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::StreamCanceled));
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+    assert!(recv.get_bool(FINISH).unwrap());
+
+    // 响应者断开
+    let id = MessageId::new();
+    let _ = requester.send(msg!{CHAN: "aaa", ID: id, TO: responder_id, REQUEST: true, STREAM: true});
+
+    assert!(responder.wait(Some(Duration::from_secs(1))).is_ok());
+
+    drop(responder);
+
+    let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::TargetSlotBroken));
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+    assert!(recv.get_message_id(FROM).unwrap() == &responder_id);
+}
+
+#[test]
+fn stream_timeout() {
+    let options = SocketOptions {
+        stream_timeout: 500,
+        stream_credit: 2,
+        ..SocketOptions::default()
+    };
+
+    let socket = Socket::with_options(MessageId::new(), options, ()).unwrap();
+
+    let requester = socket.connect(msg!{}, None, None).unwrap();
+    let other = socket.connect(msg!{}, None, None).unwrap();
+    let responder = socket.connect(msg!{}, None, None).unwrap();
+
+    let requester_id = *requester.attr().get_message_id(SLOT_ID).unwrap();
+    let responder_id = *responder.attr().get_message_id(SLOT_ID).unwrap();
+
+    // 默认的授权数
+    let id = MessageId::new();
+    let _ = requester.send(msg!{CHAN: "aaa", ID: id, TO: responder_id, REQUEST: true, STREAM: true});
+
+    let recv = responder.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_u32(CREDIT).unwrap() == 2);
+
+    // 其他请求者使用相同的 ID，响应者还未结束时不会投递
+    let _ = other.send(msg!{CHAN: "aaa", ID: id, TO: responder_id, REQUEST: true, STREAM: true});
+
+    let recv = other.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::DuplicateRequestId));
+    assert!(responder.wait(Some(Duration::from_millis(100))).is_err());
+
+    // 有回复时不会超时
+    for i in 0..2 {
+        thread::sleep(Duration::from_millis(200));
+
+        let _ = responder.send(msg!{CHAN: "aaa", ID: id, TO: requester_id, "n": i});
+
+        let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(recv.get_i32("n").unwrap() == i);
+    }
+
+    // 空闲超时，告知请求者和响应者
+    let recv = requester.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::RequestTimeout));
+    assert!(recv.get_message_id(ID).unwrap() == &id);
+    assert!(recv.get_message_id(FROM).unwrap() == &responder_id);
+
+    let recv = responder.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::StreamCanceled));
+    assert!(recv.get_bool(FINISH).unwrap());
+
+    let _ = requester.send(msg!{CHAN: GRANT, VALUE: id, CREDIT: 1u32});
+    assert!(Code::get(&requester.wait(Some(Duration::from_secs(1))).unwrap()) == Some(Code::NotFound));
+}
+
+#[test]
+fn slot_takeover() {
+    let mut options = SocketOptions::new();
+    options.takeover = true;
+
+    let socket = Socket::with_options(MessageId::new(), options, ()).unwrap();
+
+    let observer = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = observer.send(msg!{CHAN: ATTACH, VALUE: SLOT_TAKEOVER});
+    assert!(observer.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let slot_id = MessageId::new();
+
+    let wire1 = socket.connect(msg!{SLOT_ID: slot_id}, None, None).unwrap();
+
+    let _ = wire1.send(msg!{CHAN: ATTACH, VALUE: "aaa", FILTER: {"n": 1}});
+    assert!(wire1.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let _ = wire1.send(msg!{CHAN: ATTACH, VALUE: "bbb", SHARE: "g"});
+    assert!(wire1.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let _ = wire1.send(msg!{CHAN: JOIN});
+    assert!(wire1.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    // 使用相同的 SLOT_ID 连接，接管原来的 SLOT
+    let wire2 = socket.connect(msg!{SLOT_ID: slot_id, "n": 2}, None, None).unwrap();
+
+    let recv = wire1.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::SlotTakenOver));
+    assert!(wire1.wait(Some(Duration::from_secs(1))) == Err(RecvError::Disconnected));
+
+    let recv = observer.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_str(CHAN).unwrap() == SLOT_TAKEOVER);
+    assert!(recv.get_message_id(SLOT_ID).unwrap() == &slot_id);
+    assert!(recv.get_message(ATTR).unwrap().get_i32("n").unwrap() == 2);
+
+    // 订阅、过滤条件和 JOIN 状态都转移到新的 SLOT
+    let _ = wire2.send(msg!{CHAN: MINE});
+
+    let recv = wire2.wait(Some(Duration::from_secs(1))).unwrap();
+    let value = recv.get_message(VALUE).unwrap();
+    assert!(value.get_array(CHANS).unwrap().contains(&"aaa".into()));
+    assert!(value.get_message(SHARE_GROUPS).unwrap().get_str("bbb").unwrap() == "g");
+    assert!(value.get_bool(JOINED).unwrap());
+
+    let sender = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 2});
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 1});
+    let _ = sender.send(msg!{CHAN: "bbb", "m": 1});
+
+    let recv = wire2.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_str(CHAN).unwrap() == "aaa");
+    assert!(recv.get_i32("n").unwrap() == 1);
+
+    let recv = wire2.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_str(CHAN).unwrap() == "bbb");
+
+    // 接管只发送一个 SLOT_TAKEOVER 事件
+    assert!(observer.wait(Some(Duration::from_millis(100))).is_err());
+
+    // 发布原来的 SLOT 的遗嘱消息，新的 SLOT 使用自己的遗嘱消息
+    let _ = observer.send(msg!{CHAN: ATTACH, VALUE: "status"});
+    assert!(observer.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let slot_id = MessageId::new();
+
+    let wire3 = socket.connect(msg!{SLOT_ID: slot_id, WILL: {CHAN: "status", "n": 3}}, None, None).unwrap();
+
+    let _ = wire3.send(msg!{CHAN: ATTACH, VALUE: "status"});
+    assert!(wire3.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let wire4 = socket.connect(msg!{SLOT_ID: slot_id, WILL: {CHAN: "status", "n": 4}}, None, None).unwrap();
+
+    assert!(Code::get(&wire3.wait(Some(Duration::from_secs(1))).unwrap()) == Some(Code::SlotTakenOver));
+
+    let recv = observer.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_str(CHAN).unwrap() == "status");
+    assert!(recv.get_i32("n").unwrap() == 3);
+    assert!(recv.get_message_id(FROM).unwrap() == &slot_id);
+
+    let recv = observer.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_str(CHAN).unwrap() == SLOT_TAKEOVER);
+
+    // 新的 SLOT 不会收到原来的 SLOT 的遗嘱消息，但接管了订阅
+    assert!(wire4.wait(Some(Duration::from_millis(100))).is_err());
+
+    let _ = sender.send(msg!{CHAN: "status", "n": 5});
+
+    assert!(wire4.wait(Some(Duration::from_secs(1))).unwrap().get_i32("n").unwrap() == 5);
+    assert!(observer.wait(Some(Duration::from_secs(1))).unwrap().get_i32("n").unwrap() == 5);
+
+    drop(wire4);
+
+    let recv = observer.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_i32("n").unwrap() == 4);
+
+    // 默认拒绝
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let _wire = socket.connect(msg!{SLOT_ID: slot_id}, None, None).unwrap();
+    let ret = socket.connect(msg!{SLOT_ID: slot_id}, None, None);
+    assert!(matches!(ret, Err(Error::ErrorCode(Code::DuplicateSlotId))));
+}
+
+#[test]
+fn persistent_session() {
+    let mut options = SocketOptions::new();
+    options.session_expiry = 300;
+
+    let socket = Socket::with_options(MessageId::new(), options, ()).unwrap();
+
+    let ret = socket.connect(msg!{SESSION: "a"}, None, None);
+    assert!(matches!(ret, Err(Error::ErrorCode(Code::InvalidSessionFieldType))));
+
+    let slot_id = MessageId::new();
+
+    let wire = socket.connect(msg!{SLOT_ID: slot_id, SESSION: true}, None, None).unwrap();
+
+    let _ = wire.send(msg!{CHAN: ATTACH, VALUE: "aaa", FILTER: {"n": {"$gt": 0}}});
+    assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let _ = wire.send(msg!{CHAN: ATTACH, VALUE: "bbb", SHARE: "g"});
+    assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    drop(wire);
+    thread::sleep(Duration::from_millis(50));
+
+    // 离线时暂存匹配的消息
+    let sender = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 1, CONFIRM: true});
+
+    let recv = sender.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::Ok));
+    assert!(recv.get_u64(QUEUED).unwrap() == 1);
+
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 0});
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 2});
+    // 没有在线的订阅者时，SHARE 消息和共享订阅组的消息也会暂存
+    let _ = sender.send(msg!{CHAN: "bbb", "n": 3});
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 4, SHARE: true});
+
+    thread::sleep(Duration::from_millis(50));
+
+    // 共享订阅组内有在线的 SLOT 时，不再暂存
+    let other = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = other.send(msg!{CHAN: ATTACH, VALUE: "bbb", SHARE: "g"});
+    assert!(other.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let _ = sender.send(msg!{CHAN: "bbb", "n": 5});
+
+    let recv = other.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_i32("n").unwrap() == 5);
+
+    drop(other);
+    thread::sleep(Duration::from_millis(50));
+
+    // 重新连接后恢复订阅，并按顺序收到暂存的消息
+    let wire = socket.connect(msg!{SLOT_ID: slot_id}, None, None).unwrap();
+
+    for (chan, n) in [("aaa", 1), ("aaa", 2), ("bbb", 3), ("aaa", 4)] {
+        let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(recv.get_str(CHAN).unwrap() == chan);
+        assert!(recv.get_i32("n").unwrap() == n);
+    }
+
+    assert!(wire.wait(Some(Duration::from_millis(100))).is_err());
+
+    let _ = wire.send(msg!{CHAN: MINE});
+
+    let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+    let value = recv.get_message(VALUE).unwrap();
+    assert!(value.get_array(CHANS).unwrap().contains(&"aaa".into()));
+    assert!(value.get_message(SHARE_GROUPS).unwrap().get_str("bbb").unwrap() == "g");
+
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 0});
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 6});
+
+    let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_i32("n").unwrap() == 6);
+
+    // 没有 SESSION 的 SLOT 断开后不再保留订阅
+    drop(wire);
+    thread::sleep(Duration::from_millis(50));
+
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 5});
+
+    let wire = socket.connect(msg!{SLOT_ID: slot_id, SESSION: 100}, None, None).unwrap();
+    assert!(wire.wait(Some(Duration::from_millis(100))).is_err());
+
+    // 过期后不再保留订阅
+    let _ = wire.send(msg!{CHAN: ATTACH, VALUE: "aaa"});
+    assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    drop(wire);
+    thread::sleep(Duration::from_millis(300));
+
+    let _ = sender.send(msg!{CHAN: "aaa", "n": 6});
+
+    let wire = socket.connect(msg!{SLOT_ID: slot_id}, None, None).unwrap();
+    assert!(wire.wait(Some(Duration::from_millis(100))).is_err());
+
+    let _ = wire.send(msg!{CHAN: MINE});
+
+    let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_message(VALUE).unwrap().get_array(CHANS).unwrap().is_empty());
+}
+
+#[test]
+fn persistent_session_dir() {
+    let dir = std::env::temp_dir().join(format!("queen-test-{}", MessageId::new().to_hex()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut options = SocketOptions::new();
+    options.session_limit = 2;
+    options.session_dir = Some(dir.clone());
+    options.dead_letter = Some("dead".to_string());
+
+    let socket = Socket::with_options(MessageId::new(), options, ()).unwrap();
+
+    let dead = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = dead.send(msg!{CHAN: ATTACH, VALUE: "dead"});
+    assert!(dead.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let slot_id = MessageId::new();
+
+    let wire = socket.connect(msg!{SLOT_ID: slot_id, SESSION: true}, None, None).unwrap();
+
+    let _ = wire.send(msg!{CHAN: ATTACH, VALUE: "aaa"});
+    assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    drop(wire);
+    thread::sleep(Duration::from_millis(50));
+
+    let sender = socket.connect(msg!{}, None, None).unwrap();
+
+    for n in 0..3 {
+        let _ = sender.send(msg!{CHAN: "aaa", "n": n});
+    }
+
+    // 超过上限的消息进入死信频道
+    let recv = dead.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(Code::get(&recv) == Some(Code::OfflineQueueFull));
+    assert!(recv.get_message_id(TO).unwrap() == &slot_id);
+    assert!(recv.get_message(VALUE).unwrap().get_i32("n").unwrap() == 2);
+
+    assert!(dir.join(slot_id.to_hex()).exists());
+
+    let wire = socket.connect(msg!{SLOT_ID: slot_id}, None, None).unwrap();
+
+    for n in 0..2 {
+        let recv = wire.wait(Some(Duration::from_secs(1))).unwrap();
+        assert!(recv.get_i32("n").unwrap() == n);
+    }
+
+    assert!(!dir.join(slot_id.to_hex()).exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn will_message() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let ret = socket.connect(msg!{WILL: "abc"}, None, None);
+    assert!(matches!(ret, Err(Error::ErrorCode(Code::InvalidWillFieldType))));
+
+    let ret = socket.connect(msg!{WILL: {CHAN: ATTACH}}, None, None);
+    assert!(matches!(ret, Err(Error::ErrorCode(Code::InvalidWillFieldType))));
+
+    let observer = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = observer.send(msg!{CHAN: ATTACH, VALUE: "status"});
+    assert!(observer.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    // 连接时设置
+    let wire = socket.connect(msg!{WILL: {CHAN: "status", "state": "offline"}}, None, None).unwrap();
+    let wire_id = *wire.attr().get_message_id(SLOT_ID).unwrap();
+
+    drop(wire);
+
+    let recv = observer.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_str(CHAN).unwrap() == "status");
+    assert!(recv.get_str("state").unwrap() == "offline");
+    assert!(recv.get_message_id(FROM).unwrap() == &wire_id);
+
+    // 通过 WILL 设置
+    let wire = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = wire.send(msg!{CHAN: WILL, VALUE: 123});
+    assert!(Code::get(&wire.wait(Some(Duration::from_secs(1))).unwrap()) == Some(Code::InvalidWillFieldType));
+
+    let _ = wire.send(msg!{CHAN: WILL, VALUE: {CHAN: "status", "n": 1}});
+    assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let _ = wire.send(msg!{CHAN: WILL, VALUE: {CHAN: "status", "n": 2}});
+    assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    drop(wire);
+
+    let recv = observer.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_i32("n").unwrap() == 2);
+
+    // GOODBYE 后正常断开，不发布遗嘱消息
+    let wire = socket.connect(msg!{WILL: {CHAN: "status"}}, None, None).unwrap();
+
+    let _ = wire.send(msg!{CHAN: GOODBYE});
+    assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    drop(wire);
+
+    assert!(observer.wait(Some(Duration::from_millis(200))).is_err());
+
+    // TTL 和普通消息一样转换为 EXPIRE
+    let wire = socket.connect(msg!{WILL: {CHAN: "status", TTL: 1000}}, None, None).unwrap();
+    drop(wire);
+
+    let recv = observer.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get(TTL).is_none());
+    assert!(recv.get_i64(EXPIRE).is_ok());
+
+    let wire = socket.connect(msg!{WILL: {CHAN: "status", EXPIRE: 1000i64}}, None, None).unwrap();
+    drop(wire);
+
+    assert!(observer.wait(Some(Duration::from_millis(200))).is_err());
+
+    // 延迟投递
+    let wire = socket.connect(msg!{WILL: {CHAN: "status", DELAY: 300}}, None, None).unwrap();
+    let wire_id = *wire.attr().get_message_id(SLOT_ID).unwrap();
+    drop(wire);
+
+    assert!(observer.wait(Some(Duration::from_millis(150))).is_err());
+
+    let recv = observer.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_str(CHAN).unwrap() == "status");
+    assert!(recv.get_message_id(FROM).unwrap() == &wire_id);
+    assert!(recv.get(DELAY).is_none());
+}
+
+#[test]
+fn update_slot() {
+    let socket = Socket::new(MessageId::new(), ()).unwrap();
+
+    let observer = socket.connect(msg!{}, None, None).unwrap();
+
+    let _ = observer.send(msg!{CHAN: ATTACH, VALUE: SLOT_UPDATE});
+    assert!(observer.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let wire = socket.connect(msg!{"name": "a", "old": 1, TAGS: "region=eu"}, None, None).unwrap();
+    let wire_id = *wire.attr().get_message_id(SLOT_ID).unwrap();
+
+    let _ = wire.send(msg!{CHAN: ATTACH, VALUE: "aaa"});
+    assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    let sender = socket.connect(msg!{}, None, None).unwrap();
+
+    // 字段类型错误
+    let _ = wire.send(msg!{CHAN: UPDATE, ATTR: 123});
+    assert!(Code::get(&wire.wait(Some(Duration::from_secs(1))).unwrap()) == Some(Code::InvalidUpdateFieldType));
+
+    let _ = wire.send(msg!{CHAN: UPDATE, UNSET: [123]});
+    assert!(Code::get(&wire.wait(Some(Duration::from_secs(1))).unwrap()) == Some(Code::InvalidUpdateFieldType));
+
+    let _ = wire.send(msg!{CHAN: UPDATE, ADD_TAGS: 123});
+    assert!(Code::get(&wire.wait(Some(Duration::from_secs(1))).unwrap()) == Some(Code::InvalidTagsFieldType));
+
+    // 系统属性不能修改
+    let _ = wire.send(msg!{CHAN: UPDATE, ATTR: {SLOT_ID: MessageId::new()}});
+    assert!(Code::get(&wire.wait(Some(Duration::from_secs(1))).unwrap()) == Some(Code::PermissionDenied));
+
+    let _ = wire.send(msg!{CHAN: UPDATE, UNSET: [SLOT_ID]});
+    assert!(Code::get(&wire.wait(Some(Duration::from_secs(1))).unwrap()) == Some(Code::PermissionDenied));
+
+    assert!(wire.attr().get_message_id(SLOT_ID).unwrap() == &wire_id);
+
+    // 修改
+    let _ = wire.send(msg!{
+        CHAN: UPDATE,
+        ATTR: {"name": "b", "new": 2},
+        UNSET: ["old", "none"],
+        ADD_TAGS: ["beta", "region=eu"],
+        REMOVE_TAGS: "region=eu"
+    });
+    assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    {
+        let attr = wire.attr();
+        assert!(attr.get_str("name").unwrap() == "b");
+        assert!(attr.get_i32("new").unwrap() == 2);
+        assert!(attr.get("old").is_none());
+        assert!(attr.get_array(TAGS).unwrap().inner() == &vec![Value::from("beta")]);
+    }
+
+    // 事件只包含变化的部分
+    let recv = observer.wait(Some(Duration::from_secs(1))).unwrap();
+    assert!(recv.get_str(CHAN).unwrap() == SLOT_UPDATE);
+    assert!(recv.get_message_id(SLOT_ID).unwrap() == &wire_id);
+    assert!(recv.get_message(ATTR).unwrap() == &msg!{"name": "b", "new": 2});
+    assert!(recv.get_array(UNSET).unwrap().inner() == &vec![Value::from("old")]);
+    assert!(recv.get_array(ADD_TAGS).unwrap().inner() == &vec![Value::from("beta")]);
+    assert!(recv.get_array(REMOVE_TAGS).unwrap().inner() == &vec![Value::from("region=eu")]);
+
+    // 标签路由使用修改后的标签
+    let _ = sender.send(msg!{CHAN: "aaa", TAGS: "region=eu"});
+    assert!(wire.wait(Some(Duration::from_millis(100))).is_err());
+
+    let _ = sender.send(msg!{CHAN: "aaa", TAGS: "beta"});
+    assert!(wire.wait(Some(Duration::from_secs(1))).is_ok());
+
+    // 没有变化时不发送事件
+    let _ = wire.send(msg!{CHAN: UPDATE, ATTR: {"name": "b"}, ADD_TAGS: "beta"});
+    assert!(wire.wait(Some(Duration::from_secs(1))).unwrap().get_i32(CODE).unwrap() == 0);
+
+    assert!(observer.wait(Some(Duration::from_millis(200))).is_err());
+}